@@ -88,6 +88,16 @@ pub fn create_option_copy(
                         return false;
                     }
 
+                    #[doc = "Returns the names of the fields that are different in other (including"]
+                    #[doc = "the fields that are only set in one of them)"]
+                    pub fn diff(self: &Self, other: &#copy_name) -> Vec<&'static str> {
+                        let mut changed_fields = vec![];
+                        #(if self.#field_names != other.#field_names {
+                            changed_fields.push(stringify!(#field_names));
+                        })*
+                        changed_fields
+                    }
+
                     #[doc = "Returns Ok if all fields are set"]
                     pub fn all(self: &Self) -> Result<(), Vec<&str>> {
                         let mut has_all = true;
@@ -104,21 +114,73 @@ pub fn create_option_copy(
                     }
                 }
 
-                // Conversion from Lua value
-                impl<'lua> mlua::FromLua<'lua> for #copy_name {
-                    fn from_lua(lua_value: mlua::Value<'lua>, lua: &'lua mlua::Lua) -> mlua::Result<Self> {
+                impl #copy_name {
+                    #[doc = "The names of the fields, which are the keys accepted from Lua"]
+                    pub const FIELD_NAMES: &'static [&'static str] = &[#(stringify!(#field_names)),*];
+
+                    #[doc = "Returns an error message for the first of `names` that isn't a field or"]
+                    #[doc = "one of `extra_keys`"]
+                    pub fn check_keys<'a>(
+                        names: impl IntoIterator<Item = &'a str>,
+                        extra_keys: &[&str],
+                    ) -> Result<(), String> {
+                        for name in names {
+                            if !#copy_name::FIELD_NAMES.contains(&name) && !extra_keys.contains(&name) {
+                                return Err(format!("Unknown option '{}'", name));
+                            }
+                        }
+                        Ok(())
+                    }
+
+                    #[doc = "Convert from a Lua table like `from_lua`, but also accept `extra_keys`"]
+                    #[doc = "(which are ignored), for tables that have other options as well"]
+                    pub fn from_lua_with_extra_keys<'lua>(
+                        lua_value: mlua::Value<'lua>,
+                        extra_keys: &[&str],
+                    ) -> mlua::Result<Self> {
                         let mut ret = #copy_name::default();
                         match lua_value {
                             mlua::Value::Table(table) => {
-                                #(if table.contains_key("#field_names")? {
-                                    ret.#field_names = Some(table.get("#field_names")?);
+                                // Reject the keys that don't match a field, so that a typo isn't
+                                // silently ignored
+                                let keys = table
+                                    .clone()
+                                    .pairs::<mlua::Value, mlua::Value>()
+                                    .map(|pair| pair.map(|(key, _)| key))
+                                    .collect::<mlua::Result<Vec<_>>>()?;
+                                let mut names = Vec::with_capacity(keys.len());
+                                for key in &keys {
+                                    let name = match key {
+                                        mlua::Value::String(name) => name.to_str().ok(),
+                                        _ => None,
+                                    };
+                                    match name {
+                                        Some(name) => names.push(name),
+                                        None => {
+                                            return Err(mlua::Error::FromLuaConversionError {
+                                                from: "table",
+                                                to: stringify!(#copy_name),
+                                                message: Some(format!("Unknown option of type {}", key.type_name())),
+                                            });
+                                        }
+                                    }
+                                }
+                                if let Err(message) = #copy_name::check_keys(names, extra_keys) {
+                                    return Err(mlua::Error::FromLuaConversionError {
+                                        from: "table",
+                                        to: stringify!(#copy_name),
+                                        message: Some(message),
+                                    });
+                                }
+                                #(if table.contains_key(stringify!(#field_names))? {
+                                    ret.#field_names = Some(table.get(stringify!(#field_names))?);
                                 })*
                             }
                             mlua::Value::Nil => {}
                             _ => {
                                 return Err(mlua::Error::FromLuaConversionError {
                                     from: lua_value.type_name(),
-                                    to: "#copy_name",
+                                    to: stringify!(#copy_name),
                                     message: Some("Value is not a table".to_string()),
                                 });
                             }
@@ -127,6 +189,13 @@ pub fn create_option_copy(
                     }
                 }
 
+                // Conversion from Lua value
+                impl<'lua> mlua::FromLua<'lua> for #copy_name {
+                    fn from_lua(lua_value: mlua::Value<'lua>, _lua: &'lua mlua::Lua) -> mlua::Result<Self> {
+                        #copy_name::from_lua_with_extra_keys(lua_value, &[])
+                    }
+                }
+
                 impl Into<#source_name> for #copy_name {
                     fn into(self) -> #source_name {
                         let mut ret = #source_name::default();
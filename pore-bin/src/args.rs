@@ -1,37 +1,107 @@
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use std::{env, fs};
 
 use clap::ArgGroup;
 use clap::{App, Arg};
+use pore_core::file_type_globs;
 use pore_core::language::LanguageRef;
+use pore_core::version_info;
+use pore_core::DedupeBy;
+use pore_core::Encoding;
 use pore_core::FileIndexOptionsShape;
+use pore_core::LineTransform;
+use pore_core::PathSeparator;
+use pore_core::ResultOrder;
+use pore_core::ScoringModel;
+use pore_core::SnippetMode;
+use pore_core::SortBy;
 
 use crate::color_mode::ColorMode;
 use crate::config::SearchConfigOpt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CmdArg {
     Search,
     ListFiles,
     ListIndex,
-    Delete,
+    Delete { dry_run: bool },
+    Check,
+    Repair,
+    Compact,
+    TopTerms(usize),
+    TypeList,
+    ListCaches(CacheSort),
+    CachePath,
 }
 
-#[derive(Debug)]
+/// The order to print the cached indexes in for --list-caches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheSort {
+    /// By the indexed directory
+    Dir,
+    /// Largest first
+    Size,
+    /// Least recently updated first
+    Age,
+}
+
+#[derive(Debug, Clone)]
 pub struct GlobalConfig {
     pub index: FileIndexOptionsShape,
     pub search: SearchConfigOpt,
     pub command: CmdArg,
     pub query: Option<String>,
+    pub query_files: Vec<String>,
+    pub and_files: Vec<String>,
+    pub under: Option<String>,
+    pub fuzzy: Option<u8>,
+    pub regex: bool,
+    pub redact: Vec<String>,
+    /// Only search files that were modified less than this long ago
+    pub newer_than: Option<Duration>,
+    /// Only search files that were modified more than this long ago
+    pub older_than: Option<Duration>,
     pub query_path: PathBuf,
     pub search_dir: String,
     pub index_name: Option<String>,
+    pub stdin_files: bool,
+    /// Only re-index the files that match this glob before searching, instead of updating the
+    /// whole index
+    pub update_glob: Option<String>,
+    /// Print the number of files processed to stderr while updating the index
+    pub progress: bool,
+    /// Print the summary of the update (including the skipped files) to stderr
+    pub stats: bool,
+    pub watch: bool,
+    /// Answer search requests over this Unix socket instead of searching once (see `serve`)
+    pub serve: Option<PathBuf>,
 }
 
 pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
+    parse_args_from(env::args_os()).map_err(|err| match err.downcast::<clap::Error>() {
+        // Let clap print the usage errors (and --help and --version) and exit
+        Ok(err) => err.exit(),
+        Err(err) => err,
+    })
+}
+
+fn parse_args_from<I, T>(args: I) -> Result<GlobalConfig, anyhow::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let version_info = version_info();
+    let long_version = format!(
+        "{}\ntantivy {}\nindex format {}",
+        env!("CARGO_PKG_VERSION"),
+        version_info.tantivy,
+        version_info.index_format
+    );
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
+        .long_version(long_version.as_str())
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         // Index args
@@ -40,7 +110,7 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .short('i')
                 .long("index")
                 .takes_value(true)
-                .conflicts_with_all(&["in_memory", "no_memory", "hidden", "no_hidden", "follow_links", "no_follow_links", "language", "glob", "oglob", "glob_case_insensitive"])
+                .conflicts_with_all(&["in_memory", "no_memory", "hidden", "no_hidden", "follow_links", "no_follow_links", "language", "glob", "oglob", "glob_case_insensitive", "oglob_case_insensitive", "type", "type_not", "no_ignore", "ignore_root", "ignore_file_name", "extra_dir", "unignore", "store_contents", "store_line_map", "ngram", "case_sensitive", "no_positions", "encoding"])
                 .help("Use the specified index for querying (must be specified in the config file)")
         )
         .arg(
@@ -53,7 +123,7 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
             Arg::new("no_update")
                 .long("no-update")
                 .conflicts_with("update")
-                .help("Do not update the index before performing the query"),
+                .help("Do not update the index before performing the query (--rebuild still rebuilds it)"),
         )
         .arg(
             Arg::new("in_memory")
@@ -66,6 +136,14 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .conflicts_with("in_memory")
                 .help("Force the index to be saved to disk (overrides --in-memory)"),
         )
+        .arg(
+            Arg::new("cache_dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .conflicts_with("in_memory")
+                .help("Store the index in DIR instead of a directory in the cache that is derived from the searched directory"),
+        )
         .arg(
             Arg::new("hidden")
                 .long("hidden")
@@ -94,31 +172,52 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .long("language")
                 .validator(|a| LanguageRef::from_str(&a))
                 .takes_value(true)
-                .help("The language to use for parsing files"),
+                .help("The language to use for parsing files, or 'none' to match the words exactly instead of stemming them"),
         )
         .arg(
             Arg::new("glob")
                 .short('g')
                 .long("glob")
-                .help("Include or exclude files and directories for searching that match the given glob. This always overrides any other ignore logic. Multiple glob flags may be used. Precede a glob with a ! to exclude it.")
-                .value_delimiter(',')
-                .use_delimiter(true)
-                .require_delimiter(true)
-                .multiple_values(true)
+                .help("Include or exclude files and directories for searching that match the given glob. This always overrides any other ignore logic. Multiple glob flags may be used, or multiple globs separated by commas (commas inside of braces, like '*.{rs,toml}', or escaped with a backslash are part of the glob). Precede a glob with a ! to exclude it.")
+                .takes_value(true)
+                .multiple_occurrences(true)
         )
         .arg(
             Arg::new("oglob")
                 .long("oglob")
-                .help("Only search files that match this glob. Files that do not match any of these globs will be ignored.")
-                .value_delimiter(',')
-                .use_delimiter(true)
-                .require_delimiter(true)
-                .multiple_values(true)
+                .help("Only search files that match this glob. Files that do not match any of these globs will be ignored. Multiple globs may be separated by commas, like --glob.")
+                .takes_value(true)
+                .multiple_occurrences(true)
+        )
+        .arg(
+            Arg::new("type")
+                .short('t')
+                .long("type")
+                .takes_value(true)
+                .value_name("TYPE")
+                .multiple_occurrences(true)
+                .validator(|a| file_type_globs(&a).map(|_| ()))
+                .help("Only search files of this type (e.g. 'rust' or 'py'). Multiple type flags may be used. Use --type-list to print the known types.")
+        )
+        .arg(
+            Arg::new("type_not")
+                .short('T')
+                .long("type-not")
+                .takes_value(true)
+                .value_name("TYPE")
+                .multiple_occurrences(true)
+                .validator(|a| file_type_globs(&a).map(|_| ()))
+                .help("Do not search files of this type. Multiple type-not flags may be used.")
         )
         .arg(
             Arg::new("glob_case_insensitive")
                 .long("glob-case-insensitive")
-                .help("Patterns passed to --glob and --oglob will be matched in a case-insentive way.")
+                .help("Patterns passed to --glob will be matched in a case-insentive way.")
+        )
+        .arg(
+            Arg::new("oglob_case_insensitive")
+                .long("oglob-case-insensitive")
+                .help("Patterns passed to --oglob will be matched in a case-insentive way.")
         )
         // Index args that don't conflict with --index
         .arg(
@@ -129,10 +228,34 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"threads must be an unsigned integer".to_string()))
                 .help("The approximate number of threads to use. A value of 0 (which is the default) will choose the thread count using heuristics.")
         )
+        .arg(
+            Arg::new("writer_memory")
+                .long("writer-memory")
+                .takes_value(true)
+                .value_name("BYTES")
+                .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"writer-memory must be an unsigned integer".to_string()))
+                .help("The memory budget in bytes for building the index, which is split between the threads (default 50000000)")
+        )
+        .arg(
+            Arg::new("max_token_length")
+                .long("max-token-length")
+                .takes_value(true)
+                .value_name("BYTES")
+                .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"max-token-length must be an unsigned integer".to_string()))
+                .help("Do not index words longer than this (default 40). 0 means there is no limit.")
+        )
+        .arg(
+            Arg::new("max_filesize")
+                .long("max-filesize")
+                .takes_value(true)
+                .value_name("SIZE")
+                .validator(|a| parse_size(a).map(|_|()).map_err(|e| e.to_string()))
+                .help("Skip files larger than this size. Accepts suffixes like 10K, 10M, or 1G.")
+        )
         .arg(
             Arg::new("rebuild_index")
             .long("rebuild")
-            .help("Force rebuild the index before searching")
+            .help("Force rebuild the index before searching. This rebuilds the index even with --no-update.")
         )
 
         // Search args
@@ -143,6 +266,21 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"limit must be an unsigned integer".to_string()))
                 .help("Maximum number of files to return"),
         )
+        .arg(
+            Arg::new("offset")
+                .long("offset")
+                .takes_value(true)
+                .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"offset must be an unsigned integer".to_string()))
+                .help("Skip this many of the top results (use with --limit to page through the results)"),
+        )
+        .arg(
+            Arg::new("max_count")
+                .short('m')
+                .long("max-count")
+                .takes_value(true)
+                .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"max-count must be an unsigned integer".to_string()))
+                .help("Show at most this many matching lines of each file. The files with more matching lines are still returned (this doesn't count toward --limit)."),
+        )
         .arg(
             Arg::new("threshold")
                 .long("threshold")
@@ -150,11 +288,115 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .validator(|a| a.parse::<f32>().map(|_|()).map_err(|_|"threshold must be a floating point number".to_string()))
                 .help("Minimum score threshold for results"),
         )
+        .arg(
+            Arg::new("rel-threshold")
+                .long("rel-threshold")
+                .takes_value(true)
+                .validator(|a| a.parse::<f32>().map(|_|()).map_err(|_|"rel-threshold must be a floating point number".to_string()))
+                .help("Drop results that score below this fraction of the top result (e.g. 0.3)"),
+        )
+        .arg(
+            Arg::new("threshold_percentile")
+                .long("threshold-percentile")
+                .takes_value(true)
+                .value_name("P")
+                .validator(|a| a.parse::<f32>().map_err(|_|"threshold-percentile must be a number".to_string()).and_then(|p| if (0.0..=100.0).contains(&p) { Ok(()) } else { Err("threshold-percentile must be between 0 and 100".to_string()) }))
+                .help("Drop results that score below this percentile of the scores of all the matching files (e.g. 75 keeps the top quarter). Results must also pass --threshold and --rel-threshold."),
+        )
+        .arg(
+            Arg::new("scoring")
+                .long("scoring")
+                .takes_value(true)
+                .possible_values(&["bm25", "tf_idf"])
+                .help("The model used to score the results (default bm25)"),
+        )
+        .arg(
+            Arg::new("bm25_k1")
+                .long("bm25-k1")
+                .takes_value(true)
+                .validator(|a| a.parse::<f32>().map(|_|()).map_err(|_|"bm25-k1 must be a floating point number".to_string()))
+                .help("BM25 term frequency saturation (default 1.2). Higher values make repeated matches count for more."),
+        )
+        .arg(
+            Arg::new("bm25_b")
+                .long("bm25-b")
+                .takes_value(true)
+                .validator(|a| a.parse::<f32>().map(|_|()).map_err(|_|"bm25-b must be a floating point number".to_string()))
+                .help("BM25 length normalization from 0 to 1 (default 0.75). Lower values penalize long files less."),
+        )
+        .arg(
+            Arg::new("after_context")
+                .short('A')
+                .long("after-context")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"after-context must be an unsigned integer".to_string()))
+                .help("Show NUM lines after each matching line"),
+        )
+        .arg(
+            Arg::new("before_context")
+                .short('B')
+                .long("before-context")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"before-context must be an unsigned integer".to_string()))
+                .help("Show NUM lines before each matching line"),
+        )
+        .arg(
+            Arg::new("context")
+                .short('C')
+                .long("context")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|a| a.parse::<usize>().map(|_|()).map_err(|_|"context must be an unsigned integer".to_string()))
+                .help("Show NUM lines before and after each matching line. Overridden by -A and -B."),
+        )
+        .arg(
+            Arg::new("heading_regex")
+                .long("heading-regex")
+                .takes_value(true)
+                .help("(experimental) For each matching line, also print the closest line above it that matches this regex (e.g. '^\\s*(fn|def|class|func) ')"),
+        )
+        .arg(
+            Arg::new("dedupe")
+                .long("dedupe")
+                .takes_value(true)
+                .possible_values(&["none", "dir", "ext"])
+                .help("Only show the top-scoring file from each directory (dir) or with each file extension (ext)"),
+        )
+        .arg(
+            Arg::new("sort")
+                .long("sort")
+                .takes_value(true)
+                .possible_values(&["score", "matches", "path", "path_natural", "modified", "mtime", "modified_asc"])
+                .help("Sort the results by score (the default), by the number of matched words in the file, by path, or by modification time ('modified' or 'mtime' is newest first, 'modified_asc' is oldest first). 'path_natural' sorts the numbers in paths by their value, so 'file2' comes before 'file10'. The top results are chosen by score, and then sorted."),
+        )
+        .arg(
+            Arg::new("order")
+                .long("order")
+                .takes_value(true)
+                .possible_values(&["score", "location"])
+                .help("'location' prints every file that matches the search ordered by path, with the lines of each file in order (for stepping through all of the matches). --limit, --offset, and --sort are ignored. The default is 'score'."),
+        )
+        .arg(
+            Arg::new("path_separator")
+                .long("path-separator")
+                .takes_value(true)
+                .possible_values(&["native", "slash"])
+                .help("The separator to use in the printed paths. 'slash' always uses '/', even on Windows."),
+        )
+        .arg(
+            Arg::new("snippet_mode")
+                .long("snippet-mode")
+                .takes_value(true)
+                .possible_values(&["lines", "snippet"])
+                .help("How to find the matching text. 'snippet' reads it from the index instead of the files (requires --store-contents)"),
+        )
         .arg(
             Arg::new("json")
                 .long("json")
-                .conflicts_with("commands")
-                .help("Print the results as json"),
+                .conflicts_with_all(&["delete", "check", "repair", "compact", "top_terms", "type_list", "list_caches", "cache_path"])
+                .help("Print the results as newline-delimited json records (begin, match, end, and a trailing summary). With --files, print a single record with an array of the paths, and with --indexes, a single record with the location and options of the index."),
         )
         .arg(
             Arg::new("files_with_matches")
@@ -163,11 +405,101 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .conflicts_with("commands")
                 .help("Print out the files that match the search (not the matching lines)."),
         )
+        .arg(
+            Arg::new("no_heading")
+                .long("no-heading")
+                .conflicts_with("commands")
+                .help("Print the file path at the start of each matching line (path:line:text), instead of once above the lines of each file"),
+        )
+        .arg(
+            Arg::new("null")
+                .short('0')
+                .long("null")
+                .conflicts_with("commands")
+                .help("With --files-with-matches, print a NUL byte after each path instead of a newline (for xargs -0). Does nothing without --files-with-matches."),
+        )
+        .arg(
+            Arg::new("count")
+                .short('c')
+                .long("count")
+                .conflicts_with("commands")
+                .help("Print the number of matching lines in each file that matches the search (path:count)"),
+        )
+        .arg(
+            Arg::new("count_matches")
+                .long("count-matches")
+                .conflicts_with_all(&["commands", "count"])
+                .help("Print the total number of matching lines across all of the files that match the search. This counts every matching file, regardless of --limit and --offset."),
+        )
         .arg(
             Arg::new("no_ignore")
                 .long("no-ignore")
                 .help("Don't respect .gitignore files"),
         )
+        .arg(
+            Arg::new("ignore_root")
+                .long("ignore-root")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Read ignore files from the parent directories only up to DIR, even if it is not the root of a git repository. A relative DIR is relative to the searched directory, like ignore_root in the config file."),
+        )
+        .arg(
+            Arg::new("unignore")
+                .long("unignore")
+                .takes_value(true)
+                .value_name("GLOB")
+                .multiple_occurrences(true)
+                .help("Search the files that match this glob even if the ignore files exclude them (e.g. 'vendor/'). Unlike --glob, the other files are still searched. Multiple unignore flags may be used."),
+        )
+        .arg(
+            Arg::new("extra_dir")
+                .long("extra-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .multiple_occurrences(true)
+                .help("Index this directory as well as the searched directory, so one index covers both. Multiple directories may be used. The index is rebuilt when these change."),
+        )
+        .arg(
+            Arg::new("ignore_file_name")
+                .long("ignore-file-name")
+                .takes_value(true)
+                .value_name("NAME")
+                .multiple_occurrences(true)
+                .help("Respect ignore files with this name (e.g. '.poreignore'), in addition to .gitignore and .ignore. Multiple names may be used."),
+        )
+        .arg(
+            Arg::new("store_contents")
+                .long("store-contents")
+                .help("Store the file contents in the index, so --snippet-mode=snippet can be used"),
+        )
+        .arg(
+            Arg::new("store_line_map")
+                .long("store-line-map")
+                .help("Store the number of words on each line in the index, which makes finding the matching lines of large files faster"),
+        )
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .takes_value(true)
+                .possible_values(&["strict", "lossy"])
+                .help("How to read files that aren't valid UTF-8. 'strict' (the default) skips them, and 'lossy' indexes them with the invalid bytes replaced. The index is rebuilt when this changes."),
+        )
+        .arg(
+            Arg::new("no_positions")
+                .long("no-positions")
+                .help("Don't record the positions of the words in the index, which makes it much smaller. Phrase queries don't work, and finding the matching lines is slower."),
+        )
+        .arg(
+            Arg::new("ngram")
+                .long("ngram")
+                .help("Index the file contents as n-grams, so queries can match part of a word"),
+        )
+        .arg(
+            Arg::new("case_sensitive")
+                .short('s')
+                .long("case-sensitive")
+                .help("Search case-sensitively. The index is rebuilt when this changes."),
+        )
         .arg(
             Arg::new("color")
                 .long("color")
@@ -179,13 +511,147 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
    The possible values for this flag are:
 
        never    Colors will never be used.
-       auto     Auto-detect if the terminal supports colors (default).
+       auto     Auto-detect if the terminal supports colors (default). Setting NO_COLOR
+                turns colors off, and CLICOLOR_FORCE turns them on when piped.
        always   Colors will always be used regardless of where output is sent.
        ansi     Like 'always', but emits ANSI escapes (even in a Windows console).")
         )
+        .arg(
+            Arg::new("query_file")
+                .long("query-file")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .conflicts_with("commands")
+                .help("Read a query from a file instead of passing it as an argument, so the first positional argument is the directory to search. May be used multiple times, in which case results match any of the queries.")
+        )
+        .arg(
+            Arg::new("and_file")
+                .long("and-file")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .conflicts_with("commands")
+                .help("Read a query from a file that results must match. May be used multiple times. These are combined with the positional query (or the --query-file queries) using AND.")
+        )
+        .arg(
+            Arg::new("fuzzy")
+                .short('f')
+                .long("fuzzy")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with("commands")
+                .validator(|a| a.parse::<u8>().map_err(|_|"fuzzy must be an unsigned integer".to_string()).and_then(|n| if n <= 2 { Ok(()) } else { Err("fuzzy must be at most 2".to_string()) }))
+                .help("Match each word of the query with up to N typos (at most 2), instead of parsing it as a query")
+        )
+        .arg(
+            Arg::new("and")
+                .long("and")
+                .visible_alias("all")
+                .conflicts_with_all(&["commands", "or"])
+                .help("Only match files that contain all of the words of the query. By default, files that contain any of the words match.")
+        )
+        .arg(
+            Arg::new("or")
+                .long("or")
+                .conflicts_with("commands")
+                .help("Match files that contain any of the words of the query (the default, unless match_all is set in the config file)")
+        )
+        .arg(
+            Arg::new("regex")
+                .short('e')
+                .long("regex")
+                .conflicts_with_all(&["commands", "fuzzy"])
+                .help("Treat the query as a regex that matches whole indexed words, instead of parsing it as a query. The words are lowercased and stemmed, and a regex can't match across words.")
+        )
+        .arg(
+            Arg::new("redact")
+                .long("redact")
+                .takes_value(true)
+                .value_name("PATTERN")
+                .multiple_occurrences(true)
+                .validator(|a| LineTransform::redact(a).map(|_| ()).map_err(|e| e.to_string()))
+                .help("Mask the text that matches this regex in the printed lines with '*'s (e.g. to hide secrets in logs). This only changes the output: the query still matches the original text. May be used multiple times.")
+        )
+        .arg(
+            Arg::new("newer_than")
+                .long("newer-than")
+                .takes_value(true)
+                .value_name("DURATION")
+                .validator(|a| parse_duration(a).map(|_|()).map_err(|e| e.to_string()))
+                .help("Only show files that were modified less than this long ago. The duration is a number with a suffix of s, m, h, d, or w (e.g. '7d').")
+        )
+        .arg(
+            Arg::new("older_than")
+                .long("older-than")
+                .takes_value(true)
+                .value_name("DURATION")
+                .validator(|a| parse_duration(a).map(|_|()).map_err(|e| e.to_string()))
+                .help("Only show files that were modified more than this long ago (see --newer-than)")
+        )
+        .arg(
+            Arg::new("filename_boost")
+                .long("filename-boost")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|a| a.parse::<f32>().map(|_|()).map_err(|_|"filename-boost must be a number".to_string()))
+                .help("Boost the score of files whose path contains words of the query by a factor of N")
+        )
+        .arg(
+            Arg::new("recency_boost")
+                .long("recency-boost")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|a| a.parse::<f32>().map(|_|()).map_err(|_|"recency-boost must be a number".to_string()))
+                .help("Multiply the score of recently modified files by up to 1 + N. The boost halves every 30 days.")
+        )
+        .arg(
+            Arg::new("under")
+                .long("under")
+                .takes_value(true)
+                .value_name("DIR")
+                .conflicts_with("commands")
+                .help("Only return matches from files inside this directory (relative to the search directory)")
+        )
+        .arg(
+            Arg::new("progress")
+                .long("progress")
+                .help("Print a running count of the files processed to stderr while updating the index")
+        )
+        .arg(
+            Arg::new("stats")
+                .long("stats")
+                .help("After updating the index, print the number of files that were added and removed to stderr, and list the files that were skipped (and why)")
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .conflicts_with_all(&["commands", "stdin_files", "no_update"])
+                .help("Keep running after the search, and update the index and search again whenever the searched files change")
+        )
+        .arg(
+            Arg::new("serve")
+                .long("serve")
+                .takes_value(true)
+                .value_name("SOCKET")
+                .conflicts_with_all(&["commands", "stdin_files", "watch"])
+                .help("Keep the index open and answer search requests over a Unix socket at this path. Each request is a line of JSON, and each response is a line of JSON with the results (see the README for the protocol).")
+        )
+        .arg(
+            Arg::new("update_glob")
+                .long("update-glob")
+                .takes_value(true)
+                .value_name("PATTERN")
+                .conflicts_with_all(&["commands", "stdin_files", "rebuild_index"])
+                .help("Before searching, re-index only the files that match this glob (e.g. '*.rs') instead of updating the whole index. This is faster for a large tree when you know which files changed. The next full update still picks up the other changes.")
+        )
+        .arg(
+            Arg::new("stdin_files")
+                .long("stdin-files")
+                .conflicts_with_all(&["commands", "rebuild_index", "update"])
+                .help("Search file contents read from stdin instead of the files on disk. The input is a list of NUL-separated path and content pairs (path\\0content\\0...). Useful for searching staged changes in git hooks.")
+        )
         .group(
             ArgGroup::new("commands")
-             .args(&["files", "indexes", "delete"])
+             .args(&["files", "indexes", "delete", "check", "repair", "compact", "top_terms", "type_list", "list_caches", "cache_path"])
             )
         .arg(
             Arg::new("files")
@@ -202,9 +668,67 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
                 .long("delete")
                 .help("Delete the cached index files for the directory (if any)")
         )
-        .arg(Arg::new("query"))
-        .arg(Arg::new("dir"))
-        .get_matches();
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .requires("delete")
+                .help("With --delete, print the index files that would be deleted without deleting them")
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .help("Check the cached index for the directory for problems (does not modify the index)")
+        )
+        .arg(
+            Arg::new("repair")
+                .long("repair")
+                .help("Check the cached index for the directory, and rebuild it if there are any problems")
+        )
+        .arg(
+            Arg::new("compact")
+                .long("compact")
+                .help("Merge the segments of the index into one, which makes searching an index that has been updated many times faster")
+        )
+        .arg(
+            Arg::new("top_terms")
+                .long("top-terms")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|a| a.parse::<usize>().map(|_| ()).map_err(|_| "top-terms must be an unsigned integer".to_string()))
+                .help("Print the N words that are in the most files, and the number of files they are in. The words are lowercased and stemmed like the index. This reads every word in the index, so it may be slow for a large index.")
+        )
+        .arg(
+            Arg::new("type_list")
+                .long("type-list")
+                .help("Print the file types that can be used with --type and --type-not, and their globs")
+        )
+        .arg(
+            Arg::new("list_caches")
+                .long("list-caches")
+                .help("Print the indexes that are cached on disk, with their size, when they were last updated, and the directory they index")
+        )
+        .arg(
+            Arg::new("cache_path")
+                .long("cache-path")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .value_name("DIR")
+                .help("Print the directory that the index of DIR (or the current directory) is cached in, without building the index. Use with --index to get the path of a named index.")
+        )
+        .arg(
+            Arg::new("cache_sort")
+                .long("cache-sort")
+                .takes_value(true)
+                .possible_values(&["dir", "size", "age"])
+                .requires("list_caches")
+                .help("The order of --list-caches: by directory (the default), largest first (size), or least recently updated first (age)")
+        )
+        .arg(Arg::new("query").help("The search query, or - to read it from stdin (the directory to search when --query-file is used)"))
+        // With --query-file the directory is the first positional argument, so there can't be a
+        // second one
+        .arg(Arg::new("dir").conflicts_with("query_file"))
+        .try_get_matches_from(args)?;
 
     let mut index = FileIndexOptionsShape::default();
     // Parse index options
@@ -226,16 +750,65 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
     if matches.is_present("no_ignore") {
         index.ignore_files = Some(false);
     }
+    if matches.is_present("ignore_root") {
+        // Resolved by the index, relative to the searched directory
+        index.ignore_root = Some(matches.value_of("ignore_root").unwrap().to_string());
+    }
+    if matches.is_present("unignore") {
+        index.unignore = Some(
+            matches
+                .values_of("unignore")
+                .unwrap()
+                .map(|s| s.to_string())
+                .collect(),
+        );
+    }
+    if matches.is_present("extra_dir") {
+        let mut extra_dirs = Vec::new();
+        for dir in matches.values_of("extra_dir").unwrap() {
+            extra_dirs.push(fs::canonicalize(dir)?.to_string_lossy().to_string());
+        }
+        index.extra_dirs = Some(extra_dirs);
+    }
+    if matches.is_present("ignore_file_name") {
+        index.ignore_file_names = Some(
+            matches
+                .values_of("ignore_file_name")
+                .unwrap()
+                .map(|s| s.to_string())
+                .collect(),
+        );
+    }
+    if matches.is_present("store_contents") {
+        index.store_contents = Some(true);
+    }
+    if matches.is_present("store_line_map") {
+        index.store_line_map = Some(true);
+    }
+    if matches.is_present("encoding") {
+        index.encoding = Some(Encoding::from_str(matches.value_of("encoding").unwrap())?);
+    }
+    if matches.is_present("no_positions") {
+        index.record_positions = Some(false);
+    }
+    if matches.is_present("ngram") {
+        index.ngram = Some(true);
+    }
+    if matches.is_present("case_sensitive") {
+        index.case_sensitive = Some(true);
+    }
     if matches.is_present("glob_case_insensitive") {
         index.glob_case_insensitive = Some(true);
     }
+    if matches.is_present("oglob_case_insensitive") {
+        index.oglob_case_insensitive = Some(true);
+    }
     if matches.is_present("glob") {
         index.glob = Some(
             matches
                 .values_of("glob")
                 .unwrap()
-                .into_iter()
-                .map(|s| s.to_string())
+                .flat_map(split_globs)
                 .collect(),
         );
     }
@@ -244,7 +817,24 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
             matches
                 .values_of("oglob")
                 .unwrap()
-                .into_iter()
+                .flat_map(split_globs)
+                .collect(),
+        );
+    }
+    if matches.is_present("type") {
+        index.types = Some(
+            matches
+                .values_of("type")
+                .unwrap()
+                .map(|s| s.to_string())
+                .collect(),
+        );
+    }
+    if matches.is_present("type_not") {
+        index.types_not = Some(
+            matches
+                .values_of("type_not")
+                .unwrap()
                 .map(|s| s.to_string())
                 .collect(),
         );
@@ -252,6 +842,25 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
     if matches.is_present("threads") {
         index.threads = Some(matches.value_of("threads").unwrap().parse::<usize>()?);
     }
+    if matches.is_present("writer_memory") {
+        index.writer_memory = Some(
+            matches
+                .value_of("writer_memory")
+                .unwrap()
+                .parse::<usize>()?,
+        );
+    }
+    if matches.is_present("max_token_length") {
+        index.max_token_length = Some(
+            matches
+                .value_of("max_token_length")
+                .unwrap()
+                .parse::<usize>()?,
+        );
+    }
+    if matches.is_present("max_filesize") {
+        index.max_filesize = Some(parse_size(matches.value_of("max_filesize").unwrap())?);
+    }
 
     // Parse search options
     let mut search = SearchConfigOpt::default();
@@ -261,12 +870,106 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
     if matches.is_present("limit") {
         search.limit = Some(matches.value_of("limit").unwrap().parse::<usize>()?);
     }
+    if matches.is_present("offset") {
+        search.offset = Some(matches.value_of("offset").unwrap().parse::<usize>()?);
+    }
+    if matches.is_present("max_count") {
+        search.max_count = Some(matches.value_of("max_count").unwrap().parse::<usize>()?);
+    }
+    if matches.is_present("filename_boost") {
+        search.filename_boost = Some(matches.value_of("filename_boost").unwrap().parse::<f32>()?);
+    }
+    if matches.is_present("recency_boost") {
+        search.recency_boost = Some(matches.value_of("recency_boost").unwrap().parse::<f32>()?);
+    }
+    if matches.is_present("and") {
+        search.match_all = Some(true);
+    } else if matches.is_present("or") {
+        search.match_all = Some(false);
+    }
     if matches.is_present("threshold") {
         search.threshold = Some(matches.value_of("threshold").unwrap().parse::<f32>()?);
     }
+    if matches.is_present("rel-threshold") {
+        search.rel_threshold = Some(matches.value_of("rel-threshold").unwrap().parse::<f32>()?);
+    }
+    if matches.is_present("threshold_percentile") {
+        search.threshold_percentile = Some(
+            matches
+                .value_of("threshold_percentile")
+                .unwrap()
+                .parse::<f32>()?,
+        );
+    }
+    if matches.is_present("scoring") {
+        search.scoring = Some(ScoringModel::from_str(
+            matches.value_of("scoring").unwrap(),
+        )?);
+    }
+    if matches.is_present("bm25_k1") {
+        search.bm25_k1 = Some(matches.value_of("bm25_k1").unwrap().parse::<f32>()?);
+    }
+    if matches.is_present("bm25_b") {
+        search.bm25_b = Some(matches.value_of("bm25_b").unwrap().parse::<f32>()?);
+    }
+    if matches.is_present("context") {
+        let context = matches.value_of("context").unwrap().parse::<usize>()?;
+        search.before_context = Some(context);
+        search.after_context = Some(context);
+    }
+    if matches.is_present("before_context") {
+        search.before_context = Some(
+            matches
+                .value_of("before_context")
+                .unwrap()
+                .parse::<usize>()?,
+        );
+    }
+    if matches.is_present("after_context") {
+        search.after_context = Some(
+            matches
+                .value_of("after_context")
+                .unwrap()
+                .parse::<usize>()?,
+        );
+    }
+    if matches.is_present("heading_regex") {
+        search.heading_regex = Some(matches.value_of("heading_regex").unwrap().to_string());
+    }
+    if matches.is_present("dedupe") {
+        search.dedupe_by = Some(DedupeBy::from_str(matches.value_of("dedupe").unwrap())?);
+    }
+    if matches.is_present("sort") {
+        search.sort_by = Some(SortBy::from_str(matches.value_of("sort").unwrap())?);
+    }
+    if matches.is_present("order") {
+        search.order = Some(ResultOrder::from_str(matches.value_of("order").unwrap())?);
+    }
+    if matches.is_present("path_separator") {
+        search.path_separator = Some(PathSeparator::from_str(
+            matches.value_of("path_separator").unwrap(),
+        )?);
+    }
+    if matches.is_present("snippet_mode") {
+        search.snippet_mode = Some(SnippetMode::from_str(
+            matches.value_of("snippet_mode").unwrap(),
+        )?);
+    }
     if matches.is_present("files_with_matches") {
         search.filename_only = Some(true);
     }
+    if matches.is_present("no_heading") {
+        search.no_heading = Some(true);
+    }
+    if matches.is_present("null") {
+        search.null = Some(true);
+    }
+    if matches.is_present("count") {
+        search.count = Some(true);
+    }
+    if matches.is_present("count_matches") {
+        search.count_matches = Some(true);
+    }
     if matches.is_present("color") {
         let preference = matches.value_of("color").unwrap_or("auto");
         search.color = Some(ColorMode::from_str(preference).unwrap());
@@ -284,16 +987,50 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
     } else if matches.is_present("no_memory") {
         search.in_memory = Some(false);
     }
+    if let Some(dir) = matches.value_of("cache_dir") {
+        // Relative to the working directory, not the searched directory like in the config file
+        search.cache_dir = Some(env::current_dir()?.join(dir).to_string_lossy().to_string());
+    }
 
     let mut command = CmdArg::Search;
     if matches.is_present("delete") {
-        command = CmdArg::Delete;
+        command = CmdArg::Delete {
+            dry_run: matches.is_present("dry_run"),
+        };
     } else if matches.is_present("files") {
         command = CmdArg::ListFiles;
     } else if matches.is_present("indexes") {
         command = CmdArg::ListIndex;
+    } else if matches.is_present("check") {
+        command = CmdArg::Check;
+    } else if matches.is_present("repair") {
+        command = CmdArg::Repair;
+    } else if matches.is_present("compact") {
+        command = CmdArg::Compact;
+    } else if let Some(n) = matches.value_of("top_terms") {
+        command = CmdArg::TopTerms(n.parse()?);
+    } else if matches.is_present("type_list") {
+        command = CmdArg::TypeList;
+    } else if matches.is_present("cache_path") {
+        command = CmdArg::CachePath;
+    } else if matches.is_present("list_caches") {
+        command = CmdArg::ListCaches(match matches.value_of("cache_sort") {
+            Some("size") => CacheSort::Size,
+            Some("age") => CacheSort::Age,
+            _ => CacheSort::Dir,
+        });
     }
-    let search_dir = matches.value_of("dir").unwrap_or("").to_string();
+    // The queries of --query-file take the place of the positional query
+    let (query, dir) = if matches.is_present("query_file") {
+        (None, matches.value_of("query"))
+    } else {
+        (matches.value_of("query"), matches.value_of("dir"))
+    };
+    let search_dir = matches
+        .value_of("cache_path")
+        .or(dir)
+        .unwrap_or("")
+        .to_string();
     let query_path = if search_dir.is_empty() {
         env::current_dir()?
     } else {
@@ -304,9 +1041,246 @@ pub fn parse_args() -> Result<GlobalConfig, anyhow::Error> {
         index,
         search,
         command,
-        query: matches.value_of("query").map(|s| s.to_string()),
+        query: query.map(|s| s.to_string()),
+        query_files: matches
+            .values_of("query_file")
+            .map(|v| v.map(|s| s.to_string()).collect())
+            .unwrap_or_default(),
+        and_files: matches
+            .values_of("and_file")
+            .map(|v| v.map(|s| s.to_string()).collect())
+            .unwrap_or_default(),
+        under: matches.value_of("under").map(|s| s.to_string()),
+        fuzzy: matches
+            .value_of("fuzzy")
+            .map(|s| s.parse::<u8>())
+            .transpose()?,
+        regex: matches.is_present("regex"),
+        redact: matches
+            .values_of("redact")
+            .map(|v| v.map(|s| s.to_string()).collect())
+            .unwrap_or_default(),
+        newer_than: matches
+            .value_of("newer_than")
+            .map(parse_duration)
+            .transpose()?,
+        older_than: matches
+            .value_of("older_than")
+            .map(parse_duration)
+            .transpose()?,
         query_path,
         search_dir,
         index_name: matches.value_of("index").map(|s| s.to_string()),
+        stdin_files: matches.is_present("stdin_files"),
+        update_glob: matches.value_of("update_glob").map(|s| s.to_string()),
+        progress: matches.is_present("progress"),
+        stats: matches.is_present("stats"),
+        watch: matches.is_present("watch"),
+        serve: matches.value_of("serve").map(PathBuf::from),
     });
 }
+
+/// Parse a human-friendly size, like `10M`, into bytes. The suffixes are powers of 1024.
+fn parse_size(size: &str) -> Result<u64, anyhow::Error> {
+    let size = size.trim().to_uppercase();
+    let size = size.strip_suffix('B').unwrap_or(&size);
+    let (number, multiplier) = match size.chars().last() {
+        Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    let number = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Invalid size '{}'", size))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Size '{}' is too large", size))
+}
+
+/// Split a comma-separated list of globs. Commas inside of braces (e.g. `*.{rs,toml}`) or escaped
+/// with a backslash are part of the glob.
+fn split_globs(globs: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut glob = String::new();
+    let mut brace_depth = 0;
+    let mut chars = globs.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // Other escapes are left for the glob matcher
+            '\\' => match chars.next() {
+                Some(',') => glob.push(','),
+                Some(next) => {
+                    glob.push(c);
+                    glob.push(next);
+                }
+                None => glob.push(c),
+            },
+            '{' => {
+                brace_depth += 1;
+                glob.push(c);
+            }
+            '}' => {
+                brace_depth = (brace_depth - 1).max(0);
+                glob.push(c);
+            }
+            ',' if brace_depth == 0 => split.push(std::mem::take(&mut glob)),
+            _ => glob.push(c),
+        }
+    }
+    split.push(glob);
+    split.retain(|glob| !glob.is_empty());
+    split
+}
+
+/// Parse a human-friendly duration, like `7d`. The suffixes are s (seconds), m (minutes), h
+/// (hours), d (days), and w (weeks).
+fn parse_duration(duration: &str) -> Result<Duration, anyhow::Error> {
+    let duration = duration.trim().to_lowercase();
+    let seconds = match duration.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => bail!(
+            "Invalid duration '{}': missing a unit (s, m, h, d, or w)",
+            duration
+        ),
+    };
+    let number = duration[..duration.len() - 1]
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Invalid duration '{}'", duration))?;
+    let seconds = number
+        .checked_mul(seconds)
+        .ok_or_else(|| anyhow!("Duration '{}' is too long", duration))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{parse_args_from, parse_duration, parse_size, split_globs, CmdArg};
+    use crate::config::SearchConfig;
+
+    #[test]
+    fn parses_sizes_with_suffixes() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("10K").unwrap(), 10 * 1024);
+        assert_eq!(parse_size("10m").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("1GB").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("ten").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size(&format!("{}G", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(
+            parse_duration("2H").unwrap(),
+            Duration::from_secs(2 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 24 * 60 * 60)
+        );
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration(&format!("{}w", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn splits_globs_outside_of_braces() {
+        assert_eq!(split_globs("*.rs,!target/"), vec!["*.rs", "!target/"]);
+        assert_eq!(split_globs("*.{rs,toml},*.md"), vec!["*.{rs,toml}", "*.md"]);
+        assert_eq!(split_globs(r"a\,b,c"), vec!["a,b", "c"]);
+        assert_eq!(split_globs(r"\*.txt,"), vec![r"\*.txt"]);
+    }
+
+    #[test]
+    fn query_file_conflicts_with_query() {
+        let conf = parse_args_from(&["pore", "--query-file", "query.txt"]).unwrap();
+        assert_eq!(conf.query_files, vec!["query.txt"]);
+        assert_eq!(conf.query, None);
+        // The first positional argument is the directory to search
+        let tmpdir = tempfile::tempdir().unwrap();
+        let somedir = tmpdir.path().to_string_lossy().to_string();
+        let conf = parse_args_from(&["pore", "--query-file", "query.txt", &somedir]).unwrap();
+        assert_eq!(conf.query, None);
+        assert_eq!(
+            conf.query_path,
+            std::fs::canonicalize(tmpdir.path()).unwrap()
+        );
+        assert!(
+            parse_args_from(&["pore", "--query-file", "query.txt", "needle", &somedir]).is_err()
+        );
+    }
+
+    #[test]
+    fn ignore_root_is_relative_to_the_searched_dir() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path().to_string_lossy().to_string();
+        let conf = parse_args_from(&["pore", "--ignore-root", "..", "needle", &dir]).unwrap();
+        // Passed on as it is, so the index resolves it against the searched dir like the config
+        assert_eq!(conf.index.ignore_root.as_deref(), Some(".."));
+    }
+
+    #[test]
+    fn dry_run_requires_delete() {
+        let conf = parse_args_from(&["pore", "--delete", "--dry-run"]).unwrap();
+        assert!(matches!(conf.command, CmdArg::Delete { dry_run: true }));
+        let conf = parse_args_from(&["pore", "--delete"]).unwrap();
+        assert!(matches!(conf.command, CmdArg::Delete { dry_run: false }));
+        assert!(parse_args_from(&["pore", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn cache_path_takes_an_optional_dir() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path().to_string_lossy().to_string();
+        let conf = parse_args_from(&["pore", "--cache-path", &dir]).unwrap();
+        assert!(matches!(conf.command, CmdArg::CachePath));
+        assert_eq!(
+            conf.query_path,
+            std::fs::canonicalize(tmpdir.path()).unwrap()
+        );
+        assert_eq!(conf.query, None);
+        let conf = parse_args_from(&["pore", "--cache-path"]).unwrap();
+        assert!(matches!(conf.command, CmdArg::CachePath));
+        assert_eq!(conf.query_path, std::env::current_dir().unwrap());
+    }
+
+    #[test]
+    fn cache_dir_is_relative_to_working_dir() {
+        let conf = parse_args_from(&["pore", "--cache-dir", "index", "needle"]).unwrap();
+        let search: SearchConfig = conf.search.into();
+        assert_eq!(
+            PathBuf::from(search.cache_dir),
+            std::env::current_dir().unwrap().join("index")
+        );
+        assert!(
+            parse_args_from(&["pore", "--cache-dir", "index", "--in-memory", "needle"]).is_err()
+        );
+    }
+
+    #[test]
+    fn rebuild_overrides_no_update() {
+        let index_update = |args: &[&str]| {
+            let conf = parse_args_from(args).unwrap();
+            let search: SearchConfig = conf.search.into();
+            search.index_update()
+        };
+        assert_eq!(index_update(&["pore", "needle"]), Some(false));
+        assert_eq!(index_update(&["pore", "--no-update", "needle"]), None);
+        assert_eq!(index_update(&["pore", "--rebuild", "needle"]), Some(true));
+        assert_eq!(
+            index_update(&["pore", "--rebuild", "--no-update", "needle"]),
+            Some(true)
+        );
+    }
+}
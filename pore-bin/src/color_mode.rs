@@ -1,9 +1,10 @@
+use std::env;
 use std::str::FromStr;
 
 use serde::Deserialize;
-use termcolor::ColorChoice;
+use termcolor::{Color, ColorChoice, ColorSpec};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum ColorMode {
     Auto,
@@ -23,6 +24,21 @@ impl Into<ColorChoice> for ColorMode {
     }
 }
 
+impl ColorMode {
+    /// Resolve `auto` from the environment and whether stdout is a terminal (see
+    /// `auto_color_mode`). The other modes are returned as they are.
+    pub fn resolve(&self) -> ColorMode {
+        match self {
+            ColorMode::Auto => auto_color_mode(
+                env::var_os("NO_COLOR").is_some(),
+                env::var_os("CLICOLOR_FORCE").is_some_and(|force| force != "0"),
+                atty::is(atty::Stream::Stdout),
+            ),
+            mode => mode.clone(),
+        }
+    }
+}
+
 impl FromStr for ColorMode {
     type Err = anyhow::Error;
 
@@ -30,19 +46,28 @@ impl FromStr for ColorMode {
         match s.to_lowercase().as_str() {
             "always" => Ok(ColorMode::Always),
             "ansi" => Ok(ColorMode::Ansi),
-            "auto" => {
-                if atty::is(atty::Stream::Stdout) {
-                    Ok(ColorMode::Auto)
-                } else {
-                    Ok(ColorMode::Never)
-                }
-            }
+            "auto" => Ok(ColorMode::Auto),
             "never" => Ok(ColorMode::Never),
             _ => Err(anyhow!("Invalid color value '{}'", s)),
         }
     }
 }
 
+/// Resolve the `auto` color mode. Setting `NO_COLOR` (to anything) turns colors off even on a
+/// terminal, and setting `CLICOLOR_FORCE` (to anything but 0) turns them on even when the output is
+/// piped. `NO_COLOR` takes precedence.
+fn auto_color_mode(no_color: bool, clicolor_force: bool, is_tty: bool) -> ColorMode {
+    if no_color {
+        ColorMode::Never
+    } else if clicolor_force {
+        ColorMode::Always
+    } else if is_tty {
+        ColorMode::Auto
+    } else {
+        ColorMode::Never
+    }
+}
+
 impl<'lua> mlua::FromLua<'lua> for ColorMode {
     fn from_lua(lua_value: mlua::Value<'lua>, _lua: &'lua mlua::Lua) -> mlua::Result<Self> {
         return match &lua_value {
@@ -61,3 +86,104 @@ impl<'lua> mlua::FromLua<'lua> for ColorMode {
         };
     }
 }
+
+/// The color of one element of the output (like the file names), or `none` to leave it uncolored
+///
+/// This accepts the color names of `termcolor` (black, blue, green, red, cyan, magenta, yellow,
+/// and white), an ANSI 256 color number (e.g. `208`), or an RGB triple (e.g. `255,128,0`).
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ElementColor(Option<Color>);
+
+impl ElementColor {
+    pub fn new(color: Color) -> Self {
+        ElementColor(Some(color))
+    }
+
+    /// A `ColorSpec` that sets this color as the foreground color (if any)
+    pub fn spec(&self) -> ColorSpec {
+        let mut spec = ColorSpec::new();
+        spec.set_fg(self.0);
+        spec
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl FromStr for ElementColor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "none" => Ok(ElementColor(None)),
+            name => Color::from_str(name)
+                .map(ElementColor::new)
+                .map_err(|e| anyhow!("Invalid color '{}': {}", s, e)),
+        }
+    }
+}
+
+impl TryFrom<String> for ElementColor {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        ElementColor::from_str(&s)
+    }
+}
+
+impl<'lua> mlua::FromLua<'lua> for ElementColor {
+    fn from_lua(lua_value: mlua::Value<'lua>, _lua: &'lua mlua::Lua) -> mlua::Result<Self> {
+        return match &lua_value {
+            mlua::Value::String(str) => ElementColor::from_str(str.to_str()?).map_err(|e| {
+                mlua::Error::FromLuaConversionError {
+                    from: lua_value.type_name(),
+                    to: "ElementColor",
+                    message: Some(e.to_string()),
+                }
+            }),
+            _ => Err(mlua::Error::FromLuaConversionError {
+                from: lua_value.type_name(),
+                to: "ElementColor",
+                message: Some("Value is not a string".to_string()),
+            }),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use termcolor::Color;
+
+    use super::{auto_color_mode, ColorMode, ElementColor};
+
+    #[test]
+    fn auto_color_mode_checks_env() {
+        assert_eq!(auto_color_mode(false, false, true), ColorMode::Auto);
+        assert_eq!(auto_color_mode(false, false, false), ColorMode::Never);
+        assert_eq!(auto_color_mode(true, false, true), ColorMode::Never);
+        assert_eq!(auto_color_mode(false, true, false), ColorMode::Always);
+        assert_eq!(auto_color_mode(true, true, false), ColorMode::Never);
+    }
+
+    #[test]
+    fn parses_element_colors() {
+        assert_eq!(
+            ElementColor::from_str("Magenta").unwrap(),
+            ElementColor::new(Color::Magenta)
+        );
+        assert_eq!(
+            ElementColor::from_str("208").unwrap(),
+            ElementColor::new(Color::Ansi256(208))
+        );
+        assert_eq!(
+            ElementColor::from_str("255,128,0").unwrap(),
+            ElementColor::new(Color::Rgb(255, 128, 0))
+        );
+        assert!(ElementColor::from_str("none").unwrap().is_none());
+        assert!(ElementColor::from_str("mauve").is_err());
+    }
+}
@@ -1,14 +1,23 @@
 use macros::create_option_copy;
+use pore_core::DedupeBy;
 use pore_core::FileIndexOptionsShape;
 use pore_core::FileSearchOptions;
+use pore_core::PathSeparator;
+use pore_core::ResultOrder;
+use pore_core::ScoringModel;
+use pore_core::SnippetMode;
+use pore_core::SortBy;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
+use termcolor::Color;
 use toml::Value;
 
 use crate::color_mode::ColorMode;
+use crate::color_mode::ElementColor;
 const CONFIG_FILE: &str = "pore.toml";
 
 #[create_option_copy(SearchConfigOpt)]
@@ -16,12 +25,39 @@ const CONFIG_FILE: &str = "pore.toml";
 pub struct SearchConfig {
     pub json: bool,
     pub limit: usize,
+    pub offset: usize,
+    pub max_count: usize,
     pub threshold: f32,
+    pub rel_threshold: f32,
+    pub threshold_percentile: f32,
     pub filename_only: bool,
+    pub null: bool,
+    pub no_heading: bool,
+    pub count: bool,
+    pub count_matches: bool,
     pub color: ColorMode,
+    pub filename_color: ElementColor,
+    pub line_number_color: ElementColor,
+    pub match_color: ElementColor,
     pub rebuild_index: bool,
     pub update: bool,
     pub in_memory: bool,
+    pub cache_dir: String,
+    pub heading_regex: String,
+    pub dedupe_by: DedupeBy,
+    pub sort_by: SortBy,
+    pub order: ResultOrder,
+    pub path_separator: PathSeparator,
+    pub scoring: ScoringModel,
+    pub bm25_k1: f32,
+    pub bm25_b: f32,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub snippet_mode: SnippetMode,
+    pub trim_lines: bool,
+    pub filename_boost: f32,
+    pub recency_boost: f32,
+    pub match_all: bool,
 }
 
 impl Default for SearchConfig {
@@ -29,37 +65,126 @@ impl Default for SearchConfig {
         return SearchConfig {
             json: false,
             limit: 1000,
+            offset: 0,
+            max_count: 0,
             threshold: 0.0,
+            rel_threshold: 0.0,
+            threshold_percentile: 0.0,
             filename_only: false,
+            null: false,
+            no_heading: false,
+            count: false,
+            count_matches: false,
             color: ColorMode::Auto,
+            filename_color: ElementColor::new(Color::Magenta),
+            line_number_color: ElementColor::new(Color::Green),
+            match_color: ElementColor::new(Color::Red),
             rebuild_index: false,
             update: true,
             in_memory: false,
+            cache_dir: "".to_string(),
+            heading_regex: "".to_string(),
+            dedupe_by: DedupeBy::None,
+            sort_by: SortBy::Score,
+            order: ResultOrder::Score,
+            path_separator: PathSeparator::Native,
+            scoring: ScoringModel::Bm25,
+            bm25_k1: pore_core::DEFAULT_BM25_K1,
+            bm25_b: pore_core::DEFAULT_BM25_B,
+            before_context: 0,
+            after_context: 0,
+            snippet_mode: SnippetMode::Lines,
+            trim_lines: true,
+            filename_boost: 0.0,
+            recency_boost: 0.0,
+            match_all: false,
         };
     }
 }
 
 impl SearchConfig {
+    /// How to update the index before searching: None to leave it as it is, or Some(rebuild)
+    ///
+    /// `rebuild_index` always rebuilds the index, even if `update` is false (e.g. with
+    /// `--rebuild --no-update`), because asking for a rebuild is more specific than turning off
+    /// the updates.
+    pub fn index_update(&self) -> Option<bool> {
+        if self.rebuild_index {
+            Some(true)
+        } else if self.update {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
     pub fn to_opts(&self, search_dir: &str) -> FileSearchOptions {
+        // The counts need the matching lines, but not the context around them
+        let counting = self.count || self.count_matches;
         return FileSearchOptions {
             limit: self.limit,
+            offset: self.offset,
             threshold: self.threshold,
-            filename_only: self.filename_only,
+            rel_threshold: self.rel_threshold,
+            threshold_percentile: self.threshold_percentile,
+            filename_only: self.filename_only && !counting,
             root_dir: Some(search_dir.to_string()),
+            heading_regex: if self.heading_regex.is_empty() {
+                None
+            } else {
+                Some(self.heading_regex.clone())
+            },
+            dedupe_by: self.dedupe_by,
+            sort_by: self.sort_by,
+            path_separator: self.path_separator,
+            scoring: self.scoring,
+            bm25_k1: self.bm25_k1,
+            bm25_b: self.bm25_b,
+            before_context: if counting { 0 } else { self.before_context },
+            after_context: if counting { 0 } else { self.after_context },
+            snippet_mode: self.snippet_mode,
+            trim_lines: self.trim_lines,
+            line_transform: None,
+            recency_boost: self.recency_boost,
+            modified_after: None,
+            modified_before: None,
+            max_lines_per_file: if self.max_count == 0 {
+                None
+            } else {
+                Some(self.max_count)
+            },
         };
     }
 }
 
+/// Load the options for a directory from the user's config file
+///
+/// Options are layered in this order, with later layers overriding earlier ones:
+///   1. The global options at the top of the file
+///   2. The local section whose `path` matches the directory
+///   3. The named index inside of that local section (if `index_name` is provided)
+///   4. The global `[index-NAME]` section (if `index_name` is provided)
+///
+/// Command line arguments are layered on top of all of these by `merge_cli_options`.
 pub fn load_config(
     path: &Path,
     index_name: Option<&str>,
 ) -> Result<(FileIndexOptionsShape, SearchConfigOpt), anyhow::Error> {
-    let path_str = path.to_string_lossy();
     let mut config_home = env::var("XDG_CONFIG_HOME").unwrap_or("".to_string());
     if config_home == "" {
         config_home = env::var("HOME")? + "/.config";
     }
     let config_file = PathBuf::from(config_home).join(CONFIG_FILE);
+    load_config_from(&config_file, path, index_name)
+}
+
+/// Load the options for a directory from a specific config file. See `load_config`.
+pub fn load_config_from(
+    config_file: &Path,
+    path: &Path,
+    index_name: Option<&str>,
+) -> Result<(FileIndexOptionsShape, SearchConfigOpt), anyhow::Error> {
+    let path_str = path.to_string_lossy();
     if config_file.exists() {
         let contents = &fs::read_to_string(&config_file)?;
         let value = contents
@@ -70,6 +195,7 @@ pub fn load_config(
 
         let mut found_index = false;
         if let Value::Table(table) = &value {
+            check_duplicate_paths(table)?;
             // Look for a local configuration with a matching path
             for (_, val) in table.iter() {
                 if let Value::Table(local_config) = val {
@@ -106,16 +232,64 @@ pub fn load_config(
     Ok((FileIndexOptionsShape::default(), SearchConfigOpt::default()))
 }
 
+/// Return an error if two local sections of the config file have the same `path`, since only the
+/// first one would be used
+fn check_duplicate_paths(table: &toml::value::Table) -> Result<(), anyhow::Error> {
+    let mut sections: HashMap<&str, &str> = HashMap::new();
+    for (name, val) in table.iter() {
+        if let Some(Value::String(path)) = val.get("path") {
+            if let Some(other) = sections.insert(path.as_str(), name.as_str()) {
+                bail!(
+                    "Config sections '{}' and '{}' have the same path '{}'",
+                    other,
+                    name,
+                    path
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merge the options from the command line on top of the options loaded from the config file
+///
+/// When a named index is used, the options that shape the index must all come from the config
+/// file. Search options (limit, threshold, color, etc) and the thread count can still be set from
+/// the command line.
+pub fn merge_cli_options(
+    index_name: Option<&str>,
+    mut index: FileIndexOptionsShape,
+    mut search: SearchConfigOpt,
+    cli_index: &FileIndexOptionsShape,
+    cli_search: &SearchConfigOpt,
+) -> Result<(FileIndexOptionsShape, SearchConfigOpt), anyhow::Error> {
+    if let Some(name) = index_name {
+        let mut index_shaping = cli_index.clone();
+        index_shaping.threads = None;
+        if index_shaping.any() {
+            bail!(
+                "Cannot change the options of index '{}' from the command line",
+                name
+            );
+        }
+    }
+    index.merge_from(cli_index);
+    search.merge_from(cli_search);
+    Ok((index, search))
+}
+
 #[cfg(test)]
 mod tests {
     use std::{env, fs, path::PathBuf, str::FromStr};
 
-    use pore_core::FileIndexOptions;
+    use pore_core::{FileIndexOptions, SortBy};
+    use termcolor::Color;
     use toml::Value;
 
-    use crate::config::{FileIndexOptionsShape, SearchConfigOpt};
+    use crate::color_mode::ElementColor;
+    use crate::config::{FileIndexOptionsShape, SearchConfig, SearchConfigOpt};
 
-    use super::{load_config, CONFIG_FILE};
+    use super::{load_config, load_config_from, merge_cli_options, CONFIG_FILE};
 
     #[test]
     fn parsing_opt_configs_works() {
@@ -152,6 +326,25 @@ limit = 4
         assert_eq!(conf.hidden, false);
     }
 
+    #[test]
+    fn diff_lists_changed_fields() {
+        let mut search = SearchConfigOpt {
+            limit: Some(10),
+            json: Some(true),
+            ..Default::default()
+        };
+        let layer = SearchConfigOpt {
+            limit: Some(20),
+            json: Some(true),
+            sort_by: Some(SortBy::Path),
+            ..Default::default()
+        };
+        let before = search.clone();
+        search.merge_from(&layer);
+        assert_eq!(before.diff(&search), vec!["limit", "sort_by"]);
+        assert!(search.diff(&search).is_empty());
+    }
+
     #[test]
     fn can_load_and_merge_defaults() {
         let tmpdir = tempfile::tempdir().unwrap();
@@ -185,6 +378,32 @@ limit = 4
         assert_eq!(index.threads, Some(40));
     }
 
+    #[test]
+    fn duplicate_paths_are_an_error() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf_file = PathBuf::from(tmpdir.path()).join(CONFIG_FILE);
+        fs::write(
+            &conf_file,
+            "[local-1]
+        path = '/foo'
+        threads = 30
+
+        [local-2]
+        path = '/foo'
+        threads = 40
+            ",
+        )
+        .unwrap();
+
+        // Even for a path that doesn't match either section
+        let path = PathBuf::from_str("/").unwrap();
+        let err = load_config_from(&conf_file, &path, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Config sections 'local-1' and 'local-2' have the same path '/foo'"
+        );
+    }
+
     #[test]
     fn example_file_is_complete() {
         let example = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("pore.example.toml");
@@ -201,4 +420,148 @@ limit = 4
             panic!("pore.example.toml is missing fields: {:?}", missing_fields);
         }
     }
+
+    #[test]
+    fn cli_search_options_override_named_index() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf_file = PathBuf::from(tmpdir.path()).join(CONFIG_FILE);
+        fs::write(
+            &conf_file,
+            "limit = 100
+        threshold = 1.0
+        [index-foo]
+        limit = 20
+        threshold = 2.0
+        oglob = ['*.md']
+            ",
+        )
+        .unwrap();
+
+        let path = PathBuf::from_str("/").unwrap();
+        let (index, search) = load_config_from(&conf_file, &path, Some("foo")).unwrap();
+        assert_eq!(search.limit, Some(20));
+        assert_eq!(search.threshold, Some(2.0));
+
+        // --index foo --limit 10 --threshold 0.5
+        let cli_search = SearchConfigOpt {
+            limit: Some(10),
+            threshold: Some(0.5),
+            ..Default::default()
+        };
+        let (index, search) = merge_cli_options(
+            Some("foo"),
+            index,
+            search,
+            &FileIndexOptionsShape::default(),
+            &cli_search,
+        )
+        .unwrap();
+        assert_eq!(search.limit, Some(10));
+        assert_eq!(search.threshold, Some(0.5));
+        assert_eq!(index.oglob, Some(vec!["*.md".to_string()]));
+    }
+
+    #[test]
+    fn named_index_provides_search_defaults() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf_file = PathBuf::from(tmpdir.path()).join(CONFIG_FILE);
+        fs::write(
+            &conf_file,
+            "limit = 100
+        [index-foo]
+        limit = 20
+            ",
+        )
+        .unwrap();
+
+        let path = PathBuf::from_str("/").unwrap();
+        let (index, search) = load_config_from(&conf_file, &path, Some("foo")).unwrap();
+        let cli_search = SearchConfigOpt {
+            threshold: Some(0.5),
+            ..Default::default()
+        };
+        let cli_index = FileIndexOptionsShape {
+            threads: Some(4),
+            ..Default::default()
+        };
+        let (index, search) =
+            merge_cli_options(Some("foo"), index, search, &cli_index, &cli_search).unwrap();
+        assert_eq!(search.limit, Some(20));
+        assert_eq!(search.threshold, Some(0.5));
+        assert_eq!(index.threads, Some(4));
+    }
+
+    #[test]
+    fn cli_cannot_change_named_index() {
+        let cli_index = FileIndexOptionsShape {
+            hidden: Some(true),
+            ..Default::default()
+        };
+        let res = merge_cli_options(
+            Some("foo"),
+            FileIndexOptionsShape::default(),
+            SearchConfigOpt::default(),
+            &cli_index,
+            &SearchConfigOpt::default(),
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn named_index_provides_sort_and_recency_defaults() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf_file = PathBuf::from(tmpdir.path()).join(CONFIG_FILE);
+        fs::write(
+            &conf_file,
+            "[index-notes]
+        sort_by = 'modified'
+        recency_boost = 2.0
+            ",
+        )
+        .unwrap();
+
+        let path = PathBuf::from_str("/").unwrap();
+        let (_, search) = load_config_from(&conf_file, &path, None).unwrap();
+        let search: SearchConfig = search.into();
+        let opts = search.to_opts("");
+        assert_eq!(opts.sort_by, SortBy::Score);
+        assert_eq!(opts.recency_boost, 0.0);
+
+        let (index, search) = load_config_from(&conf_file, &path, Some("notes")).unwrap();
+        let (_, search) = merge_cli_options(
+            Some("notes"),
+            index,
+            search,
+            &FileIndexOptionsShape::default(),
+            &SearchConfigOpt::default(),
+        )
+        .unwrap();
+        let search: SearchConfig = search.into();
+        let opts = search.to_opts("");
+        assert_eq!(opts.sort_by, SortBy::Modified);
+        assert_eq!(opts.recency_boost, 2.0);
+    }
+
+    #[test]
+    fn parses_output_colors() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let conf_file = PathBuf::from(tmpdir.path()).join(CONFIG_FILE);
+        fs::write(
+            &conf_file,
+            "filename_color = 'blue'
+        match_color = 'none'
+            ",
+        )
+        .unwrap();
+
+        let path = PathBuf::from_str("/").unwrap();
+        let (_, search) = load_config_from(&conf_file, &path, None).unwrap();
+        let search: SearchConfig = search.into();
+        assert_eq!(search.filename_color, ElementColor::new(Color::Blue));
+        assert_eq!(search.line_number_color, ElementColor::new(Color::Green));
+        assert!(search.match_color.is_none());
+
+        fs::write(&conf_file, "filename_color = 'mauve'").unwrap();
+        assert!(load_config_from(&conf_file, &path, None).is_err());
+    }
 }
@@ -1,20 +1,48 @@
 #[macro_use]
 extern crate anyhow;
 
+use args::CacheSort;
 use args::CmdArg;
+use args::GlobalConfig;
+use chrono::{DateTime, Local};
 use config::load_config;
+use config::merge_cli_options;
 use config::SearchConfig;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
 use pore_core::FileIndex;
 use pore_core::FileIndexOptions;
+use pore_core::FileMetadata;
+use pore_core::FileSearchResult;
+use pore_core::LineTransform;
+use pore_core::ResultOrder;
+use pore_core::UpdateSummary;
+use pore_core::FILE_TYPES;
+use pore_core::METADATA_FILE;
+use std::collections::HashSet;
 use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::process;
-use tantivy::query::QueryParser;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tantivy::query::{AllQuery, BooleanQuery, BoostQuery, Occur, Query, QueryParser, RangeQuery};
+use tantivy::schema::Field;
+use termcolor::StandardStream;
 
 mod args;
 mod color_mode;
 mod config;
 mod output;
+#[cfg(unix)]
+mod serve;
+#[cfg(unix)]
+use serve::serve;
+
+/// How long a file has to stop changing before --watch updates the index
+const WATCH_DEBOUNCE_MS: u64 = 200;
 
 fn main() {
     match run_cmd() {
@@ -31,80 +59,1075 @@ fn main() {
 }
 
 fn run_cmd() -> Result<bool, anyhow::Error> {
-    let conf = args::parse_args()?;
-    let (mut index_opt, mut search_opt) =
-        load_config(&conf.query_path, conf.index_name.as_deref())?;
-    search_opt.merge_from(&conf.search);
-    if conf.index_name.is_some() {
-        if conf.index.any() {
-            bail!("Cannot use those arguments with --index");
+    let mut conf = args::parse_args()?;
+    if conf.query.as_deref() == Some("-") {
+        if conf.stdin_files {
+            bail!("Cannot read both the query and --stdin-files from stdin");
         }
-    } else {
-        index_opt.merge_from(&conf.index);
+        conf.query = Some(read_stdin_query()?);
     }
+    let (index_opt, search_opt) = load_config(&conf.query_path, conf.index_name.as_deref())?;
+    let (index_opt, search_opt) = merge_cli_options(
+        conf.index_name.as_deref(),
+        index_opt,
+        search_opt,
+        &conf.index,
+        &conf.search,
+    )?;
     let index: FileIndexOptions = index_opt.into();
-    let search: SearchConfig = search_opt.into();
+    let search: SearchConfig = search_opt.clone().into();
 
-    let cache_dir = if search.in_memory {
+    let cache_dir = if search.in_memory || conf.stdin_files {
         None
     } else {
-        Some(find_index_dir(
+        Some(get_index_dir(
             &conf.query_path,
+            &search.cache_dir,
             conf.index_name.as_deref(),
         )?)
     };
-    let mut index = FileIndex::get_or_create(conf.query_path, cache_dir, &index.into())?;
+    if let CmdArg::CachePath = conf.command {
+        let index_dir = get_index_dir(
+            &conf.query_path,
+            &search.cache_dir,
+            conf.index_name.as_deref(),
+        )?;
+        println!("{}", index_dir.to_string_lossy());
+        return Ok(true);
+    }
+    if let CmdArg::ListCaches(sort) = conf.command {
+        return list_caches(sort);
+    }
+    if let CmdArg::TypeList = conf.command {
+        for (name, globs) in FILE_TYPES {
+            println!("{}: {}", name, globs.join(", "));
+        }
+        return Ok(true);
+    }
+    if let CmdArg::Check | CmdArg::Repair = conf.command {
+        // Check before opening the index, because opening it will delete a corrupted index
+        let index_dir = cache_dir.ok_or_else(|| anyhow!("Cannot check an in-memory index"))?;
+        let problems = FileIndex::check(&index_dir, &index)?;
+        if problems.is_empty() {
+            println!(
+                "No problems found in index at {}",
+                index_dir.to_string_lossy()
+            );
+            return Ok(true);
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if let CmdArg::Repair = conf.command {
+            let mut index = FileIndex::recreate(&conf.query_path, &index_dir, &index)?;
+            update_index(&mut index, true, &conf)?;
+            println!(
+                "Rebuilt index at {}",
+                index.cache_dir().unwrap().to_string_lossy()
+            );
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+    if let CmdArg::Delete { dry_run: true } = conf.command {
+        // Check without opening the index, because opening it would create it
+        match cache_dir {
+            Some(index_dir) if index_dir.join(METADATA_FILE).exists() => {
+                println!("Would delete index at {}", index_dir.to_string_lossy());
+                println!(
+                    "Would delete metadata file {}",
+                    index_dir.join(METADATA_FILE).to_string_lossy()
+                );
+                return Ok(true);
+            }
+            _ => {
+                eprintln!("No index found to delete");
+                return Ok(false);
+            }
+        }
+    }
+    let mut index = FileIndex::get_or_create(&conf.query_path, cache_dir.as_ref(), &index.into())?;
+    if let Some(reason) = index.rebuild_reason() {
+        eprintln!("rebuilding: {}", reason);
+    }
 
     match conf.command {
-        CmdArg::Delete => {
-            index.delete()?;
-            return Ok(true);
+        CmdArg::Delete { .. } => {
+            let location = index
+                .cache_dir()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if index.delete()? {
+                println!("Deleted index at {}", location);
+                return Ok(true);
+            } else {
+                eprintln!("No index found to delete");
+                return Ok(false);
+            }
         }
         CmdArg::ListFiles => {
-            let walker = index.get_file_walker()?;
-            for result in walker.build() {
-                if let Ok(entry) = result {
-                    println!("{}", entry.path().to_string_lossy());
+            // A file can be found by more than one walker (see `unignore`)
+            let mut listed = HashSet::new();
+            let mut paths = Vec::new();
+            for walker in index.get_file_walkers()? {
+                for result in walker.build() {
+                    if let Ok(entry) = result {
+                        if listed.insert(entry.path().to_path_buf()) {
+                            let path = search.path_separator.format(entry.path());
+                            if search.json {
+                                paths.push(path);
+                            } else {
+                                println!("{}", path);
+                            }
+                        }
+                    }
                 }
             }
+            if search.json {
+                output::print_files_json(&mut io::stdout(), &paths)?;
+            }
             return Ok(true);
         }
         CmdArg::ListIndex => {
-            println!("{}", index);
+            if search.json {
+                output::print_index_json(&mut io::stdout(), &index)?;
+            } else {
+                println!("{}", index);
+            }
             return Ok(true);
         }
-        CmdArg::Search => {
-            if search.update || search.rebuild_index {
-                index.update(search.rebuild_index)?;
-            }
-            if let Some(query) = conf.query {
-                let query_parser = QueryParser::for_index(&index.index(), vec![*index.contents()]);
-                let query = query_parser.parse_query(&query)?;
-                let opts = &search.to_opts(&conf.search_dir);
-                let results = index.search(&query, &opts)?;
-                return output::print_results(results, &search);
+        CmdArg::Compact => {
+            let merged = index.compact()?;
+            if merged > 0 {
+                println!("Merged {} segments", merged);
             } else {
-                return Ok(true);
+                println!("Index is already compact");
+            }
+            return Ok(true);
+        }
+        CmdArg::TopTerms(n) => {
+            if let Some(rebuild) = search.index_update() {
+                update_index(&mut index, rebuild, &conf)?;
+            }
+            for term in index.top_terms(*index.contents(), n)? {
+                println!("{}\t{}", term.doc_freq, term.term);
+            }
+            return Ok(true);
+        }
+        CmdArg::Check
+        | CmdArg::Repair
+        | CmdArg::TypeList
+        | CmdArg::ListCaches(_)
+        | CmdArg::CachePath => {
+            unreachable!()
+        }
+        CmdArg::Search => {
+            if conf.stdin_files {
+                index.add_contents(read_stdin_files()?)?;
+            } else if let Some(pattern) = conf.update_glob.as_deref() {
+                let summary = index.update_glob(pattern)?;
+                if conf.stats {
+                    print_update_stats(&summary);
+                }
+            } else if let Some(rebuild) = search.index_update() {
+                update_index(&mut index, rebuild, &conf)?;
+            }
+            if let Some(socket) = conf.serve.as_deref() {
+                return serve(&mut index, &conf, &search_opt, socket);
             }
+            let found = run_search(&index, &conf, &search)?;
+            if conf.watch {
+                return watch(&mut index, &conf, &search);
+            }
+            return Ok(found);
         }
     }
 }
 
-fn find_index_dir(for_dir: &Path, index_name: Option<&str>) -> Result<PathBuf, anyhow::Error> {
+/// How often `update_index` prints the number of files processed
+const PROGRESS_INTERVAL: usize = 100;
+
+/// Update the index. With `--progress`, print a running count of the files processed to stderr,
+/// and with `--stats`, print the summary of the update.
+fn update_index(
+    index: &mut FileIndex,
+    rebuild: bool,
+    conf: &GlobalConfig,
+) -> Result<UpdateSummary, anyhow::Error> {
+    let summary = if conf.progress {
+        let processed = AtomicUsize::new(0);
+        let summary = index.update_with_parallel_progress(rebuild, |n| {
+            // The counts come from the walker threads, so they may be out of order
+            processed.fetch_max(n, Ordering::Relaxed);
+            if n % PROGRESS_INTERVAL == 0 {
+                eprint!("\rProcessed {} files", n);
+            }
+        })?;
+        eprintln!("\rProcessed {} files", processed.into_inner());
+        summary
+    } else {
+        index.update(rebuild)?
+    };
+    if conf.stats {
+        print_update_stats(&summary);
+    }
+    Ok(summary)
+}
+
+/// Print the summary of an update to stderr, listing the files that couldn't be indexed
+fn print_update_stats(summary: &UpdateSummary) {
+    eprintln!(
+        "Added {} files, removed {} files, skipped {} files",
+        summary.added,
+        summary.removed,
+        summary.skipped.len()
+    );
+    for skipped in &summary.skipped {
+        eprintln!("  {}: {}", skipped.path.to_string_lossy(), skipped.reason);
+    }
+    if !summary.lossy.is_empty() {
+        eprintln!(
+            "Replaced the invalid UTF-8 in {} files",
+            summary.lossy.len()
+        );
+        for path in &summary.lossy {
+            eprintln!("  {}", path.to_string_lossy());
+        }
+    }
+}
+
+/// Search the index and print the results. Returns true if anything matched.
+fn run_search(
+    index: &FileIndex,
+    conf: &GlobalConfig,
+    search: &SearchConfig,
+) -> Result<bool, anyhow::Error> {
+    let start = Instant::now();
+    match find_results(index, conf, search)? {
+        Some((results, total)) => {
+            let mut stdout = StandardStream::stdout(search.color.resolve().into());
+            output::print_results(&mut stdout, results, total, search, start.elapsed())
+        }
+        None => Ok(true),
+    }
+}
+
+/// Search the index with the query (and query files) of `conf`. Returns the results and the total
+/// number of matching files, or None if there is nothing to search for.
+fn find_results(
+    index: &FileIndex,
+    conf: &GlobalConfig,
+    search: &SearchConfig,
+) -> Result<Option<(Vec<FileSearchResult>, usize)>, anyhow::Error> {
+    let mut query_parser = index.query_parser();
+    if search.match_all {
+        query_parser.set_conjunction_by_default();
+    }
+    let query = conf
+        .query
+        .as_deref()
+        .map(|text| {
+            parse_user_query(
+                index,
+                &query_parser,
+                text,
+                conf.fuzzy,
+                conf.regex,
+                search.filename_boost,
+            )
+        })
+        .transpose()?;
+    if let Some(mut query) = build_query(
+        index,
+        &query_parser,
+        query,
+        &conf.query_files,
+        &conf.and_files,
+    )? {
+        if let Some(dir) = conf.under.as_deref() {
+            query = restrict_to_dir(query, *index.filepath(), dir);
+        }
+        let mut opts = search.to_opts(&conf.search_dir);
+        if search.count_matches {
+            // The total is of every matching file, not just the top `limit` of them
+            opts.limit = index.num_docs()?.max(1) as usize;
+            opts.offset = 0;
+        }
+        if !conf.redact.is_empty() {
+            let pattern = conf
+                .redact
+                .iter()
+                .map(|p| format!("(?:{})", p))
+                .collect::<Vec<_>>()
+                .join("|");
+            opts.line_transform = Some(LineTransform::redact(&pattern)?);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        if let Some(newer_than) = conf.newer_than {
+            opts.modified_after = Some(now.saturating_sub(newer_than).as_secs() as i64);
+        }
+        if let Some(older_than) = conf.older_than {
+            opts.modified_before = Some(now.saturating_sub(older_than).as_secs() as i64);
+        }
+        if search.order == ResultOrder::Location {
+            let results: Vec<FileSearchResult> = index.search_by_location(&query, &opts)?.collect();
+            let total = results.len();
+            return Ok(Some((results, total)));
+        }
+        return Ok(Some(index.search_with_total(&query, &opts)?));
+    } else {
+        return Ok(None);
+    }
+}
+
+#[cfg(not(unix))]
+fn serve(
+    _index: &mut FileIndex,
+    _conf: &GlobalConfig,
+    _search: &config::SearchConfigOpt,
+    _socket: &Path,
+) -> Result<bool, anyhow::Error> {
+    bail!("--serve is only supported on Unix")
+}
+
+/// Update the index and re-run the search whenever files in the indexed directory change. This
+/// runs until the process is killed.
+fn watch(
+    index: &mut FileIndex,
+    conf: &GlobalConfig,
+    search: &SearchConfig,
+) -> Result<bool, anyhow::Error> {
+    let (tx, rx) = mpsc::channel();
+    // The watcher only sends the events for a file once it stops changing for this long, so an
+    // editor writing a file in several steps (or saving many files at once) only updates once
+    let mut watcher = notify::watcher(tx, Duration::from_millis(WATCH_DEBOUNCE_MS))?;
+    for root in index.roots() {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+    }
+    loop {
+        // Handle all of the changes that are already queued with a single update
+        let mut events = vec![rx.recv()?];
+        events.extend(rx.try_iter());
+        if let Some(DebouncedEvent::Error(err, _)) = events
+            .iter()
+            .find(|event| matches!(event, DebouncedEvent::Error(..)))
+        {
+            bail!("Error watching files: {}", err);
+        }
+        // Each update opens its own writer and drops it after the commit, so the index is only
+        // locked while it's being updated, and other processes can update it in between. Pick up
+        // their updates first, so the files they indexed aren't indexed again.
+        index.refresh()?;
+        // Only re-index the paths that changed. They are found with the same ignore and glob rules
+        // as the initial update, so changes to ignored files (e.g. in .git/) don't add or remove
+        // anything and don't re-run the search.
+        let summary = match changed_paths(&events) {
+            Some(paths) if paths.is_empty() => continue,
+            Some(paths) => index.update_paths(paths.as_slice())?,
+            None => index.update(false)?,
+        };
+        if summary.added > 0 || summary.removed > 0 {
+            // A blank line would make the JSON output invalid
+            if !search.json {
+                println!();
+            }
+            run_search(index, conf, search)?;
+        }
+    }
+}
+
+/// The paths that changed in a batch of watcher events, or None if the watcher lost track of the
+/// changes and everything has to be checked
+fn changed_paths(events: &[DebouncedEvent]) -> Option<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for event in events {
+        match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Chmod(path)
+            | DebouncedEvent::Remove(path) => paths.push(path.clone()),
+            DebouncedEvent::Rename(from, to) => {
+                paths.push(from.clone());
+                paths.push(to.clone());
+            }
+            DebouncedEvent::Rescan => return None,
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Error(..) => {}
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    Some(paths)
+}
+
+/// Build the search query from the positional query or the query files
+///
+/// The --query-file queries take the place of the positional query (the arguments don't allow
+/// both), and at least one of them must match. Every --and-file query must match as well.
+fn build_query(
+    index: &FileIndex,
+    query_parser: &QueryParser,
+    query: Option<Box<dyn Query>>,
+    or_files: &[String],
+    and_files: &[String],
+) -> Result<Option<Box<dyn Query>>, anyhow::Error> {
+    let mut any_of: Vec<Box<dyn Query>> = Vec::new();
+    match query {
+        Some(query) => any_of.push(query),
+        None => {
+            for file in or_files {
+                any_of.push(parse_query_file(index, query_parser, file)?);
+            }
+        }
+    }
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+    for file in and_files {
+        clauses.push((Occur::Must, parse_query_file(index, query_parser, file)?));
+    }
+    if clauses.is_empty() && any_of.len() <= 1 {
+        return Ok(any_of.pop());
+    }
+    if !any_of.is_empty() {
+        clauses.push((Occur::Must, Box::new(BooleanQuery::union(any_of))));
+    }
+    Ok(Some(Box::new(BooleanQuery::new(clauses))))
+}
+
+/// Build the query for the text passed on the command line
+///
+/// The negated terms (see `split_negated_terms`) are removed from the text, and the files that
+/// match any of them are excluded from the results. A query of only negated terms matches every
+/// file that doesn't match them. Regexes are used as they are.
+fn parse_user_query(
+    index: &FileIndex,
+    query_parser: &QueryParser,
+    text: &str,
+    fuzzy: Option<u8>,
+    regex: bool,
+    filename_boost: f32,
+) -> Result<Box<dyn Query>, anyhow::Error> {
+    if regex {
+        return Ok(index.regex_query(text)?);
+    }
+    let (text, negated) = split_negated_terms(text);
+    let mut query: Box<dyn Query> = match fuzzy {
+        _ if text.is_empty() && !negated.is_empty() => Box::new(AllQuery),
+        Some(distance) => index.fuzzy_query(&text, distance)?,
+        None => index.parse_query_with(query_parser, &text)?,
+    };
+    if filename_boost > 0.0 && !text.is_empty() {
+        query = boost_filename(query, index, &text, filename_boost)?;
+    }
+    if negated.is_empty() {
+        return Ok(query);
+    }
+    let mut clauses = vec![(Occur::Must, query)];
+    for term in &negated {
+        clauses.push((Occur::MustNot, index.parse_query_with(query_parser, term)?));
+    }
+    Ok(Box::new(BooleanQuery::new(clauses)))
+}
+
+/// Split the negated terms out of a query, returning the rest of the query and the negated terms
+///
+/// A `-` at the start of a word negates the word (`-bar`), a quoted phrase (`-"foo bar"`), a field
+/// (`-path:test`), or a group in parentheses (`-(foo OR bar)`). A `-` inside of a word
+/// (`foo-bar`), quotes, or parentheses is left alone. Queries that use `AND`, `OR`, or `NOT` are
+/// returned unchanged, since the boolean operators already say what to negate.
+fn split_negated_terms(query: &str) -> (String, Vec<String>) {
+    let words = split_words(query);
+    if words
+        .iter()
+        .any(|word| matches!(*word, "AND" | "OR" | "NOT"))
+    {
+        return (query.to_string(), Vec::new());
+    }
+    let mut rest = Vec::new();
+    let mut negated = Vec::new();
+    for word in words {
+        match word.strip_prefix('-') {
+            Some(term) if !term.is_empty() => negated.push(term.to_string()),
+            _ => rest.push(word),
+        }
+    }
+    (rest.join(" "), negated)
+}
+
+/// Split a query on the whitespace that is not inside of quotes or parentheses
+fn split_words(query: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut in_quotes = false;
+    let mut depth = 0usize;
+    for (i, c) in query.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => depth = depth.saturating_sub(1),
+            c if c.is_whitespace() && !in_quotes && depth == 0 => {
+                if let Some(start) = start.take() {
+                    words.push(&query[start..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        words.push(&query[start..]);
+    }
+    words
+}
+
+/// Rank the files whose path contains words of the query higher
+///
+/// The query still has to match the file contents, and matches in the path only add to the score.
+fn boost_filename(
+    query: Box<dyn Query>,
+    index: &FileIndex,
+    text: &str,
+    boost: f32,
+) -> Result<Box<dyn Query>, anyhow::Error> {
+    let path_parser = QueryParser::for_index(index.index(), index.path_fields().to_vec());
+    let path_query = path_parser.parse_query(text)?;
+    Ok(Box::new(BooleanQuery::new(vec![
+        (Occur::Must, query),
+        (Occur::Should, Box::new(BoostQuery::new(path_query, boost))),
+    ])))
+}
+
+/// Restrict a query to only match files inside of a directory
+///
+/// The filepath field stores paths relative to the root of the index, so this is a range query
+/// over all paths that start with `dir/`.
+fn restrict_to_dir(query: Box<dyn Query>, filepath: Field, dir: &str) -> Box<dyn Query> {
+    let dir = dir.trim_start_matches("./").trim_end_matches('/');
+    if dir.is_empty() || dir == "." {
+        return query;
+    }
+    // '0' is the character right after '/', so this range covers everything under "dir/"
+    let start = format!("{}/", dir);
+    let end = format!("{}0", dir);
+    let prefix_query =
+        RangeQuery::new_str_bounds(filepath, Bound::Included(&start), Bound::Excluded(&end));
+    // The range query only filters, so it mustn't add to the scores (and shift the thresholds)
+    Box::new(BooleanQuery::new(vec![
+        (
+            Occur::Must,
+            Box::new(BoostQuery::new(Box::new(prefix_query), 0.0)),
+        ),
+        (Occur::Must, query),
+    ]))
+}
+
+fn parse_query_file(
+    index: &FileIndex,
+    query_parser: &QueryParser,
+    file: &str,
+) -> Result<Box<dyn Query>, anyhow::Error> {
+    let query = fs::read_to_string(file)
+        .map_err(|e| anyhow!("Could not read query file '{}': {}", file, e))?;
+    Ok(index.parse_query_with(query_parser, query.trim())?)
+}
+
+/// Read the query from stdin, for a query of `-`
+fn read_stdin_query() -> Result<String, anyhow::Error> {
+    let mut query = String::new();
+    io::stdin().read_to_string(&mut query)?;
+    Ok(trim_newline(&query).to_string())
+}
+
+/// Remove one trailing newline (`\n` or `\r\n`) from the text
+fn trim_newline(text: &str) -> &str {
+    match text.strip_suffix('\n') {
+        Some(text) => text.strip_suffix('\r').unwrap_or(text),
+        None => text,
+    }
+}
+
+/// Read the (path, contents) pairs for --stdin-files. The format is `path\0contents\0path\0...`
+fn read_stdin_files() -> Result<Vec<(String, String)>, anyhow::Error> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let mut fields = input.split('\0');
+    let mut files = Vec::new();
+    while let Some(path) = fields.next() {
+        // The input may or may not have a trailing NUL
+        if path.is_empty() {
+            break;
+        }
+        let contents = fields
+            .next()
+            .ok_or_else(|| anyhow!("Missing contents for file '{}' on stdin", path))?;
+        files.push((path.to_string(), contents.to_string()));
+    }
+    Ok(files)
+}
+
+/// The directory that the indexes are cached in (`$XDG_CACHE_HOME/pore`)
+fn cache_root() -> Result<PathBuf, anyhow::Error> {
     let mut cache_home = env::var("XDG_CACHE_HOME").unwrap_or("".to_string());
     if cache_home == "" {
         cache_home = env::var("HOME")? + "/.cache";
     }
-    let mut index_root = PathBuf::from(cache_home);
-    index_root.push(env!("CARGO_PKG_NAME"));
+    let mut cache_root = PathBuf::from(cache_home);
+    cache_root.push(env!("CARGO_PKG_NAME"));
+    Ok(cache_root)
+}
+
+/// The directory to store the index in: the cache_dir option (relative to the searched
+/// directory) if it is set, or else the directory in the cache for the searched directory. A named
+/// index (see --index) is stored in a subdirectory of it.
+fn get_index_dir(
+    for_dir: &Path,
+    cache_dir: &str,
+    index_name: Option<&str>,
+) -> Result<PathBuf, anyhow::Error> {
+    let mut index_dir = if cache_dir.is_empty() {
+        find_index_dir(for_dir)?
+    } else {
+        for_dir.join(cache_dir)
+    };
+    if let Some(name) = index_name {
+        index_dir.push(format!("__index_{}", name));
+    }
+    Ok(index_dir)
+}
+
+fn find_index_dir(for_dir: &Path) -> Result<PathBuf, anyhow::Error> {
+    let mut index_root = cache_root()?;
     if for_dir.is_absolute() {
         index_root.push(for_dir.strip_prefix("/")?);
     } else {
         index_root.push(env::current_dir()?.strip_prefix("/")?);
         index_root.push(for_dir)
     }
-    if let Some(name) = index_name {
-        index_root.push(format!("__index_{}", name));
-    }
     return Ok(index_root);
 }
+
+/// An index found in the cache directory by --list-caches
+struct CachedIndex {
+    meta: FileMetadata,
+    /// The name of the index, if it is a named index (see --index)
+    name: Option<String>,
+    /// The total size of the index files in bytes
+    size: u64,
+}
+
+/// Find the indexes in a directory of the cache and all of its subdirectories. The index of a
+/// subdirectory of an indexed directory is cached in a subdirectory of its cache.
+fn find_cached_indexes(dir: &Path, indexes: &mut Vec<CachedIndex>) -> Result<(), anyhow::Error> {
+    // Skip any metadata that can't be read, so one bad index doesn't hide the others
+    if let Ok(Some(meta)) = FileMetadata::load(dir) {
+        let mut size = 0;
+        for entry in fs::read_dir(dir)? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+        indexes.push(CachedIndex {
+            meta,
+            name: dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("__index_"))
+                .map(|name| name.to_string()),
+            size,
+        });
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            find_cached_indexes(&entry.path(), indexes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the indexes in the cache directory. Returns true if there are any.
+fn list_caches(sort: CacheSort) -> Result<bool, anyhow::Error> {
+    let cache_root = cache_root()?;
+    let mut indexes = Vec::new();
+    if cache_root.is_dir() {
+        find_cached_indexes(&cache_root, &mut indexes)?;
+    }
+    match sort {
+        CacheSort::Dir => {
+            indexes.sort_by(|a, b| (a.meta.for_dir(), &a.name).cmp(&(b.meta.for_dir(), &b.name)))
+        }
+        CacheSort::Size => indexes.sort_by(|a, b| b.size.cmp(&a.size)),
+        CacheSort::Age => indexes.sort_by(|a, b| a.meta.last_update().cmp(b.meta.last_update())),
+    }
+    for index in &indexes {
+        let mut dir = index.meta.for_dir().to_string_lossy().to_string();
+        if let Some(name) = &index.name {
+            dir = format!("{} (index {})", dir, name);
+        }
+        println!(
+            "{:>8}  {}  {}",
+            format_size(index.size),
+            DateTime::<Local>::from(*index.meta.last_update()).format("%Y-%m-%d %H:%M"),
+            dir
+        );
+    }
+    Ok(!indexes.is_empty())
+}
+
+/// Format a number of bytes with a suffix, like `1.5M`. The suffixes are powers of 1024.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "K", "M", "G"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use pore_core::{FileIndex, FileIndexOptions, FileSearchOptions};
+    use tantivy::query::{Query, QueryParser};
+
+    use super::{
+        boost_filename, build_query, changed_paths, find_cached_indexes, format_size,
+        get_index_dir, parse_user_query, restrict_to_dir, split_negated_terms, trim_newline,
+    };
+
+    /// Create an index in `dir` with the default options, and add the (path, contents) `files`
+    fn index_files(dir: &Path, files: &[(&str, &str)]) -> FileIndex {
+        let mut index = FileIndex::get_or_create(dir, None, &FileIndexOptions::default()).unwrap();
+        index
+            .add_contents(
+                files
+                    .iter()
+                    .map(|(path, contents)| (path.to_string(), contents.to_string())),
+            )
+            .unwrap();
+        index
+    }
+
+    /// The sorted paths of the files that match the query
+    fn search(index: &FileIndex, query: Box<dyn Query>) -> Vec<String> {
+        let opts = FileSearchOptions {
+            root_dir: Some("".to_string()),
+            ..Default::default()
+        };
+        let mut files: Vec<String> = index
+            .search(&query, &opts)
+            .unwrap()
+            .iter()
+            .map(|r| r.file().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn search_under(index: &FileIndex, query: &str, dir: &str) -> Vec<String> {
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = restrict_to_dir(
+            query_parser.parse_query(query).unwrap(),
+            *index.filepath(),
+            dir,
+        );
+        search(index, query)
+    }
+
+    #[test]
+    fn under_only_matches_inside_dir() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let index = index_files(
+            tmpdir.path(),
+            &[
+                ("src/a.rs", "needle"),
+                ("src/nested/b.rs", "needle"),
+                ("src2/c.rs", "needle"),
+                ("docs/d.md", "needle"),
+                ("src/e.rs", "haystack"),
+            ],
+        );
+        assert_eq!(
+            search_under(&index, "needle", "src"),
+            vec!["src/a.rs", "src/nested/b.rs"]
+        );
+        assert_eq!(
+            search_under(&index, "needle", "./src/nested/"),
+            vec!["src/nested/b.rs"]
+        );
+        assert_eq!(search_under(&index, "needle", "docs"), vec!["docs/d.md"]);
+        assert!(search_under(&index, "needle", "missing").is_empty());
+    }
+
+    #[test]
+    fn under_does_not_change_scores() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let index = index_files(
+            tmpdir.path(),
+            &[("src/a.rs", "needle"), ("docs/b.md", "needle haystack")],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let score = |dir: &str| {
+            let query = restrict_to_dir(
+                query_parser.parse_query("needle").unwrap(),
+                *index.filepath(),
+                dir,
+            );
+            let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+            results
+                .iter()
+                .find(|r| r.file().ends_with("a.rs"))
+                .unwrap()
+                .score()
+        };
+        assert_eq!(score("src"), score(""));
+    }
+
+    #[test]
+    fn filename_boost_ranks_path_matches_higher() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let index = index_files(
+            tmpdir.path(),
+            &[
+                ("src/body.rs", "parser parser parser input"),
+                ("src/parser.rs", "the parser is here too"),
+                ("src/other.rs", "nothing"),
+            ],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let opts = FileSearchOptions {
+            root_dir: Some("".to_string()),
+            ..Default::default()
+        };
+        let files = |query| -> Vec<String> {
+            index
+                .search(&query, &opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.file().to_string_lossy().to_string())
+                .collect()
+        };
+        let query = query_parser.parse_query("parser").unwrap();
+        assert_eq!(files(query), vec!["src/body.rs", "src/parser.rs"]);
+        let query = query_parser.parse_query("parser").unwrap();
+        let query = boost_filename(query, &index, "parser", 10.0).unwrap();
+        assert_eq!(files(query), vec!["src/parser.rs", "src/body.rs"]);
+    }
+
+    #[test]
+    fn changed_paths_of_watch_events() {
+        use notify::DebouncedEvent;
+        use std::path::PathBuf;
+        let path = |p: &str| PathBuf::from(p);
+        let events = vec![
+            DebouncedEvent::NoticeWrite(path("a.txt")),
+            DebouncedEvent::Write(path("a.txt")),
+            DebouncedEvent::Remove(path(".git/index.lock")),
+            DebouncedEvent::Rename(path("b.txt"), path("c.txt")),
+        ];
+        assert_eq!(
+            changed_paths(&events),
+            Some(vec![
+                path(".git/index.lock"),
+                path("a.txt"),
+                path("b.txt"),
+                path("c.txt")
+            ])
+        );
+        assert_eq!(
+            changed_paths(&[DebouncedEvent::NoticeRemove(path("a.txt"))]),
+            Some(vec![])
+        );
+        assert_eq!(
+            changed_paths(&[DebouncedEvent::Write(path("a.txt")), DebouncedEvent::Rescan]),
+            None
+        );
+    }
+
+    #[test]
+    fn splits_negated_terms() {
+        let split = |query| {
+            let (rest, negated) = split_negated_terms(query);
+            (rest, negated.join("|"))
+        };
+        assert_eq!(split("foo -bar"), ("foo".to_string(), "bar".to_string()));
+        assert_eq!(split("-bar foo"), ("foo".to_string(), "bar".to_string()));
+        assert_eq!(
+            split(r#"foo -"bar baz" -path:test"#),
+            ("foo".to_string(), r#""bar baz"|path:test"#.to_string())
+        );
+        assert_eq!(
+            split("-(a OR b) c"),
+            ("c".to_string(), "(a OR b)".to_string())
+        );
+        // These aren't negations
+        assert_eq!(
+            split("foo-bar - x"),
+            ("foo-bar - x".to_string(), "".to_string())
+        );
+        assert_eq!(
+            split(r#""foo -bar" (a -b)"#),
+            (r#""foo -bar" (a -b)"#.to_string(), "".to_string())
+        );
+        assert_eq!(
+            split("foo AND NOT bar -baz"),
+            ("foo AND NOT bar -baz".to_string(), "".to_string())
+        );
+    }
+
+    #[test]
+    fn negated_terms_exclude_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let index = index_files(
+            tmpdir.path(),
+            &[
+                ("a.txt", "foo bar"),
+                ("b.txt", "foo baz qux"),
+                ("c.txt", "bar"),
+            ],
+        );
+        let query_parser = index.query_parser();
+        let files = |text: &str, fuzzy: Option<u8>| {
+            let query = parse_user_query(&index, &query_parser, text, fuzzy, false, 0.0).unwrap();
+            search(&index, query)
+        };
+        assert_eq!(files("foo -bar", None), vec!["b.txt"]);
+        assert_eq!(files("-bar foo", None), vec!["b.txt"]);
+        assert_eq!(files(r#"foo -"baz qux""#, None), vec!["a.txt"]);
+        assert_eq!(files("-foo", None), vec!["c.txt"]);
+        // The negated terms are not fuzzy
+        assert_eq!(files("fob -bar", Some(1)), vec!["b.txt"]);
+    }
+
+    #[test]
+    fn query_files_match_any_and_files_match_all() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let index = index_files(
+            tmpdir.path(),
+            &[("a.txt", "foo"), ("b.txt", "bar baz"), ("c.txt", "foo baz")],
+        );
+        let query_file = |name: &str, query: &str| {
+            let path = tmpdir.path().join(name);
+            std::fs::write(&path, query).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let or_files = vec![query_file("foo.txt", "foo"), query_file("bar.txt", "bar")];
+        let and_files = vec![query_file("baz.txt", "baz")];
+        let files = |and_files: &[String]| {
+            let query = build_query(&index, &index.query_parser(), None, &or_files, and_files)
+                .unwrap()
+                .unwrap();
+            search(&index, query)
+        };
+        assert_eq!(files(&[]), vec!["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(files(&and_files), vec!["b.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn conjunction_parser_requires_every_word() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let index = index_files(
+            tmpdir.path(),
+            &[
+                ("both.rs", "async request handler"),
+                ("one.rs", "async runtime"),
+            ],
+        );
+        let query_file = tmpdir.path().join("query.txt");
+        std::fs::write(&query_file, "async handler\n").unwrap();
+        let query_files = vec![query_file.to_string_lossy().to_string()];
+        let files = |query_parser: &QueryParser| {
+            let query = index
+                .parse_query_with(query_parser, "async handler")
+                .unwrap();
+            let query = build_query(&index, query_parser, Some(query), &[], &query_files)
+                .unwrap()
+                .unwrap();
+            search(&index, query)
+        };
+        let mut query_parser = index.query_parser();
+        assert_eq!(files(&query_parser), vec!["both.rs", "one.rs"]);
+        query_parser.set_conjunction_by_default();
+        assert_eq!(files(&query_parser), vec!["both.rs"]);
+    }
+
+    #[test]
+    fn trims_one_trailing_newline() {
+        assert_eq!(trim_newline("foo bar\n"), "foo bar");
+        assert_eq!(trim_newline("foo\r\n"), "foo");
+        assert_eq!(trim_newline("foo\n\n"), "foo\n");
+        assert_eq!(trim_newline("foo\r"), "foo\r");
+        assert_eq!(trim_newline("foo"), "foo");
+    }
+
+    #[test]
+    fn formats_sizes_with_suffixes() {
+        assert_eq!(format_size(100), "100B");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(10 * 1024 * 1024), "10.0M");
+        assert_eq!(format_size(2048 * 1024 * 1024 * 1024), "2048.0G");
+    }
+
+    #[test]
+    fn finds_nested_and_named_cached_indexes() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_root = tempfile::tempdir().unwrap();
+        let project = cache_root.path().join("project");
+        let named = project.join("__index_notes");
+        for cache_dir in &[&project, &named] {
+            let mut index = FileIndex::get_or_create(
+                tmpdir.path(),
+                Some(cache_dir.as_path()),
+                &FileIndexOptions::default(),
+            )
+            .unwrap();
+            index.update(false).unwrap();
+        }
+        let mut indexes = Vec::new();
+        find_cached_indexes(cache_root.path(), &mut indexes).unwrap();
+        let mut names: Vec<Option<String>> = indexes.iter().map(|i| i.name.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec![None, Some("notes".to_string())]);
+        assert!(indexes.iter().all(|i| i.size > 0));
+    }
+
+    #[test]
+    fn cache_dir_keeps_named_indexes_separate() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let default_dir = get_index_dir(tmpdir.path(), "index", None).unwrap();
+        let named_dir = get_index_dir(tmpdir.path(), "index", Some("notes")).unwrap();
+        assert_ne!(default_dir, named_dir);
+        let notes_config = FileIndexOptions {
+            glob: vec!["*.md".to_string()],
+            ..Default::default()
+        };
+        for (cache_dir, config) in [
+            (&default_dir, FileIndexOptions::default()),
+            (&named_dir, notes_config.clone()),
+        ] {
+            let mut index =
+                FileIndex::get_or_create(tmpdir.path(), Some(cache_dir), &config).unwrap();
+            index.update(false).unwrap();
+        }
+        // Neither index was rebuilt with the options of the other
+        let index = FileIndex::get_or_create(
+            tmpdir.path(),
+            Some(&default_dir),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        assert!(index.rebuild_reason().is_none());
+        let index =
+            FileIndex::get_or_create(tmpdir.path(), Some(&named_dir), &notes_config).unwrap();
+        assert!(index.rebuild_reason().is_none());
+    }
+}
@@ -1,41 +1,529 @@
 use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
 
-use pore_core::FileSearchResult;
-use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
+use pore_core::{FileIndex, FileMetadata, FileSearchResult};
+use serde::Serialize;
+use termcolor::{ColorSpec, WriteColor};
 
 use crate::config::SearchConfig;
 
-/// Prints the search results to stdout
-pub fn print_results(
+/// Version of the --json record format. Bump this whenever the shape of a record changes.
+pub const JSON_SCHEMA_VERSION: u32 = 2;
+
+/// A single newline-delimited record in the --json output
+#[derive(Debug, Serialize)]
+struct JsonRecord<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    event: JsonEvent<'a>,
+}
+
+/// The events of the --json stream. Each matching file is framed by a begin and end record, and
+/// the stream is terminated by a single summary record.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    Begin {
+        path: &'a Path,
+    },
+    Match(&'a FileSearchResult),
+    End {
+        path: &'a Path,
+        matched_lines: usize,
+    },
+    Summary {
+        version: &'static str,
+        results: usize,
+        /// The number of files that matched the query, including the ones past the limit
+        total: usize,
+        elapsed_ms: u64,
+    },
+    /// The output of --indexes
+    Index(JsonIndex<'a>),
+    /// The output of --files
+    Files(&'a [String]),
+    /// The output of --count and --count-matches
+    Count(JsonCount<'a>),
+}
+
+fn print_json_event<W: Write>(out: &mut W, event: JsonEvent) -> Result<(), anyhow::Error> {
+    let record = JsonRecord {
+        schema_version: JSON_SCHEMA_VERSION,
+        event,
+    };
+    writeln!(out, "{}", serde_json::to_string(&record)?)?;
+    Ok(())
+}
+
+/// The --json output of --count and --count-matches
+#[derive(Debug, Serialize)]
+struct JsonCount<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'a Path>,
+    count: usize,
+}
+
+/// The --json output of --indexes
+#[derive(Debug, Serialize)]
+struct JsonIndex<'a> {
+    /// The directory of the index files, or null for an in-memory index
+    location: Option<&'a Path>,
+    #[serde(flatten)]
+    meta: &'a FileMetadata,
+}
+
+/// Prints the location, version, time of the last update, and options of the index as a JSON
+/// record
+pub fn print_index_json<W: Write>(out: &mut W, index: &FileIndex) -> Result<(), anyhow::Error> {
+    print_json_event(
+        out,
+        JsonEvent::Index(JsonIndex {
+            location: index.cache_dir(),
+            meta: index.metadata(),
+        }),
+    )
+}
+
+/// Prints the paths of the files that would be indexed as a JSON record with an array of the
+/// paths
+pub fn print_files_json<W: Write>(out: &mut W, paths: &[String]) -> Result<(), anyhow::Error> {
+    print_json_event(out, JsonEvent::Files(paths))
+}
+
+/// Prints the number of matching lines for each result (or the total, for --count-matches)
+fn print_counts<W: Write>(
+    out: &mut W,
+    results: &[FileSearchResult],
+    conf: &SearchConfig,
+) -> Result<(), anyhow::Error> {
+    let count = |result: &FileSearchResult| result.lines().iter().filter(|l| !l.is_context).count();
+    if conf.count_matches {
+        let total = results.iter().map(count).sum();
+        if conf.json {
+            print_json_event(
+                out,
+                JsonEvent::Count(JsonCount {
+                    file: None,
+                    count: total,
+                }),
+            )?;
+        } else {
+            writeln!(out, "{}", total)?;
+        }
+        return Ok(());
+    }
+    for result in results {
+        if conf.json {
+            print_json_event(
+                out,
+                JsonEvent::Count(JsonCount {
+                    file: Some(result.file()),
+                    count: count(result),
+                }),
+            )?;
+        } else {
+            writeln!(out, "{}:{}", result.file().to_string_lossy(), count(result))?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a path exactly as it is. On Unix a path can be any bytes, so it isn't converted to UTF-8
+/// (which would replace the invalid bytes), and the output can be used to open the file.
+fn write_path<W: Write>(out: &mut W, path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        out.write_all(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        write!(out, "{}", path.to_string_lossy())
+    }
+}
+
+/// Prints the search results to `out` (usually a `StandardStream` for stdout with the color choice
+/// of `conf`)
+pub fn print_results<W: WriteColor>(
+    out: &mut W,
     results: Vec<FileSearchResult>,
+    total: usize,
     conf: &SearchConfig,
+    elapsed: Duration,
 ) -> Result<bool, anyhow::Error> {
-    let mut stdout = StandardStream::stdout(conf.color.clone().into());
-    // TODO make colors configurable
-    let mut filename_color = ColorSpec::new();
-    filename_color.set_fg(Some(Color::Magenta));
+    if conf.count || conf.count_matches {
+        print_counts(out, &results, conf)?;
+        return Ok(results.len() > 0);
+    }
+    let filename_color = conf.filename_color.spec();
     let default_color = ColorSpec::new();
-    let mut line_number_color = ColorSpec::new();
-    line_number_color.set_fg(Some(Color::Green));
+    let line_number_color = conf.line_number_color.spec();
+    let mut heading_color = ColorSpec::new();
+    heading_color.set_dimmed(true);
+    let mut match_color = conf.match_color.spec();
+    match_color.set_bold(!conf.match_color.is_none());
 
     for (i, result) in results.iter().enumerate() {
         if conf.json {
-            println!("{}", serde_json::to_string(&result)?);
+            print_json_event(
+                out,
+                JsonEvent::Begin {
+                    path: result.file(),
+                },
+            )?;
+            print_json_event(out, JsonEvent::Match(result))?;
+            print_json_event(
+                out,
+                JsonEvent::End {
+                    path: result.file(),
+                    matched_lines: result.lines().iter().filter(|l| !l.is_context).count(),
+                },
+            )?;
         } else {
-            stdout.set_color(&filename_color)?;
-            writeln!(&mut stdout, "{}", result.file().to_string_lossy())?;
+            let path = result.file().to_string_lossy();
+            // Without headings the path is printed at the start of each line instead
+            if !conf.no_heading || conf.filename_only {
+                out.set_color(&filename_color)?;
+                if conf.filename_only && conf.null {
+                    // Only the paths are printed, so they can be separated by NUL instead of
+                    // newlines
+                    write_path(out, result.file())?;
+                    write!(out, "\0")?;
+                } else {
+                    writeln!(out, "{}", path)?;
+                }
+            }
+            let mut last_heading = None;
+            let mut last_number = None;
+            let has_context = conf.before_context > 0 || conf.after_context > 0;
             for line in result.lines() {
-                stdout.set_color(&line_number_color)?;
-                write!(&mut stdout, "{}", line.number)?;
-                stdout.set_color(&default_color)?;
-                writeln!(&mut stdout, ":{}", line.text)?;
+                // Separate the non-contiguous groups of lines, like grep
+                if has_context && last_number.map(|n| n + 1 < line.number).unwrap_or(false) {
+                    out.set_color(&default_color)?;
+                    writeln!(out, "--")?;
+                }
+                last_number = Some(line.number);
+                if let Some(heading) = line
+                    .context_heading
+                    .as_ref()
+                    .filter(|&heading| last_heading != Some(heading))
+                {
+                    // Like a context line, so it still says which file it's from
+                    if conf.no_heading {
+                        out.set_color(&filename_color)?;
+                        write!(out, "{}", path)?;
+                        out.set_color(&default_color)?;
+                        write!(out, "-")?;
+                    }
+                    out.set_color(&heading_color)?;
+                    writeln!(out, "@ {}", heading)?;
+                    last_heading = Some(heading);
+                }
+                let separator = if line.is_context { '-' } else { ':' };
+                if conf.no_heading {
+                    out.set_color(&filename_color)?;
+                    write!(out, "{}", path)?;
+                    out.set_color(&default_color)?;
+                    write!(out, "{}", separator)?;
+                }
+                out.set_color(&line_number_color)?;
+                write!(out, "{}", line.number)?;
+                out.set_color(&default_color)?;
+                write!(out, "{}", separator)?;
+                let mut start = 0;
+                for &(from, to) in &line.matches {
+                    let (text, matched) =
+                        match (line.text.get(start..from), line.text.get(from..to)) {
+                            (Some(text), Some(matched)) => (text, matched),
+                            _ => continue,
+                        };
+                    write!(out, "{}", text)?;
+                    out.set_color(&match_color)?;
+                    write!(out, "{}", matched)?;
+                    out.set_color(&default_color)?;
+                    start = to;
+                }
+                writeln!(out, "{}", &line.text[start..])?;
+            }
+            if result.lines_truncated() {
+                // Like the context headings, so it still says which file it's from
+                if conf.no_heading {
+                    out.set_color(&filename_color)?;
+                    write!(out, "{}", path)?;
+                    out.set_color(&default_color)?;
+                    write!(out, "-")?;
+                }
+                out.set_color(&heading_color)?;
+                writeln!(out, "(more matching lines not shown)")?;
+            }
+            if let Some(snippet) = result.snippet() {
+                if conf.no_heading {
+                    out.set_color(&filename_color)?;
+                    write!(out, "{}", path)?;
+                    out.set_color(&default_color)?;
+                    write!(out, ":")?;
+                }
+                out.set_color(&default_color)?;
+                writeln!(out, "{}", snippet)?;
             }
-            if !conf.filename_only {
+            if !conf.filename_only && !conf.no_heading {
                 if i < results.len() - 1 {
-                    println!("");
+                    writeln!(out)?;
                 }
             }
         }
     }
+    if conf.json {
+        print_json_event(
+            out,
+            JsonEvent::Summary {
+                version: env!("CARGO_PKG_VERSION"),
+                results: results.len(),
+                total,
+                elapsed_ms: elapsed.as_millis() as u64,
+            },
+        )?;
+    }
     Ok(results.len() > 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pore_core::{FileIndex, FileIndexOptions, SortBy};
+    use tantivy::query::QueryParser;
+    use termcolor::NoColor;
+
+    use super::{
+        print_files_json, print_index_json, print_results, write_path, JSON_SCHEMA_VERSION,
+    };
+    use crate::config::SearchConfig;
+
+    fn print(conf: &SearchConfig) -> String {
+        print_files(
+            conf,
+            &[
+                ("a.rs", "fn main() {\n    needle();\n}\n"),
+                ("b.rs", "needle\n"),
+            ],
+        )
+    }
+
+    /// Search the files for `needle` and print the results
+    fn print_files(conf: &SearchConfig, files: &[(&str, &str)]) -> String {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index
+            .add_contents(
+                files
+                    .iter()
+                    .map(|(path, contents)| (path.to_string(), contents.to_string())),
+            )
+            .unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let (results, total) = index.search_with_total(&query, &conf.to_opts("")).unwrap();
+        let mut out = NoColor::new(Vec::new());
+        print_results(&mut out, results, total, conf, Duration::default()).unwrap();
+        String::from_utf8(out.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn prints_lines_under_file_headings() {
+        let conf = SearchConfig {
+            sort_by: SortBy::Path,
+            ..Default::default()
+        };
+        assert_eq!(print(&conf), "a.rs\n2:    needle();\n\nb.rs\n1:needle\n");
+        let conf = SearchConfig {
+            no_heading: true,
+            ..conf
+        };
+        assert_eq!(print(&conf), "a.rs:2:    needle();\nb.rs:1:needle\n");
+        let conf = SearchConfig {
+            count: true,
+            ..conf
+        };
+        assert_eq!(print(&conf), "a.rs:1\nb.rs:1\n");
+    }
+
+    #[test]
+    fn omits_file_headings_with_no_heading() {
+        let files = [
+            ("a.rs", "needle();\nhay\nneedle();\n"),
+            ("b.rs", "needle\n"),
+        ];
+        let conf = SearchConfig {
+            sort_by: SortBy::Path,
+            no_heading: true,
+            ..Default::default()
+        };
+        let output = print_files(&conf, &files);
+        assert_eq!(
+            output,
+            "a.rs:1:needle();\na.rs:3:needle();\nb.rs:1:needle\n"
+        );
+        // Every line starts with its path, and there are no lines of only a path (or blank lines)
+        for line in output.lines() {
+            assert!(line.starts_with("a.rs:") || line.starts_with("b.rs:"));
+        }
+    }
+
+    #[test]
+    fn prints_results_as_json_records() {
+        let conf = SearchConfig {
+            json: true,
+            ..Default::default()
+        };
+        let records = print_files(&conf, &[("a.rs", "hay\nneedle();\n")])
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let types: Vec<&str> = records
+            .iter()
+            .map(|r| r["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(types, vec!["begin", "match", "end", "summary"]);
+        assert!(records
+            .iter()
+            .all(|r| r["schema_version"] == JSON_SCHEMA_VERSION));
+        assert_eq!(records[0]["data"], serde_json::json!({"path": "a.rs"}));
+        let data = &records[1]["data"];
+        assert_eq!(data["file"], "a.rs");
+        assert!(data["score"].as_f64().unwrap() > 0.0);
+        assert_eq!(data["match_count"], 1);
+        assert_eq!(
+            data["lines"],
+            serde_json::json!([{"number": 2, "text": "needle();", "matches": [[0, 6]]}])
+        );
+        assert_eq!(
+            records[2]["data"],
+            serde_json::json!({"path": "a.rs", "matched_lines": 1})
+        );
+        assert_eq!(records[3]["data"]["results"], 1);
+        assert_eq!(records[3]["data"]["total"], 1);
+    }
+
+    #[test]
+    fn prints_counts_as_json() {
+        let conf = SearchConfig {
+            sort_by: SortBy::Path,
+            count: true,
+            json: true,
+            ..Default::default()
+        };
+        let records = print(&conf)
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["schema_version"], JSON_SCHEMA_VERSION);
+        assert_eq!(records[0]["type"], "count");
+        assert_eq!(records[0]["data"]["file"], "a.rs");
+        assert_eq!(records[0]["data"]["count"], 1);
+        let conf = SearchConfig {
+            count_matches: true,
+            ..conf
+        };
+        let json: serde_json::Value = serde_json::from_str(&print(&conf)).unwrap();
+        assert_eq!(json["type"], "count");
+        assert!(json["data"].get("file").is_none());
+        assert_eq!(json["data"]["count"], 2);
+    }
+
+    #[test]
+    fn prefixes_headings_with_path_without_file_headings() {
+        let conf = SearchConfig {
+            sort_by: SortBy::Path,
+            heading_regex: "^fn ".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            print(&conf),
+            "a.rs\n@ fn main() {\n2:    needle();\n\nb.rs\n1:needle\n"
+        );
+        let conf = SearchConfig {
+            no_heading: true,
+            ..conf
+        };
+        assert_eq!(
+            print(&conf),
+            "a.rs-@ fn main() {\na.rs:2:    needle();\nb.rs:1:needle\n"
+        );
+    }
+
+    #[test]
+    fn notes_truncated_lines() {
+        let files = [("a.rs", "needle();\nneedle();\n")];
+        let conf = SearchConfig {
+            max_count: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            print_files(&conf, &files),
+            "a.rs\n1:needle();\n(more matching lines not shown)\n"
+        );
+        let conf = SearchConfig {
+            no_heading: true,
+            ..conf
+        };
+        assert_eq!(
+            print_files(&conf, &files),
+            "a.rs:1:needle();\na.rs-(more matching lines not shown)\n"
+        );
+    }
+
+    #[test]
+    fn separates_paths_with_nul() {
+        let conf = SearchConfig {
+            sort_by: SortBy::Path,
+            filename_only: true,
+            null: true,
+            ..Default::default()
+        };
+        assert_eq!(print(&conf), "a.rs\0b.rs\0");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_paths_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Path;
+        let bytes = b"caf\xe9.txt";
+        let mut out = Vec::new();
+        write_path(&mut out, Path::new(OsStr::from_bytes(bytes))).unwrap();
+        assert_eq!(out, bytes);
+    }
+
+    #[test]
+    fn prints_index_as_json() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        let mut out = Vec::new();
+        print_index_json(&mut out, &index).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["schema_version"], JSON_SCHEMA_VERSION);
+        assert_eq!(json["type"], "index");
+        let data = &json["data"];
+        assert!(data["location"].is_null());
+        assert_eq!(data["for_dir"], index.for_dir().to_string_lossy().as_ref());
+        assert_eq!(data["version"], index.metadata().version());
+        assert!(data["last_update"].is_string());
+        assert_eq!(data["config"]["language"], "english");
+    }
+
+    #[test]
+    fn prints_files_as_json() {
+        let mut out = Vec::new();
+        print_files_json(&mut out, &["a.rs".to_string(), "b/c.rs".to_string()]).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["schema_version"], JSON_SCHEMA_VERSION);
+        assert_eq!(json["type"], "files");
+        assert_eq!(json["data"], serde_json::json!(["a.rs", "b/c.rs"]));
+    }
+}
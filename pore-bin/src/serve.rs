@@ -0,0 +1,151 @@
+//! `pore --serve SOCKET` keeps the index open and answers search requests over a Unix socket, so
+//! an editor can search many times without opening the index for each search.
+//!
+//! Each request is a line of JSON with the `query` and (optionally) any of the search options of
+//! the config file, which override the options the server was started with for that search:
+//!
+//! ```json
+//! {"query": "parse config", "limit": 10}
+//! ```
+//!
+//! These can be set as well:
+//!   - `update`: update the index before searching (default false). The index is only locked while
+//!     it's being updated, so other processes can update it while the server is running.
+//!   - `fuzzy`: match each word of the query with up to this many typos, like `--fuzzy`
+//!   - `regex`: treat the query as a regex, like `--regex`
+//!   - `under`: only return matches from files inside this directory, like `--under`
+//!
+//! Each response is a line of JSON, either the results or an error:
+//!
+//! ```json
+//! {"results": [...], "total": 1284, "elapsed_ms": 3}
+//! {"error": "Error message"}
+//! ```
+//!
+//! The results have the same format as the `match` records of `--json`. Connections are handled
+//! one at a time, and a connection can send any number of requests.
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Instant;
+
+use pore_core::{FileIndex, FileSearchResult};
+use serde::{Deserialize, Serialize};
+
+use crate::args::GlobalConfig;
+use crate::config::{SearchConfig, SearchConfigOpt};
+use crate::find_results;
+
+#[derive(Debug, Deserialize)]
+struct SearchRequest {
+    query: String,
+    #[serde(default)]
+    update: bool,
+    #[serde(default)]
+    fuzzy: Option<u8>,
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    under: Option<String>,
+    #[serde(flatten)]
+    search: SearchConfigOpt,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum SearchResponse {
+    Results {
+        results: Vec<FileSearchResult>,
+        total: usize,
+        elapsed_ms: u64,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// Answer search requests on `socket` until the process is killed
+pub fn serve(
+    index: &mut FileIndex,
+    conf: &GlobalConfig,
+    search: &SearchConfigOpt,
+    socket: &Path,
+) -> Result<bool, anyhow::Error> {
+    // Remove the socket left behind by a server that didn't shut down cleanly, but don't delete
+    // some other file by accident
+    if let Ok(metadata) = fs::symlink_metadata(socket) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", socket.to_string_lossy());
+        }
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    for stream in listener.incoming() {
+        if let Err(err) = handle_connection(index, conf, search, stream?) {
+            eprintln!("Error: {}", err);
+        }
+    }
+    Ok(true)
+}
+
+/// Answer the requests of one connection until it is closed
+fn handle_connection(
+    index: &mut FileIndex,
+    conf: &GlobalConfig,
+    search: &SearchConfigOpt,
+    stream: UnixStream,
+) -> Result<(), anyhow::Error> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match handle_request(index, conf, search, &line) {
+            Ok(response) => response,
+            Err(err) => SearchResponse::Error {
+                error: err.to_string(),
+            },
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
+
+fn handle_request(
+    index: &mut FileIndex,
+    conf: &GlobalConfig,
+    search: &SearchConfigOpt,
+    line: &str,
+) -> Result<SearchResponse, anyhow::Error> {
+    let start = Instant::now();
+    let request: SearchRequest = serde_json::from_str(line)?;
+    // The writer is only opened for the update, so the server doesn't keep the index locked and
+    // other processes can update it too. Pick up their updates first, so the files they indexed
+    // aren't indexed again.
+    if request.update {
+        index.refresh()?;
+        index.update(false)?;
+    }
+    let mut search = search.clone();
+    search.merge_from(&request.search);
+    let search: SearchConfig = search.into();
+    // The query files of the command line don't apply to the requests
+    let conf = GlobalConfig {
+        query: Some(request.query),
+        query_files: Vec::new(),
+        and_files: Vec::new(),
+        fuzzy: request.fuzzy,
+        regex: request.regex,
+        under: request.under,
+        ..conf.clone()
+    };
+    let (results, total) = find_results(index, &conf, &search)?.unwrap_or_default();
+    Ok(SearchResponse::Results {
+        results,
+        total,
+        elapsed_ms: start.elapsed().as_millis() as u64,
+    })
+}
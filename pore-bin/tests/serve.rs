@@ -0,0 +1,72 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Kills the server when the test ends, even if it fails
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn serve_answers_queries_over_socket() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let search_dir = tmpdir.path().join("files");
+    fs::create_dir(&search_dir).unwrap();
+    fs::write(search_dir.join("a.txt"), "the needle is here").unwrap();
+    fs::write(search_dir.join("b.txt"), "just hay").unwrap();
+    let socket = tmpdir.path().join("pore.sock");
+
+    let mut server = Server(
+        Command::new(env!("CARGO_BIN_EXE_pore"))
+            .arg("--in-memory")
+            .arg("--serve")
+            .arg(&socket)
+            .current_dir(&search_dir)
+            // Don't read the user's config file
+            .env("XDG_CONFIG_HOME", tmpdir.path())
+            .spawn()
+            .unwrap(),
+    );
+    // The socket is created once the index is built
+    let start = Instant::now();
+    let stream = loop {
+        match UnixStream::connect(&socket) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(30) => {
+                assert!(server.0.try_wait().unwrap().is_none(), "Server exited");
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(err) => panic!("Could not connect to server: {}", err),
+        }
+    };
+    let mut writer = stream.try_clone().unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    let mut request = |line: &str| -> serde_json::Value {
+        writeln!(writer, "{}", line).unwrap();
+        serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap()
+    };
+
+    let response = request(r#"{"query": "needle"}"#);
+    assert_eq!(response["total"], 1);
+    let results = response["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0]["file"].as_str().unwrap().ends_with("a.txt"));
+
+    // The search options override the options of the server
+    let response = request(r#"{"query": "needle OR hay", "limit": 1}"#);
+    assert_eq!(response["total"], 2);
+    assert_eq!(response["results"].as_array().unwrap().len(), 1);
+
+    let response = request(r#"{"limit": 1}"#);
+    assert!(response["error"].is_string());
+}
@@ -7,20 +7,40 @@ use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 use tantivy::doc;
 
-use tantivy::directory::MmapDirectory;
+use tantivy::directory::error::LockError;
+use tantivy::directory::{Directory, MmapDirectory};
 use tantivy::schema::*;
 use tantivy::tokenizer::*;
-use tantivy::Index;
+use tantivy::{
+    Index, IndexReader, IndexWriter, LeasedItem, ReloadPolicy, Searcher, SegmentComponent,
+    TantivyError,
+};
 
+use crate::directory::BoxedDirectory;
+use crate::error::PoreError;
+use crate::field_kind::{FieldDef, FieldKind};
 use crate::language::LanguageRef;
+use crate::ngram::WordNgramTokenizer;
 
 pub trait IndexMetadata<T: MetadataConfig + Eq> {
+    /// The format of the indexes built by this version of pore. Bump it whenever the schema changes
+    /// in a way that an existing index can't be opened with (e.g. a new field), so that the old
+    /// indexes are rebuilt.
+    const FORMAT_VERSION: u32 = 0;
     fn config(&self) -> &T;
     fn version(&self) -> &str;
+    /// The format of the index (see `FORMAT_VERSION`). Indexes from before the format was recorded
+    /// are 0.
+    fn format_version(&self) -> u32 {
+        0
+    }
     fn last_update(&self) -> &DateTime<Utc>;
     fn set_last_update(&mut self, time: DateTime<Utc>);
+    fn set_config(&mut self, config: T);
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,6 +48,9 @@ pub struct Metadata<T: MetadataConfig + Eq> {
     version: String,
     last_update: DateTime<Utc>,
     config: T,
+    /// The name of the field that identifies each document. Older indexes don't record it.
+    #[serde(default)]
+    id_field: String,
 }
 
 impl<T: MetadataConfig + Eq> Metadata<T> {
@@ -36,8 +59,15 @@ impl<T: MetadataConfig + Eq> Metadata<T> {
             config,
             version: env!("CARGO_PKG_VERSION").to_string(),
             last_update: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
+            id_field: String::new(),
         }
     }
+    pub fn id_field(&self) -> &str {
+        &self.id_field
+    }
+    pub fn set_id_field(&mut self, name: &str) {
+        self.id_field = name.to_string();
+    }
 }
 
 impl<T: MetadataConfig + Eq> IndexMetadata<T> for Metadata<T> {
@@ -53,77 +83,318 @@ impl<T: MetadataConfig + Eq> IndexMetadata<T> for Metadata<T> {
     fn set_last_update(&mut self, time: DateTime<Utc>) {
         self.last_update = time;
     }
+    fn set_config(&mut self, config: T) {
+        self.config = config;
+    }
 }
 
 pub trait MetadataConfig {
     fn language(&self) -> LanguageRef;
+    /// A copy of the config with the options that only affect how the index is written (like the
+    /// memory budget of the writer) reset to their defaults. Only the other options are compared to
+    /// decide whether an existing index has to be rebuilt.
+    fn without_write_options(&self) -> Self
+    where
+        Self: Sized;
+    /// Additional languages to stem the text fields with. Each one adds a copy of every text field
+    /// (see `language_field_name`) that is stemmed with that language.
+    fn extra_languages(&self) -> &[LanguageRef] {
+        &[]
+    }
+    /// Store the text of the text fields in the index (but not the copies for the extra
+    /// languages), so it can be read back without the original source
+    fn store_text(&self) -> bool {
+        false
+    }
+    /// Split the words of the text fields (but not the copies for the extra languages) into
+    /// n-grams instead of stemming them, as `(min_gram, max_gram, prefix_only)`
+    fn ngram(&self) -> Option<(usize, usize, bool)> {
+        None
+    }
+    /// Index the text fields without lowercasing them, so that searches are case-sensitive
+    fn case_sensitive(&self) -> bool {
+        false
+    }
+    /// Words longer than this many bytes are not indexed (unless the words are split into
+    /// n-grams). 0 means there is no limit.
+    fn max_token_length(&self) -> usize {
+        DEFAULT_MAX_TOKEN_LENGTH
+    }
+    /// Words that are not indexed (or searched for), like noise words that are in most of the
+    /// documents. They don't apply to the words that are split into n-grams.
+    fn stopwords(&self) -> &[String] {
+        &[]
+    }
+    /// Record the positions of the words in the text fields, which phrase queries need. Without
+    /// them, the text fields are indexed `WithFreqs`, which makes the index much smaller.
+    fn record_positions(&self) -> bool {
+        true
+    }
+    /// Additional text fields that are stored, but not indexed
+    fn stored_fields(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// Describe the fields that differ between two configs, like `language changed english→french`
+///
+/// The configs are compared by their serialized fields, so this works for any config type.
+pub fn config_diff<T: Serialize>(old: &T, new: &T) -> Vec<String> {
+    let display = |value: Option<&serde_json::Value>| match value {
+        None => "(none)".to_string(),
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    };
+    let old = serde_json::to_value(old).unwrap_or_default();
+    let new = serde_json::to_value(new).unwrap_or_default();
+    let (old_fields, new_fields) = match (old.as_object(), new.as_object()) {
+        (Some(old_fields), Some(new_fields)) => (old_fields, new_fields),
+        _ => return vec![format!("config changed {}→{}", old, new)],
+    };
+    let mut names: Vec<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| old_fields.get(*name) != new_fields.get(*name))
+        .map(|name| {
+            format!(
+                "{} changed {}→{}",
+                name,
+                display(old_fields.get(name)),
+                display(new_fields.get(name))
+            )
+        })
+        .collect()
+}
+
+/// The name of the copy of a text field that is stemmed with one of the extra languages
+pub fn language_field_name(name: &str, language: LanguageRef) -> String {
+    format!("{}_{:?}", name, language).to_lowercase()
+}
+
+/// The tokenizer of the text fields without the filters that remove tokens (like the stop words)
+///
+/// The removed tokens still take up a position, so this is what the positions of the tokens on
+/// each line have to be counted with.
+pub fn position_tokenizer<T: MetadataConfig>(config: &T) -> TextAnalyzer {
+    match config.ngram() {
+        Some((min_gram, max_gram, prefix_only)) => {
+            TextAnalyzer::from(WordNgramTokenizer::new(min_gram, max_gram, prefix_only))
+        }
+        None => TextAnalyzer::from(SimpleTokenizer),
+    }
 }
 
 pub const METADATA_FILE: &str = "pore_meta.json";
 
+/// The default for `MetadataConfig::max_token_length`. Longer words are usually not worth
+/// indexing (e.g. hashes or base64 data), and they make the index bigger.
+pub const DEFAULT_MAX_TOKEN_LENGTH: usize = 40;
+
+/// The default memory budget (in bytes) for an index writer, which is split between its threads
+pub const DEFAULT_WRITER_MEMORY: usize = 50_000_000;
+
+/// Open the index in `directory` or `cache_dir` (or create it in memory if neither is given)
+///
+/// The metadata of an existing index is returned if the index can be reused with `config`.
+/// Otherwise the index is rebuilt, and the reason for that (like the options that changed) is
+/// returned instead, so the caller can tell the user.
 pub fn create_index<
     T: IndexMetadata<U> + DeserializeOwned,
-    U: MetadataConfig + Eq,
+    U: MetadataConfig + Eq + Clone + Serialize,
     P: AsRef<Path>,
     I: IntoIterator<Item = V>,
-    V: Into<String>,
+    V: Into<FieldDef>,
 >(
     cache_dir: Option<P>,
+    directory: Option<&BoxedDirectory>,
     config: &U,
     id_field: &str,
-    text_fields: I,
-) -> Result<(Option<T>, Index), anyhow::Error> {
+    fields: I,
+) -> Result<(Option<T>, Index, Option<String>), anyhow::Error> {
     let mut ret_meta: Option<T> = None;
-    let metafile = cache_dir.as_ref().map(|p| p.as_ref().join(METADATA_FILE));
-    if metafile.as_deref().map(|p| p.exists()).unwrap_or(false) {
-        let meta_res = serde_json::from_str::<T>(&fs::read_to_string(metafile.unwrap())?);
-        if let Ok(meta) = meta_res {
-            if meta.config() == config {
+    let mut rebuild_reason = None;
+    let meta_contents = match directory {
+        Some(directory) => directory.atomic_read(Path::new(METADATA_FILE)).ok(),
+        None => cache_dir
+            .as_ref()
+            .map(|p| p.as_ref().join(METADATA_FILE))
+            .filter(|metafile| metafile.exists())
+            .map(fs::read)
+            .transpose()?,
+    };
+    let mut incompatible_version = false;
+    if let Some(contents) = meta_contents {
+        if let Ok(mut meta) = serde_json::from_slice::<T>(&contents) {
+            let old_config = meta.config().without_write_options();
+            let new_config = config.without_write_options();
+            if let Some(reason) = incompatibility(&meta) {
+                incompatible_version = true;
+                rebuild_reason = Some(format!("index was {}", reason));
+            } else if old_config == new_config {
+                // Use the new write options with the existing index
+                meta.set_config(config.clone());
                 ret_meta = Some(meta);
+            } else {
+                rebuild_reason = Some(config_diff(&old_config, &new_config).join(", "));
             }
         }
     }
 
+    let case_sensitive = config.case_sensitive();
+    let case_suffix = if case_sensitive { "_cased" } else { "" };
+    let lowercase = |analyzer: TextAnalyzer| {
+        if case_sensitive {
+            analyzer
+        } else {
+            analyzer.filter(LowerCaser)
+        }
+    };
+    let max_token_length = config.max_token_length();
+    // The tokens are lowercased before the stop words are removed
+    let stopwords: Vec<String> = config
+        .stopwords()
+        .iter()
+        .map(|word| {
+            if case_sensitive {
+                word.clone()
+            } else {
+                word.to_lowercase()
+            }
+        })
+        .collect();
     let mut tokenizers = HashMap::new();
-    let mut get_tokenizer = |lang: Language| {
-        let key = format!("stemmer_{:?}", lang);
+    let get_tokenizer = |tokenizers: &mut HashMap<String, TextAnalyzer>, lang: Option<Language>| {
+        let key = match lang {
+            Some(lang) => format!("stemmer_{:?}{}", lang, case_suffix),
+            None => format!("raw{}", case_suffix),
+        };
         if !tokenizers.contains_key(&key) {
-            let tokenizer = TextAnalyzer::from(SimpleTokenizer)
-                .filter(RemoveLongFilter::limit(40))
-                .filter(LowerCaser)
-                .filter(Stemmer::new(lang));
+            let mut analyzer = TextAnalyzer::from(SimpleTokenizer);
+            if max_token_length > 0 {
+                // The filter removes the tokens that are at least this long
+                analyzer = analyzer.filter(RemoveLongFilter::limit(max_token_length + 1));
+            }
+            let mut tokenizer = lowercase(analyzer);
+            if !stopwords.is_empty() {
+                tokenizer = tokenizer.filter(StopWordFilter::remove(stopwords.clone()));
+            }
+            if let Some(lang) = lang {
+                tokenizer = tokenizer.filter(Stemmer::new(lang));
+            }
             tokenizers.insert(key.clone(), tokenizer);
         }
         return key;
     };
+    let main_tokenizer = match config.ngram() {
+        None => get_tokenizer(&mut tokenizers, config.language().into()),
+        Some((min_gram, max_gram, prefix_only)) => {
+            if min_gram == 0 || min_gram > max_gram {
+                bail!(PoreError::Schema(format!(
+                    "Invalid n-gram sizes {}..{}: the minimum must be at least 1 and at most the maximum",
+                    min_gram,
+                    max_gram
+                )));
+            }
+            let key = format!(
+                "ngram_{}_{}_{}{}",
+                min_gram, max_gram, prefix_only, case_suffix
+            );
+            let tokenizer = lowercase(TextAnalyzer::from(WordNgramTokenizer::new(
+                min_gram,
+                max_gram,
+                prefix_only,
+            )));
+            tokenizers.insert(key.clone(), tokenizer);
+            key
+        }
+    };
     let mut schema_builder = Schema::builder();
     schema_builder.add_text_field(id_field, STRING | STORED);
-    for name in text_fields {
-        let text_options = TextOptions::default().set_indexing_options(
+    let record_option = if config.record_positions() {
+        IndexRecordOption::WithFreqsAndPositions
+    } else {
+        IndexRecordOption::WithFreqs
+    };
+    let text_options = |tokenizer: &str| {
+        TextOptions::default().set_indexing_options(
             TextFieldIndexing::default()
-                .set_tokenizer(&get_tokenizer(config.language().into()))
-                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
-        );
-        schema_builder.add_text_field(&name.into(), text_options);
+                .set_tokenizer(tokenizer)
+                .set_index_option(record_option),
+        )
+    };
+    for field in fields {
+        let FieldDef { name, kind } = field.into();
+        // Numeric fields can be filtered with range queries, and are fast fields for sorting
+        match kind {
+            FieldKind::Text => {}
+            // Raw fields are indexed as a single token, so they only match the exact value
+            FieldKind::Raw => {
+                let mut options = STRING;
+                if config.store_text() {
+                    options = options.set_stored();
+                }
+                schema_builder.add_text_field(&name, options);
+                continue;
+            }
+            FieldKind::U64 => {
+                schema_builder.add_u64_field(&name, INDEXED | FAST | STORED);
+                continue;
+            }
+            FieldKind::I64 => {
+                schema_builder.add_i64_field(&name, INDEXED | FAST | STORED);
+                continue;
+            }
+            FieldKind::F64 => {
+                schema_builder.add_f64_field(&name, INDEXED | FAST | STORED);
+                continue;
+            }
+            FieldKind::Date => {
+                schema_builder.add_date_field(&name, INDEXED | FAST | STORED);
+                continue;
+            }
+        }
+        let mut options = text_options(&main_tokenizer);
+        if config.store_text() {
+            options = options.set_stored();
+        }
+        schema_builder.add_text_field(&name, options);
+        for lang in config.extra_languages() {
+            let tokenizer = get_tokenizer(&mut tokenizers, (*lang).into());
+            schema_builder
+                .add_text_field(&language_field_name(&name, *lang), text_options(&tokenizer));
+        }
+    }
+    for name in config.stored_fields() {
+        schema_builder.add_text_field(&name, STORED);
     }
     let schema = schema_builder.build();
-    let index = match cache_dir {
-        None => Index::create_in_ram(schema.clone()),
-        Some(index_dir) => {
+    let index = match (directory, cache_dir) {
+        (Some(directory), _) => {
+            // The schema of an incompatible index may not match, so start a new index instead of
+            // opening it. The files of the old index are garbage collected by tantivy.
+            if incompatible_version {
+                directory.delete(Path::new(TANTIVY_META_FILE)).ok();
+            }
+            Index::open_or_create(directory.clone(), schema.clone())?
+        }
+        (None, None) => Index::create_in_ram(schema.clone()),
+        (None, Some(index_dir)) => {
             fs::create_dir_all(&index_dir)?;
+            if incompatible_version {
+                remove_index_files(index_dir.as_ref())?;
+            }
             let mut index_res =
                 Index::open_or_create(MmapDirectory::open(&index_dir)?, schema.clone());
-            // If it fails to load, it's probably because the schema is different or the index is
-            // corrupted. Delete all files in the dir and try again.
+            // If it fails to load, it's probably because the index is corrupted. Delete all files
+            // in the dir and try again.
             if index_res.is_err() {
                 eprintln!("Index is corrupted. Deleting index files");
-                for dir_entry in fs::read_dir(&index_dir)? {
-                    if let Ok(entry) = dir_entry {
-                        if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-                            fs::remove_file(entry.path())?;
-                        }
-                    }
-                }
+                // The metadata describes the deleted index, so the new one has to be built from
+                // scratch
+                ret_meta = None;
+                remove_index_files(index_dir.as_ref())?;
                 index_res = Index::open_or_create(MmapDirectory::open(&index_dir)?, schema.clone());
             }
             index_res?
@@ -132,23 +403,312 @@ pub fn create_index<
     for (name, tokenizer) in tokenizers {
         index.tokenizers().register(&name, tokenizer);
     }
-    Ok((ret_meta, index))
+    Ok((ret_meta, index, rebuild_reason))
+}
+
+/// The file that tantivy stores the list of segments (and the schema) of an index in
+const TANTIVY_META_FILE: &str = "meta.json";
+
+/// Delete the files of the index (and its metadata) in `index_dir`
+pub fn remove_index_files(index_dir: &Path) -> anyhow::Result<()> {
+    for dir_entry in fs::read_dir(index_dir)? {
+        if let Ok(entry) = dir_entry {
+            if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check an index on disk for problems, without modifying it
+///
+/// This verifies that the metadata file can be loaded and matches the config, that the tantivy
+/// index can be opened, and that the files of every segment exist and pass their checksums.
+/// Returns a description of each problem found.
+pub fn check_index<T: IndexMetadata<U> + DeserializeOwned, U: MetadataConfig + Eq + Serialize>(
+    index_dir: &Path,
+    config: &U,
+) -> anyhow::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    if !index_dir.exists() {
+        problems.push(format!("No index found at {}", index_dir.to_string_lossy()));
+        return Ok(problems);
+    }
+
+    let metafile = index_dir.join(METADATA_FILE);
+    if !metafile.exists() {
+        problems.push(format!("Missing metadata file {}", METADATA_FILE));
+    } else {
+        match serde_json::from_slice::<T>(&fs::read(&metafile)?) {
+            Err(err) => problems.push(format!("Invalid metadata file {}: {}", METADATA_FILE, err)),
+            Ok(meta) => {
+                if let Some(reason) = incompatibility(&meta) {
+                    problems.push(format!("Index was {} and will be rebuilt", reason));
+                } else {
+                    let old_config = meta.config().without_write_options();
+                    let new_config = config.without_write_options();
+                    if old_config != new_config {
+                        problems.push(format!(
+                            "Index was built with different options and will be rebuilt: {}",
+                            config_diff(&old_config, &new_config).join(", ")
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    let index = match Index::open(MmapDirectory::open(index_dir)?) {
+        Ok(index) => index,
+        Err(err) => {
+            problems.push(format!("Could not open index: {}", err));
+            return Ok(problems);
+        }
+    };
+    for segment in index.searchable_segment_metas()? {
+        for component in SegmentComponent::iterator() {
+            match component {
+                SegmentComponent::TempStore => continue,
+                SegmentComponent::Delete if !segment.has_deletes() => continue,
+                _ => {}
+            }
+            let path = segment.relative_path(*component);
+            if !index.directory().exists(&path)? {
+                problems.push(format!("Missing segment file {}", path.to_string_lossy()));
+            }
+        }
+    }
+    match index.validate_checksum() {
+        Ok(damaged) => {
+            for path in damaged {
+                problems.push(format!("Corrupted segment file {}", path.to_string_lossy()));
+            }
+        }
+        Err(err) => problems.push(format!("Could not validate segment files: {}", err)),
+    }
+    Ok(problems)
+}
+
+/// If an index can't be used by this version of pore, describe why (as in "index was ...")
+fn incompatibility<T: IndexMetadata<U>, U: MetadataConfig + Eq>(meta: &T) -> Option<String> {
+    if !is_compatible_version(meta.version()) {
+        Some(format!(
+            "built by pore {} (this is {})",
+            meta.version(),
+            env!("CARGO_PKG_VERSION")
+        ))
+    } else if meta.format_version() != T::FORMAT_VERSION {
+        Some(format!(
+            "built with index format {} (this is {})",
+            meta.format_version(),
+            T::FORMAT_VERSION
+        ))
+    } else {
+        None
+    }
+}
+
+/// Load the metadata of an existing index, so it can be opened with the config it was built with
+///
+/// Returns an error if there is no index in `index_dir`, or if it was built by an incompatible
+/// version of pore (which would have to be rebuilt).
+pub fn read_metadata<T: IndexMetadata<U> + DeserializeOwned, U: MetadataConfig + Eq>(
+    index_dir: &Path,
+) -> anyhow::Result<T> {
+    let metafile = index_dir.join(METADATA_FILE);
+    if !metafile.exists() {
+        bail!(PoreError::Metadata(format!(
+            "No index found at {}",
+            index_dir.to_string_lossy()
+        )));
+    }
+    let meta: T = serde_json::from_slice(&fs::read(&metafile)?).map_err(|err| {
+        PoreError::Metadata(format!("Invalid metadata file {}: {}", METADATA_FILE, err))
+    })?;
+    if let Some(reason) = incompatibility(&meta) {
+        bail!(PoreError::Metadata(format!(
+            "Index at {} was {} and needs to be rebuilt",
+            index_dir.to_string_lossy(),
+            reason
+        )));
+    }
+    Ok(meta)
+}
+
+/// Returns true if an index built by this version of pore can be used by the current one
+///
+/// The index layout may change in any minor version (this is still 0.x), so the major and minor
+/// versions have to match.
+fn is_compatible_version(version: &str) -> bool {
+    let major_minor = |v: &str| v.split('.').take(2).collect::<Vec<_>>().join(".");
+    major_minor(version) == major_minor(env!("CARGO_PKG_VERSION"))
+}
+
+/// Save the index metadata next to the index files, so it can be loaded by `create_index`
+pub fn write_metadata<T: Serialize>(
+    meta: &T,
+    cache_dir: Option<&Path>,
+    directory: Option<&BoxedDirectory>,
+) -> anyhow::Result<()> {
+    let contents = serde_json::to_string(meta)?;
+    if let Some(directory) = directory {
+        directory.atomic_write(Path::new(METADATA_FILE), contents.as_bytes())?;
+    } else if let Some(index_dir) = cache_dir {
+        fs::write(index_dir.join(METADATA_FILE), contents)?;
+    }
+    Ok(())
 }
 
-pub fn delete_index(index: &Index, cache_dir: Option<&Path>) -> anyhow::Result<bool> {
+/// An `IndexReader` that is created when the index is opened and shared by every search
+///
+/// Creating a reader is relatively expensive, and an editor may search on every keystroke. The
+/// reader is reloaded before each search, so it sees the changes that were just committed (the
+/// `OnCommit` policy only reloads once the directory notices the commit).
+#[derive(Clone)]
+pub struct CachedReader(IndexReader);
+
+impl CachedReader {
+    pub fn new(index: &Index) -> Result<Self, anyhow::Error> {
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommit)
+            .try_into()?;
+        Ok(CachedReader(reader))
+    }
+
+    /// Get a searcher for the latest commit
+    pub fn searcher(&self) -> Result<LeasedItem<Searcher>, anyhow::Error> {
+        self.0.reload()?;
+        Ok(self.0.searcher())
+    }
+}
+
+impl std::fmt::Debug for CachedReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CachedReader")
+    }
+}
+
+/// How long to wait for another process to release the lock on an index before giving up (see
+/// `FileIndex::set_lock_timeout`)
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Create a writer for `index`, retrying with backoff while another process holds the lock on the
+/// index (e.g. pore updating the same index from an editor and a terminal). Returns
+/// `PoreError::IndexLocked` if it is still locked after `timeout`.
+pub fn create_writer(
+    index: &Index,
+    writer_memory: usize,
+    timeout: Duration,
+) -> anyhow::Result<IndexWriter> {
+    let start = Instant::now();
+    let mut delay = Duration::from_millis(20);
+    loop {
+        match index.writer(writer_memory) {
+            Err(TantivyError::LockFailure(LockError::LockBusy, _)) => {
+                let elapsed = start.elapsed();
+                if elapsed >= timeout {
+                    return Err(PoreError::IndexLocked.into());
+                }
+                thread::sleep(delay.min(timeout - elapsed));
+                delay = (delay * 2).min(Duration::from_millis(500));
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Delete all documents and files for an index
+///
+/// Returns true if there was a previously-built index to delete. Opening an index creates the
+/// directory, so the existence of the metadata file is what indicates a built index.
+pub fn delete_index(
+    index: &Index,
+    cache_dir: Option<&Path>,
+    writer_memory: usize,
+    lock_timeout: Duration,
+) -> anyhow::Result<bool> {
     match cache_dir {
         None => return Ok(false),
         Some(index_dir) => {
             if !index_dir.exists() {
                 return Ok(false);
             }
-            let mut index_writer = index.writer(50_000_000)?;
+            let metafile = index_dir.join(METADATA_FILE);
+            let existed = metafile.exists();
+            let mut index_writer = create_writer(index, writer_memory, lock_timeout)?;
             index_writer.delete_all_documents()?;
             index_writer.commit()?;
-            let metafile = index_dir.join(METADATA_FILE);
             fs::remove_file(metafile).ok();
             fs::remove_dir(&index_dir).ok();
-            Ok(true)
+            Ok(existed)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use tantivy::schema::Schema;
+    use tantivy::Index;
+
+    use super::{config_diff, create_writer, is_compatible_version, DEFAULT_WRITER_MEMORY};
+    use crate::error::PoreError;
+    use crate::language::LanguageRef;
+    use crate::FileIndexOptions;
+
+    #[test]
+    fn config_diff_lists_changed_fields() {
+        let old = FileIndexOptions::default();
+        assert!(config_diff(&old, &old).is_empty());
+        let new = FileIndexOptions {
+            language: LanguageRef::French,
+            hidden: true,
+            glob: vec!["*.rs".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            config_diff(&old, &new),
+            vec![
+                "glob changed []→[\"*.rs\"]",
+                "hidden changed false→true",
+                "language changed english→french",
+            ]
+        );
+    }
+
+    #[test]
+    fn versions_are_compatible_within_a_minor_version() {
+        let current = env!("CARGO_PKG_VERSION");
+        assert!(is_compatible_version(current));
+        let mut parts: Vec<u64> = current.split('.').map(|p| p.parse().unwrap()).collect();
+        parts[2] += 1;
+        let patch = parts.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert!(is_compatible_version(&patch.join(".")));
+        parts[1] += 1;
+        let minor = parts.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert!(!is_compatible_version(&minor.join(".")));
+        assert!(!is_compatible_version(""));
+    }
+
+    #[test]
+    fn create_writer_waits_for_lock() {
+        let index = Index::create_in_ram(Schema::builder().build());
+        let writer = create_writer(&index, DEFAULT_WRITER_MEMORY, Duration::ZERO).unwrap();
+        let res = create_writer(&index, DEFAULT_WRITER_MEMORY, Duration::from_millis(50));
+        assert!(matches!(
+            res.map_err(PoreError::from),
+            Err(PoreError::IndexLocked)
+        ));
+
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(writer);
+        });
+        create_writer(&index, DEFAULT_WRITER_MEMORY, Duration::from_secs(30)).unwrap();
+        holder.join().unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// How to group search results so that only the top-scoring result of each group is returned
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupeBy {
+    None,
+    #[serde(alias = "dir")]
+    Directory,
+    #[serde(alias = "ext")]
+    Extension,
+}
+
+impl DedupeBy {
+    /// The key of the group that a file belongs to (or None if results are not deduped)
+    pub fn group_key(&self, path: &Path) -> Option<String> {
+        match self {
+            DedupeBy::None => None,
+            DedupeBy::Directory => Some(
+                path.parent()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            ),
+            DedupeBy::Extension => Some(
+                path.extension()
+                    .map(|e| e.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+string_enum!(DedupeBy, "dedupe value", {
+    "none" => DedupeBy::None,
+    "dir" | "directory" => DedupeBy::Directory,
+    "ext" | "extension" => DedupeBy::Extension,
+});
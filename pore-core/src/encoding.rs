@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// How to read files that are not valid UTF-8
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// Skip the files (they are reported as `SkipReason::NotUtf8`)
+    Strict,
+    /// Index the files with the invalid bytes replaced by `U+FFFD`, so mostly-ASCII files (e.g.
+    /// latin-1 source files) can still be searched. The files are reported in
+    /// `UpdateSummary::lossy`.
+    Lossy,
+}
+
+string_enum!(Encoding, "encoding", {
+    "strict" => Encoding::Strict,
+    "lossy" => Encoding::Lossy,
+});
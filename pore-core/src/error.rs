@@ -0,0 +1,104 @@
+use std::io;
+
+use tantivy::directory::error::LockError;
+use tantivy::query::QueryParserError;
+use tantivy::TantivyError;
+
+/// The errors returned by the public methods of `FileIndex` and `GenericIndex`
+///
+/// The indexes use `anyhow` internally, and the errors are sorted into these variants when they
+/// are returned, so callers can tell them apart. Errors that don't fit any of them are `Other`.
+#[derive(Debug, thiserror::Error)]
+pub enum PoreError {
+    /// Another writer (usually another process updating the same index) held the lock on the
+    /// index for longer than pore waits for it
+    #[error("The index is locked: another pore process is updating this index")]
+    IndexLocked,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The schema of the index couldn't be built from the options (e.g. an invalid field name)
+    #[error("Schema error: {0}")]
+    Schema(String),
+    /// The metadata file of the index is missing, invalid, or was written by an incompatible
+    /// version of pore
+    #[error("{0}")]
+    Metadata(String),
+    /// The query (or the pattern of a regex query) couldn't be parsed
+    #[error("Invalid query: {0}")]
+    QueryParse(String),
+    #[error(transparent)]
+    Tantivy(TantivyError),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<TantivyError> for PoreError {
+    fn from(err: TantivyError) -> Self {
+        match err {
+            TantivyError::LockFailure(LockError::LockBusy, _) => PoreError::IndexLocked,
+            TantivyError::LockFailure(LockError::IoError(err), _) => PoreError::Io(err),
+            TantivyError::IoError(err) => PoreError::Io(err),
+            TantivyError::SchemaError(message) => PoreError::Schema(message),
+            err => PoreError::Tantivy(err),
+        }
+    }
+}
+
+impl From<QueryParserError> for PoreError {
+    fn from(err: QueryParserError) -> Self {
+        PoreError::QueryParse(err.to_string())
+    }
+}
+
+impl From<anyhow::Error> for PoreError {
+    /// Recover the variant from the error that the `anyhow::Error` wraps, if it is one of the
+    /// errors that has a variant
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<PoreError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<TantivyError>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<QueryParserError>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        match err.downcast::<io::Error>() {
+            Ok(err) => PoreError::Io(err),
+            Err(err) => PoreError::Other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tantivy::directory::error::LockError;
+    use tantivy::TantivyError;
+
+    use super::PoreError;
+
+    #[test]
+    fn anyhow_errors_keep_their_variant() {
+        let err: PoreError =
+            anyhow::Error::from(TantivyError::LockFailure(LockError::LockBusy, None)).into();
+        assert!(matches!(err, PoreError::IndexLocked));
+        // Only a lock held by another writer means the index is locked
+        let err: PoreError = anyhow::Error::from(TantivyError::LockFailure(
+            LockError::IoError(io::Error::from(io::ErrorKind::PermissionDenied)),
+            None,
+        ))
+        .into();
+        assert!(matches!(err, PoreError::Io(_)));
+        let err: PoreError = anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound)).into();
+        assert!(matches!(err, PoreError::Io(_)));
+        let err: PoreError = anyhow!(PoreError::Metadata("Invalid".to_string())).into();
+        assert!(matches!(err, PoreError::Metadata(_)));
+        let err: PoreError = anyhow!("Something else").into();
+        assert!(matches!(err, PoreError::Other(_)));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeSet;
+
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA, SINK_STATE};
+use tantivy::{
+    query::{BooleanQuery, Occur, Query, TermQuery},
+    schema::{Field, IndexRecordOption},
+    Searcher, Term,
+};
+use tantivy_fst::Automaton;
+
+/// Find the terms of a field in any of the index segments that match a predicate
+///
+/// Queries like `FuzzyTermQuery` and `RegexQuery` match terms with an automaton, so they don't
+/// report the terms they matched and the matching lines can't be found from the term positions.
+/// Expanding the query to the matching terms up front (and searching for those) avoids that
+/// problem. This checks every term of the field, so prefer `expand_fuzzy_terms` when it applies.
+pub fn expand_terms<F: Fn(&str) -> bool>(
+    searcher: &Searcher,
+    field: Field,
+    is_match: F,
+) -> Result<BTreeSet<String>, anyhow::Error> {
+    let mut terms = BTreeSet::new();
+    for segment_reader in searcher.segment_readers() {
+        let inverted_index = segment_reader.inverted_index(field)?;
+        let mut stream = inverted_index.terms().stream()?;
+        while let Some((key, _)) = stream.next() {
+            if let Ok(text) = std::str::from_utf8(key) {
+                if is_match(text) {
+                    terms.insert(text.to_string());
+                }
+            }
+        }
+    }
+    Ok(terms)
+}
+
+/// Find the terms of a field that are within `distance` edits of any of `words`
+///
+/// Like `expand_terms`, but the term dictionary is searched with a Levenshtein automaton, so only
+/// the parts of the dictionary that can match are read.
+pub fn expand_fuzzy_terms(
+    searcher: &Searcher,
+    field: Field,
+    words: &[String],
+    distance: u8,
+) -> Result<BTreeSet<String>, anyhow::Error> {
+    let builder = LevenshteinAutomatonBuilder::new(distance, false);
+    let automata: Vec<LevenshteinAutomaton> = words
+        .iter()
+        .map(|word| LevenshteinAutomaton(builder.build_dfa(word)))
+        .collect();
+    let mut terms = BTreeSet::new();
+    for segment_reader in searcher.segment_readers() {
+        let inverted_index = segment_reader.inverted_index(field)?;
+        for automaton in &automata {
+            let mut stream = inverted_index.terms().search(automaton).into_stream()?;
+            while let Some((key, _)) = stream.next() {
+                if let Ok(text) = std::str::from_utf8(key) {
+                    terms.insert(text.to_string());
+                }
+            }
+        }
+    }
+    Ok(terms)
+}
+
+/// Adapts a Levenshtein DFA to search the term dictionary with
+struct LevenshteinAutomaton(DFA);
+
+impl Automaton for LevenshteinAutomaton {
+    type State = u32;
+
+    fn start(&self) -> u32 {
+        self.0.initial_state()
+    }
+
+    fn is_match(&self, state: &u32) -> bool {
+        matches!(self.0.distance(*state), Distance::Exact(_))
+    }
+
+    fn can_match(&self, state: &u32) -> bool {
+        *state != SINK_STATE
+    }
+
+    fn accept(&self, state: &u32, byte: u8) -> u32 {
+        self.0.transition(*state, byte)
+    }
+}
+
+/// A query that matches any of the terms
+pub fn union_of_terms(terms: Vec<Term>) -> Box<dyn Query> {
+    let clauses: Vec<(Occur, Box<dyn Query>)> = terms
+        .into_iter()
+        .map(|term| {
+            // Scoring only needs the frequencies, and the index may not record the positions
+            let query: Box<dyn Query> =
+                Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs));
+            (Occur::Should, query)
+        })
+        .collect();
+    Box::new(BooleanQuery::new(clauses))
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::doc;
+    use tantivy::schema::{Schema, TEXT};
+    use tantivy::Index;
+
+    use super::expand_fuzzy_terms;
+
+    #[test]
+    fn expands_terms_within_distance() {
+        let mut schema_builder = Schema::builder();
+        let body = schema_builder.add_text_field("body", TEXT);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut writer = index.writer(50_000_000).unwrap();
+        writer.add_document(doc!(body => "search serch saerch sea cat dogs"));
+        writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let expand = |words: &[&str], distance| {
+            let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+            expand_fuzzy_terms(&searcher, body, &words, distance)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(expand(&["search"], 0), vec!["search"]);
+        assert_eq!(expand(&["search"], 1), vec!["search", "serch"]);
+        // A transposition is two edits
+        assert_eq!(expand(&["search"], 2), vec!["saerch", "search", "serch"]);
+        assert_eq!(expand(&["cat", "dog"], 1), vec!["cat", "dogs"]);
+    }
+}
@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tantivy::schema::{FieldType, Value};
+
+/// The type of values in a field of a `GenericIndex`
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    /// Full-text searchable text
+    Text,
+    /// Text that is indexed as-is (without splitting, lowercasing, or stemming), for keywords like
+    /// status codes that should only match exactly
+    Raw,
+    /// Unsigned 64-bit integer
+    U64,
+    /// Signed 64-bit integer
+    I64,
+    /// 64-bit float
+    F64,
+    /// A date, as an RFC 3339 string (e.g. `2021-10-01T12:00:00Z`) or a unix timestamp in seconds
+    Date,
+}
+
+impl FieldKind {
+    /// The kind of a field in the index schema
+    pub fn of(field_type: &FieldType) -> FieldKind {
+        match field_type {
+            FieldType::U64(_) => FieldKind::U64,
+            FieldType::I64(_) => FieldKind::I64,
+            FieldType::F64(_) => FieldKind::F64,
+            FieldType::Date(_) => FieldKind::Date,
+            FieldType::Str(options)
+                if options
+                    .get_indexing_options()
+                    .map(|indexing| indexing.tokenizer() == "raw")
+                    .unwrap_or(false) =>
+            {
+                FieldKind::Raw
+            }
+            _ => FieldKind::Text,
+        }
+    }
+
+    /// Parse the text of a field into a value of this kind
+    pub fn parse_value(&self, text: &str) -> anyhow::Result<Value> {
+        let invalid =
+            |e: &dyn std::fmt::Display| anyhow!("Invalid {:?} value '{}': {}", self, text, e);
+        Ok(match self {
+            FieldKind::Text | FieldKind::Raw => Value::Str(text.to_string()),
+            FieldKind::U64 => Value::U64(text.trim().parse().map_err(|e| invalid(&e))?),
+            FieldKind::I64 => Value::I64(text.trim().parse().map_err(|e| invalid(&e))?),
+            FieldKind::F64 => Value::F64(text.trim().parse().map_err(|e| invalid(&e))?),
+            FieldKind::Date => {
+                let text = text.trim();
+                let date = match text.parse::<i64>() {
+                    Ok(timestamp) => DateTime::from_timestamp(timestamp, 0)
+                        .ok_or_else(|| invalid(&"timestamp is out of range"))?,
+                    Err(_) => DateTime::parse_from_rfc3339(text)
+                        .map_err(|e| invalid(&e))?
+                        .with_timezone(&Utc),
+                };
+                Value::Date(date)
+            }
+        })
+    }
+}
+
+string_enum!(FieldKind, "field kind", {
+    "text" => FieldKind::Text,
+    "raw" => FieldKind::Raw,
+    "u64" => FieldKind::U64,
+    "i64" => FieldKind::I64,
+    "f64" => FieldKind::F64,
+    "date" => FieldKind::Date,
+});
+
+/// The name and kind of a field in an index
+///
+/// This can be created from just a name, for a text field.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FieldDef {
+    pub name: String,
+    pub kind: FieldKind,
+}
+
+impl From<String> for FieldDef {
+    fn from(name: String) -> Self {
+        FieldDef {
+            name,
+            kind: FieldKind::Text,
+        }
+    }
+}
+
+impl From<&str> for FieldDef {
+    fn from(name: &str) -> Self {
+        FieldDef::from(name.to_string())
+    }
+}
+
+impl<S: Into<String>> From<(S, FieldKind)> for FieldDef {
+    fn from((name, kind): (S, FieldKind)) -> Self {
+        FieldDef {
+            name: name.into(),
+            kind,
+        }
+    }
+}
+
+impl<'lua> mlua::FromLua<'lua> for FieldDef {
+    /// Either a field name (for a text field), or a table like `{name = "size", kind = "u64"}`
+    fn from_lua(lua_value: mlua::Value<'lua>, lua: &'lua mlua::Lua) -> mlua::Result<Self> {
+        match lua_value {
+            mlua::Value::String(name) => Ok(FieldDef::from(name.to_str()?)),
+            mlua::Value::Table(table) => Ok(FieldDef {
+                name: table.get("name")?,
+                kind: match table.get::<_, mlua::Value>("kind")? {
+                    mlua::Value::Nil => FieldKind::Text,
+                    kind => <FieldKind as mlua::FromLua>::from_lua(kind, lua)?,
+                },
+            }),
+            _ => Err(mlua::Error::FromLuaConversionError {
+                from: lua_value.type_name(),
+                to: "FieldDef",
+                message: Some("Value is not a string or table".to_string()),
+            }),
+        }
+    }
+}
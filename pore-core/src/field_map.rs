@@ -2,6 +2,13 @@ use std::{borrow::Cow, collections::HashMap};
 
 pub trait FieldMap {
     fn get_field(&self, key: &str) -> anyhow::Result<Cow<str>>;
+
+    /// Get all of the values of a field, for fields with multiple values (e.g. a list of tags)
+    ///
+    /// By default this returns the single value from `get_field`.
+    fn get_field_multi(&self, key: &str) -> anyhow::Result<Vec<Cow<str>>> {
+        Ok(vec![self.get_field(key)?])
+    }
 }
 
 impl FieldMap for HashMap<String, String> {
@@ -12,10 +19,37 @@ impl FieldMap for HashMap<String, String> {
     }
 }
 
+impl FieldMap for HashMap<String, Vec<String>> {
+    /// The first value of the field
+    fn get_field(&self, key: &str) -> anyhow::Result<Cow<str>> {
+        self.get(key)
+            .and_then(|values| values.first())
+            .map(|s| Cow::Borrowed(s.as_str()))
+            .ok_or_else(|| anyhow!("Missing field {}", key))
+    }
+
+    fn get_field_multi(&self, key: &str) -> anyhow::Result<Vec<Cow<str>>> {
+        self.get(key)
+            .map(|values| values.iter().map(|s| Cow::Borrowed(s.as_str())).collect())
+            .ok_or_else(|| anyhow!("Missing field {}", key))
+    }
+}
+
 impl FieldMap for mlua::Table<'_> {
     fn get_field(&self, key: &str) -> anyhow::Result<Cow<str>> {
         self.get::<&str, String>(key)
             .map(|s| Cow::Owned(s))
             .map_err(|e| anyhow!(e))
     }
+
+    /// If the key holds an array, returns all of its elements
+    fn get_field_multi(&self, key: &str) -> anyhow::Result<Vec<Cow<str>>> {
+        match self.get::<&str, mlua::Value>(key).map_err(|e| anyhow!(e))? {
+            mlua::Value::Table(values) => values
+                .sequence_values::<String>()
+                .map(|s| s.map(Cow::Owned).map_err(|e| anyhow!(e)))
+                .collect(),
+            _ => Ok(vec![self.get_field(key)?]),
+        }
+    }
 }
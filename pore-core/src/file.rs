@@ -1,30 +1,75 @@
+use crate::common::check_index;
 use crate::common::create_index;
+use crate::common::create_writer;
 use crate::common::delete_index;
+use crate::common::language_field_name;
+use crate::common::position_tokenizer;
+use crate::common::read_metadata;
+use crate::common::remove_index_files;
+use crate::common::write_metadata;
+use crate::common::CachedReader;
 use crate::common::IndexMetadata;
 use crate::common::MetadataConfig;
+use crate::common::DEFAULT_LOCK_TIMEOUT;
+use crate::common::DEFAULT_MAX_TOKEN_LENGTH;
+use crate::common::DEFAULT_WRITER_MEMORY;
 use crate::common::METADATA_FILE;
+use crate::dedupe::DedupeBy;
+use crate::directory::BoxedDirectory;
+use crate::encoding::Encoding;
+use crate::error::PoreError;
+use crate::expand::{expand_fuzzy_terms, expand_terms, union_of_terms};
+use crate::field_kind::{FieldDef, FieldKind};
+use crate::file_type::file_type_globs;
+use crate::frontmatter::parse_frontmatter;
 use crate::language::LanguageRef;
+use crate::line_transform::LineTransform;
 use crate::location;
-use crate::location::DocResult;
+use crate::location::{DocResult, PositionCollector, PositionMap};
+use crate::path_separator::PathSeparator;
+use crate::scoring::{
+    AllScores, RecencyBoost, Rescorer, ScoringModel, ScoringParams, DEFAULT_BM25_B, DEFAULT_BM25_K1,
+};
+use crate::snippet::SnippetMode;
+use crate::sort::{natural_path_cmp, SortBy};
+use crate::top_terms::{top_terms, TermFrequency};
 use chrono::DateTime;
 use chrono::Local;
 use chrono::NaiveDateTime;
 use chrono::Utc;
-use ignore::overrides::OverrideBuilder;
+use futures::executor::block_on;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::DirEntry;
+use ignore::Match;
 use ignore::WalkBuilder;
 use ignore::WalkState;
 use macros::create_option_copy;
 use mlua::ToLua;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fmt::Display;
 use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
-use tantivy::collector::TopDocs;
-use tantivy::doc;
-use tantivy::query::Query;
-use tantivy::ReloadPolicy;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::Directory;
+use tantivy::query::{Query, QueryParser, TermQuery};
+use tantivy::tokenizer::TextAnalyzer;
+use tantivy::DocAddress;
+use tantivy::IndexWriter;
+use tantivy::Searcher;
+use tantivy::SnippetGenerator;
 
 use tantivy::schema::*;
 use tantivy::Index;
@@ -33,23 +78,186 @@ use tantivy::Index;
 pub struct FileIndex {
     meta: FileMetadata,
     cache_dir: Option<PathBuf>,
+    /// Custom storage for the index (instead of `cache_dir` or memory)
+    directory: Option<BoxedDirectory>,
     index: Index,
     filepath: Field,
     contents: Field,
+    /// Copies of the contents field that are stemmed with `FileIndexOptions::languages`
+    language_contents: Vec<Field>,
+    /// The words of the file path, so it can be searched (including the copies for each language)
+    path_fields: Vec<Field>,
+    /// The time each file was last modified
+    modified: Field,
+    /// The size of each file in bytes
+    size: Field,
+    /// The real path of files that were indexed through a symlink, if `follow` is enabled
+    realpath: Option<Field>,
+    /// The number of tokens on each line of the file, if `store_line_map` is enabled (see
+    /// `location::line_map`)
+    line_map: Option<Field>,
+    /// The fields for each of `FileIndexOptions::frontmatter_fields` (including the copies for
+    /// each language), if `parse_frontmatter` is enabled
+    frontmatter_fields: Vec<(String, Vec<Field>)>,
+    /// Contents of files that were added directly instead of read from disk (keyed by filepath)
+    provided_contents: HashMap<String, String>,
+    /// Set to abort the in-progress (or next) call to `update`
+    cancel: Arc<AtomicBool>,
+    /// How long a write waits for another process to release the lock on the index
+    lock_timeout: Duration,
+    /// Why the existing index couldn't be used when it was opened (see `rebuild_reason`)
+    rebuild_reason: Option<String>,
+    reader: CachedReader,
+    writer: KeptWriter,
 }
 
+/// How an update reports the number of files processed so far
+enum Progress<'a> {
+    None,
+    /// Called on the calling thread, which walks the files itself (see `update_with_progress`)
+    Local(&'a mut dyn FnMut(usize) -> bool),
+    /// Called from the walker threads (see `update_with_parallel_progress`)
+    Parallel(&'a (dyn Fn(usize) + Sync)),
+}
+
+/// The writer kept open between updates by `FileIndex::open_writer`. Clones of the index share it,
+/// because an index can only have one writer at a time.
+#[derive(Clone, Default)]
+struct KeptWriter(Arc<Mutex<Option<IndexWriter>>>);
+
+impl std::fmt::Debug for KeptWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let open = self.0.lock().map(|w| w.is_some()).unwrap_or(false);
+        write!(f, "KeptWriter(open: {})", open)
+    }
+}
+
+/// The files that an update is restricted to (see `update_glob` and `update_paths`)
+#[derive(Clone)]
+enum OnlyFiles {
+    /// The files that match a glob
+    Glob(Override),
+    /// These files, and the files under these directories
+    Paths(Vec<PathBuf>),
+}
+
+impl OnlyFiles {
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            OnlyFiles::Glob(matcher) => matcher.matched(path, false).is_whitelist(),
+            OnlyFiles::Paths(paths) => paths.iter().any(|only| path.starts_with(only)),
+        }
+    }
+
+    /// Whether the walker has to look inside of a directory to find the files
+    fn walks_dir(&self, dir: &Path) -> bool {
+        match self {
+            OnlyFiles::Glob(_) => true,
+            OnlyFiles::Paths(paths) => paths
+                .iter()
+                .any(|only| only.starts_with(dir) || dir.starts_with(only)),
+        }
+    }
+}
+
+/// The options of a `FileIndex`
+///
+/// Changing any of these rebuilds the index, except for `threads` and `writer_memory` (which only
+/// affect how the index is written).
 #[create_option_copy(FileIndexOptionsShape)]
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct FileIndexOptions {
     pub follow: bool,
     pub glob: Vec<String>,
+    /// Match the `glob` patterns case-insensitively
     pub glob_case_insensitive: bool,
     pub hidden: bool,
     pub ignore_files: bool,
+    /// Read the ignore files of the parent directories up to this directory instead of up to the
+    /// filesystem root. A relative path is relative to the indexed directory, and an empty string
+    /// reads them all the way up.
+    pub ignore_root: String,
+    /// The names of additional ignore files to respect (e.g. `.poreignore`). They use the
+    /// `.gitignore` syntax and take precedence over the other ignore files, with later names taking
+    /// precedence over earlier ones.
+    pub ignore_file_names: Vec<String>,
+    /// Globs (like the lines of a `.gitignore`) of the files to index even if the ignore files
+    /// exclude them, e.g. `vendor/`. Unlike `glob`, this doesn't exclude the files that don't match.
+    pub unignore: Vec<String>,
+    /// Additional directories to index along with the indexed directory. Their files are stored
+    /// with their path relative to the indexed directory (e.g. `../lib/parse.rs`), or their
+    /// absolute path if the directory is absolute.
+    pub extra_dirs: Vec<String>,
     pub language: LanguageRef,
+    /// Additional languages to stem the file contents with (for files that mix languages). Each
+    /// one indexes the contents again.
+    pub languages: Vec<LanguageRef>,
     pub oglob: Vec<String>,
-    // TODO move this elsewhere
+    /// Match the `oglob` patterns case-insensitively
+    pub oglob_case_insensitive: bool,
+    /// Automatically exclude the globs in `project_ignores` for the types of project detected in
+    /// the indexed directory
+    pub smart_ignores: bool,
+    /// Globs to exclude when `smart_ignores` is enabled, keyed by a file name that marks the type
+    /// of project (e.g. `Cargo.toml`) when it's at the root of the indexed directory
+    pub project_ignores: BTreeMap<String, Vec<String>>,
+    /// Only index the files of these built-in types (see `FILE_TYPES`), e.g. `rust` or `py`
+    pub types: Vec<String>,
+    /// Don't index the files of these built-in types (see `FILE_TYPES`)
+    pub types_not: Vec<String>,
+    /// Store the file contents in the index, so `SnippetMode::Snippet` doesn't have to read the
+    /// files. This roughly doubles the size of the index.
+    pub store_contents: bool,
+    /// Store the number of words on each line of the files, so searches only tokenize the matching
+    /// lines instead of every line up to the last match
+    pub store_line_map: bool,
+    /// Index the frontmatter at the top of files (e.g. markdown notes) as separate fields, so they
+    /// can be searched with queries like `tags:rust`
+    pub parse_frontmatter: bool,
+    /// The file extensions to parse frontmatter from
+    pub frontmatter_extensions: Vec<String>,
+    /// The frontmatter keys to index as fields
+    pub frontmatter_fields: Vec<String>,
+    /// Split the words of the file contents into n-grams instead of stemming them, so that a
+    /// query can match part of a word (e.g. `conf` matches `configuration`). This makes the index
+    /// several times larger.
+    pub ngram: bool,
+    /// The length (in characters) of the shortest n-grams. Shorter words are indexed whole.
+    pub ngram_min: usize,
+    /// The length (in characters) of the longest n-grams
+    pub ngram_max: usize,
+    /// Only index the n-grams at the start of each word, so a query only matches word prefixes
+    pub ngram_prefix_only: bool,
+    /// Index the file contents without lowercasing them, so that searches are case-sensitive
+    pub case_sensitive: bool,
+    /// Don't index words longer than this many bytes (e.g. hashes and minified code). 0 means
+    /// there is no limit.
+    pub max_token_length: usize,
+    /// Words that are not indexed, so they don't match (or affect the ranking of) any file
+    pub stopwords: Vec<String>,
+    /// How to read files that are not valid UTF-8. By default they are skipped.
+    pub encoding: Encoding,
+    /// Record the position of each word in the files. Without positions the index is roughly half
+    /// the size, but phrase queries don't work and `match_count` is always 0.
+    pub record_positions: bool,
+    /// The number of threads that walk and index the files. 0 chooses the number from the CPU
+    /// count.
     pub threads: usize,
+    /// The memory budget (in bytes) for writing to the index, which is split between the indexing
+    /// threads
+    pub writer_memory: usize,
+    /// Skip files larger than this many bytes. 0 means there is no limit.
+    pub max_filesize: u64,
+}
+
+fn default_project_ignores() -> BTreeMap<String, Vec<String>> {
+    let mut project_ignores = BTreeMap::new();
+    project_ignores.insert("Cargo.toml".to_string(), vec!["target/".to_string()]);
+    project_ignores.insert(
+        "package.json".to_string(),
+        vec!["node_modules/".to_string()],
+    );
+    project_ignores
 }
 
 impl Default for FileIndexOptions {
@@ -58,38 +266,153 @@ impl Default for FileIndexOptions {
             follow: false,
             hidden: false,
             language: LanguageRef::English,
+            languages: vec![],
             ignore_files: true,
+            ignore_root: "".to_string(),
+            ignore_file_names: vec![],
+            unignore: vec![],
+            extra_dirs: vec![],
             glob_case_insensitive: false,
             glob: vec![],
             oglob: vec![],
+            oglob_case_insensitive: false,
+            smart_ignores: false,
+            project_ignores: default_project_ignores(),
+            types: vec![],
+            types_not: vec![],
+            store_contents: false,
+            store_line_map: false,
+            parse_frontmatter: false,
+            frontmatter_extensions: vec!["md".to_string(), "markdown".to_string()],
+            frontmatter_fields: vec!["title".to_string(), "tags".to_string()],
+            ngram: false,
+            ngram_min: 2,
+            ngram_max: 3,
+            ngram_prefix_only: false,
+            case_sensitive: false,
+            max_token_length: DEFAULT_MAX_TOKEN_LENGTH,
+            stopwords: vec![],
+            encoding: Encoding::Strict,
+            record_positions: true,
             threads: 0,
+            writer_memory: DEFAULT_WRITER_MEMORY,
+            max_filesize: 0,
         }
     }
 }
 
 #[create_option_copy(FileSearchOptionsShape)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileSearchOptions {
     pub limit: usize,
+    /// The number of top results to skip, for paging through the results
+    pub offset: usize,
     pub threshold: f32,
+    /// Minimum score relative to the top result, from 0 to 1 (e.g. 0.3 drops results that score
+    /// below 30% of the best result)
+    pub rel_threshold: f32,
+    /// Minimum score as a percentile of the scores of all the files that match the query, from 0
+    /// to 100 (e.g. 75 keeps the top quarter of the matches). Results have to pass this and both of
+    /// the other thresholds.
+    pub threshold_percentile: f32,
     pub filename_only: bool,
     pub root_dir: Option<String>,
+    /// (experimental) Regex for lines that are headings, such as function or class definitions.
+    /// Each matching line will include the closest heading above it as `context_heading`.
+    pub heading_regex: Option<String>,
+    /// Only return the top-scoring result from each directory (or extension)
+    pub dedupe_by: DedupeBy,
+    /// The order of the results. The top `limit` results are always chosen by score, and then
+    /// sorted by this.
+    pub sort_by: SortBy,
+    /// The separator to use for the paths of the results
+    pub path_separator: PathSeparator,
+    /// The model used to score the results
+    pub scoring: ScoringModel,
+    /// BM25 term frequency saturation. Higher values reward repeated terms more.
+    pub bm25_k1: f32,
+    /// BM25 length normalization, from 0 (ignore file length) to 1 (fully normalize by length)
+    pub bm25_b: f32,
+    /// The number of lines of context to include before each matching line
+    pub before_context: usize,
+    /// The number of lines of context to include after each matching line
+    pub after_context: usize,
+    /// How to find the matching lines of each result
+    pub snippet_mode: SnippetMode,
+    /// Remove the trailing whitespace from the text of each line. If false, only the line ending
+    /// is removed.
+    pub trim_lines: bool,
+    /// Change the text of the returned lines (and headings and snippets), e.g. to redact secrets.
+    /// This is display-only: queries still match the original text.
+    pub line_transform: Option<LineTransform>,
+    /// Multiply the score of recently modified files by up to `1 + recency_boost`, so they rank
+    /// higher. The boost halves every `RECENCY_HALF_LIFE_DAYS`. 0 disables the boost.
+    pub recency_boost: f32,
+    /// Only return files that were last modified after this time (a unix timestamp in seconds).
+    /// This checks the files on disk, so files that were deleted since they were indexed are not
+    /// returned.
+    pub modified_after: Option<i64>,
+    /// Only return files that were last modified before this time (a unix timestamp in seconds)
+    pub modified_before: Option<i64>,
+    /// Only return the first this many matching lines of each file (not counting the context
+    /// lines). Results with more matching lines have `lines_truncated` set.
+    pub max_lines_per_file: Option<usize>,
 }
 
 impl Default for FileSearchOptions {
     fn default() -> Self {
         FileSearchOptions {
             limit: 1000,
+            offset: 0,
             threshold: 0.0,
+            rel_threshold: 0.0,
+            threshold_percentile: 0.0,
             filename_only: false,
             root_dir: None,
+            heading_regex: None,
+            dedupe_by: DedupeBy::None,
+            sort_by: SortBy::Score,
+            path_separator: PathSeparator::Native,
+            scoring: ScoringModel::Bm25,
+            bm25_k1: DEFAULT_BM25_K1,
+            bm25_b: DEFAULT_BM25_B,
+            before_context: 0,
+            after_context: 0,
+            snippet_mode: SnippetMode::Lines,
+            trim_lines: true,
+            line_transform: None,
+            recency_boost: 0.0,
+            modified_after: None,
+            modified_before: None,
+            max_lines_per_file: None,
+        }
+    }
+}
+
+impl FileSearchOptions {
+    pub fn scoring_params(&self) -> ScoringParams {
+        ScoringParams {
+            model: self.scoring,
+            k1: self.bm25_k1,
+            b: self.bm25_b,
         }
     }
 }
 
+/// The format of the file indexes (see `IndexMetadata::FORMAT_VERSION`)
+///
+/// 1: Added the `path` field
+/// 2: Added the `modified` field
+/// 3: Added the `size` field
+/// 4: The `line_map` counts the positions of the removed tokens (e.g. stop words) as well
+const FILE_INDEX_FORMAT_VERSION: u32 = 4;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     version: String,
+    /// Older indexes don't record it, and are format 0
+    #[serde(default)]
+    format_version: u32,
     last_update: DateTime<Utc>,
     config: FileIndexOptions,
     for_dir: PathBuf,
@@ -99,23 +422,77 @@ impl MetadataConfig for FileIndexOptions {
     fn language(&self) -> LanguageRef {
         self.language
     }
+    fn without_write_options(&self) -> Self {
+        FileIndexOptions {
+            threads: 0,
+            writer_memory: DEFAULT_WRITER_MEMORY,
+            ..self.clone()
+        }
+    }
+    fn extra_languages(&self) -> &[LanguageRef] {
+        &self.languages
+    }
+    fn store_text(&self) -> bool {
+        self.store_contents
+    }
+    fn ngram(&self) -> Option<(usize, usize, bool)> {
+        if self.ngram {
+            Some((self.ngram_min, self.ngram_max, self.ngram_prefix_only))
+        } else {
+            None
+        }
+    }
+    fn case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+    fn max_token_length(&self) -> usize {
+        self.max_token_length
+    }
+    fn stopwords(&self) -> &[String] {
+        &self.stopwords
+    }
+    fn record_positions(&self) -> bool {
+        self.record_positions
+    }
+    fn stored_fields(&self) -> Vec<String> {
+        let mut fields = vec![];
+        if self.follow {
+            fields.push("realpath".to_string());
+        }
+        if self.store_line_map {
+            fields.push("line_map".to_string());
+        }
+        fields
+    }
 }
 
 impl FileMetadata {
+    /// A relative `for_dir` is relative to the current directory
     pub fn new<P: AsRef<Path>>(
         config: FileIndexOptions,
         for_dir: P,
     ) -> Result<Self, anyhow::Error> {
         let path = for_dir.as_ref();
+        if path.is_absolute() {
+            Self::new_relative_to(config, path, path)
+        } else {
+            Self::new_relative_to(config, path, env::current_dir()?)
+        }
+    }
+
+    /// Like `new`, but a relative `for_dir` is relative to `base_dir` instead of the current
+    /// directory
+    pub fn new_relative_to<P: AsRef<Path>, B: AsRef<Path>>(
+        config: FileIndexOptions,
+        for_dir: P,
+        base_dir: B,
+    ) -> Result<Self, anyhow::Error> {
         Ok(FileMetadata {
             config,
             version: env!("CARGO_PKG_VERSION").to_string(),
+            format_version: FILE_INDEX_FORMAT_VERSION,
             last_update: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc),
-            for_dir: fs::canonicalize(if path.is_absolute() {
-                path.to_path_buf()
-            } else {
-                env::current_dir()?.join(path)
-            })?,
+            for_dir: fs::canonicalize(base_dir.as_ref().join(for_dir))?,
         })
     }
 }
@@ -124,8 +501,17 @@ impl FileMetadata {
 pub struct FileSearchResult {
     file: PathBuf,
     score: f32,
+    /// The number of matched tokens in the file
+    match_count: usize,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     lines: Vec<Line>,
+    /// True if some of the matching lines were dropped because of
+    /// `FileSearchOptions::max_lines_per_file`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    lines_truncated: bool,
+    /// The matching text from `SnippetMode::Snippet`, if it couldn't be mapped back to lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
 }
 
 impl FileSearchResult {
@@ -135,9 +521,49 @@ impl FileSearchResult {
     pub fn score(&self) -> f32 {
         self.score
     }
+    pub fn match_count(&self) -> usize {
+        self.match_count
+    }
     pub fn lines(&self) -> &Vec<Line> {
         &self.lines
     }
+    pub fn lines_truncated(&self) -> bool {
+        self.lines_truncated
+    }
+    pub fn snippet(&self) -> Option<&str> {
+        self.snippet.as_deref()
+    }
+    /// The number of bytes of text in the result (its lines, their context headings, and the
+    /// snippet)
+    pub fn text_len(&self) -> usize {
+        let line_bytes: usize = self
+            .lines
+            .iter()
+            .map(|line| line.text.len() + line.context_heading.as_ref().map_or(0, |h| h.len()))
+            .sum();
+        line_bytes + self.snippet.as_ref().map_or(0, |s| s.len())
+    }
+}
+
+/// Keep the first `results` whose text fits in `max_bytes` in total (see
+/// `FileSearchResult::text_len`), and return whether any were dropped
+///
+/// The first result is always kept, even if it doesn't fit, so a single large file doesn't make
+/// the search look like it found nothing.
+pub fn take_within_bytes(
+    results: Vec<FileSearchResult>,
+    max_bytes: usize,
+) -> (Vec<FileSearchResult>, bool) {
+    let mut total_bytes = 0;
+    let mut kept = Vec::new();
+    for result in results {
+        total_bytes += result.text_len();
+        if total_bytes > max_bytes && !kept.is_empty() {
+            return (kept, true);
+        }
+        kept.push(result);
+    }
+    (kept, false)
 }
 
 impl<'lua> ToLua<'lua> for FileSearchResult {
@@ -145,9 +571,46 @@ impl<'lua> ToLua<'lua> for FileSearchResult {
         let tbl = lua.create_table()?;
         tbl.set("file", self.file.to_string_lossy())?;
         tbl.set("score", self.score)?;
+        tbl.set("match_count", self.match_count)?;
         if !self.lines.is_empty() {
             tbl.set("lines", self.lines)?;
         }
+        if self.lines_truncated {
+            tbl.set("lines_truncated", true)?;
+        }
+        if let Some(snippet) = self.snippet {
+            tbl.set("snippet", snippet)?;
+        }
+        Ok(mlua::Value::Table(tbl))
+    }
+}
+
+/// The stored fields of a file in the index
+#[derive(Debug, Serialize)]
+pub struct StoredDoc {
+    /// The path relative to the root of the index
+    filepath: String,
+    /// The file contents, if the index was built with `store_contents`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contents: Option<String>,
+}
+
+impl StoredDoc {
+    pub fn filepath(&self) -> &str {
+        &self.filepath
+    }
+    pub fn contents(&self) -> Option<&str> {
+        self.contents.as_deref()
+    }
+}
+
+impl<'lua> ToLua<'lua> for StoredDoc {
+    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let tbl = lua.create_table()?;
+        tbl.set("filepath", self.filepath)?;
+        if let Some(contents) = self.contents {
+            tbl.set("contents", contents)?;
+        }
         Ok(mlua::Value::Table(tbl))
     }
 }
@@ -156,6 +619,14 @@ impl<'lua> ToLua<'lua> for FileSearchResult {
 pub struct Line {
     pub number: u32,
     pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_heading: Option<String>,
+    /// True if this line is only included as context around a matching line
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub is_context: bool,
+    /// The byte ranges `(start, end)` of the matched terms in `text`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matches: Vec<(usize, usize)>,
 }
 
 impl<'lua> ToLua<'lua> for Line {
@@ -163,29 +634,148 @@ impl<'lua> ToLua<'lua> for Line {
         let tbl = lua.create_table()?;
         tbl.set("number", self.number)?;
         tbl.set("text", self.text)?;
+        if let Some(heading) = self.context_heading {
+            tbl.set("context_heading", heading)?;
+        }
+        if self.is_context {
+            tbl.set("is_context", true)?;
+        }
+        if !self.matches.is_empty() {
+            let matches = lua.create_table()?;
+            for (i, (start, end)) in self.matches.into_iter().enumerate() {
+                matches.set(i + 1, vec![start, end])?;
+            }
+            tbl.set("matches", matches)?;
+        }
+        Ok(mlua::Value::Table(tbl))
+    }
+}
+
+/// Why a file was not added to the index during an update
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    NotUtf8,
+    ReadError,
+    /// The file is bigger than `FileIndexOptions::max_filesize`
+    TooLarge,
+    /// The path is a FIFO, socket, or device file, which can't be indexed (and reading a FIFO
+    /// would block forever)
+    NotAFile,
+}
+
+impl From<&io::Error> for SkipReason {
+    fn from(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::InvalidData => SkipReason::NotUtf8,
+            _ => SkipReason::ReadError,
+        }
+    }
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::NotUtf8 => write!(f, "not utf-8"),
+            SkipReason::ReadError => write!(f, "read error"),
+            SkipReason::TooLarge => write!(f, "too large"),
+            SkipReason::NotAFile => write!(f, "not a regular file"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+impl<'lua> ToLua<'lua> for SkippedFile {
+    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let tbl = lua.create_table()?;
+        tbl.set("path", self.path.to_string_lossy())?;
+        tbl.set("reason", self.reason.to_string())?;
+        Ok(mlua::Value::Table(tbl))
+    }
+}
+
+/// The result of updating a FileIndex
+#[derive(Debug, Default, Serialize)]
+pub struct UpdateSummary {
+    /// The number of files that were added to (or refreshed in) the index
+    pub added: usize,
+    /// The files that would have been added, but could not be indexed
+    pub skipped: Vec<SkippedFile>,
+    /// The files that were not valid UTF-8, and were indexed with the invalid bytes replaced (see
+    /// `Encoding::Lossy`). These are counted in `added` as well.
+    pub lossy: Vec<PathBuf>,
+    /// The number of files that were removed from the index because they no longer exist
+    pub removed: usize,
+    /// True if the update was cancelled. None of the changes will have been written to the index.
+    pub cancelled: bool,
+}
+
+impl<'lua> ToLua<'lua> for UpdateSummary {
+    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let tbl = lua.create_table()?;
+        tbl.set("added", self.added)?;
+        tbl.set("skipped", self.skipped)?;
+        tbl.set(
+            "lossy",
+            self.lossy
+                .iter()
+                .map(|path| path.to_string_lossy().to_string())
+                .collect::<Vec<_>>(),
+        )?;
+        tbl.set("removed", self.removed)?;
+        tbl.set("cancelled", self.cancelled)?;
         Ok(mlua::Value::Table(tbl))
     }
 }
 
 impl FileMetadata {
+    /// Load the metadata of the index stored in `index_dir`. Returns None if there is no index.
+    ///
+    /// Unlike `FileIndex::open`, this doesn't check that the index can be used by this version of
+    /// pore, so it can list indexes that need to be rebuilt.
+    pub fn load(index_dir: &Path) -> Result<Option<Self>, anyhow::Error> {
+        let metafile = index_dir.join(METADATA_FILE);
+        if !metafile.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(&metafile)?)?))
+    }
     pub fn for_dir(&self) -> &Path {
         &self.for_dir
     }
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+    pub fn last_update(&self) -> &DateTime<Utc> {
+        &self.last_update
+    }
 }
 
 impl IndexMetadata<FileIndexOptions> for FileMetadata {
+    const FORMAT_VERSION: u32 = FILE_INDEX_FORMAT_VERSION;
     fn config(&self) -> &FileIndexOptions {
         &self.config
     }
     fn version(&self) -> &str {
         &self.version
     }
+    fn format_version(&self) -> u32 {
+        self.format_version
+    }
     fn last_update(&self) -> &DateTime<Utc> {
         &self.last_update
     }
     fn set_last_update(&mut self, time: DateTime<Utc>) {
         self.last_update = time;
     }
+    fn set_config(&mut self, config: FileIndexOptions) {
+        self.config = config;
+    }
 }
 
 impl FileIndex {
@@ -198,17 +788,187 @@ impl FileIndex {
     pub fn contents(&self) -> &Field {
         &self.contents
     }
-    pub fn delete(&self) -> anyhow::Result<bool> {
-        delete_index(&self.index, self.cache_dir.as_deref())
+    /// All of the fields that index the file contents (one per language)
+    pub fn content_fields(&self) -> Vec<Field> {
+        let mut fields = vec![self.contents];
+        fields.extend(&self.language_contents);
+        fields
+    }
+    /// The fields that index the words of the file path (one per language), for queries like
+    /// `path:parser`
+    pub fn path_fields(&self) -> &[Field] {
+        &self.path_fields
+    }
+    /// The directory that is indexed
+    pub fn for_dir(&self) -> &Path {
+        self.meta.for_dir()
+    }
+    pub fn cache_dir(&self) -> Option<&Path> {
+        self.cache_dir.as_deref()
+    }
+    /// The metadata of the index (the indexed directory, options, and time of the last update)
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.meta
+    }
+    /// Why the index that was stored in the cache dir was discarded when it was opened (e.g. it was
+    /// built with different options, or by an incompatible version of pore). The index is empty
+    /// until it's rebuilt with `update`.
+    pub fn rebuild_reason(&self) -> Option<&str> {
+        self.rebuild_reason.as_deref()
+    }
+    /// True if the index records the positions of the words (see
+    /// `FileIndexOptions::record_positions`)
+    pub fn record_positions(&self) -> bool {
+        self.meta.config.record_positions
+    }
+    /// The tokenizer to count the token positions on each line with (see
+    /// `common::position_tokenizer`)
+    pub(crate) fn position_tokenizer(&self) -> TextAnalyzer {
+        position_tokenizer(&self.meta.config)
+    }
+    pub fn delete(&self) -> Result<bool, PoreError> {
+        // Deleting needs a writer of its own
+        self.writer.0.lock().unwrap().take();
+        Ok(delete_index(
+            &self.index,
+            self.cache_dir.as_deref(),
+            self.meta.config.writer_memory,
+            self.lock_timeout,
+        )?)
+    }
+    /// Check the index stored in `cache_dir` for problems without modifying it. See
+    /// `common::check_index`.
+    pub fn check(cache_dir: &Path, config: &FileIndexOptions) -> Result<Vec<String>, PoreError> {
+        Ok(check_index::<FileMetadata, FileIndexOptions>(
+            cache_dir, config,
+        )?)
+    }
+    /// Delete the index of `for_dir` stored in `cache_dir` and create a new, empty one in its place,
+    /// which has to be rebuilt with `update`
+    ///
+    /// This repairs an index that `check` found problems in. Opening a damaged index may still
+    /// succeed (tantivy only reads the damaged segments when they're searched), so the files are
+    /// deleted instead of reusing any of them.
+    pub fn recreate<P: AsRef<Path>>(
+        for_dir: P,
+        cache_dir: P,
+        config: &FileIndexOptions,
+    ) -> Result<Self, PoreError> {
+        if cache_dir.as_ref().exists() {
+            remove_index_files(cache_dir.as_ref())?;
+        }
+        Self::get_or_create(for_dir, Some(cache_dir), config)
     }
+    /// Open the index of `for_dir` stored in `cache_dir`, or an in-memory index if `cache_dir` is
+    /// None. The index is created (or rebuilt, if it was made with a different config) if needed.
+    ///
+    /// A relative `for_dir` is relative to the current directory. See `get_or_create_relative_to`
+    /// to avoid depending on it.
     pub fn get_or_create<P: AsRef<Path>>(
         for_dir: P,
         cache_dir: Option<P>,
         config: &FileIndexOptions,
+    ) -> Result<Self, PoreError> {
+        Ok(Self::open_or_create(
+            for_dir, None, cache_dir, None, config,
+        )?)
+    }
+
+    /// Like `get_or_create`, but a relative `for_dir` is relative to `base_dir` instead of the
+    /// current directory
+    ///
+    /// The index doesn't read any process state (the current directory or environment variables
+    /// like `HOME` and `XDG_CACHE_HOME`), so the same arguments always build the same index. The
+    /// exceptions are the global gitignore file, which is read when `ignore_files` is enabled, and
+    /// the number of threads, which depends on the CPU count unless `threads` is set. Tests that
+    /// rely on the ranking should set `threads = 1`, so that files are always added in the same
+    /// order.
+    pub fn get_or_create_relative_to<P: AsRef<Path>, B: AsRef<Path>>(
+        base_dir: B,
+        for_dir: P,
+        cache_dir: Option<P>,
+        config: &FileIndexOptions,
+    ) -> Result<Self, PoreError> {
+        Ok(Self::open_or_create(
+            for_dir,
+            Some(base_dir.as_ref()),
+            cache_dir,
+            None,
+            config,
+        )?)
+    }
+
+    /// Like `get_or_create`, but store the index in a custom tantivy `Directory` instead of on disk
+    /// or in memory. The index metadata is stored in the directory as well.
+    pub fn get_or_create_in_directory<P: AsRef<Path>>(
+        for_dir: P,
+        directory: Box<dyn Directory>,
+        config: &FileIndexOptions,
+    ) -> Result<Self, PoreError> {
+        Ok(Self::open_or_create(
+            for_dir,
+            None,
+            None,
+            Some(BoxedDirectory(directory)),
+            config,
+        )?)
+    }
+
+    /// Open the existing index stored in `cache_dir`, with the directory and config that it was
+    /// built with (read from its metadata file)
+    ///
+    /// This is for searching an index without knowing how it was built. Returns an error if there
+    /// is no index in `cache_dir`, or if it was built by an incompatible version of pore.
+    pub fn open<P: AsRef<Path>>(cache_dir: P) -> Result<Self, PoreError> {
+        let cache_dir = cache_dir.as_ref();
+        let meta = read_metadata::<FileMetadata, FileIndexOptions>(cache_dir)?;
+        Ok(Self::open_or_create(
+            meta.for_dir.as_path(),
+            None,
+            Some(cache_dir),
+            None,
+            &meta.config,
+        )?)
+    }
+
+    fn open_or_create<P: AsRef<Path>>(
+        for_dir: P,
+        base_dir: Option<&Path>,
+        cache_dir: Option<P>,
+        directory: Option<BoxedDirectory>,
+        config: &FileIndexOptions,
     ) -> Result<Self, anyhow::Error> {
-        let (meta_opt, index): (Option<FileMetadata>, Index) =
-            create_index(cache_dir.as_ref(), config, "filepath", vec!["contents"])?;
-        let meta = meta_opt.unwrap_or_else(|| FileMetadata::new(config.clone(), for_dir).unwrap());
+        let mut text_fields = vec!["contents".to_string(), "path".to_string()];
+        if config.parse_frontmatter {
+            for name in &config.frontmatter_fields {
+                if ["filepath", "realpath", "line_map", "modified", "size"].contains(&name.as_str())
+                    || text_fields.contains(name)
+                {
+                    bail!(PoreError::Schema(format!(
+                        "Invalid frontmatter field '{}'",
+                        name
+                    )));
+                }
+                text_fields.push(name.clone());
+            }
+        }
+        let (meta_opt, index, rebuild_reason): (Option<FileMetadata>, Index, _) = create_index(
+            cache_dir.as_ref(),
+            directory.as_ref(),
+            config,
+            "filepath",
+            text_fields.into_iter().map(FieldDef::from).chain(vec![
+                FieldDef::from(("modified", FieldKind::Date)),
+                FieldDef::from(("size", FieldKind::U64)),
+            ]),
+        )?;
+        let meta = match (meta_opt, base_dir) {
+            (Some(meta), _) => meta,
+            (None, Some(base_dir)) => {
+                FileMetadata::new_relative_to(config.clone(), for_dir, base_dir)?
+            }
+            (None, None) => FileMetadata::new(config.clone(), for_dir)?,
+        };
         let filepath = index
             .schema()
             .get_field("filepath")
@@ -217,132 +977,1638 @@ impl FileIndex {
             .schema()
             .get_field("contents")
             .expect("No field named 'contents'");
+        let language_contents = config
+            .languages
+            .iter()
+            .map(|lang| {
+                index
+                    .schema()
+                    .get_field(&language_field_name("contents", *lang))
+                    .expect("Missing contents field for language")
+            })
+            .collect();
+        let mut path_fields = vec![index
+            .schema()
+            .get_field("path")
+            .expect("No field named 'path'")];
+        for lang in &config.languages {
+            path_fields.push(
+                index
+                    .schema()
+                    .get_field(&language_field_name("path", *lang))
+                    .expect("Missing path field for language"),
+            );
+        }
+        let modified = index
+            .schema()
+            .get_field("modified")
+            .expect("No field named 'modified'");
+        let size = index
+            .schema()
+            .get_field("size")
+            .expect("No field named 'size'");
+        let realpath = index.schema().get_field("realpath");
+        let line_map = index.schema().get_field("line_map");
+        let mut frontmatter_fields = Vec::new();
+        if config.parse_frontmatter {
+            let schema = index.schema();
+            for name in &config.frontmatter_fields {
+                let mut fields = vec![schema
+                    .get_field(name)
+                    .expect("Missing field for frontmatter key")];
+                for lang in &config.languages {
+                    fields.push(
+                        schema
+                            .get_field(&language_field_name(name, *lang))
+                            .expect("Missing frontmatter field for language"),
+                    );
+                }
+                frontmatter_fields.push((name.clone(), fields));
+            }
+        }
+        let reader = CachedReader::new(&index)?;
         Ok(Self {
             index,
             cache_dir: cache_dir.map(|p| fs::canonicalize(p).unwrap()),
+            directory,
             meta,
             filepath,
             contents,
+            language_contents,
+            path_fields,
+            modified,
+            size,
+            realpath,
+            line_map,
+            frontmatter_fields,
+            provided_contents: HashMap::new(),
+            cancel: Arc::new(AtomicBool::new(false)),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            rebuild_reason,
+            reader,
+            writer: KeptWriter::default(),
         })
     }
 
-    pub fn get_file_walker(&self) -> Result<WalkBuilder, anyhow::Error> {
-        let mut builder = WalkBuilder::new(&self.meta.for_dir);
+    fn make_document(
+        &self,
+        filepath: String,
+        contents: String,
+        modified: DateTime<Utc>,
+    ) -> Document {
+        let mut doc = Document::default();
+        doc.add_date(self.modified, &modified);
+        doc.add_u64(self.size, contents.len() as u64);
+        if self.has_frontmatter(&filepath) {
+            if let Some(frontmatter) = parse_frontmatter(&contents) {
+                for (name, fields) in &self.frontmatter_fields {
+                    if let Some(value) = frontmatter.get(name) {
+                        for field in fields {
+                            doc.add_text(*field, value);
+                        }
+                    }
+                }
+            }
+        }
+        for field in &self.path_fields {
+            doc.add_text(*field, &filepath);
+        }
+        doc.add_text(self.filepath, filepath);
+        for field in &self.language_contents {
+            doc.add_text(*field, &contents);
+        }
+        if let Some(line_map) = self.line_map {
+            doc.add_text(
+                line_map,
+                location::line_map(&self.position_tokenizer(), &contents),
+            );
+        }
+        doc.add_text(self.contents, contents);
+        doc
+    }
+
+    fn has_frontmatter(&self, filepath: &str) -> bool {
+        if self.frontmatter_fields.is_empty() {
+            return false;
+        }
+        let extension = Path::new(filepath)
+            .extension()
+            .map(|ext| ext.to_string_lossy())
+            .unwrap_or_default();
+        self.meta
+            .config
+            .frontmatter_extensions
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(&extension))
+    }
+
+    pub fn get_file_walker(&self) -> Result<WalkBuilder, PoreError> {
+        Ok(self.file_walker(None)?)
+    }
+
+    /// Get all of the walkers that find the files to index: the one from `get_file_walker`, and
+    /// one for the files that `unignore` re-includes (if any). A file may be found by both.
+    pub fn get_file_walkers(&self) -> Result<Vec<WalkBuilder>, PoreError> {
+        Ok(self.file_walkers(None)?)
+    }
+
+    fn file_walkers(&self, only: Option<&OnlyFiles>) -> Result<Vec<WalkBuilder>, anyhow::Error> {
+        let mut walkers = vec![self.file_walker(only)?];
+        walkers.extend(self.unignore_walker(only)?);
+        Ok(walkers)
+    }
+
+    /// The directories that are walked to build the index: the indexed directory, and then the
+    /// `extra_dirs`
+    pub fn roots(&self) -> Vec<PathBuf> {
+        let mut roots = vec![self.meta.for_dir.clone()];
+        roots.extend(
+            self.meta
+                .config
+                .extra_dirs
+                .iter()
+                .map(|dir| self.meta.for_dir.join(dir)),
+        );
+        roots
+    }
+
+    /// Like `get_file_walker`, but if `only` is set, only walk the files that it whitelists
+    fn file_walker(&self, only: Option<&OnlyFiles>) -> Result<WalkBuilder, anyhow::Error> {
+        let roots = self.roots();
+        for dir in roots.iter().skip(1) {
+            if !dir.is_dir() {
+                bail!("Extra directory '{}' does not exist", dir.to_string_lossy());
+            }
+        }
+        self.build_walker(&roots, only, None)
+    }
+
+    /// Get a walker for the files that `unignore` re-includes, which doesn't read the ignore
+    /// files. It only walks the directories that the globs start with (e.g. `vendor` for
+    /// `vendor/**/*.c`), so it doesn't have to walk the whole tree again.
+    fn unignore_walker(
+        &self,
+        only: Option<&OnlyFiles>,
+    ) -> Result<Option<WalkBuilder>, anyhow::Error> {
+        if !self.meta.config.ignore_files || self.meta.config.unignore.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GitignoreBuilder::new(&self.meta.for_dir);
+        for glob in &self.meta.config.unignore {
+            builder.add_line(None, glob)?;
+        }
+        let unignore = builder.build()?;
+        let mut roots: Vec<PathBuf> = self
+            .meta
+            .config
+            .unignore
+            .iter()
+            .map(|glob| self.meta.for_dir.join(glob_base_dir(glob)))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        roots.sort();
+        // Walking a directory walks everything inside of it as well
+        roots.dedup_by(|dir, parent| dir.starts_with(parent));
+        if roots.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.build_walker(&roots, only, Some(unignore))?))
+    }
+
+    /// Build a walker over `roots` with the options of the index. With `unignore`, the ignore
+    /// files are not read, and only the files that match `unignore` are walked.
+    fn build_walker(
+        &self,
+        roots: &[PathBuf],
+        only: Option<&OnlyFiles>,
+        unignore: Option<Gitignore>,
+    ) -> Result<WalkBuilder, anyhow::Error> {
+        let ignore_files = self.meta.config.ignore_files && unignore.is_none();
+        let mut builder = WalkBuilder::new(&roots[0]);
+        for dir in &roots[1..] {
+            builder.add(dir);
+        }
         builder
             .hidden(!self.meta.config.hidden)
             .threads(self.meta.config.threads)
-            .ignore(self.meta.config.ignore_files)
-            .git_global(self.meta.config.ignore_files)
-            .git_ignore(self.meta.config.ignore_files)
-            .git_exclude(self.meta.config.ignore_files)
+            .ignore(ignore_files)
+            .git_global(ignore_files)
+            .git_ignore(ignore_files)
+            .git_exclude(ignore_files)
             .follow_links(self.meta.config.follow);
-        if !self.meta.config.glob.is_empty() {
+        if unignore.is_some() {
+            builder.parents(false);
+        }
+        if ignore_files {
+            for name in &self.meta.config.ignore_file_names {
+                builder.add_custom_ignore_filename(name);
+            }
+        }
+        let project_globs = self.get_project_ignores();
+        if !self.meta.config.glob.is_empty()
+            || !project_globs.is_empty()
+            || !self.meta.config.types.is_empty()
+            || !self.meta.config.types_not.is_empty()
+        {
             let mut globs = OverrideBuilder::new(&self.meta.for_dir);
             globs.case_insensitive(self.meta.config.glob_case_insensitive)?;
+            // Later globs take precedence, so add these first to let the user globs override them
+            for glob in &project_globs {
+                globs.add(&format!("!{}", glob))?;
+            }
+            for name in &self.meta.config.types {
+                for glob in file_type_globs(name)? {
+                    globs.add(glob)?;
+                }
+            }
+            for name in &self.meta.config.types_not {
+                for glob in file_type_globs(name)? {
+                    globs.add(&format!("!{}", glob))?;
+                }
+            }
             for glob in &self.meta.config.glob {
                 globs.add(&glob)?;
             }
             builder.overrides(globs.build()?);
         }
-        if !self.meta.config.oglob.is_empty() {
+        let ancestor_ignores = if ignore_files && !self.meta.config.ignore_root.is_empty() {
+            // We read the parent ignore files ourselves, so there won't be a parent .git directory
+            // for the walker to find
+            builder.parents(false).require_git(false);
+            self.get_ancestor_ignores()?
+        } else {
+            vec![]
+        };
+        let oglob_matcher = if !self.meta.config.oglob.is_empty() {
             let mut globs = OverrideBuilder::new(&self.meta.for_dir);
-            globs.case_insensitive(self.meta.config.glob_case_insensitive)?;
+            globs.case_insensitive(self.meta.config.oglob_case_insensitive)?;
             for glob in &self.meta.config.oglob {
                 globs.add(&glob)?;
             }
-            let matcher = globs.build()?;
+            Some(globs.build()?)
+        } else {
+            None
+        };
+        let only = only.cloned();
+        // The index may be stored inside of the indexed directory (e.g. with --cache-dir), and
+        // it shouldn't index itself
+        let index_dir = self
+            .cache_dir
+            .clone()
+            .filter(|dir| roots.iter().any(|root| dir.starts_with(root)));
+        if oglob_matcher.is_some()
+            || only.is_some()
+            || unignore.is_some()
+            || index_dir.is_some()
+            || !ancestor_ignores.is_empty()
+        {
             builder.filter_entry(move |e| {
-                if e.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
-                    return true;
-                } else {
-                    return matcher.matched(e.path(), false).is_whitelist();
-                };
+                let is_dir = e.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
+                if is_dir && index_dir.as_deref() == Some(e.path()) {
+                    return false;
+                }
+                for ignore in &ancestor_ignores {
+                    // The extra directories may be outside of the directory of the ignore file
+                    if !e.path().starts_with(ignore.path()) {
+                        continue;
+                    }
+                    match ignore.matched_path_or_any_parents(e.path(), is_dir) {
+                        Match::Ignore(_) => return false,
+                        Match::Whitelist(_) => break,
+                        Match::None => {}
+                    }
+                }
+                if is_dir {
+                    return only.as_ref().map_or(true, |only| only.walks_dir(e.path()));
+                }
+                if let Some(only) = &only {
+                    if !only.matches(e.path()) {
+                        return false;
+                    }
+                }
+                if let Some(unignore) = &unignore {
+                    if !unignore
+                        .matched_path_or_any_parents(e.path(), false)
+                        .is_ignore()
+                    {
+                        return false;
+                    }
+                }
+                match &oglob_matcher {
+                    Some(matcher) => matcher.matched(e.path(), false).is_whitelist(),
+                    None => true,
+                }
             });
         }
         Ok(builder)
     }
 
-    pub fn update(&mut self, rebuild: bool) -> Result<&mut Self, anyhow::Error> {
-        let mut index_writer = self.index.writer(50_000_000)?;
-        let walker = self.get_file_walker()?;
-        let now = Utc::now();
-        walker.build_parallel().run(|| {
-            Box::new(|result| {
-                if let Ok(entry) = result {
-                    if let Ok(contents) = fs::read_to_string(entry.path()) {
-                        let modified: DateTime<Utc> =
-                            entry.metadata().unwrap().modified().unwrap().into();
-                        if rebuild || modified > self.meta.last_update {
-                            let filepath = entry.path().strip_prefix(&self.meta.for_dir).unwrap();
-                            let doc = doc!(
-                                self.filepath => String::from(filepath.to_string_lossy()),
-                                self.contents => contents,
-                            );
-                            index_writer.add_document(doc);
-                        }
+    /// Get the globs to exclude for the types of project detected in the indexed directory
+    fn get_project_ignores(&self) -> Vec<&String> {
+        if !self.meta.config.smart_ignores {
+            return vec![];
+        }
+        self.meta
+            .config
+            .project_ignores
+            .iter()
+            .filter(|(marker, _)| self.meta.for_dir.join(marker).exists())
+            .flat_map(|(_, globs)| globs)
+            .collect()
+    }
+
+    /// Load the ignore files from the directories between `ignore_root` and the indexed directory,
+    /// ordered from highest to lowest precedence
+    fn get_ancestor_ignores(&self) -> Result<Vec<Gitignore>, anyhow::Error> {
+        let ignore_root = fs::canonicalize(self.meta.for_dir.join(&self.meta.config.ignore_root))?;
+        if !self.meta.for_dir.starts_with(&ignore_root) {
+            bail!(
+                "ignore_root '{}' is not a parent of '{}'",
+                ignore_root.to_string_lossy(),
+                self.meta.for_dir.to_string_lossy()
+            );
+        }
+        let mut ignores = Vec::new();
+        let mut dir = self.meta.for_dir.as_path();
+        while dir != ignore_root {
+            dir = match dir.parent() {
+                Some(parent) => parent,
+                None => break,
+            };
+            // The custom ignore files take precedence over .ignore files, which take precedence
+            // over .gitignore files
+            let filenames = self
+                .meta
+                .config
+                .ignore_file_names
+                .iter()
+                .rev()
+                .map(String::as_str)
+                .chain([".ignore", ".gitignore"]);
+            for filename in filenames {
+                let file = dir.join(filename);
+                if file.is_file() {
+                    let mut builder = GitignoreBuilder::new(dir);
+                    if let Some(err) = builder.add(&file) {
+                        return Err(err.into());
                     }
+                    ignores.push(builder.build()?);
                 }
-                WalkState::Continue
-            })
-        });
-
-        index_writer.commit()?;
-        self.meta.last_update = now;
-        if let Some(index_dir) = &self.cache_dir {
-            fs::write(
-                index_dir.join(METADATA_FILE),
-                serde_json::to_string(&self.meta)?,
-            )?;
+            }
         }
-
-        return Ok(self);
+        Ok(ignores)
     }
 
-    pub fn search(
-        &self,
-        query: &Box<dyn Query>,
-        opts: &FileSearchOptions,
-    ) -> Result<Vec<FileSearchResult>, anyhow::Error> {
-        let reader = self
-            .index
-            .reader_builder()
-            .reload_policy(ReloadPolicy::OnCommit)
-            .try_into()?;
-        let searcher = reader.searcher();
-        let top_docs = searcher.search(query, &TopDocs::with_limit(opts.limit))?;
-        let mut doc_results = Vec::new();
-        for (score, doc_address) in top_docs {
-            if score > opts.threshold {
-                doc_results.push(DocResult {
-                    score,
-                    address: doc_address,
-                });
+    /// Get the paths of the files that the walkers find (see `get_file_walkers`), relative to the
+    /// indexed directory like `indexed_paths` and sorted. A file found by more than one walker is
+    /// only listed once. The files are not read, so this includes the files that an update would
+    /// skip (e.g. because they are too large).
+    pub fn list_files(&self) -> Result<Vec<String>, PoreError> {
+        let mut paths = HashSet::new();
+        for walker in self.get_file_walkers()? {
+            for entry in walker.build().filter_map(|result| result.ok()) {
+                if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(true) {
+                    continue;
+                }
+                let path = entry.path();
+                paths.insert(String::from(
+                    path.strip_prefix(&self.meta.for_dir)
+                        .unwrap_or(path)
+                        .to_string_lossy(),
+                ));
             }
         }
-        let mut position_map = location::get_search_results(self, query, &searcher, &doc_results)?;
-        let mut results = Vec::new();
-        for doc_result in doc_results {
-            let doc = searcher.doc(doc_result.address)?;
-            let filepath = doc.get_first(*self.filepath()).unwrap().text().unwrap();
-            let fullpath = if let Some(root_dir) = opts.root_dir.as_deref() {
-                PathBuf::from(root_dir).join(filepath)
-            } else {
-                PathBuf::from(self.meta.for_dir()).join(filepath)
-            };
+        let mut paths: Vec<String> = paths.into_iter().collect();
+        paths.sort();
+        Ok(paths)
+    }
 
-            let mut lines = Vec::new();
-            if !opts.filename_only {
-                if let Some(mut position_data) = position_map.get_mut(&doc_result.address) {
-                    location::positions_to_lines(&self, &fullpath, &mut position_data, &mut lines)?
+    /// Get the paths of all the files stored in the index (relative to the indexed directory),
+    /// sorted
+    pub fn indexed_paths(&self) -> Result<Vec<String>, PoreError> {
+        let searcher = self.reader.searcher()?;
+        let mut paths = HashSet::new();
+        for segment_reader in searcher.segment_readers() {
+            let inverted_index = segment_reader.inverted_index(self.filepath)?;
+            let mut terms = inverted_index.terms().stream()?;
+            while let Some((key, _)) = terms.next() {
+                // Deleted documents may still have terms, so check that the file is actually live
+                let term = Term::from_field_bytes(self.filepath, key);
+                let is_live = match inverted_index.read_postings(&term, IndexRecordOption::Basic)? {
+                    Some(postings) => match segment_reader.delete_bitset() {
+                        Some(deletes) => postings.doc_freq_given_deletes(deletes) > 0,
+                        None => true,
+                    },
+                    None => false,
                 };
+                if is_live {
+                    paths.insert(String::from_utf8_lossy(key).to_string());
+                }
             }
+        }
+        let mut paths: Vec<String> = paths.into_iter().collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// The number of files in the index (not counting deleted files)
+    pub fn num_docs(&self) -> Result<u64, PoreError> {
+        Ok(self.reader.searcher()?.num_docs())
+    }
+
+    /// Find the `n` terms of a field (e.g. `contents`) that are in the most files. The terms are
+    /// the indexed words, so they are lowercased and stemmed.
+    ///
+    /// This reads the whole terms dictionary, so it may be slow on a large index.
+    pub fn top_terms(&self, field: Field, n: usize) -> Result<Vec<TermFrequency>, PoreError> {
+        Ok(top_terms(&*self.reader.searcher()?, field, n)?)
+    }
+
+    /// Look up the stored document for a file by its path relative to the root of the index
+    ///
+    /// Returns None if the file is not in the index.
+    pub fn get_document(&self, relative_path: &str) -> Result<Option<StoredDoc>, PoreError> {
+        let searcher = self.reader.searcher()?;
+        let query = TermQuery::new(
+            Term::from_field_text(self.filepath, relative_path),
+            IndexRecordOption::Basic,
+        );
+        let doc_address = match searcher.search(&query, &TopDocs::with_limit(1))?.first() {
+            Some((_, doc_address)) => *doc_address,
+            None => return Ok(None),
+        };
+        let doc = searcher.doc(doc_address)?;
+        Ok(Some(StoredDoc {
+            filepath: relative_path.to_string(),
+            contents: doc
+                .get_first(self.contents)
+                .and_then(|v| v.text())
+                .map(|s| s.to_string()),
+        }))
+    }
+
+    /// Parse a query of the file contents
+    ///
+    /// Words of the file paths can be matched with the `path:` prefix (e.g. `path:handler`). The
+    /// `filepath` field stores whole paths, so `filepath:` is searched as `path:` as well. The
+    /// `size` (in bytes) and `modified` (an RFC 3339 date) fields can be matched with range
+    /// queries, like `size:[10000 TO *]` or `modified:[2021-10-01T00:00:00Z TO *]`.
+    pub fn parse_query(&self, query: &str) -> Result<Box<dyn Query>, PoreError> {
+        self.parse_query_with(&self.query_parser(), query)
+    }
+
+    /// The parser used by `parse_query`, which searches the file contents by default. This can be
+    /// configured (e.g. with `set_conjunction_by_default`) and passed to `parse_query_with`.
+    pub fn query_parser(&self) -> QueryParser {
+        QueryParser::for_index(&self.index, self.content_fields())
+    }
+
+    /// Like `parse_query`, but with a custom parser (see `query_parser`)
+    pub fn parse_query_with(
+        &self,
+        query_parser: &QueryParser,
+        query: &str,
+    ) -> Result<Box<dyn Query>, PoreError> {
+        Ok(query_parser.parse_query(&rename_field_prefix(query, "filepath", "path"))?)
+    }
+
+    /// Build a query that matches the words of `text` with up to `distance` typos each
+    ///
+    /// This works like a union of `FuzzyTermQuery` for each word, except that the words are
+    /// expanded to the matching terms in the index up front. That way the query reports the terms
+    /// it matched, so the matching lines can be found, and the results are scored by how often
+    /// those terms appear. The distance can be at most 2.
+    pub fn fuzzy_query(&self, text: &str, distance: u8) -> Result<Box<dyn Query>, PoreError> {
+        if distance > 2 {
+            return Err(PoreError::QueryParse(format!(
+                "Fuzzy distance of {} is not allowed. It must be at most 2",
+                distance
+            )));
+        }
+        let searcher = self.reader.searcher()?;
+        let mut terms = Vec::new();
+        for field in self.content_fields() {
+            let mut words = Vec::new();
+            let tokenizer = self.index.tokenizer_for_field(field)?;
+            let mut token_stream = tokenizer.token_stream(text);
+            while let Some(token) = token_stream.next() {
+                words.push(token.text.clone());
+            }
+            let field_terms = expand_fuzzy_terms(&searcher, field, &words, distance)?;
+            terms.extend(
+                field_terms
+                    .iter()
+                    .map(|term| Term::from_field_text(field, term)),
+            );
+        }
+        Ok(union_of_terms(terms))
+    }
+
+    /// Build a query that matches the indexed terms that `pattern` fully matches
+    ///
+    /// Like `fuzzy_query`, this expands the pattern to the matching terms in the index up front.
+    /// The pattern is matched against the indexed terms, not the raw text of the files, so it
+    /// can't match across words (e.g. `fn\s+\w+` never matches), and the terms have been
+    /// lowercased (unless `case_sensitive` is set) and stemmed. Use a pattern like `\w*test\w*`
+    /// to match part of a word.
+    pub fn regex_query(&self, pattern: &str) -> Result<Box<dyn Query>, PoreError> {
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|err| PoreError::QueryParse(err.to_string()))?;
+        let searcher = self.reader.searcher()?;
+        let mut terms = Vec::new();
+        for field in self.content_fields() {
+            let field_terms = expand_terms(&searcher, field, |term| regex.is_match(term))?;
+            terms.extend(
+                field_terms
+                    .iter()
+                    .map(|term| Term::from_field_text(field, term)),
+            );
+        }
+        Ok(union_of_terms(terms))
+    }
+
+    /// Keep one index writer open for the calls to `update` and `add_contents`, instead of creating
+    /// a new one for each call
+    ///
+    /// Creating a writer starts its indexing threads, which is wasteful when the same index is
+    /// updated many times (e.g. by a long-running process that watches for changes). The changes
+    /// are still committed at the end of each call. While the writer is open it holds the lock on
+    /// the index, so other processes can't update it until `close_writer` is called or the index
+    /// is dropped (they wait for the lock up to their lock timeout, and then fail with
+    /// `PoreError::IndexLocked`). Does nothing if the writer is already open.
+    pub fn open_writer(&mut self) -> Result<(), PoreError> {
+        let mut writer = self.writer.0.lock().unwrap();
+        if writer.is_none() {
+            *writer = Some(create_writer(
+                &self.index,
+                self.meta.config.writer_memory,
+                self.lock_timeout,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Close the writer opened by `open_writer`, and wait for its background merges to finish.
+    /// Does nothing if the writer is not open.
+    pub fn close_writer(&mut self) -> Result<(), PoreError> {
+        let writer = self.writer.0.lock().unwrap().take();
+        if let Some(index_writer) = writer {
+            index_writer.wait_merging_threads()?;
+        }
+        Ok(())
+    }
+
+    /// Pick up the updates made by other processes (or other instances of the same index) since
+    /// this instance was opened
+    ///
+    /// Searches always see the latest commit, but `update` only re-reads the files that changed
+    /// since this instance's last update. Refreshing reads the time of the latest update from the
+    /// cache, so a long-lived instance doesn't re-index the files that were already updated by
+    /// someone else.
+    pub fn refresh(&mut self) -> Result<(), PoreError> {
+        if let Some(cache_dir) = self.cache_dir.as_deref() {
+            if let Some(meta) = FileMetadata::load(cache_dir)? {
+                if meta.last_update > self.meta.last_update {
+                    self.meta.last_update = meta.last_update;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge all of the segments of the index into one, and return the number of segments that
+    /// were merged (0 if there was nothing to merge)
+    ///
+    /// Every update adds at least one segment, and searches have to look through each of them, so
+    /// an index that has been updated many times gets slower to search. Compacting it makes
+    /// searches faster again and removes the files of the merged segments.
+    pub fn compact(&mut self) -> Result<usize, PoreError> {
+        let segment_ids = self.index.searchable_segment_ids()?;
+        if segment_ids.len() < 2 {
+            return Ok(0);
+        }
+        self.with_writer(|_, index_writer| {
+            block_on(index_writer.merge(&segment_ids))?;
+            block_on(index_writer.garbage_collect_files())?;
+            Ok(())
+        })?;
+        Ok(segment_ids.len())
+    }
+
+    /// Run `f` with the writer opened by `open_writer`, or with a new writer if it is not open
+    fn with_writer<R, F>(&mut self, f: F) -> Result<R, anyhow::Error>
+    where
+        F: FnOnce(&mut Self, &mut IndexWriter) -> Result<R, anyhow::Error>,
+    {
+        let kept_writer = self.writer.clone();
+        let mut kept_writer = kept_writer.0.lock().unwrap();
+        match kept_writer.as_mut() {
+            Some(index_writer) => f(self, index_writer),
+            None => {
+                let mut index_writer = create_writer(
+                    &self.index,
+                    self.meta.config.writer_memory,
+                    self.lock_timeout,
+                )?;
+                f(self, &mut index_writer)
+            }
+        }
+    }
+
+    /// Get a handle that can be used to cancel `update` from another thread
+    ///
+    /// Storing `true` in the handle stops the file walker and rolls back all of the changes made by
+    /// the update, leaving the index exactly as it was before the update started (and with the
+    /// same last update time, so the next update will pick up the same files). The flag is reset
+    /// once an update finishes, so setting it while no update is running cancels the next one.
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        self.cancel.clone()
+    }
+
+    /// Set how long `update` and the other writes wait for another process to release the lock on
+    /// the index before failing with `PoreError::IndexLocked` (`DEFAULT_LOCK_TIMEOUT` unless this
+    /// is called)
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    pub fn update(&mut self, rebuild: bool) -> Result<UpdateSummary, PoreError> {
+        Ok(self.update_inner(rebuild, Progress::None)?)
+    }
+
+    /// Like `update`, but call `progress` with the number of files processed so far after each
+    /// file. If `progress` returns false, the update is cancelled (see `cancel_handle`).
+    ///
+    /// `progress` is always called on the calling thread, so it can use state that isn't `Send`
+    /// (such as a Lua function). To make that possible, the files are walked and read on the
+    /// calling thread instead of by the `threads` walker threads, which can make the update slower.
+    /// The documents are still indexed in parallel by the index writer.
+    pub fn update_with_progress<F: FnMut(usize) -> bool>(
+        &mut self,
+        rebuild: bool,
+        mut progress: F,
+    ) -> Result<UpdateSummary, PoreError> {
+        Ok(self.update_inner(rebuild, Progress::Local(&mut progress))?)
+    }
+
+    /// Like `update`, but call `progress` with the number of files processed so far after each
+    /// file, e.g. to show a running count while indexing a large tree
+    ///
+    /// Unlike `update_with_progress`, the files are still walked and read by the `threads` walker
+    /// threads, so `progress` is called from those threads and has to be thread-safe. The counts
+    /// may arrive slightly out of order. Use `cancel_handle` to cancel the update.
+    pub fn update_with_parallel_progress<F: Fn(usize) + Sync>(
+        &mut self,
+        rebuild: bool,
+        progress: F,
+    ) -> Result<UpdateSummary, PoreError> {
+        Ok(self.update_inner(rebuild, Progress::Parallel(&progress))?)
+    }
+
+    /// Re-index only the files at `paths`, and the files under them if they are directories, e.g.
+    /// the paths that a file watcher reported as changed
+    ///
+    /// The files are found by walking the tree, so the ignored files are left out, and the indexed
+    /// files under `paths` that no longer exist (or are now ignored) are removed. Only the
+    /// directories that lead to `paths` are walked. The paths are either relative to the indexed
+    /// directory or absolute.
+    pub fn update_paths<P: AsRef<Path>>(
+        &mut self,
+        paths: &[P],
+    ) -> Result<UpdateSummary, PoreError> {
+        let only = OnlyFiles::Paths(
+            paths
+                .iter()
+                .map(|path| self.meta.for_dir.join(path))
+                .collect(),
+        );
+        Ok(self.with_writer(|this, index_writer| {
+            this.update_with_writer(index_writer, true, Progress::None, Some(&only))
+        })?)
+    }
+
+    fn update_inner(
+        &mut self,
+        rebuild: bool,
+        progress: Progress,
+    ) -> Result<UpdateSummary, anyhow::Error> {
+        self.with_writer(|this, index_writer| {
+            this.update_with_writer(index_writer, rebuild, progress, None)
+        })
+    }
+
+    /// Re-index only the files that match a glob (like the `glob` option, relative to the indexed
+    /// directory), e.g. `*.rs` after editing all of the Rust files
+    ///
+    /// This is faster than `update` for a large tree when you know which files changed. Every file
+    /// that matches the glob is read again, and the indexed files that match it but no longer
+    /// exist (or are now ignored) are removed. The other files are left alone, so this doesn't
+    /// change the time of the last update, and the next `update` still picks up their changes.
+    pub fn update_glob(&mut self, pattern: &str) -> Result<UpdateSummary, PoreError> {
+        let only = OnlyFiles::Glob(self.glob_matcher(pattern)?);
+        Ok(self.with_writer(|this, index_writer| {
+            this.update_with_writer(index_writer, true, Progress::None, Some(&only))
+        })?)
+    }
+
+    /// Build a matcher that whitelists the files that match `pattern` (see `update_glob`)
+    fn glob_matcher(&self, pattern: &str) -> Result<Override, anyhow::Error> {
+        let mut globs = OverrideBuilder::new(&self.meta.for_dir);
+        globs.case_insensitive(self.meta.config.glob_case_insensitive)?;
+        globs.add(pattern)?;
+        Ok(globs.build()?)
+    }
+
+    fn update_with_writer(
+        &mut self,
+        index_writer: &mut IndexWriter,
+        rebuild: bool,
+        progress: Progress,
+        only: Option<&OnlyFiles>,
+    ) -> Result<UpdateSummary, anyhow::Error> {
+        let walkers = self.file_walkers(only)?;
+        let now = Utc::now();
+        let summary = Mutex::new(UpdateSummary::default());
+        let walked_paths = Mutex::new(HashSet::new());
+        let mut progress = progress;
+        let processed = AtomicUsize::new(0);
+        let is_file = |result: &Result<DirEntry, ignore::Error>| match result {
+            Ok(entry) => !entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false),
+            Err(_) => false,
+        };
+        for walker in walkers {
+            if self.cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            match &mut progress {
+                Progress::None => walker.build_parallel().run(|| {
+                    Box::new(|result| {
+                        self.visit_entry(result, rebuild, &*index_writer, &summary, &walked_paths)
+                    })
+                }),
+                Progress::Parallel(progress) => {
+                    let progress = *progress;
+                    walker.build_parallel().run(|| {
+                        Box::new(|result| {
+                            let is_file = is_file(&result);
+                            let state = self.visit_entry(
+                                result,
+                                rebuild,
+                                &*index_writer,
+                                &summary,
+                                &walked_paths,
+                            );
+                            if is_file {
+                                progress(processed.fetch_add(1, Ordering::Relaxed) + 1);
+                            }
+                            state
+                        })
+                    })
+                }
+                Progress::Local(progress) => {
+                    for result in walker.build() {
+                        let is_file = is_file(&result);
+                        let state = self.visit_entry(
+                            result,
+                            rebuild,
+                            index_writer,
+                            &summary,
+                            &walked_paths,
+                        );
+                        if matches!(state, WalkState::Quit) {
+                            break;
+                        }
+                        if is_file && !progress(processed.fetch_add(1, Ordering::Relaxed) + 1) {
+                            self.cancel.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.finish_update(
+            index_writer,
+            summary.into_inner().unwrap(),
+            walked_paths.into_inner().unwrap(),
+            now,
+            only,
+        )
+    }
+
+    /// Add a file found by the walker to the index (if it changed)
+    fn visit_entry(
+        &self,
+        result: Result<DirEntry, ignore::Error>,
+        rebuild: bool,
+        index_writer: &IndexWriter,
+        summary: &Mutex<UpdateSummary>,
+        walked_paths: &Mutex<HashSet<String>>,
+    ) -> WalkState {
+        if self.cancel.load(Ordering::Relaxed) {
+            return WalkState::Quit;
+        }
+        if let Ok(entry) = result {
+            if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
+                return WalkState::Continue;
+            }
+            // The extra directories are joined to the indexed directory, so their files are
+            // relative to it as well (e.g. `../lib/a.rs`), unless they are absolute
+            let path = entry.path();
+            let filepath = String::from(
+                path.strip_prefix(&self.meta.for_dir)
+                    .unwrap_or(path)
+                    .to_string_lossy(),
+            );
+            let metadata = entry.metadata().unwrap();
+            let modified: DateTime<Utc> = metadata.modified().unwrap().into();
+            if !is_regular_file(&entry) {
+                if rebuild || modified > self.meta.last_update {
+                    summary.lock().unwrap().skipped.push(SkippedFile {
+                        path: entry.path().to_path_buf(),
+                        reason: SkipReason::NotAFile,
+                    });
+                }
+                return WalkState::Continue;
+            }
+            let max_filesize = self.meta.config.max_filesize;
+            if max_filesize > 0 && metadata.len() > max_filesize {
+                // Not marking the file as walked removes it if it was indexed before
+                if rebuild || modified > self.meta.last_update {
+                    summary.lock().unwrap().skipped.push(SkippedFile {
+                        path: entry.path().to_path_buf(),
+                        reason: SkipReason::TooLarge,
+                    });
+                }
+                return WalkState::Continue;
+            }
+            // The file may be found by more than one walker (see `get_file_walkers`)
+            if !walked_paths.lock().unwrap().insert(filepath.clone()) {
+                return WalkState::Continue;
+            }
+            if rebuild || modified > self.meta.last_update {
+                match self.read_file(entry.path(), summary) {
+                    Ok(contents) => {
+                        // Replace the existing document for this file (if any)
+                        index_writer.delete_term(Term::from_field_text(self.filepath, &filepath));
+                        let doc = self.file_document(filepath, entry.path(), contents, modified);
+                        index_writer.add_document(doc);
+                        summary.lock().unwrap().added += 1;
+                    }
+                    Err(err) => {
+                        summary.lock().unwrap().skipped.push(SkippedFile {
+                            path: entry.path().to_path_buf(),
+                            reason: SkipReason::from(&err),
+                        });
+                    }
+                }
+            }
+        }
+        WalkState::Continue
+    }
+
+    /// Make the document of a file read from `path`
+    fn file_document(
+        &self,
+        filepath: String,
+        path: &Path,
+        contents: String,
+        modified: DateTime<Utc>,
+    ) -> Document {
+        let mut doc = self.make_document(filepath, contents, modified);
+        if let Some(field) = self.realpath {
+            // Files under a symlink are displayed with the path of the link, but read from the
+            // real path
+            if let Ok(realpath) = fs::canonicalize(path) {
+                if realpath != path {
+                    doc.add_text(field, realpath.to_string_lossy());
+                }
+            }
+        }
+        doc
+    }
+
+    /// Re-index a single file, e.g. after an editor saves it
+    ///
+    /// `path` is either relative to the indexed directory or absolute, and must be in one of the
+    /// `roots`. Unlike `update_glob`, this only walks the directories that lead to the file. If
+    /// the file no longer exists, is excluded by the ignore files (or options like `glob` and
+    /// `types`), or is skipped because it's too large or not a regular file, it is removed from the
+    /// index, the same as a full `update` would. If it can't be read, the index is left unchanged.
+    /// Like `update_glob`, this doesn't change the time of the last update.
+    pub fn update_file(&mut self, path: &Path) -> Result<UpdateSummary, PoreError> {
+        Ok(self.with_writer(|this, index_writer| {
+            let (filepath, fullpath) = this.file_paths(path)?;
+            let summary = Mutex::new(UpdateSummary::default());
+            let metadata = match fs::metadata(&fullpath) {
+                Ok(metadata) => Some(metadata),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            // The files that walking the tree leaves out are removed, like the deleted files
+            let metadata = match metadata {
+                Some(_) if !this.is_walked(&fullpath)? => None,
+                metadata => metadata,
+            };
+            let max_filesize = this.meta.config.max_filesize;
+            let skip_reason = match &metadata {
+                Some(metadata) if !metadata.is_file() => Some(SkipReason::NotAFile),
+                Some(metadata) if max_filesize > 0 && metadata.len() > max_filesize => {
+                    Some(SkipReason::TooLarge)
+                }
+                _ => None,
+            };
+            let doc = match metadata {
+                Some(metadata) if skip_reason.is_none() => {
+                    match this.read_file(&fullpath, &summary) {
+                        Ok(contents) => {
+                            let modified: DateTime<Utc> = metadata.modified()?.into();
+                            Some(this.file_document(
+                                filepath.clone(),
+                                &fullpath,
+                                contents,
+                                modified,
+                            ))
+                        }
+                        Err(err) => {
+                            let mut summary = summary.into_inner().unwrap();
+                            summary.skipped.push(SkippedFile {
+                                path: fullpath,
+                                reason: SkipReason::from(&err),
+                            });
+                            return Ok(summary);
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let mut summary = summary.into_inner().unwrap();
+            if let Some(reason) = skip_reason {
+                summary.skipped.push(SkippedFile {
+                    path: fullpath,
+                    reason,
+                });
+            }
+            let was_indexed = this.is_indexed(&filepath)?;
+            index_writer.delete_term(Term::from_field_text(this.filepath, &filepath));
+            match doc {
+                Some(doc) => {
+                    index_writer.add_document(doc);
+                    summary.added += 1;
+                }
+                None if was_indexed => summary.removed += 1,
+                None => {}
+            }
+            index_writer.commit()?;
+            this.provided_contents.remove(&filepath);
+            Ok(summary)
+        })?)
+    }
+
+    /// Remove a single file from the index, and return whether it was in the index
+    ///
+    /// `path` is either relative to the indexed directory or absolute, and must be in one of the
+    /// `roots`. The file doesn't have to be deleted from disk, but if it still exists, the next
+    /// `update` only adds it back if it was modified since the last update.
+    pub fn delete_file(&mut self, path: &Path) -> Result<bool, PoreError> {
+        Ok(self.with_writer(|this, index_writer| {
+            let (filepath, _) = this.file_paths(path)?;
+            if !this.is_indexed(&filepath)? {
+                return Ok(false);
+            }
+            index_writer.delete_term(Term::from_field_text(this.filepath, &filepath));
+            index_writer.commit()?;
+            this.provided_contents.remove(&filepath);
+            Ok(true)
+        })?)
+    }
+
+    /// The path of a file as it is stored in the index, and its full path, the same as walking the
+    /// `roots` would find them. Paths that aren't in any of the roots are an error.
+    fn file_paths(&self, path: &Path) -> Result<(String, PathBuf), anyhow::Error> {
+        let normalized = normalize_path(&self.meta.for_dir.join(path));
+        for root in self.roots() {
+            if let Ok(rest) = normalized.strip_prefix(normalize_path(&root)) {
+                let fullpath = root.join(rest);
+                let filepath = String::from(
+                    fullpath
+                        .strip_prefix(&self.meta.for_dir)
+                        .unwrap_or(&fullpath)
+                        .to_string_lossy(),
+                );
+                return Ok((filepath, fullpath));
+            }
+        }
+        bail!(
+            "'{}' is not in any of the indexed directories",
+            path.to_string_lossy()
+        )
+    }
+
+    /// Whether walking the tree finds the file at `fullpath`, i.e. it isn't excluded by the ignore
+    /// files or the options of the index. Only the directories that lead to the file are walked.
+    fn is_walked(&self, fullpath: &Path) -> Result<bool, anyhow::Error> {
+        let only = OnlyFiles::Paths(vec![fullpath.to_path_buf()]);
+        for walker in self.file_walkers(Some(&only))? {
+            for entry in walker.build().flatten() {
+                if entry.path() == fullpath {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether the index has a (live) document for a file
+    fn is_indexed(&self, filepath: &str) -> Result<bool, anyhow::Error> {
+        let query = TermQuery::new(
+            Term::from_field_text(self.filepath, filepath),
+            IndexRecordOption::Basic,
+        );
+        Ok(self.reader.searcher()?.search(&query, &Count)? > 0)
+    }
+
+    /// Read the contents of a file to index. Files that are not valid UTF-8 are an error, unless
+    /// the encoding is lossy.
+    fn read_file(&self, path: &Path, summary: &Mutex<UpdateSummary>) -> io::Result<String> {
+        let bytes = fs::read(path)?;
+        match String::from_utf8(bytes) {
+            Ok(contents) => Ok(contents),
+            Err(err) if self.meta.config.encoding == Encoding::Lossy => {
+                summary.lock().unwrap().lossy.push(path.to_path_buf());
+                Ok(String::from_utf8_lossy(err.as_bytes()).into_owned())
+            }
+            Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    }
+
+    /// Commit (or roll back, if cancelled) the changes made by the walker
+    fn finish_update(
+        &mut self,
+        index_writer: &mut IndexWriter,
+        mut summary: UpdateSummary,
+        walked_paths: HashSet<String>,
+        now: DateTime<Utc>,
+        only: Option<&OnlyFiles>,
+    ) -> Result<UpdateSummary, anyhow::Error> {
+        if self.cancel.swap(false, Ordering::Relaxed) {
+            index_writer.rollback()?;
+            summary.cancelled = true;
+            return Ok(summary);
+        }
+        // Remove the files that no longer exist (or are now ignored)
+        for filepath in self.indexed_paths()? {
+            // With `only`, the files that don't match it weren't walked
+            let in_walk = only.map_or(true, |only| {
+                only.matches(&self.meta.for_dir.join(&filepath))
+            });
+            if in_walk && !walked_paths.contains(&filepath) {
+                index_writer.delete_term(Term::from_field_text(self.filepath, &filepath));
+                summary.removed += 1;
+            }
+        }
+        index_writer.commit()?;
+        // Only some of the files were updated, so the rest may still be out of date
+        if only.is_some() {
+            return Ok(summary);
+        }
+        self.meta.last_update = now;
+        write_metadata(
+            &self.meta,
+            self.cache_dir.as_deref(),
+            self.directory.as_ref(),
+        )?;
+
+        return Ok(summary);
+    }
+
+    /// Add documents using the provided file contents instead of reading files from disk
+    ///
+    /// This is intended for ephemeral in-memory indexes, such as searching the staged version of
+    /// files from a git hook. The contents are retained so that search can extract the matching
+    /// lines from them instead of from the files on disk.
+    pub fn add_contents<I>(&mut self, files: I) -> Result<&mut Self, PoreError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.with_writer(|this, index_writer| {
+            let now = Utc::now();
+            for (filepath, contents) in files {
+                index_writer.delete_term(Term::from_field_text(this.filepath, &filepath));
+                let doc = this.make_document(filepath.clone(), contents.clone(), now);
+                index_writer.add_document(doc);
+                this.provided_contents.insert(filepath, contents);
+            }
+            index_writer.commit()?;
+            Ok(())
+        })?;
+        Ok(self)
+    }
+
+    /// Open a file for reading, preferring the contents provided by `add_contents`
+    fn open_contents(&self, filepath: &str, fullpath: &Path) -> io::Result<Box<dyn BufRead + '_>> {
+        Ok(match self.provided_contents.get(filepath) {
+            Some(contents) => Box::new(contents.as_bytes()),
+            // Decode the file the same way it was indexed, so reading the lines doesn't stop at
+            // the first invalid byte
+            None if self.meta.config.encoding == Encoding::Lossy => Box::new(io::Cursor::new(
+                String::from_utf8_lossy(&fs::read(fullpath)?).into_owned(),
+            )),
+            None => Box::new(io::BufReader::new(File::open(fullpath)?)),
+        })
+    }
+
+    /// Returns true if the file was modified after it was indexed, so the positions stored in
+    /// `doc` may not line up with its contents anymore
+    fn is_stale(&self, filepath: &str, readpath: &Path, doc: &Document) -> bool {
+        if self.provided_contents.contains_key(filepath) {
+            return false;
+        }
+        let indexed = match doc.get_first(self.modified).and_then(|v| v.date_value()) {
+            Some(indexed) => indexed.timestamp(),
+            None => return false,
+        };
+        match fs::metadata(readpath).and_then(|m| m.modified()) {
+            Ok(modified) => DateTime::<Utc>::from(modified).timestamp() > indexed,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns true if the file was last modified within `modified_after` and `modified_before`.
+    /// Files that can't be read from disk (e.g. they were deleted since they were indexed) are not
+    /// in range.
+    fn modified_in_range(&self, filepath: &str, opts: &FileSearchOptions) -> bool {
+        let modified =
+            match fs::metadata(self.meta.for_dir.join(filepath)).and_then(|m| m.modified()) {
+                Ok(modified) => DateTime::<Utc>::from(modified).timestamp(),
+                Err(_) => return false,
+            };
+        opts.modified_after.map_or(true, |after| modified > after)
+            && opts
+                .modified_before
+                .map_or(true, |before| modified < before)
+    }
+
+    /// Whether a matching document can be listed in the results: its file was modified within
+    /// the `modified_*` range, and it's the first of its group (tracked in `seen_groups`) when the
+    /// results are deduped. The documents have to be checked from the highest score down.
+    fn is_listed(
+        &self,
+        searcher: &Searcher,
+        doc_address: DocAddress,
+        opts: &FileSearchOptions,
+        seen_groups: &mut HashSet<Option<String>>,
+    ) -> Result<bool, anyhow::Error> {
+        let doc = searcher.doc(doc_address)?;
+        let filepath = doc.get_first(self.filepath).unwrap().text().unwrap();
+        // Filter before deduping, so a filtered file doesn't hide the rest of its group
+        let filter_modified = opts.modified_after.is_some() || opts.modified_before.is_some();
+        if filter_modified && !self.modified_in_range(filepath, opts) {
+            return Ok(false);
+        }
+        Ok(opts.dedupe_by == DedupeBy::None
+            || seen_groups.insert(opts.dedupe_by.group_key(Path::new(filepath))))
+    }
+
+    /// Find the top scoring documents, keeping only the best document in each group if the results
+    /// are being deduped. Also returns the total number of documents that match the query, and the
+    /// position data of the query terms in each of the top documents.
+    fn get_top_docs(
+        &self,
+        query: &Box<dyn Query>,
+        searcher: &Searcher,
+        opts: &FileSearchOptions,
+    ) -> Result<(Vec<DocResult>, usize, PositionMap), anyhow::Error> {
+        let mut doc_results = Vec::new();
+        let mut position_map = PositionMap::new();
+        let position_terms = location::position_terms(self, query.as_ref());
+        let mut seen_groups = HashSet::new();
+        // Collect from the top even when there is an offset, so that the relative threshold and
+        // the deduping are the same for every page of results
+        let page_size = opts.offset + opts.limit;
+        let mut page_offset = 0;
+        let mut skipped = 0;
+        let mut top_score = None;
+        let filter_modified = opts.modified_after.is_some() || opts.modified_before.is_some();
+        let rescorer = Rescorer::new(searcher, query.as_ref(), opts.scoring_params())?;
+        let recency_boost = if opts.recency_boost > 0.0 {
+            Some(RecencyBoost::new(
+                self.modified,
+                opts.recency_boost,
+                rescorer.clone(),
+            ))
+        } else {
+            None
+        };
+        // Count the matches and collect the positions of the terms while collecting the top docs,
+        // so neither needs another pass over the index
+        let search_page = |limit: usize, offset: usize, terms: Vec<Term>| {
+            let collector = TopDocs::with_limit(limit).and_offset(offset);
+            match (&recency_boost, &rescorer) {
+                (Some(recency_boost), _) => searcher.search(
+                    query,
+                    &(
+                        PositionCollector::new(collector.tweak_score(recency_boost.clone()), terms),
+                        Count,
+                    ),
+                ),
+                (None, Some(rescorer)) => searcher.search(
+                    query,
+                    &(
+                        PositionCollector::new(collector.tweak_score(rescorer.clone()), terms),
+                        Count,
+                    ),
+                ),
+                (None, None) => {
+                    searcher.search(query, &(PositionCollector::new(collector, terms), Count))
+                }
+            }
+        };
+        // The percentile needs the scores of every match, so this scores the whole result set
+        // before collecting the top pages
+        let percentile_score = if opts.threshold_percentile > 0.0 {
+            let mut scores = match (&recency_boost, &rescorer) {
+                (Some(recency_boost), _) => {
+                    searcher.search(query, &AllScores(Some(recency_boost.clone())))?
+                }
+                (None, Some(rescorer)) => {
+                    searcher.search(query, &AllScores(Some(rescorer.clone())))?
+                }
+                (None, None) => searcher.search(query, &AllScores::<Rescorer>(None))?,
+            };
+            scores.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+            // Only the files that can be in the results count towards the percentile
+            if opts.dedupe_by != DedupeBy::None || filter_modified {
+                let mut seen_groups = HashSet::new();
+                let mut kept = Vec::new();
+                for (score, doc_address) in scores {
+                    if self.is_listed(searcher, doc_address, opts, &mut seen_groups)? {
+                        kept.push((score, doc_address));
+                    }
+                }
+                scores = kept;
+            }
+            let scores: Vec<f32> = scores.into_iter().map(|(score, _)| score).collect();
+            percentile(&scores, opts.threshold_percentile)
+        } else {
+            None
+        };
+        // When deduping, keep fetching pages of results until we have enough distinct groups
+        loop {
+            let ((top_docs, page_positions), total) =
+                search_page(page_size, page_offset, position_terms.clone())?;
+            position_map.extend(page_positions);
+            let num_found = top_docs.len();
+            for (score, doc_address) in top_docs {
+                let top_score = *top_score.get_or_insert(score);
+                // The results are sorted by score, so nothing after this will pass the threshold
+                if score <= opts.threshold
+                    || score < top_score * opts.rel_threshold
+                    || percentile_score.map_or(false, |min_score| score < min_score)
+                {
+                    return Ok((doc_results, total, position_map));
+                }
+                if (opts.dedupe_by != DedupeBy::None || filter_modified)
+                    && !self.is_listed(searcher, doc_address, opts, &mut seen_groups)?
+                {
+                    continue;
+                }
+                if skipped < opts.offset {
+                    skipped += 1;
+                    continue;
+                }
+                doc_results.push(DocResult {
+                    score,
+                    address: doc_address,
+                });
+                if doc_results.len() >= opts.limit {
+                    return Ok((doc_results, total, position_map));
+                }
+            }
+            if (opts.dedupe_by == DedupeBy::None && !filter_modified)
+                || num_found == 0
+                || num_found < page_size
+            {
+                return Ok((doc_results, total, position_map));
+            }
+            page_offset += num_found;
+        }
+    }
+
+    pub fn search(
+        &self,
+        query: &Box<dyn Query>,
+        opts: &FileSearchOptions,
+    ) -> Result<Vec<FileSearchResult>, PoreError> {
+        Ok(self.search_with_total(query, opts)?.0)
+    }
+
+    /// Return every file that matches the query in location order: by path, and then by line
+    /// number within each file
+    ///
+    /// This is for stepping through all of the matches in file order (e.g. an editor's quickfix
+    /// list), so it ignores `limit`, `offset`, and `sort_by`. The thresholds and filters still
+    /// apply.
+    pub fn search_by_location(
+        &self,
+        query: &Box<dyn Query>,
+        opts: &FileSearchOptions,
+    ) -> Result<impl Iterator<Item = FileSearchResult>, PoreError> {
+        let opts = FileSearchOptions {
+            limit: self.num_docs()?.max(1) as usize,
+            offset: 0,
+            sort_by: SortBy::Path,
+            ..opts.clone()
+        };
+        let mut results = self.search(query, &opts)?;
+        for result in results.iter_mut() {
+            result.lines.sort_by_key(|line| line.number);
+        }
+        Ok(results.into_iter())
+    }
+
+    /// Like `search`, but also return the total number of files that match the query (e.g. for
+    /// "showing 50 of 1284 matches"). The total counts every match, before `limit`, `offset`, the
+    /// thresholds, the modification time filters, and deduping are applied.
+    pub fn search_with_total(
+        &self,
+        query: &Box<dyn Query>,
+        opts: &FileSearchOptions,
+    ) -> Result<(Vec<FileSearchResult>, usize), PoreError> {
+        Ok(self.search_inner(query, opts)?)
+    }
+
+    fn search_inner(
+        &self,
+        query: &Box<dyn Query>,
+        opts: &FileSearchOptions,
+    ) -> Result<(Vec<FileSearchResult>, usize), anyhow::Error> {
+        let searcher = self.reader.searcher()?;
+        let (doc_results, total, mut position_map) = self.get_top_docs(query, &searcher, opts)?;
+        let heading_regex = opts.heading_regex.as_deref().map(Regex::new).transpose()?;
+        let query_terms = location::content_terms(self, query);
+        let snippet_generator = match opts.snippet_mode {
+            _ if opts.filename_only => None,
+            SnippetMode::Lines => None,
+            SnippetMode::Snippet if self.meta.config.store_contents => Some(
+                SnippetGenerator::create(&searcher, query.as_ref(), self.contents)?,
+            ),
+            SnippetMode::Snippet => {
+                return Err(anyhow!(
+                    "Snippet mode requires an index built with store_contents"
+                ))
+            }
+        };
+        let mut results = Vec::new();
+        let mut modified_times = Vec::new();
+        for doc_result in doc_results {
+            let doc = searcher.doc(doc_result.address)?;
+            let filepath = doc.get_first(*self.filepath()).unwrap().text().unwrap();
+            let fullpath = if let Some(root_dir) = opts.root_dir.as_deref() {
+                PathBuf::from(root_dir).join(filepath)
+            } else {
+                PathBuf::from(self.meta.for_dir()).join(filepath)
+            };
+            let readpath = self
+                .realpath
+                .and_then(|field| doc.get_first(field))
+                .and_then(|v| v.text())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| fullpath.clone());
+
+            // The same position can be matched by the contents field and the language fields
+            let match_count = position_map
+                .get(&doc_result.address)
+                .map(|positions| positions.iter().map(|p| p.0).collect::<HashSet<_>>().len())
+                .unwrap_or(0);
+            let mut lines = Vec::new();
+            let mut lines_truncated = false;
+            let mut snippet = None;
+            if let Some(snippet_generator) = &snippet_generator {
+                let text = doc
+                    .get_first(self.contents)
+                    .and_then(|v| v.text())
+                    .unwrap_or_default();
+                let doc_snippet = snippet_generator.snippet(text);
+                if !location::snippet_to_lines(
+                    text,
+                    &doc_snippet,
+                    heading_regex.as_ref(),
+                    opts.trim_lines,
+                    &mut lines,
+                ) {
+                    snippet = Some(doc_snippet.fragments().to_string());
+                } else {
+                    lines_truncated = truncate_lines(&mut lines, opts.max_lines_per_file);
+                    if opts.before_context > 0 || opts.after_context > 0 {
+                        lines = location::add_context_lines(
+                            text.as_bytes(),
+                            lines,
+                            opts.before_context,
+                            opts.after_context,
+                            opts.trim_lines,
+                        );
+                    }
+                }
+            } else if !opts.filename_only {
+                // If the file can't be read anymore, or it was edited since it was indexed, fall
+                // back to the contents stored in the index
+                let stored_contents = doc.get_first(self.contents).and_then(|v| v.text());
+                let stale = self.is_stale(filepath, &readpath, &doc);
+                let open = || match (self.open_contents(filepath, &readpath), stored_contents) {
+                    (_, Some(text)) if stale => {
+                        Ok(Box::new(text.as_bytes()) as Box<dyn BufRead + '_>)
+                    }
+                    (Err(_), Some(text)) => Ok(Box::new(text.as_bytes()) as Box<dyn BufRead + '_>),
+                    (result, _) => result,
+                };
+                if let Some(position_data) = position_map.get_mut(&doc_result.address) {
+                    if !position_data.is_empty() {
+                        let line_map = self
+                            .line_map
+                            .and_then(|field| doc.get_first(field))
+                            .and_then(|v| v.text())
+                            .map(location::parse_line_map);
+                        match (open(), &line_map) {
+                            // The positions don't line up with the edited file, but the line map
+                            // still has the line numbers they were on
+                            (Ok(reader), Some(line_map)) if stale && stored_contents.is_none() => {
+                                location::line_map_to_lines(line_map, position_data, &mut lines);
+                                location::add_line_text(reader, &mut lines, opts.trim_lines);
+                            }
+                            (Ok(reader), _) => location::positions_to_lines(
+                                self,
+                                reader,
+                                position_data,
+                                line_map.as_deref(),
+                                heading_regex.as_ref(),
+                                opts.trim_lines,
+                                &mut lines,
+                            )?,
+                            // The line map still has the line numbers, just not the text
+                            (Err(_), Some(line_map)) => {
+                                location::line_map_to_lines(line_map, position_data, &mut lines)
+                            }
+                            (Err(err), None) => return Err(err.into()),
+                        }
+                    }
+                }
+                // There is no position data if the index doesn't record positions (see
+                // `FileIndexOptions::record_positions`), so fall back to scanning the file for
+                // the terms
+                if lines.is_empty() && !query_terms.is_empty() {
+                    location::scan_lines(
+                        self,
+                        open()?,
+                        &query_terms,
+                        heading_regex.as_ref(),
+                        opts.trim_lines,
+                        &mut lines,
+                    )?;
+                }
+                lines_truncated = truncate_lines(&mut lines, opts.max_lines_per_file);
+                if !lines.is_empty() && (opts.before_context > 0 || opts.after_context > 0) {
+                    // There is no context for the lines found with only the line map
+                    if let Ok(reader) = open() {
+                        lines = location::add_context_lines(
+                            reader,
+                            lines,
+                            opts.before_context,
+                            opts.after_context,
+                            opts.trim_lines,
+                        );
+                    }
+                }
+            }
+            if let Some(transform) = &opts.line_transform {
+                for line in lines.iter_mut() {
+                    transform.apply_to_line(line);
+                }
+                snippet = snippet.map(|text| transform.apply(&text));
+            }
+            modified_times.push(
+                doc.get_first(self.modified)
+                    .and_then(|v| v.date_value())
+                    .cloned(),
+            );
             results.push(FileSearchResult {
-                file: fullpath,
+                file: opts.path_separator.apply(fullpath),
                 score: doc_result.score,
+                match_count,
                 lines,
+                lines_truncated,
+                snippet,
             });
         }
-        Ok(results)
+        match opts.sort_by {
+            SortBy::Score => {}
+            SortBy::Matches => {
+                // Stable sort, so files with the same number of matches stay sorted by score
+                results.sort_by_key(|r| Reverse(r.match_count));
+            }
+            SortBy::Path => results.sort_by(|a, b| a.file.cmp(&b.file)),
+            SortBy::PathNatural => results.sort_by(|a, b| natural_path_cmp(&a.file, &b.file)),
+            SortBy::Modified | SortBy::ModifiedAsc => {
+                // Stable sort, so files with the same modification time stay sorted by score
+                let mut sorted: Vec<_> = results.into_iter().zip(modified_times).collect();
+                if opts.sort_by == SortBy::Modified {
+                    sorted.sort_by_key(|(_, modified)| Reverse(*modified));
+                } else {
+                    sorted.sort_by_key(|(_, modified)| *modified);
+                }
+                results = sorted.into_iter().map(|(result, _)| result).collect();
+            }
+        }
+        Ok((results, total))
+    }
+}
+
+/// Returns true if the entry is a regular file, or a symlink to one
+fn is_regular_file(entry: &DirEntry) -> bool {
+    match entry.file_type() {
+        Some(ft) if ft.is_symlink() => fs::metadata(entry.path())
+            .map(|m| m.is_file())
+            .unwrap_or(false),
+        Some(ft) => ft.is_file(),
+        None => false,
+    }
+}
+
+/// The score at a percentile (from 0 to 100) of scores that are sorted from highest to lowest, so
+/// that roughly `100 - percentile` percent of the scores are at least that high. Returns None if
+/// there are no scores.
+fn percentile(scores: &[f32], percentile: f32) -> Option<f32> {
+    if scores.is_empty() {
+        return None;
+    }
+    let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * scores.len() as f32) as usize;
+    Some(scores[scores.len() - 1 - rank.min(scores.len() - 1)])
+}
+
+/// Resolve the `.` and `..` components of a path, without reading the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The directory that a gitignore-style glob starts with, which contains all of the files it can
+/// match (e.g. `vendor/lib` for `vendor/lib/**/*.c`). Globs without a `/` (other than at the end)
+/// match at any depth, so their base is the root.
+fn glob_base_dir(glob: &str) -> PathBuf {
+    if !glob.trim_end_matches('/').contains('/') {
+        return PathBuf::new();
+    }
+    // The last part may be a file name, so it's not part of the directory
+    let parts: Vec<&str> = glob.trim_start_matches('/').split('/').collect();
+    parts[..parts.len() - 1]
+        .iter()
+        .take_while(|part| !part.contains(['*', '?', '[', '{', '\\', '!']))
+        .collect()
+}
+
+/// Replace the `from:` field prefix of the terms of a query with `to:`, except inside of quoted
+/// phrases
+fn rename_field_prefix(query: &str, from: &str, to: &str) -> String {
+    let prefix = format!("{}:", from);
+    let mut renamed = String::with_capacity(query.len());
+    let mut in_quotes = false;
+    let mut term_start = true;
+    let mut rest = query;
+    while let Some(c) = rest.chars().next() {
+        if !in_quotes && term_start && rest.starts_with(&prefix) {
+            renamed.push_str(to);
+            renamed.push(':');
+            rest = &rest[prefix.len()..];
+            term_start = false;
+            continue;
+        }
+        if c == '"' {
+            in_quotes = !in_quotes;
+        }
+        term_start = c.is_whitespace() || c == '(' || c == '+' || c == '-';
+        renamed.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    renamed
+}
+
+/// Drop the matching lines after the first `max` (the lines are in order, so these are the ones
+/// furthest into the file). Returns true if any lines were dropped.
+fn truncate_lines(lines: &mut Vec<Line>, max: Option<usize>) -> bool {
+    match max {
+        Some(max) if lines.len() > max => {
+            lines.truncate(max);
+            true
+        }
+        _ => false,
     }
 }
 
@@ -369,3 +2635,2146 @@ impl Display for FileIndex {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tantivy::directory::RamDirectory;
+
+    use tantivy::query::QueryParser;
+
+    use super::{
+        glob_base_dir, rename_field_prefix, take_within_bytes, FileIndex, FileIndexOptions,
+        FileSearchOptions, FileSearchResult, SkipReason,
+    };
+    use crate::common::{IndexMetadata, DEFAULT_WRITER_MEMORY};
+    use crate::dedupe::DedupeBy;
+    use crate::encoding::Encoding;
+    use crate::error::PoreError;
+    use crate::language::LanguageRef;
+    use crate::snippet::SnippetMode;
+    use crate::sort::SortBy;
+
+    fn search(index: &FileIndex, query: &str) -> Vec<String> {
+        search_with_opts(index, query, &FileSearchOptions::default())
+            .iter()
+            .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    fn search_with_opts(
+        index: &FileIndex,
+        query: &str,
+        opts: &FileSearchOptions,
+    ) -> Vec<FileSearchResult> {
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query(query).unwrap();
+        index.search(&query, opts).unwrap()
+    }
+
+    /// The names of the files that the walker of an index over `dir` finds, sorted
+    fn walked_files(dir: &Path, opts: &FileIndexOptions) -> Vec<String> {
+        let index = FileIndex::get_or_create(dir, None, opts).unwrap();
+        let mut files: Vec<String> = index
+            .get_file_walker()
+            .unwrap()
+            .build()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().map(|ft| ft.is_file()).unwrap_or(false))
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn add_files(index: &mut FileIndex, files: &[(&str, &str)]) {
+        index
+            .add_contents(
+                files
+                    .iter()
+                    .map(|(path, contents)| (path.to_string(), contents.to_string())),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn stems_with_multiple_languages() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let files = [
+            ("en.txt", "the horses were running"),
+            ("fr.txt", "les chevaux mangent"),
+        ];
+
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(&mut index, &files);
+        assert_eq!(search(&index, "runs"), vec!["en.txt"]);
+        assert!(search(&index, "cheval").is_empty());
+
+        let config = FileIndexOptions {
+            languages: vec![LanguageRef::French],
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(&mut index, &files);
+        assert_eq!(search(&index, "runs"), vec!["en.txt"]);
+        assert_eq!(search(&index, "cheval"), vec!["fr.txt"]);
+    }
+
+    #[test]
+    fn language_none_does_not_stem() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let files = [("a.txt", "the horses were running"), ("b.txt", "run")];
+        let config = FileIndexOptions {
+            language: LanguageRef::None,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(&mut index, &files);
+        assert_eq!(search(&index, "running"), vec!["a.txt"]);
+        assert_eq!(search(&index, "run"), vec!["b.txt"]);
+        assert!(search(&index, "horse").is_empty());
+    }
+
+    #[test]
+    fn finds_lines_after_dropped_tokens() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        // The long tokens are dropped from the index, but they still take up a token position.
+        // The first line matches, so the lines after the long tokens can't be found by scanning.
+        let long = "a".repeat(100);
+        let contents = format!("needle\n{}\nneedle\n{} needle\n", long, long);
+        add_files(&mut index, &[("file.txt", &contents)]);
+        let results = search_with_opts(&index, "needle", &FileSearchOptions::default());
+        assert_eq!(results.len(), 1);
+        let lines: Vec<(u32, &[(usize, usize)])> = results[0]
+            .lines()
+            .iter()
+            .map(|l| (l.number, l.matches.as_slice()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (1, &[(0, 6)][..]),
+                (3, &[(0, 6)][..]),
+                (4, &[(101, 107)][..])
+            ]
+        );
+    }
+
+    #[test]
+    fn indexes_extra_dirs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmpdir.path()).unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::create_dir(root.join("lib")).unwrap();
+        fs::write(root.join("src").join("a.txt"), "hay\nneedle").unwrap();
+        fs::write(root.join("lib").join("a.txt"), "needle").unwrap();
+        let config = FileIndexOptions {
+            extra_dirs: vec!["../lib".to_string()],
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(root.join("src"), None, &config).unwrap();
+        assert_eq!(index.update(false).unwrap().added, 2);
+        // The files with the same name in each root are stored separately
+        assert_eq!(
+            index.indexed_paths().unwrap(),
+            vec!["../lib/a.txt", "a.txt"]
+        );
+        let query = index.parse_query("needle").unwrap();
+        let mut results: Vec<(PathBuf, u32)> = index
+            .search(&query, &FileSearchOptions::default())
+            .unwrap()
+            .iter()
+            .map(|r| (r.file().to_path_buf(), r.lines()[0].number))
+            .collect();
+        results.sort();
+        assert_eq!(
+            results,
+            vec![
+                (root.join("src").join("../lib/a.txt"), 1),
+                (root.join("src").join("a.txt"), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignore_root_applies_parent_ignore_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        let sub = root.join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(sub.join("a.txt"), "").unwrap();
+        fs::write(sub.join("b.log"), "").unwrap();
+        let list_files = |ignore_root: &str| -> Vec<String> {
+            let opts = FileIndexOptions {
+                ignore_root: ignore_root.to_string(),
+                ..Default::default()
+            };
+            walked_files(&sub, &opts)
+        };
+        // Not in a git repo, so the parent .gitignore is only used with ignore_root
+        assert_eq!(list_files(""), vec!["a.txt", "b.log"]);
+        assert_eq!(list_files(".."), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn ignore_root_skips_extra_dirs_outside_of_it() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmpdir.path()).unwrap();
+        let project = root.join("project");
+        let sub = project.join("sub");
+        let other = root.join("other");
+        fs::create_dir_all(&sub).unwrap();
+        fs::create_dir(&other).unwrap();
+        fs::write(project.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(sub.join("a.txt"), "").unwrap();
+        fs::write(sub.join("b.log"), "").unwrap();
+        fs::write(other.join("c.txt"), "").unwrap();
+        fs::write(other.join("d.log"), "").unwrap();
+        let opts = FileIndexOptions {
+            ignore_root: "..".to_string(),
+            extra_dirs: vec![other.to_string_lossy().to_string()],
+            ..Default::default()
+        };
+        assert_eq!(walked_files(&sub, &opts), vec!["a.txt", "c.txt", "d.log"]);
+    }
+
+    #[test]
+    fn respects_custom_ignore_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::write(root.join(".poreignore"), "*.log\n").unwrap();
+        fs::write(root.join("a.txt"), "").unwrap();
+        fs::write(root.join("b.log"), "").unwrap();
+        let list_files = |ignore_file_names: &[&str], ignore_files: bool| -> Vec<String> {
+            let opts = FileIndexOptions {
+                ignore_file_names: ignore_file_names.iter().map(|s| s.to_string()).collect(),
+                ignore_files,
+                ..Default::default()
+            };
+            walked_files(root, &opts)
+        };
+        assert_eq!(list_files(&[], true), vec!["a.txt", "b.log"]);
+        assert_eq!(list_files(&[".poreignore"], true), vec!["a.txt"]);
+        assert_eq!(list_files(&[".poreignore"], false), vec!["a.txt", "b.log"]);
+    }
+
+    #[test]
+    fn unignore_reincludes_ignored_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::create_dir_all(root.join("vendor").join("lib")).unwrap();
+        fs::write(root.join(".ignore"), "vendor/\n*.log\n").unwrap();
+        fs::write(root.join("a.txt"), "needle").unwrap();
+        fs::write(root.join("b.log"), "needle").unwrap();
+        fs::write(root.join("vendor").join("lib").join("c.txt"), "needle").unwrap();
+        fs::write(root.join("vendor").join("d.log"), "needle").unwrap();
+        let indexed_paths = |unignore: &[&str]| -> Vec<String> {
+            let opts = FileIndexOptions {
+                unignore: unignore.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            };
+            let mut index = FileIndex::get_or_create(root, None, &opts).unwrap();
+            let summary = index.update(false).unwrap();
+            let paths = index.indexed_paths().unwrap();
+            // Files that are found by both walkers are only indexed once
+            assert_eq!(summary.added, paths.len());
+            paths
+        };
+        assert_eq!(indexed_paths(&[]), vec!["a.txt"]);
+        assert_eq!(
+            indexed_paths(&["/vendor/lib/"]),
+            vec!["a.txt", "vendor/lib/c.txt"]
+        );
+        assert_eq!(
+            indexed_paths(&["vendor/", "*.txt"]),
+            vec!["a.txt", "vendor/d.log", "vendor/lib/c.txt"]
+        );
+        assert_eq!(
+            indexed_paths(&["*.log"]),
+            vec!["a.txt", "b.log", "vendor/d.log"]
+        );
+    }
+
+    #[test]
+    fn list_files_lists_files_found_by_both_walkers_once() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::create_dir(root.join("vendor")).unwrap();
+        fs::write(root.join(".ignore"), "vendor/\n").unwrap();
+        fs::write(root.join("a.txt"), "").unwrap();
+        fs::write(root.join("vendor").join("b.txt"), "").unwrap();
+        let opts = FileIndexOptions {
+            unignore: vec!["vendor/".to_string(), "*.txt".to_string()],
+            ..Default::default()
+        };
+        let index = FileIndex::get_or_create(root, None, &opts).unwrap();
+        assert_eq!(index.list_files().unwrap(), vec!["a.txt", "vendor/b.txt"]);
+        // Listing the files doesn't index them
+        assert!(index.indexed_paths().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_the_base_dir_of_globs() {
+        assert_eq!(glob_base_dir("vendor/lib/**/*.c"), Path::new("vendor/lib"));
+        assert_eq!(glob_base_dir("/vendor/"), Path::new("vendor"));
+        assert_eq!(glob_base_dir("vendor/lib"), Path::new("vendor"));
+        assert_eq!(glob_base_dir("vendor/"), Path::new(""));
+        assert_eq!(glob_base_dir("*.log"), Path::new(""));
+        assert_eq!(glob_base_dir("src/*/gen/"), Path::new("src"));
+    }
+
+    #[test]
+    fn cancelled_update_leaves_index_unchanged() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index.cancel_handle().store(true, Ordering::Relaxed);
+        let summary = index.update(false).unwrap();
+        assert!(summary.cancelled);
+        assert!(search(&index, "needle").is_empty());
+
+        let summary = index.update(false).unwrap();
+        assert!(!summary.cancelled);
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn update_with_progress_reports_each_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(tmpdir.path().join(name), "needle").unwrap();
+        }
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        let summary = index.update_with_progress(false, |n| n < 2).unwrap();
+        assert!(summary.cancelled);
+        assert!(search(&index, "needle").is_empty());
+
+        let mut calls = Vec::new();
+        let summary = index
+            .update_with_progress(false, |n| {
+                calls.push(n);
+                true
+            })
+            .unwrap();
+        assert!(!summary.cancelled);
+        assert_eq!(summary.added, 3);
+        assert_eq!(calls, vec![1, 2, 3]);
+        assert_eq!(search(&index, "needle").len(), 3);
+    }
+
+    #[test]
+    fn update_with_parallel_progress_counts_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(tmpdir.path().join(name), "needle").unwrap();
+        }
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        let calls = Mutex::new(Vec::new());
+        let summary = index
+            .update_with_parallel_progress(false, |n| calls.lock().unwrap().push(n))
+            .unwrap();
+        assert_eq!(summary.added, 3);
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort();
+        assert_eq!(calls, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn top_terms_counts_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("a.txt", "apple banana cherry"),
+                ("b.txt", "banana cherry cherry"),
+                ("c.txt", "cherry"),
+            ],
+        );
+        let terms: Vec<(String, u64)> = index
+            .top_terms(*index.contents(), 2)
+            .unwrap()
+            .into_iter()
+            .map(|t| (t.term, t.doc_freq))
+            .collect();
+        assert_eq!(
+            terms,
+            vec![("cherri".to_string(), 3), ("banana".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn lossy_encoding_indexes_invalid_utf8() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        // "café needle" in latin-1
+        fs::write(tmpdir.path().join("latin1.txt"), b"caf\xe9\nneedle\n").unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        let summary = index.update(false).unwrap();
+        assert_eq!(summary.added, 0);
+        assert_eq!(summary.skipped[0].reason, SkipReason::NotUtf8);
+
+        let config = FileIndexOptions {
+            encoding: Encoding::Lossy,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        let summary = index.update(false).unwrap();
+        assert_eq!(summary.added, 1);
+        assert!(summary.skipped.is_empty());
+        assert_eq!(summary.lossy, vec![tmpdir.path().join("latin1.txt")]);
+        let query = index.parse_query("needle").unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        let lines: Vec<u32> = results[0].lines().iter().map(|l| l.number).collect();
+        assert_eq!(lines, vec![2]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn update_skips_special_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let status = std::process::Command::new("mkfifo")
+            .arg(tmpdir.path().join("fifo"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        // This would block forever if it tried to read the FIFO
+        let summary = index.update(false).unwrap();
+        assert_eq!(summary.added, 1);
+        assert_eq!(summary.skipped.len(), 1);
+        assert_eq!(summary.skipped[0].reason, SkipReason::NotAFile);
+        assert_eq!(index.indexed_paths().unwrap(), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn line_map_finds_the_same_lines() {
+        let contents = "fn first() {}\n\nlet needle = 1;\n// a comment\nneedle(needle)\n";
+        let mut results = Vec::new();
+        for store_line_map in [false, true] {
+            let tmpdir = tempfile::tempdir().unwrap();
+            fs::write(tmpdir.path().join("a.rs"), contents).unwrap();
+            let config = FileIndexOptions {
+                store_line_map,
+                ..Default::default()
+            };
+            let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+            index.update(false).unwrap();
+            let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+            let query = query_parser.parse_query("needle").unwrap();
+            let lines: Vec<(u32, Vec<(usize, usize)>)> =
+                index.search(&query, &FileSearchOptions::default()).unwrap()[0]
+                    .lines()
+                    .iter()
+                    .map(|l| (l.number, l.matches.clone()))
+                    .collect();
+            results.push(lines);
+        }
+        assert_eq!(
+            results[0],
+            vec![(3, vec![(4, 10)]), (5, vec![(0, 6), (7, 13)])]
+        );
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn stopwords_at_the_end_of_a_line_keep_their_position() {
+        let contents = "foo the\nbar needle\nneedle baz\n";
+        for store_line_map in [false, true] {
+            let tmpdir = tempfile::tempdir().unwrap();
+            fs::write(tmpdir.path().join("a.txt"), contents).unwrap();
+            let config = FileIndexOptions {
+                stopwords: vec!["the".to_string()],
+                store_line_map,
+                ..Default::default()
+            };
+            let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+            index.update(false).unwrap();
+            let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+            let query = query_parser.parse_query("needle").unwrap();
+            let lines: Vec<(u32, Vec<(usize, usize)>)> =
+                index.search(&query, &FileSearchOptions::default()).unwrap()[0]
+                    .lines()
+                    .iter()
+                    .map(|l| (l.number, l.matches.clone()))
+                    .collect();
+            assert_eq!(lines, vec![(2, vec![(4, 10)]), (3, vec![(0, 6)])]);
+        }
+    }
+
+    #[test]
+    fn line_map_finds_lines_of_edited_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("a.rs");
+        fs::write(&path, "fn first() {}\nlet needle = 1;\nneedle();\n").unwrap();
+        let config = FileIndexOptions {
+            store_line_map: true,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        index.update(false).unwrap();
+        // Edit the file without updating the index
+        fs::write(&path, "// new\nfn first() {}\nlet needle = 1;\nneedle();\n").unwrap();
+        let later =
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() + 10, 0);
+        filetime::set_file_mtime(&path, later).unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let lines: Vec<(u32, String)> =
+            index.search(&query, &FileSearchOptions::default()).unwrap()[0]
+                .lines()
+                .iter()
+                .map(|l| (l.number, l.text.clone()))
+                .collect();
+        // The lines the matches were on when the file was indexed, with their text now
+        assert_eq!(
+            lines,
+            vec![
+                (2, "fn first() {}".to_string()),
+                (3, "let needle = 1;".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_are_found_without_positions() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(
+            tmpdir.path().join("a.txt"),
+            "hay
+the needle
+more hay
+",
+        )
+        .unwrap();
+        let config = FileIndexOptions {
+            record_positions: false,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        index.update(false).unwrap();
+        assert!(!index.record_positions());
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        let lines: Vec<u32> = results[0].lines().iter().map(|l| l.number).collect();
+        assert_eq!(lines, vec![2]);
+        // Phrase queries need the positions
+        assert!(query_parser.parse_query("\"the needle\"").is_err());
+    }
+
+    #[test]
+    fn positions_are_collected_across_segments() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[("a.txt", "needle hay needle"), ("b.txt", "needle")],
+        );
+        // Replacing a.txt deletes its old doc and adds the new one in another segment
+        add_files(&mut index, &[("a.txt", "needle needle hay needle")]);
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle OR hay").unwrap();
+        let opts = FileSearchOptions {
+            filename_only: true,
+            ..Default::default()
+        };
+        let mut counts: Vec<(String, usize)> = index
+            .search(&query, &opts)
+            .unwrap()
+            .iter()
+            .map(|r| (r.file().to_string_lossy().to_string(), r.match_count()))
+            .collect();
+        counts.sort();
+        assert_eq!(counts.len(), 2);
+        assert!(counts[0].0.ends_with("a.txt"));
+        assert_eq!(counts[0].1, 4);
+        assert_eq!(counts[1].1, 1);
+    }
+
+    #[test]
+    fn positions_are_only_read_for_the_top_docs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("a.txt", "needle needle needle"),
+                ("b.txt", "hay needle"),
+                ("c.txt", "hay hay hay hay needle"),
+            ],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let searcher = index.reader.searcher().unwrap();
+        let opts = FileSearchOptions {
+            limit: 1,
+            ..Default::default()
+        };
+        let (top_docs, total, position_map) = index.get_top_docs(&query, &searcher, &opts).unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(top_docs.len(), 1);
+        assert_eq!(position_map.len(), 1);
+        let mut positions: Vec<u32> = position_map[&top_docs[0].address]
+            .iter()
+            .map(|pos| pos.0)
+            .collect();
+        positions.sort_unstable();
+        assert_eq!(positions, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn search_by_location_orders_by_path_and_line() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("b.txt", "needle needle needle"),
+                ("a.txt", "needle\nhay\nneedle"),
+                ("c.txt", "hay"),
+            ],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        // The limit doesn't apply
+        let opts = FileSearchOptions {
+            limit: 1,
+            root_dir: Some("".to_string()),
+            ..Default::default()
+        };
+        let matches: Vec<(String, u32)> = index
+            .search_by_location(&query, &opts)
+            .unwrap()
+            .flat_map(|r| {
+                let file = r.file().to_string_lossy().to_string();
+                r.lines
+                    .into_iter()
+                    .map(move |line| (file.clone(), line.number))
+            })
+            .collect();
+        assert_eq!(
+            matches,
+            vec![
+                ("a.txt".to_string(), 1),
+                ("a.txt".to_string(), 3),
+                ("b.txt".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn dedupe_by_directory() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("a/1.txt", "needle needle"),
+                ("a/2.txt", "needle"),
+                ("b/3.txt", "needle"),
+            ],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let opts = FileSearchOptions {
+            dedupe_by: DedupeBy::Directory,
+            ..Default::default()
+        };
+        let mut files: Vec<String> = index
+            .search(&query, &opts)
+            .unwrap()
+            .iter()
+            .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["1.txt", "3.txt"]);
+    }
+
+    #[test]
+    fn smart_ignores_exclude_project_globs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::write(root.join("Cargo.toml"), "").unwrap();
+        fs::create_dir(root.join("target")).unwrap();
+        fs::write(root.join("target").join("out.txt"), "").unwrap();
+        fs::create_dir(root.join("node_modules")).unwrap();
+        fs::write(root.join("node_modules").join("dep.txt"), "").unwrap();
+        let opts = FileIndexOptions {
+            smart_ignores: true,
+            ..Default::default()
+        };
+        // There is no package.json, so node_modules is still searched
+        assert_eq!(walked_files(root, &opts), vec!["Cargo.toml", "dep.txt"]);
+    }
+
+    #[test]
+    fn types_select_files_by_extension() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        for name in &["main.rs", "setup.py", "notes.txt"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+        assert_eq!(
+            walked_files(
+                root,
+                &FileIndexOptions {
+                    types: vec!["rust".to_string(), "py".to_string()],
+                    ..Default::default()
+                }
+            ),
+            vec!["main.rs", "setup.py"]
+        );
+        assert_eq!(
+            walked_files(
+                root,
+                &FileIndexOptions {
+                    types_not: vec!["py".to_string()],
+                    ..Default::default()
+                }
+            ),
+            vec!["main.rs", "notes.txt"]
+        );
+
+        let opts = FileIndexOptions {
+            types: vec!["notalanguage".to_string()],
+            ..Default::default()
+        };
+        let index = FileIndex::get_or_create(root, None, &opts).unwrap();
+        assert!(index.get_file_walker().is_err());
+    }
+
+    #[test]
+    fn counts_and_sorts_by_matches() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("short.txt", "needle"),
+                (
+                    "long.txt",
+                    "needle\nhay hay hay hay needle\nhay hay hay needle hay",
+                ),
+            ],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let opts = FileSearchOptions {
+            sort_by: SortBy::Matches,
+            ..Default::default()
+        };
+        let results = index.search(&query, &opts).unwrap();
+        let counts: Vec<(String, usize)> = results
+            .iter()
+            .map(|r| {
+                (
+                    r.file().file_name().unwrap().to_string_lossy().to_string(),
+                    r.match_count(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("long.txt".to_string(), 3), ("short.txt".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn updating_a_modified_file_replaces_it() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let file = tmpdir.path().join("a.txt");
+        fs::write(&file, "needle").unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+
+        fs::write(&file, "needle thread").unwrap();
+        let later =
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() + 10, 0);
+        filetime::set_file_mtime(&file, later).unwrap();
+        let summary = index.update(false).unwrap();
+        assert_eq!(summary.added, 1);
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+        assert_eq!(search(&index, "thread"), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn bm25_params_change_ranking() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        let long = format!("needle needle needle {}", "hay ".repeat(17));
+        add_files(&mut index, &[("short.txt", "needle"), ("long.txt", &long)]);
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let ranking = |opts: &FileSearchOptions| -> Vec<String> {
+            index
+                .search(&query, opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        // By default the length normalization favors the short file
+        assert_eq!(
+            ranking(&FileSearchOptions::default()),
+            vec!["short.txt", "long.txt"]
+        );
+        // Without length normalization the file with more matches wins
+        let opts = FileSearchOptions {
+            bm25_b: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(ranking(&opts), vec!["long.txt", "short.txt"]);
+    }
+
+    #[test]
+    fn bm25_params_reject_boosted_terms() {
+        use tantivy::query::{AllQuery, BooleanQuery, BoostQuery, Occur, Query};
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(&mut index, &[("a.txt", "needle")]);
+        let opts = FileSearchOptions {
+            bm25_b: 0.0,
+            ..Default::default()
+        };
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let boosted: Box<dyn Query> = Box::new(BooleanQuery::new(vec![(
+            Occur::Must,
+            Box::new(BoostQuery::new(
+                query_parser.parse_query("needle").unwrap(),
+                2.0,
+            )),
+        )]));
+        assert!(index.search(&boosted, &opts).is_err());
+        assert_eq!(
+            index.search(&boosted, &Default::default()).unwrap().len(),
+            1
+        );
+        // A boost without terms doesn't change the rescored scores
+        let filter: Box<dyn Query> = Box::new(BooleanQuery::new(vec![
+            (Occur::Must, query_parser.parse_query("needle").unwrap()),
+            (
+                Occur::Must,
+                Box::new(BoostQuery::new(Box::new(AllQuery), 0.0)),
+            ),
+        ]));
+        assert_eq!(index.search(&filter, &opts).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn take_within_bytes_keeps_the_first_result() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("a.txt", "needle needle needle"),
+                ("b.txt", "needle needle"),
+                ("c.txt", "needle"),
+            ],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let opts = FileSearchOptions {
+            limit: 2,
+            ..Default::default()
+        };
+        let search = || index.search_with_total(&query, &opts).unwrap();
+        let (results, total) = search();
+        assert_eq!(results.len(), 2);
+        // The total counts the results past the limit
+        assert_eq!(total, 3);
+        assert_eq!(results[0].text_len(), "needle needle needle".len());
+        assert_eq!(results[1].text_len(), "needle needle".len());
+
+        let (results, truncated) = take_within_bytes(search().0, 1000);
+        assert_eq!(results.len(), 2);
+        assert!(!truncated);
+        let (results, truncated) = take_within_bytes(search().0, 25);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file().file_name().unwrap(), "a.txt");
+        assert!(truncated);
+        // The first result is kept even if it's over the budget
+        let (results, truncated) = take_within_bytes(search().0, 1);
+        assert_eq!(results.len(), 1);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn rel_threshold_drops_weak_results() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        let weak = format!("needle {}", "hay ".repeat(50));
+        add_files(&mut index, &[("strong.txt", "needle"), ("weak.txt", &weak)]);
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 2);
+        let opts = FileSearchOptions {
+            rel_threshold: 0.6,
+            ..Default::default()
+        };
+        let (results, total) = index.search_with_total(&query, &opts).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file().file_name().unwrap(), "strong.txt");
+        // The total counts the results that were dropped by the threshold
+        assert_eq!(total, 2);
+        // Both thresholds must pass
+        let opts = FileSearchOptions {
+            rel_threshold: 0.1,
+            threshold: results[0].score(),
+            ..Default::default()
+        };
+        assert!(index.search(&query, &opts).unwrap().is_empty());
+    }
+
+    #[test]
+    fn threshold_percentile_keeps_top_scores() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        // 0.txt is alone in its directory, for deduping
+        let files: Vec<(String, String)> = (0..4)
+            .map(|i| {
+                (
+                    format!("{}/{}.txt", if i == 0 { "a" } else { "b" }, i),
+                    format!("needle {}", "hay ".repeat(i * 20)),
+                )
+            })
+            .collect();
+        let files: Vec<(&str, &str)> = files
+            .iter()
+            .map(|(name, contents)| (name.as_str(), contents.as_str()))
+            .collect();
+        add_files(&mut index, &files);
+        let query = index.parse_query("needle").unwrap();
+        let names = |opts: &FileSearchOptions| -> Vec<String> {
+            index
+                .search(&query, opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        let opts = FileSearchOptions {
+            threshold_percentile: 50.0,
+            ..Default::default()
+        };
+        assert_eq!(names(&opts), vec!["0.txt", "1.txt"]);
+        let opts = FileSearchOptions {
+            threshold_percentile: 75.0,
+            ..Default::default()
+        };
+        assert_eq!(names(&opts), vec!["0.txt"]);
+        // The percentile is of all the matches, not just the returned page
+        let opts = FileSearchOptions {
+            threshold_percentile: 50.0,
+            limit: 1,
+            offset: 1,
+            ..Default::default()
+        };
+        assert_eq!(names(&opts), vec!["1.txt"]);
+        // The files that are deduped away don't count towards the percentile, so it's the median
+        // of 0.txt and 1.txt
+        let opts = FileSearchOptions {
+            threshold_percentile: 50.0,
+            dedupe_by: DedupeBy::Directory,
+            ..Default::default()
+        };
+        assert_eq!(names(&opts), vec!["0.txt"]);
+        assert_eq!(super::percentile(&[4.0, 3.0, 2.0, 1.0], 0.0), Some(1.0));
+        assert_eq!(super::percentile(&[4.0, 3.0, 2.0, 1.0], 100.0), Some(4.0));
+        assert_eq!(super::percentile(&[], 50.0), None);
+    }
+
+    #[test]
+    fn update_removes_deleted_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        fs::write(tmpdir.path().join("b.txt"), "needle").unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+        let mut files = search(&index, "needle");
+        files.sort();
+        assert_eq!(files, vec!["a.txt", "b.txt"]);
+
+        fs::remove_file(tmpdir.path().join("b.txt")).unwrap();
+        let summary = index.update(false).unwrap();
+        assert_eq!(summary.removed, 1);
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+
+        // The file should only be counted as removed once
+        let summary = index.update(false).unwrap();
+        assert_eq!(summary.removed, 0);
+    }
+
+    #[test]
+    fn compact_merges_segments() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        // Each commit adds a segment
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            add_files(&mut index, &[(name, "needle")]);
+        }
+        assert_eq!(index.index().searchable_segment_ids().unwrap().len(), 3);
+        assert_eq!(index.compact().unwrap(), 3);
+        assert_eq!(index.index().searchable_segment_ids().unwrap().len(), 1);
+        assert_eq!(index.compact().unwrap(), 0);
+        let mut files = search(&index, "needle");
+        files.sort();
+        assert_eq!(files, vec!["a.txt", "b.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn update_glob_only_updates_matching_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::write(root.join("a.rs"), "needle").unwrap();
+        fs::write(root.join("b.txt"), "needle").unwrap();
+        let mut index = FileIndex::get_or_create(root, None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+
+        fs::write(root.join("a.rs"), "haystack").unwrap();
+        fs::write(root.join("b.txt"), "haystack").unwrap();
+        let later =
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() + 10, 0);
+        filetime::set_file_mtime(root.join("b.txt"), later).unwrap();
+        let summary = index.update_glob("*.rs").unwrap();
+        assert_eq!((summary.added, summary.removed), (1, 0));
+        assert_eq!(search(&index, "haystack"), vec!["a.rs"]);
+        assert_eq!(search(&index, "needle"), vec!["b.txt"]);
+
+        // Deleted files that match the glob are removed
+        fs::remove_file(root.join("a.rs")).unwrap();
+        fs::write(root.join("c.rs"), "haystack").unwrap();
+        filetime::set_file_mtime(root.join("c.rs"), later).unwrap();
+        let summary = index.update_glob("*.rs").unwrap();
+        assert_eq!((summary.added, summary.removed), (1, 1));
+        assert_eq!(index.indexed_paths().unwrap(), vec!["b.txt", "c.rs"]);
+
+        // The next full update still picks up the other changes
+        assert_eq!(index.update(false).unwrap().added, 2);
+        let mut files = search(&index, "haystack");
+        files.sort();
+        assert_eq!(files, vec!["b.txt", "c.rs"]);
+    }
+
+    #[test]
+    fn update_file_and_delete_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::write(root.join("a.txt"), "needle").unwrap();
+        fs::write(root.join("b.txt"), "needle").unwrap();
+        let mut index = FileIndex::get_or_create(root, None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+
+        fs::write(root.join("a.txt"), "haystack").unwrap();
+        fs::write(root.join("b.txt"), "haystack").unwrap();
+        let summary = index.update_file(Path::new("a.txt")).unwrap();
+        assert_eq!((summary.added, summary.removed), (1, 0));
+        assert_eq!(search(&index, "haystack"), vec!["a.txt"]);
+        assert_eq!(search(&index, "needle"), vec!["b.txt"]);
+
+        // Absolute paths work too, and a file that no longer exists is removed
+        fs::remove_file(root.join("b.txt")).unwrap();
+        let summary = index.update_file(&root.join("b.txt")).unwrap();
+        assert_eq!((summary.added, summary.removed), (0, 1));
+        assert_eq!(index.indexed_paths().unwrap(), vec!["a.txt"]);
+
+        assert!(index.delete_file(Path::new("a.txt")).unwrap());
+        assert!(!index.delete_file(Path::new("a.txt")).unwrap());
+        assert!(index.indexed_paths().unwrap().is_empty());
+    }
+
+    #[test]
+    fn update_file_follows_the_walker() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(tmpdir.path()).unwrap();
+        let project = root.join("project");
+        let lib = root.join("lib");
+        fs::create_dir_all(project.join("target")).unwrap();
+        fs::create_dir(&lib).unwrap();
+        fs::write(project.join(".ignore"), "target/\n").unwrap();
+        fs::write(project.join("target").join("a.txt"), "needle").unwrap();
+        fs::write(lib.join("b.txt"), "needle").unwrap();
+        fs::write(root.join("c.txt"), "needle").unwrap();
+        let config = FileIndexOptions {
+            extra_dirs: vec!["../lib".to_string()],
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(&project, None, &config).unwrap();
+
+        // Ignored files are left out, the same as with a full update
+        let summary = index.update_file(Path::new("target/a.txt")).unwrap();
+        assert_eq!((summary.added, summary.removed), (0, 0));
+        // The files in the extra directories are stored the same way the walker finds them
+        let summary = index.update_file(&lib.join("b.txt")).unwrap();
+        assert_eq!((summary.added, summary.removed), (1, 0));
+        assert_eq!(index.indexed_paths().unwrap(), vec!["../lib/b.txt"]);
+        // Files outside of the indexed directories are rejected
+        assert!(index.update_file(&root.join("c.txt")).is_err());
+        assert!(index.delete_file(Path::new("../c.txt")).is_err());
+        assert_eq!(index.indexed_paths().unwrap(), vec!["../lib/b.txt"]);
+    }
+
+    #[test]
+    fn open_writer_is_reused_between_updates() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let mut index = FileIndex::get_or_create(
+            tmpdir.path(),
+            Some(cache_dir.path()),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        index.open_writer().unwrap();
+        assert_eq!(index.update(false).unwrap().added, 1);
+        // Make sure the new file is newer than the last update
+        fs::write(tmpdir.path().join("b.txt"), "needle").unwrap();
+        let later =
+            filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() + 10, 0);
+        filetime::set_file_mtime(tmpdir.path().join("b.txt"), later).unwrap();
+        assert_eq!(index.update(false).unwrap().added, 1);
+        let mut files = search(&index, "needle");
+        files.sort();
+        assert_eq!(files, vec!["a.txt", "b.txt"]);
+
+        // The open writer holds the lock on the index
+        let mut other = FileIndex::get_or_create(
+            tmpdir.path(),
+            Some(cache_dir.path()),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        // Don't wait for the lock, which is held until the writer is closed
+        other.set_lock_timeout(Duration::ZERO);
+        assert!(matches!(other.update(true), Err(PoreError::IndexLocked)));
+        index.close_writer().unwrap();
+        assert_eq!(other.update(true).unwrap().added, 2);
+    }
+
+    #[test]
+    fn refresh_reads_updates_from_other_instances() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let open = || {
+            FileIndex::get_or_create(
+                tmpdir.path(),
+                Some(cache_dir.path()),
+                &FileIndexOptions::default(),
+            )
+            .unwrap()
+        };
+        let mut index = open();
+        let mut other = open();
+        assert_eq!(other.update(false).unwrap().added, 1);
+        // Searches always see the latest commit
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+        index.refresh().unwrap();
+        assert_eq!(index.update(false).unwrap().added, 0);
+    }
+
+    #[test]
+    fn index_in_custom_directory() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let directory = RamDirectory::create();
+        let mut index = FileIndex::get_or_create_in_directory(
+            tmpdir.path(),
+            Box::new(directory.clone()),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        index.update(false).unwrap();
+
+        // Reopening the same directory loads the existing index and metadata
+        let mut index = FileIndex::get_or_create_in_directory(
+            tmpdir.path(),
+            Box::new(directory),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+        assert_eq!(index.update(false).unwrap().added, 0);
+    }
+
+    #[test]
+    fn context_lines_merge_and_stop_at_file_edges() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[(
+                "file.txt",
+                "needle\none\ntwo\nneedle\nthree\nfour\nfive\nsix\nneedle",
+            )],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let opts = FileSearchOptions {
+            before_context: 2,
+            after_context: 1,
+            ..Default::default()
+        };
+        let results = index.search(&query, &opts).unwrap();
+        let lines: Vec<(u32, bool)> = results[0]
+            .lines()
+            .iter()
+            .map(|l| (l.number, l.is_context))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (1, false),
+                (2, true),
+                (3, true),
+                (4, false),
+                (5, true),
+                (7, true),
+                (8, true),
+                (9, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn max_lines_per_file_keeps_first_lines() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                (
+                    "many.txt",
+                    "needle
+one
+needle
+two
+needle",
+                ),
+                ("one.txt", "needle"),
+            ],
+        );
+        let opts = FileSearchOptions {
+            max_lines_per_file: Some(2),
+            after_context: 1,
+            sort_by: SortBy::Path,
+            ..Default::default()
+        };
+        let results = search_with_opts(&index, "needle", &opts);
+        let lines: Vec<(u32, bool)> = results[0]
+            .lines()
+            .iter()
+            .map(|l| (l.number, l.is_context))
+            .collect();
+        assert_eq!(lines, vec![(1, false), (2, true), (3, false), (4, true)]);
+        assert!(results[0].lines_truncated());
+        assert_eq!(results[1].lines().len(), 1);
+        assert!(!results[1].lines_truncated());
+    }
+
+    #[test]
+    fn lines_record_match_offsets() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[("file.txt", "intro\nthe horse runs and running\n")],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("run").unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        let lines = results[0].lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].matches, vec![(10, 14), (19, 26)]);
+    }
+
+    #[test]
+    fn check_finds_corrupted_segment_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let config = FileIndexOptions::default();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        index.update(false).unwrap();
+        drop(index);
+        assert!(FileIndex::check(cache_dir.path(), &config)
+            .unwrap()
+            .is_empty());
+
+        let postings = fs::read_dir(cache_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().map(|ext| ext == "idx").unwrap_or(false))
+            .unwrap();
+        fs::write(&postings, "this is not a postings file").unwrap();
+        assert!(!FileIndex::check(cache_dir.path(), &config)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn recreate_repairs_corrupted_index() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let config = FileIndexOptions::default();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        index.update(false).unwrap();
+        drop(index);
+        let postings = fs::read_dir(cache_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().map(|ext| ext == "idx").unwrap_or(false))
+            .unwrap();
+        fs::write(&postings, "this is not a postings file").unwrap();
+        assert!(!FileIndex::check(cache_dir.path(), &config)
+            .unwrap()
+            .is_empty());
+
+        let mut index = FileIndex::recreate(tmpdir.path(), cache_dir.path(), &config).unwrap();
+        index.update(true).unwrap();
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+        drop(index);
+        assert!(FileIndex::check(cache_dir.path(), &config)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn snippet_mode_reads_stored_contents() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "intro\nthe needle is here\n").unwrap();
+        let config = FileIndexOptions {
+            store_contents: true,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        index.update(false).unwrap();
+        // The snippet comes from the index, so changes to the file don't matter
+        fs::write(tmpdir.path().join("a.txt"), "changed").unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let opts = FileSearchOptions {
+            snippet_mode: SnippetMode::Snippet,
+            ..Default::default()
+        };
+        let results = index.search(&query, &opts).unwrap();
+        let lines = results[0].lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].number, 2);
+        assert_eq!(lines[0].text, "the needle is here");
+        assert_eq!(lines[0].matches, vec![(4, 10)]);
+    }
+
+    #[test]
+    fn offset_pages_through_results() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("a.txt", "needle needle needle"),
+                ("b.txt", "needle needle hay"),
+                ("c.txt", "needle hay hay"),
+            ],
+        );
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let page = |offset: usize| -> Vec<String> {
+            let opts = FileSearchOptions {
+                limit: 2,
+                offset,
+                filename_only: true,
+                ..Default::default()
+            };
+            index
+                .search(&query, &opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        assert_eq!(page(0), vec!["a.txt", "b.txt"]);
+        assert_eq!(page(1), vec!["b.txt", "c.txt"]);
+        assert_eq!(page(2), vec!["c.txt"]);
+        assert!(page(3).is_empty());
+    }
+
+    #[test]
+    fn frontmatter_fields_are_searchable() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let config = FileIndexOptions {
+            parse_frontmatter: true,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("tagged.md", "---\ntags: [rust, search]\n---\nSome notes"),
+                ("mentioned.md", "Some notes about rust"),
+                ("tagged.txt", "---\ntags: [rust]\n---\nSome notes"),
+            ],
+        );
+        assert_eq!(search(&index, "tags:rust"), vec!["tagged.md"]);
+        let mut files = search(&index, "rust");
+        files.sort();
+        assert_eq!(files, vec!["mentioned.md", "tagged.md", "tagged.txt"]);
+    }
+
+    #[test]
+    fn ngram_matches_part_of_a_word() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let config = FileIndexOptions {
+            ngram: true,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("config.txt", "intro\nload the Configuration\n"),
+                ("cone.txt", "a cone of fun"),
+            ],
+        );
+        assert_eq!(search(&index, "conf"), vec!["config.txt"]);
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("figur").unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        let lines = results[0].lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].number, 2);
+        assert_eq!(lines[0].matches, vec![(9, 22)]);
+    }
+
+    #[test]
+    fn case_sensitive_search() {
+        let files = [("upper.txt", "TODO: fix"), ("lower.txt", "a todo list")];
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(&mut index, &files);
+        let mut found = search(&index, "TODO");
+        found.sort();
+        assert_eq!(found, vec!["lower.txt", "upper.txt"]);
+
+        let config = FileIndexOptions {
+            case_sensitive: true,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(&mut index, &files);
+        assert_eq!(search(&index, "TODO"), vec!["upper.txt"]);
+        assert_eq!(search(&index, "todo"), vec!["lower.txt"]);
+    }
+
+    #[test]
+    fn list_files_lists_walked_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::write(root.join(".ignore"), "*.log\n").unwrap();
+        fs::write(root.join("a.txt"), "").unwrap();
+        fs::write(root.join("b.log"), "").unwrap();
+        let index = FileIndex::get_or_create(root, None, &FileIndexOptions::default()).unwrap();
+        assert_eq!(index.list_files().unwrap(), vec!["a.txt"]);
+        // Listing the files doesn't index them
+        assert!(index.indexed_paths().unwrap().is_empty());
+    }
+
+    #[test]
+    fn indexed_paths_lists_live_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(&mut index, &[("b.txt", "one"), ("a/c.txt", "two")]);
+        // Re-adding a file replaces the old document, which must not be listed twice
+        add_files(&mut index, &[("b.txt", "three")]);
+        assert_eq!(index.indexed_paths().unwrap(), vec!["a/c.txt", "b.txt"]);
+        assert_eq!(index.num_docs().unwrap(), 2);
+    }
+
+    #[test]
+    fn index_inside_of_indexed_dir_is_skipped() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "one").unwrap();
+        let cache_dir = tmpdir.path().join("index");
+        fs::create_dir(&cache_dir).unwrap();
+        let mut index = FileIndex::get_or_create(
+            tmpdir.path(),
+            Some(&cache_dir),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        index.update(false).unwrap();
+        assert_eq!(index.indexed_paths().unwrap(), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn get_document_looks_up_stored_fields() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let config = FileIndexOptions {
+            store_contents: true,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(&mut index, &[("dir/a b.txt", "one"), ("c.txt", "two")]);
+        let doc = index.get_document("dir/a b.txt").unwrap().unwrap();
+        assert_eq!(doc.filepath(), "dir/a b.txt");
+        assert_eq!(doc.contents(), Some("one"));
+        assert!(index.get_document("dir").unwrap().is_none());
+
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(&mut index, &[("c.txt", "two")]);
+        assert_eq!(
+            index.get_document("c.txt").unwrap().unwrap().contents(),
+            None
+        );
+    }
+
+    #[test]
+    fn fuzzy_query_matches_typos() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("a.txt", "intro\nsearch the index\n"),
+                ("b.txt", "nothing to see"),
+            ],
+        );
+        let query = index.fuzzy_query("serch", 1).unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file().file_name().unwrap(), "a.txt");
+        let lines = results[0].lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].number, 2);
+        assert_eq!(lines[0].matches, vec![(0, 6)]);
+        let query = index.fuzzy_query("srch", 1).unwrap();
+        assert!(index
+            .search(&query, &FileSearchOptions::default())
+            .unwrap()
+            .is_empty());
+        assert!(index.fuzzy_query("search", 3).is_err());
+    }
+
+    #[test]
+    fn regex_query_matches_whole_terms() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(
+            &mut index,
+            &[
+                ("a.txt", "intro\nconfigure the widget\n"),
+                ("b.txt", "nothing to see"),
+            ],
+        );
+        let query = index.regex_query("conf\\w*").unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file().file_name().unwrap(), "a.txt");
+        assert_eq!(results[0].lines()[0].number, 2);
+        // The pattern must match the whole term
+        let query = index.regex_query("conf").unwrap();
+        assert!(index
+            .search(&query, &FileSearchOptions::default())
+            .unwrap()
+            .is_empty());
+        assert!(index.regex_query("(").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_reads_symlinks_from_the_real_path() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("target.txt"), "intro\nneedle\n").unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("target.txt"),
+            tmpdir.path().join("link.txt"),
+        )
+        .unwrap();
+        let config = FileIndexOptions {
+            follow: true,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        index.update(false).unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        // Display the paths relative to a directory that the link path can't be read from
+        let opts = FileSearchOptions {
+            root_dir: Some("not/a/real/dir".to_string()),
+            ..Default::default()
+        };
+        let results = index.search(&query, &opts).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].file(), Path::new("not/a/real/dir/link.txt"));
+        assert_eq!(results[0].lines().len(), 1);
+        assert_eq!(results[0].lines()[0].number, 2);
+    }
+
+    #[test]
+    fn max_token_length_drops_long_words() {
+        let word = "a".repeat(60);
+        let files = [("long.txt", format!("short {}", word))];
+        let files: Vec<(&str, &str)> = files.iter().map(|(p, c)| (*p, c.as_str())).collect();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        add_files(&mut index, &files);
+        assert_eq!(search(&index, "short"), vec!["long.txt"]);
+        assert!(search(&index, &word).is_empty());
+
+        let config = FileIndexOptions {
+            max_token_length: 0,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(&mut index, &files);
+        assert_eq!(search(&index, &word), vec!["long.txt"]);
+    }
+
+    #[test]
+    fn stopwords_are_not_indexed() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let config = FileIndexOptions {
+            stopwords: vec!["Lorem".to_string()],
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        add_files(&mut index, &[("a.txt", "lorem ipsum"), ("b.txt", "dolor")]);
+        assert!(search(&index, "lorem").is_empty());
+        assert_eq!(search(&index, "ipsum"), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn update_skips_large_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("small.txt"), "needle").unwrap();
+        fs::write(tmpdir.path().join("large.txt"), "needle ".repeat(100)).unwrap();
+        let config = FileIndexOptions {
+            max_filesize: 100,
+            ..Default::default()
+        };
+        let mut index = FileIndex::get_or_create(tmpdir.path(), None, &config).unwrap();
+        let summary = index.update(false).unwrap();
+        assert_eq!(summary.added, 1);
+        assert_eq!(summary.skipped.len(), 1);
+        assert_eq!(summary.skipped[0].reason, SkipReason::TooLarge);
+        assert_eq!(search(&index, "needle"), vec!["small.txt"]);
+    }
+
+    #[test]
+    fn glob_and_oglob_case_insensitivity_are_separate() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "").unwrap();
+        fs::write(tmpdir.path().join("b.md"), "").unwrap();
+        let oglob = vec!["*.TXT".to_string()];
+        let opts = FileIndexOptions {
+            oglob: oglob.clone(),
+            glob_case_insensitive: true,
+            ..Default::default()
+        };
+        assert!(walked_files(tmpdir.path(), &opts).is_empty());
+        let opts = FileIndexOptions {
+            oglob,
+            oglob_case_insensitive: true,
+            ..Default::default()
+        };
+        assert_eq!(walked_files(tmpdir.path(), &opts), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn trim_lines_can_keep_trailing_whitespace() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        // Two trailing spaces are a hard line break in markdown
+        add_files(&mut index, &[("notes.md", "a needle  \r\nnext line\n")]);
+        let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let results = index.search(&query, &FileSearchOptions::default()).unwrap();
+        assert_eq!(results[0].lines()[0].text, "a needle");
+        let opts = FileSearchOptions {
+            trim_lines: false,
+            ..Default::default()
+        };
+        let results = index.search(&query, &opts).unwrap();
+        assert_eq!(results[0].lines()[0].text, "a needle  ");
+    }
+
+    /// Replace the index in `cache_dir` with an empty one that has `schema`, and metadata from
+    /// before the index format was recorded, like the indexes built by older versions of pore
+    fn write_old_index(for_dir: &Path, cache_dir: &Path, schema: tantivy::schema::Schema) {
+        let config = FileIndexOptions::default();
+        let mut index = FileIndex::get_or_create(for_dir, Some(cache_dir), &config).unwrap();
+        index.update(false).unwrap();
+        drop(index);
+        let metafile = cache_dir.join(crate::common::METADATA_FILE);
+        let mut meta: serde_json::Value =
+            serde_json::from_slice(&fs::read(&metafile).unwrap()).unwrap();
+        meta.as_object_mut().unwrap().remove("format_version");
+        for entry in fs::read_dir(cache_dir).unwrap() {
+            fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+        tantivy::Index::create_in_dir(cache_dir, schema).unwrap();
+        fs::write(&metafile, serde_json::to_string(&meta).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keeps_index_when_writer_options_change() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let mut index = FileIndex::get_or_create(
+            tmpdir.path(),
+            Some(cache_dir.path()),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        index.update(false).unwrap();
+        drop(index);
+
+        let config = FileIndexOptions {
+            threads: 2,
+            writer_memory: 2 * DEFAULT_WRITER_MEMORY,
+            ..Default::default()
+        };
+        assert!(FileIndex::check(cache_dir.path(), &config)
+            .unwrap()
+            .is_empty());
+        let index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        assert!(index.rebuild_reason().is_none());
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+        assert_eq!(index.metadata().config(), &config);
+    }
+
+    #[test]
+    fn reports_why_index_is_rebuilt() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let mut index = FileIndex::get_or_create(
+            tmpdir.path(),
+            Some(cache_dir.path()),
+            &FileIndexOptions::default(),
+        )
+        .unwrap();
+        assert!(index.rebuild_reason().is_none());
+        index.update(false).unwrap();
+        drop(index);
+
+        let config = FileIndexOptions {
+            language: LanguageRef::French,
+            ..Default::default()
+        };
+        let index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        assert_eq!(
+            index.rebuild_reason(),
+            Some("language changed english→french")
+        );
+    }
+
+    #[test]
+    fn rebuilds_index_without_path_field() {
+        use tantivy::schema::{Schema, STORED, STRING, TEXT};
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("filepath", STRING | STORED);
+        schema_builder.add_text_field("contents", TEXT);
+        write_old_index(tmpdir.path(), cache_dir.path(), schema_builder.build());
+
+        let config = FileIndexOptions::default();
+        assert!(!FileIndex::check(cache_dir.path(), &config)
+            .unwrap()
+            .is_empty());
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        assert!(index.index().schema().get_field("path").is_some());
+        index.update(false).unwrap();
+        assert_eq!(search(&index, "needle"), vec!["a.txt"]);
+        assert_eq!(search(&index, "path:a"), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn relative_to_base_dir_ignores_current_dir() {
+        // The base dir is passed in, so the test doesn't have to change the process state (which
+        // is shared with the tests running in parallel)
+        let base_dir = tempfile::tempdir().unwrap();
+        let project = base_dir.path().join("project");
+        fs::create_dir(&project).unwrap();
+        fs::write(project.join("a.txt"), "needle needle haystack").unwrap();
+        fs::write(project.join("b.txt"), "needle haystack haystack").unwrap();
+        let config = FileIndexOptions {
+            threads: 1,
+            ignore_files: false,
+            ..Default::default()
+        };
+        let mut scores = Vec::new();
+        for _ in 0..2 {
+            let cache_dir = tempfile::tempdir().unwrap();
+            let mut index = FileIndex::get_or_create_relative_to(
+                base_dir.path(),
+                Path::new("project"),
+                Some(cache_dir.path()),
+                &config,
+            )
+            .unwrap();
+            index.update(false).unwrap();
+            let query_parser = QueryParser::for_index(index.index(), index.content_fields());
+            let query = query_parser.parse_query("needle").unwrap();
+            let results: Vec<(String, f32)> = index
+                .search(&query, &FileSearchOptions::default())
+                .unwrap()
+                .iter()
+                .map(|r| (r.file().to_string_lossy().to_string(), r.score()))
+                .collect();
+            scores.push(results);
+        }
+        assert_eq!(scores[0].len(), 2);
+        assert!(scores[0][0].0.ends_with("a.txt"));
+        assert!(Path::new(&scores[0][0].0).starts_with(fs::canonicalize(&project).unwrap()));
+        assert_eq!(scores[0], scores[1]);
+    }
+
+    #[test]
+    fn update_paths_skips_ignored_files() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::create_dir(root.join("target")).unwrap();
+        fs::write(root.join(".ignore"), "target/\n").unwrap();
+        fs::write(root.join("src/a.txt"), "needle").unwrap();
+        fs::write(root.join("target/b.txt"), "needle").unwrap();
+        let mut index = FileIndex::get_or_create(root, None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+
+        fs::write(root.join("src/a.txt"), "haystack").unwrap();
+        fs::write(root.join("src/c.txt"), "haystack").unwrap();
+        fs::write(root.join("target/b.txt"), "haystack").unwrap();
+        let summary = index
+            .update_paths(&["src/a.txt", "src/c.txt", "target/b.txt"])
+            .unwrap();
+        assert_eq!((summary.added, summary.removed), (2, 0));
+        let mut files = search(&index, "haystack");
+        files.sort();
+        assert_eq!(files, vec!["a.txt", "c.txt"]);
+
+        // The indexed files under a deleted directory are removed
+        fs::remove_dir_all(root.join("src")).unwrap();
+        let summary = index.update_paths(&[root.join("src")]).unwrap();
+        assert_eq!((summary.added, summary.removed), (0, 2));
+        assert!(index.indexed_paths().unwrap().is_empty());
+    }
+
+    #[test]
+    fn filepath_prefix_searches_path_words() {
+        assert_eq!(
+            rename_field_prefix(
+                "filepath:a (-filepath:b) \"filepath:c\"",
+                "filepath",
+                "path"
+            ),
+            "path:a (-path:b) \"filepath:c\""
+        );
+        assert_eq!(
+            rename_field_prefix("myfilepath:a", "filepath", "path"),
+            "myfilepath:a"
+        );
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index
+            .add_contents(vec![
+                ("src/handler.rs".to_string(), "fn run()".to_string()),
+                ("src/main.rs".to_string(), "fn handler()".to_string()),
+            ])
+            .unwrap();
+        let opts = FileSearchOptions {
+            root_dir: Some("".to_string()),
+            ..Default::default()
+        };
+        let files = |query: &str| -> Vec<String> {
+            let query = index.parse_query(query).unwrap();
+            let mut files: Vec<String> = index
+                .search(&query, &opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.file().to_string_lossy().to_string())
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(files("handler"), vec!["src/main.rs"]);
+        assert_eq!(files("filepath:handler"), vec!["src/handler.rs"]);
+        assert_eq!(files("path:handler"), vec!["src/handler.rs"]);
+        assert_eq!(
+            files("filepath:src AND fn"),
+            vec!["src/handler.rs", "src/main.rs"]
+        );
+    }
+
+    #[test]
+    fn recency_boost_and_sort_by_modified() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("old.txt"), "needle needle").unwrap();
+        fs::write(tmpdir.path().join("new.txt"), "needle haystack").unwrap();
+        let year_ago = filetime::FileTime::from_unix_time(
+            filetime::FileTime::now().unix_seconds() - 365 * 24 * 60 * 60,
+            0,
+        );
+        filetime::set_file_mtime(tmpdir.path().join("old.txt"), year_ago).unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+        let query = QueryParser::for_index(index.index(), index.content_fields())
+            .parse_query("needle")
+            .unwrap();
+        let files = |opts: FileSearchOptions| -> Vec<String> {
+            index
+                .search(&query, &opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        assert_eq!(
+            files(FileSearchOptions::default()),
+            vec!["old.txt", "new.txt"]
+        );
+        assert_eq!(
+            files(FileSearchOptions {
+                recency_boost: 10.0,
+                ..Default::default()
+            }),
+            vec!["new.txt", "old.txt"]
+        );
+        assert_eq!(
+            files(FileSearchOptions {
+                sort_by: SortBy::Modified,
+                ..Default::default()
+            }),
+            vec!["new.txt", "old.txt"]
+        );
+        assert_eq!(
+            files(FileSearchOptions {
+                sort_by: SortBy::ModifiedAsc,
+                ..Default::default()
+            }),
+            vec!["old.txt", "new.txt"]
+        );
+    }
+
+    #[test]
+    fn rebuilds_index_without_modified_field() {
+        use tantivy::schema::{Schema, STORED, STRING, TEXT};
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("filepath", STRING | STORED);
+        schema_builder.add_text_field("contents", TEXT);
+        schema_builder.add_text_field("path", TEXT);
+        write_old_index(tmpdir.path(), cache_dir.path(), schema_builder.build());
+
+        let config = FileIndexOptions::default();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        assert!(index.index().schema().get_field("modified").is_some());
+        index.update(false).unwrap();
+        assert_eq!(
+            search(&index, "needle AND modified:[2000-01-01T00:00:00Z TO *]"),
+            vec!["a.txt"]
+        );
+    }
+
+    #[test]
+    fn open_uses_the_persisted_config() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        assert!(FileIndex::open(cache_dir.path()).is_err());
+
+        let config = FileIndexOptions {
+            store_contents: true,
+            ..Default::default()
+        };
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        index.update(false).unwrap();
+        drop(index);
+
+        let index = FileIndex::open(cache_dir.path()).unwrap();
+        assert_eq!(index.for_dir(), fs::canonicalize(tmpdir.path()).unwrap());
+        // The index was opened with store_contents, so it wasn't rebuilt and snippets work
+        let query = QueryParser::for_index(index.index(), index.content_fields())
+            .parse_query("needle")
+            .unwrap();
+        let opts = FileSearchOptions {
+            snippet_mode: SnippetMode::Snippet,
+            ..Default::default()
+        };
+        assert_eq!(index.search(&query, &opts).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn filter_by_modification_time() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        for name in &["old.txt", "new.txt", "deleted.txt"] {
+            fs::write(tmpdir.path().join(name), "needle").unwrap();
+        }
+        let now = filetime::FileTime::now().unix_seconds();
+        let week_ago = now - 7 * 24 * 60 * 60;
+        filetime::set_file_mtime(
+            tmpdir.path().join("old.txt"),
+            filetime::FileTime::from_unix_time(week_ago - 60, 0),
+        )
+        .unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+        let query = QueryParser::for_index(index.index(), index.content_fields())
+            .parse_query("needle")
+            .unwrap();
+        let files = |opts: FileSearchOptions| -> Vec<String> {
+            let mut files: Vec<String> = index
+                .search(&query, &opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            files(FileSearchOptions::default()),
+            vec!["deleted.txt", "new.txt", "old.txt"]
+        );
+        // The deleted file is still in the index, but is dropped by the filter
+        fs::remove_file(tmpdir.path().join("deleted.txt")).unwrap();
+        assert_eq!(
+            files(FileSearchOptions {
+                modified_after: Some(week_ago),
+                ..Default::default()
+            }),
+            vec!["new.txt"]
+        );
+        assert_eq!(
+            files(FileSearchOptions {
+                modified_before: Some(week_ago),
+                ..Default::default()
+            }),
+            vec!["old.txt"]
+        );
+        assert_eq!(
+            files(FileSearchOptions {
+                modified_after: Some(0),
+                limit: 1,
+                ..Default::default()
+            })
+            .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn range_queries_on_size_and_modified() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("small.txt"), "needle").unwrap();
+        fs::write(
+            tmpdir.path().join("large.txt"),
+            format!("needle {}", "haystack ".repeat(100)),
+        )
+        .unwrap();
+        filetime::set_file_mtime(
+            tmpdir.path().join("small.txt"),
+            filetime::FileTime::from_unix_time(1_600_000_000, 0),
+        )
+        .unwrap();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), None, &FileIndexOptions::default()).unwrap();
+        index.update(false).unwrap();
+        let files = |query: &str| -> Vec<String> {
+            let query = index.parse_query(query).unwrap();
+            index
+                .search(&query, &FileSearchOptions::default())
+                .unwrap()
+                .iter()
+                .map(|r| r.file().file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        assert_eq!(files("needle AND size:[100 TO *]"), vec!["large.txt"]);
+        assert_eq!(files("needle AND size:[0 TO 100}"), vec!["small.txt"]);
+        assert_eq!(
+            files("needle AND modified:[* TO 2021-01-01T00:00:00Z}"),
+            vec!["small.txt"]
+        );
+    }
+
+    #[test]
+    fn rebuilds_index_without_size_field() {
+        use tantivy::schema::{Schema, FAST, INDEXED, STORED, STRING, TEXT};
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(tmpdir.path().join("a.txt"), "needle").unwrap();
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("filepath", STRING | STORED);
+        schema_builder.add_text_field("contents", TEXT);
+        schema_builder.add_text_field("path", TEXT);
+        schema_builder.add_date_field("modified", INDEXED | FAST | STORED);
+        write_old_index(tmpdir.path(), cache_dir.path(), schema_builder.build());
+
+        let config = FileIndexOptions::default();
+        let mut index =
+            FileIndex::get_or_create(tmpdir.path(), Some(cache_dir.path()), &config).unwrap();
+        assert!(index.index().schema().get_field("size").is_some());
+        index.update(false).unwrap();
+        assert_eq!(search(&index, "needle AND size:[1 TO 100]"), vec!["a.txt"]);
+    }
+}
@@ -0,0 +1,38 @@
+/// The built-in file types that can be used for `FileIndexOptions::types`, and the globs of the
+/// files that belong to each one
+pub const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("c", &["*.c", "*.h"]),
+    (
+        "cpp",
+        &["*.cc", "*.cpp", "*.cxx", "*.hh", "*.hpp", "*.hxx", "*.h"],
+    ),
+    ("cs", &["*.cs"]),
+    ("css", &["*.css", "*.scss", "*.sass", "*.less"]),
+    ("go", &["*.go"]),
+    ("html", &["*.html", "*.htm"]),
+    ("java", &["*.java"]),
+    ("js", &["*.js", "*.jsx", "*.mjs", "*.cjs"]),
+    ("json", &["*.json"]),
+    ("lua", &["*.lua"]),
+    ("markdown", &["*.md", "*.markdown"]),
+    ("php", &["*.php"]),
+    ("py", &["*.py", "*.pyi"]),
+    ("rb", &["*.rb", "Gemfile", "Rakefile"]),
+    ("rust", &["*.rs"]),
+    ("sh", &["*.sh", "*.bash", "*.zsh"]),
+    ("sql", &["*.sql"]),
+    ("toml", &["*.toml"]),
+    ("ts", &["*.ts", "*.tsx", "*.mts", "*.cts"]),
+    ("txt", &["*.txt"]),
+    ("vim", &["*.vim"]),
+    ("yaml", &["*.yaml", "*.yml"]),
+];
+
+/// Get the globs of the files that belong to a built-in file type
+pub fn file_type_globs(name: &str) -> Result<&'static [&'static str], anyhow::Error> {
+    FILE_TYPES
+        .iter()
+        .find(|(type_name, _)| *type_name == name)
+        .map(|(_, globs)| *globs)
+        .ok_or_else(|| anyhow!("Unknown file type '{}'", name))
+}
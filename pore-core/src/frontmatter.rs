@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+/// Parse the frontmatter at the top of a file (e.g. a markdown note) into the text of each key
+///
+/// YAML frontmatter is delimited by `---` lines and TOML frontmatter by `+++` lines. This is not a
+/// full YAML or TOML parser: it only reads the top-level `key: value` (or `key = value`) pairs,
+/// where the value is a scalar, an inline list, or (for YAML) a block list of `- item` lines. The
+/// items of a list are joined with spaces. Returns None if the file has no frontmatter.
+pub fn parse_frontmatter(contents: &str) -> Option<HashMap<String, String>> {
+    let mut lines = contents.lines();
+    let (delimiter, separator) = match lines.next()?.trim_end() {
+        "---" => ("---", ':'),
+        "+++" => ("+++", '='),
+        _ => return None,
+    };
+    let mut fields: HashMap<String, Vec<String>> = HashMap::new();
+    let mut last_key: Option<String> = None;
+    for line in lines {
+        let line = line.trim_end();
+        if line == delimiter {
+            return Some(
+                fields
+                    .into_iter()
+                    .map(|(key, values)| (key, values.join(" ")))
+                    .collect(),
+            );
+        }
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(item) = trimmed.strip_prefix('-') {
+            // An item of a YAML block list
+            if let Some(values) = last_key.as_ref().and_then(|key| fields.get_mut(key)) {
+                values.push(unquote(item).to_string());
+            }
+            continue;
+        }
+        if trimmed.len() != line.len() {
+            // Nested values are not supported
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(separator) {
+            let key = unquote(key).to_string();
+            let value = value.trim();
+            let values = match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                Some(items) => items
+                    .split(',')
+                    .map(unquote)
+                    .filter(|item| !item.is_empty())
+                    .map(String::from)
+                    .collect(),
+                None if value.is_empty() => Vec::new(),
+                None => vec![unquote(value).to_string()],
+            };
+            fields.insert(key.clone(), values);
+            last_key = Some(key);
+        }
+    }
+    // There was no closing delimiter, so this isn't frontmatter
+    None
+}
+
+fn unquote(s: &str) -> &str {
+    s.trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_frontmatter;
+
+    #[test]
+    fn parses_yaml_and_toml() {
+        let yaml = "---\ntitle: \"My note\"\ntags:\n  - rust\n  - search\nnested:\n  key: value\n---\nbody";
+        let fields = parse_frontmatter(yaml).unwrap();
+        assert_eq!(fields["title"], "My note");
+        assert_eq!(fields["tags"], "rust search");
+        assert_eq!(fields["nested"], "");
+
+        let toml = "+++\ntitle = 'My note'\ntags = [\"rust\", \"search\"]\n+++\nbody";
+        let fields = parse_frontmatter(toml).unwrap();
+        assert_eq!(fields["title"], "My note");
+        assert_eq!(fields["tags"], "rust search");
+    }
+
+    #[test]
+    fn requires_delimiters() {
+        assert!(parse_frontmatter("title: My note\n").is_none());
+        assert!(parse_frontmatter("---\ntitle: My note\n").is_none());
+    }
+}
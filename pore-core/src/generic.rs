@@ -1,61 +1,145 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::executor::block_on;
 use macros::create_option_copy;
 use mlua::ToLua;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
-use tantivy::collector::TopDocs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::Directory;
 use tantivy::doc;
 use tantivy::query::Query;
-use tantivy::ReloadPolicy;
+use tantivy::IndexWriter;
 
 use tantivy::schema::*;
 use tantivy::Index;
 
 use crate::common::create_index;
+use crate::common::create_writer;
 use crate::common::delete_index;
+use crate::common::write_metadata;
+use crate::common::CachedReader;
 use crate::common::IndexMetadata;
 use crate::common::Metadata;
 use crate::common::MetadataConfig;
-use crate::common::METADATA_FILE;
+use crate::common::DEFAULT_LOCK_TIMEOUT;
+use crate::common::DEFAULT_MAX_TOKEN_LENGTH;
+use crate::common::DEFAULT_WRITER_MEMORY;
+use crate::directory::BoxedDirectory;
+use crate::error::PoreError;
+use crate::field_kind::{FieldDef, FieldKind};
 use crate::field_map::FieldMap;
 use crate::language::LanguageRef;
+use crate::merge_policy::MergePolicyOptions;
+use crate::scoring::{Rescorer, ScoringModel, ScoringParams, DEFAULT_BM25_B, DEFAULT_BM25_K1};
 
 #[derive(Debug, Clone)]
 pub struct GenericIndex {
     meta: Metadata<IndexOptions>,
     cache_dir: Option<PathBuf>,
+    /// Custom storage for the index (instead of `cache_dir` or memory)
+    directory: Option<BoxedDirectory>,
     index: Index,
+    merge_policy: MergePolicyOptions,
+    /// How long a write waits for another process to release the lock on the index
+    lock_timeout: Duration,
+    /// Why the existing index couldn't be used when it was opened (see `rebuild_reason`)
+    rebuild_reason: Option<String>,
+    batch: BatchWriter,
+    reader: CachedReader,
+}
+
+/// The writer of a batch of changes started by `GenericIndex::begin`, which stays open until
+/// `GenericIndex::commit`. Clones of the index share it, because an index can only have one writer
+/// at a time.
+#[derive(Clone, Default)]
+struct BatchWriter(Arc<Mutex<Option<IndexWriter>>>);
+
+impl Debug for BatchWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let open = self.0.lock().map(|w| w.is_some()).unwrap_or(false);
+        write!(f, "BatchWriter(open: {})", open)
+    }
 }
 
 #[create_option_copy(SearchOptionsShape)]
 #[derive(Debug)]
 pub struct SearchOptions {
     pub limit: usize,
+    /// The number of top results to skip, for paging through the results
+    pub offset: usize,
     pub threshold: f32,
+    /// Minimum score relative to the top result, from 0 to 1
+    pub rel_threshold: f32,
+    /// Return the stored fields of each result in `SearchResult::fields`. Only the id is stored,
+    /// unless the index was created with `store_text`.
+    pub with_fields: bool,
+    /// The model used to score the results
+    pub scoring: ScoringModel,
+    /// BM25 term frequency saturation. Higher values reward repeated terms more.
+    pub bm25_k1: f32,
+    /// BM25 length normalization, from 0 (ignore document length) to 1 (fully normalize by length)
+    pub bm25_b: f32,
 }
 
 impl Default for SearchOptions {
     fn default() -> Self {
         SearchOptions {
             limit: 1000,
+            offset: 0,
             threshold: 0.0,
+            rel_threshold: 0.0,
+            with_fields: false,
+            scoring: ScoringModel::Bm25,
+            bm25_k1: DEFAULT_BM25_K1,
+            bm25_b: DEFAULT_BM25_B,
+        }
+    }
+}
+
+impl SearchOptions {
+    pub fn scoring_params(&self) -> ScoringParams {
+        ScoringParams {
+            model: self.scoring,
+            k1: self.bm25_k1,
+            b: self.bm25_b,
         }
     }
 }
 
+/// The options of a `GenericIndex`
+///
+/// Changing any of these rebuilds the index, except for `writer_memory`.
 #[create_option_copy(IndexOptionsShape)]
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct IndexOptions {
     pub language: LanguageRef,
+    /// The memory budget (in bytes) for writing to the index, which is split between the indexing
+    /// threads
+    pub writer_memory: usize,
+    /// Words longer than this many bytes are not indexed. 0 means there is no limit.
+    pub max_token_length: usize,
+    /// Words that are not indexed (or searched for), e.g. noise words that are in most of the
+    /// documents
+    pub stopwords: Vec<String>,
+    /// Store the text fields in the index, so that searches with `with_fields` can return them
+    pub store_text: bool,
 }
 
 impl Default for IndexOptions {
     fn default() -> Self {
         IndexOptions {
             language: LanguageRef::English,
+            writer_memory: DEFAULT_WRITER_MEMORY,
+            max_token_length: DEFAULT_MAX_TOKEN_LENGTH,
+            stopwords: vec![],
+            store_text: false,
         }
     }
 }
@@ -64,12 +148,42 @@ impl MetadataConfig for IndexOptions {
     fn language(&self) -> LanguageRef {
         self.language
     }
+    fn without_write_options(&self) -> Self {
+        IndexOptions {
+            writer_memory: DEFAULT_WRITER_MEMORY,
+            ..self.clone()
+        }
+    }
+    fn max_token_length(&self) -> usize {
+        self.max_token_length
+    }
+    fn stopwords(&self) -> &[String] {
+        &self.stopwords
+    }
+    fn store_text(&self) -> bool {
+        self.store_text
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct SearchResult {
     id: String,
     score: f32,
+    /// The stored fields of the document, if the search used `with_fields`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<HashMap<String, String>>,
+}
+
+impl SearchResult {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+    pub fn fields(&self) -> Option<&HashMap<String, String>> {
+        self.fields.as_ref()
+    }
 }
 
 impl<'lua> ToLua<'lua> for SearchResult {
@@ -77,125 +191,693 @@ impl<'lua> ToLua<'lua> for SearchResult {
         let tbl = lua.create_table()?;
         tbl.set("id", self.id)?;
         tbl.set("score", self.score)?;
+        if let Some(fields) = self.fields {
+            tbl.set("fields", fields)?;
+        }
         Ok(mlua::Value::Table(tbl))
     }
 }
 
+/// Convert a stored value to a string, or None if it can't be represented as one
+fn stored_value_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Str(text) => Some(text.clone()),
+        Value::U64(num) => Some(num.to_string()),
+        Value::I64(num) => Some(num.to_string()),
+        Value::F64(num) => Some(num.to_string()),
+        Value::Date(date) => Some(date.to_rfc3339()),
+        Value::Facet(facet) => Some(facet.to_string()),
+        Value::PreTokStr(_) | Value::Bytes(_) => None,
+    }
+}
+
 impl GenericIndex {
     pub fn index(&self) -> &Index {
         &self.index
     }
-    pub fn delete(&self) -> anyhow::Result<bool> {
-        delete_index(&self.index, self.cache_dir.as_deref())
+    /// Why the index that was stored in the cache dir was discarded when it was opened (e.g. it was
+    /// built with different options). The documents have to be added again.
+    pub fn rebuild_reason(&self) -> Option<&str> {
+        self.rebuild_reason.as_deref()
+    }
+    pub fn delete(&self) -> Result<bool, PoreError> {
+        Ok(delete_index(
+            &self.index,
+            self.cache_dir.as_deref(),
+            self.meta.config().writer_memory,
+            self.lock_timeout,
+        )?)
     }
 
+    /// Open the index stored in `cache_dir`, or an in-memory index if `cache_dir` is None
+    ///
+    /// This doesn't read any process state, such as environment variables. The only exception is a
+    /// relative `cache_dir`, which is relative to the current directory.
     pub fn get_or_create<I, T>(
         id_field: &str,
-        text_fields: I,
+        fields: I,
+        config: &IndexOptions,
+        cache_dir: Option<&Path>,
+    ) -> Result<Self, PoreError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<FieldDef>,
+    {
+        Ok(Self::open(id_field, fields, config, cache_dir, None)?)
+    }
+
+    /// Like `get_or_create`, but store the index in a custom tantivy `Directory` instead of on disk
+    /// or in memory. The index metadata is stored in the directory as well.
+    pub fn get_or_create_in_directory<I, T>(
+        id_field: &str,
+        fields: I,
+        config: &IndexOptions,
+        directory: Box<dyn Directory>,
+    ) -> Result<Self, PoreError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<FieldDef>,
+    {
+        Ok(Self::open(
+            id_field,
+            fields,
+            config,
+            None,
+            Some(BoxedDirectory(directory)),
+        )?)
+    }
+
+    fn open<I, T>(
+        id_field: &str,
+        fields: I,
         config: &IndexOptions,
         cache_dir: Option<&Path>,
+        directory: Option<BoxedDirectory>,
     ) -> Result<Self, anyhow::Error>
     where
         I: IntoIterator<Item = T>,
-        T: Into<String>,
+        T: Into<FieldDef>,
     {
-        let (meta_opt, index) = create_index(cache_dir, config, id_field, text_fields)?;
-        let meta = meta_opt.unwrap_or_else(|| Metadata::new(config.clone()));
+        let (meta_opt, index, rebuild_reason) =
+            create_index(cache_dir, directory.as_ref(), config, id_field, fields)?;
+        let mut meta = meta_opt.unwrap_or_else(|| Metadata::new(config.clone()));
+        // If the index had a different id field, the schema changed and it was rebuilt
+        meta.set_id_field(id_field);
+        let reader = CachedReader::new(&index)?;
         Ok(Self {
             index,
-            cache_dir: cache_dir.map(|p| fs::canonicalize(p).unwrap()),
+            cache_dir: cache_dir.map(fs::canonicalize).transpose()?,
+            directory,
             meta,
+            merge_policy: MergePolicyOptions::default(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            rebuild_reason,
+            batch: BatchWriter::default(),
+            reader,
         })
     }
 
-    fn get_id_field(&self) -> anyhow::Result<Field> {
-        for (field, entry) in self.index.schema().fields() {
-            if entry.is_stored() {
-                return Ok(field);
+    /// Set how segments are merged in the background by future writes. See `MergePolicyOptions`.
+    pub fn set_merge_policy(&mut self, merge_policy: MergePolicyOptions) {
+        self.merge_policy = merge_policy;
+    }
+
+    /// Set how long writes wait for another process to release the lock on the index before
+    /// failing with `PoreError::IndexLocked` (`DEFAULT_LOCK_TIMEOUT` unless this is called)
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    fn writer(&self) -> anyhow::Result<IndexWriter> {
+        let index_writer = create_writer(
+            &self.index,
+            self.meta.config().writer_memory,
+            self.lock_timeout,
+        )?;
+        index_writer.set_merge_policy(self.merge_policy.merge_policy());
+        Ok(index_writer)
+    }
+
+    /// Start a batch of changes
+    ///
+    /// Until `commit` is called, `add_documents`, `update_documents`, and `delete_documents` share
+    /// one writer and don't commit their changes. Committing is slow and creates a new segment, so
+    /// this is much faster when making many small changes. The changes in the batch are not visible
+    /// to searches until they are committed. Does nothing if a batch is already started.
+    pub fn begin(&mut self) -> Result<(), PoreError> {
+        let batch = self.batch.clone();
+        let mut writer = batch.0.lock().unwrap();
+        if writer.is_none() {
+            *writer = Some(self.writer()?);
+        }
+        Ok(())
+    }
+
+    /// Commit the batch of changes started by `begin`. Does nothing if there is no batch.
+    pub fn commit(&mut self) -> Result<(), PoreError> {
+        let writer = self.batch.0.lock().unwrap().take();
+        if let Some(mut index_writer) = writer {
+            let now = Utc::now();
+            index_writer.commit()?;
+            self.set_last_update(now)?;
+        }
+        Ok(())
+    }
+
+    fn set_last_update(&mut self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        self.meta.set_last_update(now);
+        write_metadata(
+            &self.meta,
+            self.cache_dir.as_deref(),
+            self.directory.as_ref(),
+        )
+    }
+
+    /// Make changes with the writer of the current batch, or with a new writer that is committed
+    /// afterwards if there is no batch (see `begin`)
+    fn with_writer<R, F>(&mut self, f: F) -> anyhow::Result<R>
+    where
+        F: FnOnce(&Self, &mut IndexWriter) -> anyhow::Result<R>,
+    {
+        let batch = self.batch.clone();
+        let mut batch_writer = batch.0.lock().unwrap();
+        match batch_writer.as_mut() {
+            Some(index_writer) => f(self, index_writer),
+            None => {
+                let now = Utc::now();
+                let mut index_writer = self.writer()?;
+                let ret = f(self, &mut index_writer)?;
+                index_writer.commit()?;
+                self.set_last_update(now)?;
+                Ok(ret)
             }
         }
-        Err(anyhow!("Could not find stored ID field in index"))
+    }
+
+    /// Merge all of the segments into one and wait for the merge to finish
+    ///
+    /// This is useful after ingesting with `background_merges` disabled. It blocks until the merge
+    /// is complete, and searches will be faster afterwards. Commits the current batch first (see
+    /// `begin`).
+    pub fn optimize(&mut self) -> Result<(), PoreError> {
+        self.commit()?;
+        let segment_ids = self.index.searchable_segment_ids()?;
+        let mut index_writer = self.writer()?;
+        if segment_ids.len() > 1 {
+            block_on(index_writer.merge(&segment_ids))?;
+        }
+        index_writer.wait_merging_threads()?;
+        Ok(())
+    }
+
+    fn get_id_field(&self) -> anyhow::Result<Field> {
+        let name = self.meta.id_field();
+        self.index.schema().get_field(name).ok_or_else(|| {
+            anyhow!(PoreError::Schema(format!(
+                "Could not find ID field '{}' in index",
+                name
+            )))
+        })
     }
 
     pub fn get_text_fields(&self) -> Vec<Field> {
+        let id_field = self.get_id_field().ok();
         let mut ret = Vec::new();
         for (field, entry) in self.index.schema().fields() {
-            if !entry.is_stored() {
+            // The text fields may be stored as well (see `store_text`). Raw fields are only
+            // searched when the query names them (e.g. `status:OK`).
+            if Some(field) != id_field && FieldKind::of(entry.field_type()) == FieldKind::Text {
                 ret.push(field);
             }
         }
         ret
     }
 
-    pub fn delete_documents<I, T>(&mut self, document_ids: I) -> anyhow::Result<()>
+    pub fn delete_documents<I, T>(&mut self, document_ids: I) -> Result<(), PoreError>
     where
         I: IntoIterator<Item = T>,
         T: Into<String>,
     {
-        let mut index_writer = self.index.writer(50_000_000)?;
         let id_field = self.get_id_field()?;
-        for id in document_ids {
-            index_writer.delete_term(Term::from_field_text(id_field, id.into().as_str()));
-        }
-        index_writer.commit()?;
-        Ok(())
+        Ok(self.with_writer(|_, index_writer| {
+            for id in document_ids {
+                index_writer.delete_term(Term::from_field_text(id_field, id.into().as_str()));
+            }
+            Ok(())
+        })?)
     }
 
-    pub fn update_documents<T: FieldMap>(&mut self, documents: Vec<T>) -> anyhow::Result<()> {
+    /// Replace the documents that have the same ids as `documents`
+    pub fn update_documents<T: FieldMap>(&mut self, documents: Vec<T>) -> Result<(), PoreError> {
         let id_field = self.get_id_field()?;
         let schema = self.index.schema();
-        let id_field_entry = schema.get_field_entry(id_field);
-        let id_name = id_field_entry.name();
-        let document_ids = documents
-            .iter()
-            .map(|d| d.get_field(id_name).unwrap().to_owned());
-        self.delete_documents(document_ids)?;
-        self.add_documents(documents)
-    }
-
-    pub fn add_documents<T: FieldMap>(&mut self, documents: Vec<T>) -> anyhow::Result<()> {
-        let mut index_writer = self.index.writer(50_000_000)?;
-        let now = Utc::now();
-        for document in documents {
-            let mut doc = Document::default();
-            for (field, entry) in self.index.schema().fields() {
-                let text = document.get_field(entry.name())?;
-                doc.add(FieldValue::new(field, text.as_ref().into()));
+        let id_name = schema.get_field_entry(id_field).name();
+        Ok(self.with_writer(|this, index_writer| {
+            for document in &documents {
+                let id = document.get_field(id_name)?;
+                index_writer.delete_term(Term::from_field_text(id_field, id.as_ref()));
+            }
+            for document in &documents {
+                index_writer.add_document(this.make_document(document)?);
+            }
+            Ok(())
+        })?)
+    }
+
+    pub fn add_documents<T: FieldMap>(&mut self, documents: Vec<T>) -> Result<(), PoreError> {
+        Ok(self.with_writer(|this, index_writer| {
+            for document in &documents {
+                index_writer.add_document(this.make_document(document)?);
+            }
+            Ok(())
+        })?)
+    }
+
+    /// Add documents from an iterator, committing after every `batch_size` documents
+    ///
+    /// This bounds the memory used when ingesting a large number of documents. If the iterator
+    /// returns an error, the batches that were already committed remain in the index and the
+    /// current batch is discarded. This commits every `batch_size` documents even in a batch
+    /// started by `begin`.
+    pub fn add_documents_batched<I, T, E>(
+        &mut self,
+        documents: I,
+        batch_size: usize,
+    ) -> Result<(), PoreError>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        T: FieldMap,
+        anyhow::Error: From<E>,
+    {
+        Ok(self.with_writer(|this, index_writer| {
+            let mut pending = 0;
+            for document in documents {
+                index_writer.add_document(this.make_document(&document?)?);
+                pending += 1;
+                if pending >= batch_size {
+                    index_writer.commit()?;
+                    pending = 0;
+                }
+            }
+            Ok(())
+        })?)
+    }
+
+    fn make_document<T: FieldMap>(&self, document: &T) -> anyhow::Result<Document> {
+        let mut doc = Document::default();
+        for (field, entry) in self.index.schema().fields() {
+            let kind = FieldKind::of(entry.field_type());
+            // Each value of a multi-valued field is added separately
+            for text in document.get_field_multi(entry.name())? {
+                doc.add(FieldValue::new(field, kind.parse_value(text.as_ref())?));
             }
-            index_writer.add_document(doc);
-        }
-        index_writer.commit()?;
-        self.meta.set_last_update(now);
-        if let Some(index_dir) = &self.cache_dir {
-            fs::write(
-                index_dir.join(METADATA_FILE),
-                serde_json::to_string(&self.meta)?,
-            )?;
         }
-        Ok(())
+        Ok(doc)
     }
 
     pub fn search(
         &self,
         query: &Box<dyn Query>,
         opts: &SearchOptions,
-    ) -> anyhow::Result<Vec<SearchResult>> {
-        let reader = self
-            .index
-            .reader_builder()
-            .reload_policy(ReloadPolicy::OnCommit)
-            .try_into()?;
-        let searcher = reader.searcher();
-        let top_docs = searcher.search(query, &TopDocs::with_limit(opts.limit))?;
+    ) -> Result<Vec<SearchResult>, PoreError> {
+        Ok(self.search_with_total(query, opts)?.0)
+    }
+
+    /// Like `search`, but also return the total number of documents that match the query, before
+    /// `limit`, `offset`, and the thresholds are applied
+    pub fn search_with_total(
+        &self,
+        query: &Box<dyn Query>,
+        opts: &SearchOptions,
+    ) -> Result<(Vec<SearchResult>, usize), PoreError> {
+        let searcher = self.reader.searcher()?;
+        let rescorer = Rescorer::new(&searcher, query.as_ref(), opts.scoring_params())?;
+        let search_page = |limit: usize, offset: usize| {
+            let collector = TopDocs::with_limit(limit).and_offset(offset);
+            match &rescorer {
+                Some(rescorer) => {
+                    searcher.search(query, &(collector.tweak_score(rescorer.clone()), Count))
+                }
+                None => searcher.search(query, &(collector, Count)),
+            }
+        };
+        let (top_docs, total) = search_page(opts.limit, opts.offset)?;
         let id_field = self.get_id_field()?;
+        // The results are sorted by score, so the top score is the first result of the first page
+        let top_score = if opts.offset == 0 {
+            top_docs.first().map(|(score, _)| *score)
+        } else {
+            let (top, _) = search_page(1, 0)?;
+            top.first().map(|(score, _)| *score)
+        };
+        let min_score = top_score
+            .map(|score| score * opts.rel_threshold)
+            .unwrap_or(0.0);
         let mut results = Vec::new();
         for (score, doc_address) in top_docs {
-            if score > opts.threshold {
+            if score > opts.threshold && score >= min_score {
                 let doc = searcher.doc(doc_address)?;
                 let id = doc.get_first(id_field).unwrap().text().unwrap().to_string();
-                results.push(SearchResult { id, score });
+                let fields = if opts.with_fields {
+                    let schema = self.index.schema();
+                    let mut fields = HashMap::new();
+                    for field_value in doc.field_values() {
+                        if let Some(value) = stored_value_to_string(field_value.value()) {
+                            let name = schema.get_field_name(field_value.field());
+                            // Only keep the first value of fields with multiple values
+                            fields.entry(name.to_string()).or_insert(value);
+                        }
+                    }
+                    Some(fields)
+                } else {
+                    None
+                };
+                results.push(SearchResult { id, score, fields });
             }
         }
-        Ok(results)
+        Ok((results, total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tantivy::query::QueryParser;
+
+    use super::{GenericIndex, IndexOptions, SearchOptions, SearchOptionsShape};
+    use crate::field_kind::FieldKind;
+    use crate::merge_policy::MergePolicyOptions;
+
+    #[test]
+    fn optimize_merges_segments() {
+        let mut index =
+            GenericIndex::get_or_create("id", vec!["text"], &IndexOptions::default(), None)
+                .unwrap();
+        index.set_merge_policy(MergePolicyOptions {
+            background_merges: false,
+            ..Default::default()
+        });
+        for i in 0..3 {
+            let doc: HashMap<String, String> = [
+                ("id".to_string(), i.to_string()),
+                ("text".to_string(), "needle".to_string()),
+            ]
+            .into_iter()
+            .collect();
+            index.add_documents(vec![doc]).unwrap();
+        }
+        assert_eq!(index.index().searchable_segment_ids().unwrap().len(), 3);
+        index.optimize().unwrap();
+        assert_eq!(index.index().searchable_segment_ids().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn search_returns_stored_fields() {
+        let config = IndexOptions {
+            store_text: true,
+            ..Default::default()
+        };
+        let mut index =
+            GenericIndex::get_or_create("id", vec!["title", "body"], &config, None).unwrap();
+        let doc: HashMap<String, String> = [
+            ("id".to_string(), "1".to_string()),
+            ("title".to_string(), "A needle".to_string()),
+            ("body".to_string(), "in a haystack".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        index.add_documents(vec![doc.clone()]).unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.get_text_fields());
+        let query = query_parser.parse_query("haystack").unwrap();
+
+        let results = index.search(&query, &SearchOptions::default()).unwrap();
+        assert_eq!(results[0].id(), "1");
+        assert!(results[0].fields().is_none());
+
+        let opts = SearchOptions {
+            with_fields: true,
+            ..Default::default()
+        };
+        let results = index.search(&query, &opts).unwrap();
+        assert_eq!(results[0].fields(), Some(&doc));
+    }
+
+    #[test]
+    fn bm25_params_change_ranking() {
+        let mut index =
+            GenericIndex::get_or_create("id", vec!["text"], &IndexOptions::default(), None)
+                .unwrap();
+        let long = format!("needle needle needle {}", "hay ".repeat(17));
+        let docs: Vec<HashMap<String, String>> = [("short", "needle"), ("long", long.as_str())]
+            .into_iter()
+            .map(|(id, text)| {
+                [
+                    ("id".to_string(), id.to_string()),
+                    ("text".to_string(), text.to_string()),
+                ]
+                .into_iter()
+                .collect()
+            })
+            .collect();
+        index.add_documents(docs).unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.get_text_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let ranking = |opts: &SearchOptions| -> Vec<String> {
+            index
+                .search(&query, opts)
+                .unwrap()
+                .iter()
+                .map(|r| r.id().to_string())
+                .collect()
+        };
+        // By default the length normalization favors the short document
+        assert_eq!(ranking(&SearchOptions::default()), vec!["short", "long"]);
+        // Without length normalization the document with more matches wins
+        let opts = SearchOptions {
+            bm25_b: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(ranking(&opts), vec!["long", "short"]);
+    }
+
+    #[test]
+    fn lua_options_reject_unknown_keys() {
+        assert_eq!(
+            SearchOptionsShape::check_keys(["limit", "offset"], &[]),
+            Ok(())
+        );
+        assert_eq!(
+            SearchOptionsShape::check_keys(["limit", "limt"], &[]),
+            Err("Unknown option 'limt'".to_string())
+        );
+        // Tables with other options can accept more keys
+        assert_eq!(
+            SearchOptionsShape::check_keys(["query"], &["query"]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn numeric_and_date_fields() {
+        let fields = vec![
+            ("title", FieldKind::Text),
+            ("size", FieldKind::U64),
+            ("created", FieldKind::Date),
+        ];
+        let mut index =
+            GenericIndex::get_or_create("id", fields, &IndexOptions::default(), None).unwrap();
+        let docs: Vec<HashMap<String, String>> = [
+            ("1", "5", "2021-10-01T12:00:00Z"),
+            ("2", "15", "1633089600"),
+            ("3", "25", "2021-10-01T12:00:00+02:00"),
+        ]
+        .iter()
+        .map(|(id, size, created)| {
+            [
+                ("id".to_string(), id.to_string()),
+                ("title".to_string(), "needle".to_string()),
+                ("size".to_string(), size.to_string()),
+                ("created".to_string(), created.to_string()),
+            ]
+            .into_iter()
+            .collect()
+        })
+        .collect();
+        index.add_documents(docs).unwrap();
+        assert_eq!(index.get_text_fields().len(), 1);
+        let query_parser = QueryParser::for_index(index.index(), index.get_text_fields());
+        let search = |query: &str| {
+            let query = query_parser.parse_query(query).unwrap();
+            let mut ids: Vec<String> = index
+                .search(&query, &SearchOptions::default())
+                .unwrap()
+                .into_iter()
+                .map(|r| r.id().to_string())
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(search("needle AND size:[10 TO 20]"), vec!["2"]);
+        assert_eq!(
+            search("needle AND created:\"2021-10-01T12:00:00Z\""),
+            vec!["1", "2"]
+        );
+
+        let bad: HashMap<String, String> = [
+            ("id".to_string(), "4".to_string()),
+            ("title".to_string(), "needle".to_string()),
+            ("size".to_string(), "big".to_string()),
+            ("created".to_string(), "0".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert!(index.add_documents(vec![bad]).is_err());
+
+        let out_of_range: HashMap<String, String> = [
+            ("id".to_string(), "5".to_string()),
+            ("title".to_string(), "needle".to_string()),
+            ("size".to_string(), "5".to_string()),
+            ("created".to_string(), i64::MAX.to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert!(index.add_documents(vec![out_of_range]).is_err());
+    }
+
+    #[test]
+    fn multi_valued_fields() {
+        let mut index =
+            GenericIndex::get_or_create("id", vec!["tags"], &IndexOptions::default(), None)
+                .unwrap();
+        let docs: Vec<HashMap<String, Vec<String>>> =
+            [("1", vec!["red", "green"]), ("2", vec!["blue"])]
+                .iter()
+                .map(|(id, tags)| {
+                    [
+                        ("id".to_string(), vec![id.to_string()]),
+                        (
+                            "tags".to_string(),
+                            tags.iter().map(|t| t.to_string()).collect(),
+                        ),
+                    ]
+                    .into_iter()
+                    .collect()
+                })
+                .collect();
+        index.add_documents(docs).unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.get_text_fields());
+        for (query, id) in [("red", "1"), ("green", "1"), ("blue", "2")] {
+            let query = query_parser.parse_query(query).unwrap();
+            let results = index.search(&query, &SearchOptions::default()).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].id(), id);
+        }
+    }
+
+    #[test]
+    fn id_field_is_looked_up_by_name() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = IndexOptions {
+            store_text: true,
+            ..Default::default()
+        };
+        // The text fields are stored too, so the id field has to be found by name
+        let fields = vec!["a_title", "b_body"];
+        let mut index =
+            GenericIndex::get_or_create("z_id", fields.clone(), &config, Some(cache_dir.path()))
+                .unwrap();
+        let doc: HashMap<String, String> = [
+            ("z_id".to_string(), "1".to_string()),
+            ("a_title".to_string(), "needle".to_string()),
+            ("b_body".to_string(), "haystack".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        index.add_documents(vec![doc]).unwrap();
+        assert_eq!(index.get_text_fields().len(), 2);
+
+        let index =
+            GenericIndex::get_or_create("z_id", fields, &config, Some(cache_dir.path())).unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.get_text_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        let results = index.search(&query, &SearchOptions::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id(), "1");
+    }
+
+    #[test]
+    fn batch_commits_once() {
+        let mut index =
+            GenericIndex::get_or_create("id", vec!["text"], &IndexOptions::default(), None)
+                .unwrap();
+        index.set_merge_policy(MergePolicyOptions {
+            background_merges: false,
+            ..Default::default()
+        });
+        let query_parser = QueryParser::for_index(index.index(), index.get_text_fields());
+        let query = query_parser.parse_query("needle").unwrap();
+        index.begin().unwrap();
+        for i in 0..5 {
+            let doc: HashMap<String, String> = [
+                ("id".to_string(), i.to_string()),
+                ("text".to_string(), "needle".to_string()),
+            ]
+            .into_iter()
+            .collect();
+            index.add_documents(vec![doc]).unwrap();
+        }
+        index.delete_documents(vec!["4"]).unwrap();
+        assert!(index
+            .search(&query, &SearchOptions::default())
+            .unwrap()
+            .is_empty());
+        index.commit().unwrap();
+        assert_eq!(
+            index
+                .search(&query, &SearchOptions::default())
+                .unwrap()
+                .len(),
+            4
+        );
+        let opts = SearchOptions {
+            limit: 2,
+            ..Default::default()
+        };
+        let (results, total) = index.search_with_total(&query, &opts).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(total, 4);
+    }
+
+    #[test]
+    fn raw_fields_match_exactly() {
+        let fields = vec![("text", FieldKind::Text), ("status", FieldKind::Raw)];
+        let mut index =
+            GenericIndex::get_or_create("id", fields, &IndexOptions::default(), None).unwrap();
+        let docs: Vec<HashMap<String, String>> = [("1", "OK"), ("2", "ok"), ("3", "NOT OK")]
+            .iter()
+            .map(|(id, status)| {
+                [
+                    ("id".to_string(), id.to_string()),
+                    ("text".to_string(), "needle".to_string()),
+                    ("status".to_string(), status.to_string()),
+                ]
+                .into_iter()
+                .collect()
+            })
+            .collect();
+        index.add_documents(docs).unwrap();
+        let query_parser = QueryParser::for_index(index.index(), index.get_text_fields());
+        let search = |query: &str| {
+            let query = query_parser.parse_query(query).unwrap();
+            let results = index.search(&query, &SearchOptions::default()).unwrap();
+            results
+                .iter()
+                .map(|r| r.id().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search("status:OK"), vec!["1"]);
+        assert_eq!(search("status:\"NOT OK\""), vec!["3"]);
+        assert!(search("OK").is_empty());
     }
 }
@@ -24,11 +24,16 @@ pub enum LanguageRef {
     Swedish,
     Tamil,
     Turkish,
+    /// Don't stem the words, so they only match exactly (e.g. `running` doesn't match `run`). This
+    /// is often better for source code, where the words are identifiers.
+    #[serde(alias = "raw")]
+    None,
 }
 
-impl Into<Language> for LanguageRef {
-    fn into(self) -> Language {
-        match self {
+/// The language of the stemmer to use, or None to not stem the words
+impl Into<Option<Language>> for LanguageRef {
+    fn into(self) -> Option<Language> {
+        let language = match self {
             LanguageRef::Arabic => Language::Arabic,
             LanguageRef::Danish => Language::Danish,
             LanguageRef::Dutch => Language::Dutch,
@@ -47,7 +52,9 @@ impl Into<Language> for LanguageRef {
             LanguageRef::Swedish => Language::Swedish,
             LanguageRef::Tamil => Language::Tamil,
             LanguageRef::Turkish => Language::Turkish,
-        }
+            LanguageRef::None => return None,
+        };
+        Some(language)
     }
 }
 
@@ -74,6 +81,7 @@ impl FromStr for LanguageRef {
             "swedish" => Ok(LanguageRef::Swedish),
             "tamil" => Ok(LanguageRef::Tamil),
             "turkish" => Ok(LanguageRef::Turkish),
+            "none" | "raw" => Ok(LanguageRef::None),
             _ => Err(anyhow!("Invalid language value '{}'", s)),
         }
     }
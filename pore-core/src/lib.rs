@@ -1,13 +1,48 @@
 #[macro_use]
 extern crate anyhow;
 
+// The macros have to be declared before the modules that use them
+#[macro_use]
+mod string_enum;
+
 mod common;
+mod dedupe;
+mod directory;
+mod encoding;
+mod error;
+mod expand;
+mod field_kind;
 mod field_map;
 mod file;
+mod file_type;
+mod frontmatter;
 mod generic;
 pub mod language;
+mod line_transform;
 mod location;
+mod merge_policy;
+mod ngram;
+mod path_separator;
+mod scoring;
+mod snippet;
+mod sort;
+mod top_terms;
+mod version;
 
+pub use common::{DEFAULT_LOCK_TIMEOUT, METADATA_FILE};
+pub use dedupe::*;
+pub use encoding::*;
+pub use error::*;
+pub use field_kind::*;
 pub use field_map::*;
 pub use file::*;
+pub use file_type::*;
 pub use generic::*;
+pub use line_transform::*;
+pub use merge_policy::*;
+pub use path_separator::*;
+pub use scoring::*;
+pub use snippet::*;
+pub use sort::*;
+pub use top_terms::*;
+pub use version::*;
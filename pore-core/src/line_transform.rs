@@ -0,0 +1,108 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::file::Line;
+
+/// A function that changes the text of the matching lines before they are returned, such as
+/// redacting secrets
+///
+/// This only changes the returned text. The index still contains the original text, and queries
+/// still match it.
+#[derive(Clone)]
+pub struct LineTransform(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl LineTransform {
+    pub fn new<F: Fn(&str) -> String + Send + Sync + 'static>(transform: F) -> Self {
+        LineTransform(Arc::new(transform))
+    }
+
+    /// Replace every match of the regex with `*`s
+    ///
+    /// Each match is replaced with one `*` per byte, so the matched ranges of the line still line
+    /// up with the text.
+    pub fn redact(pattern: &str) -> anyhow::Result<Self> {
+        let regex = Regex::new(pattern)?;
+        Ok(LineTransform::new(move |text| {
+            regex
+                .replace_all(text, |caps: &regex::Captures| "*".repeat(caps[0].len()))
+                .into_owned()
+        }))
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        (self.0)(text)
+    }
+
+    /// Transform the text and heading of a line. If the length of the text changes, the matched
+    /// ranges are dropped because they may no longer line up with it.
+    pub fn apply_to_line(&self, line: &mut Line) {
+        let text = self.apply(&line.text);
+        if text.len() != line.text.len() {
+            line.matches.clear();
+        }
+        line.text = text;
+        line.context_heading = line.context_heading.as_deref().map(|h| self.apply(h));
+    }
+}
+
+impl Debug for LineTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LineTransform")
+    }
+}
+
+/// Two transforms are equal if they are clones of the same function
+impl PartialEq for LineTransform {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<'lua> mlua::FromLua<'lua> for LineTransform {
+    /// Lua can only pass a regex to redact (see `LineTransform::redact`)
+    fn from_lua(lua_value: mlua::Value<'lua>, _lua: &'lua mlua::Lua) -> mlua::Result<Self> {
+        return match &lua_value {
+            mlua::Value::String(str) => LineTransform::redact(str.to_str()?).map_err(|e| {
+                mlua::Error::FromLuaConversionError {
+                    from: lua_value.type_name(),
+                    to: "LineTransform",
+                    message: Some(e.to_string()),
+                }
+            }),
+            _ => Err(mlua::Error::FromLuaConversionError {
+                from: lua_value.type_name(),
+                to: "LineTransform",
+                message: Some("Value is not a string".to_string()),
+            }),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineTransform;
+    use crate::file::Line;
+
+    #[test]
+    fn redact_keeps_the_matched_ranges() {
+        let transform = LineTransform::redact(r"token=\w+").unwrap();
+        let mut line = Line {
+            number: 1,
+            text: "login token=abc123 ok".to_string(),
+            context_heading: Some("token=xyz".to_string()),
+            is_context: false,
+            matches: vec![(0, 5)],
+        };
+        transform.apply_to_line(&mut line);
+        assert_eq!(line.text, "login ************ ok");
+        assert_eq!(line.context_heading.as_deref(), Some("*********"));
+        assert_eq!(line.matches, vec![(0, 5)]);
+
+        let transform = LineTransform::new(|text| text.replace("login", "-"));
+        transform.apply_to_line(&mut line);
+        assert_eq!(line.text, "- ************ ok");
+        assert!(line.matches.is_empty());
+    }
+}
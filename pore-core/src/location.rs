@@ -1,14 +1,18 @@
 use std::{
     cmp::Reverse,
-    collections::{BTreeMap, BinaryHeap, HashMap},
-    fs::File,
-    io::{self, BufRead},
-    path::Path,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    io::BufRead,
+    sync::Arc,
 };
 
+use regex::Regex;
 use tantivy::{
-    query::Query, schema::IndexRecordOption, DocAddress, DocSet, LeasedItem, Postings, Searcher,
-    TERMINATED,
+    collector::{Collector, SegmentCollector},
+    query::Query,
+    schema::{Field, IndexRecordOption},
+    tokenizer::TextAnalyzer,
+    DocAddress, DocId, DocSet, InvertedIndexReader, Postings, Score, SegmentOrdinal, SegmentReader,
+    Snippet, Term,
 };
 
 use crate::{FileIndex, Line};
@@ -21,115 +25,525 @@ pub struct DocResult {
     pub address: DocAddress,
 }
 
-/// Get the position data for a query's search terms
-///
-/// The only way I've found to do this is to iterate through each of the index segments and look up
-/// the docs for each of the query terms in that segment. For each doc, get the term position data
-/// and save it.
+/// The position data of the query terms for each matched doc
+pub type PositionMap = HashMap<DocAddress, BytePositions>;
+
+/// Get the terms of a query whose position data can be mapped back to lines in the file
 ///
-/// TODO: this may not work well for FuzzyTermQuery or PhraseQuery. Needs testing.
+/// Queries that match terms with an automaton (like FuzzyTermQuery) don't report the terms they
+/// matched, so there is no position data for them. `FileIndex::fuzzy_query` avoids this by
+/// expanding the words to the matching terms. TODO: this may not work well for PhraseQuery.
 ///
-/// This effectively amounts to a second full-index scan, doubling the performance cost of the
-/// query (at least). A better way to do this would be to implement a custom Collector (and
-/// possibly Weight and other traits) that keep track of term positions while the search query is
-/// being performed.
-pub fn get_search_results(
-    index: &FileIndex,
-    query: &Box<dyn Query>,
-    searcher: &LeasedItem<Searcher>,
-    results: &Vec<DocResult>,
-) -> Result<HashMap<DocAddress, BytePositions>, anyhow::Error> {
-    let mut position_map: HashMap<DocAddress, BytePositions> = HashMap::new();
-    for result in results {
-        position_map.insert(result.address, BinaryHeap::new());
+/// If the index doesn't record positions (see `FileIndexOptions::record_positions`), there are no
+/// terms, so the lines are found with `scan_lines` instead.
+pub fn position_terms(index: &FileIndex, query: &dyn Query) -> Vec<Term> {
+    if !index.record_positions() {
+        return Vec::new();
     }
     let mut terms = BTreeMap::new();
     query.query_terms(&mut terms);
-    // this buffer will be used to request for positions
-    let mut positions: Vec<u32> = Vec::with_capacity(100);
-    for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
-        let inverted_index = segment_reader.inverted_index(*index.contents())?;
-        for term in terms.keys() {
-            if let Some(mut segment_postings) =
-                inverted_index.read_postings(&term, IndexRecordOption::WithFreqsAndPositions)?
-            {
-                let mut doc_id = segment_postings.doc();
-                while doc_id != TERMINATED {
-                    // This MAY contains deleted documents as well.
-                    if segment_reader.is_deleted(doc_id) {
-                        doc_id = segment_postings.advance();
-                        continue;
-                    }
+    // Only terms in the contents can be mapped back to lines in the file
+    let content_fields = index.content_fields();
+    terms
+        .into_keys()
+        .filter(|term| content_fields.contains(&term.field()))
+        .collect()
+}
+
+/// Wraps a collector of the top docs (e.g. `TopDocs`) to also collect the position data of the
+/// `position_terms` for the docs that it returns
+///
+/// The positions are only read once the segments are merged and the top docs are known, so the
+/// cost doesn't grow with the number of matches. The docs of each segment are visited in order,
+/// so the postings of each term only have to be read once per segment.
+pub struct PositionCollector<C> {
+    inner: C,
+    terms: Vec<Term>,
+}
+
+impl<C> PositionCollector<C> {
+    pub fn new(inner: C, terms: Vec<Term>) -> Self {
+        PositionCollector { inner, terms }
+    }
+}
+
+impl<C> Collector for PositionCollector<C>
+where
+    C: Collector<Fruit = Vec<(Score, DocAddress)>>,
+{
+    type Fruit = (Vec<(Score, DocAddress)>, PositionMap);
+    type Child = SegmentPositionCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_ord: SegmentOrdinal,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let mut terms = Vec::new();
+        for term in &self.terms {
+            terms.push((term.clone(), segment_reader.inverted_index(term.field())?));
+        }
+        Ok(SegmentPositionCollector {
+            inner: self.inner.for_segment(segment_ord, segment_reader)?,
+            terms: SegmentTerms { segment_ord, terms },
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
 
-                    if let Some(position_data) = position_map.get_mut(&DocAddress {
-                        segment_ord: segment_ord.try_into()?,
-                        doc_id,
-                    }) {
-                        segment_postings.positions(&mut positions);
-                        for pos in &positions {
-                            position_data.push(Reverse(*pos));
-                        }
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        let mut inner_fruits = Vec::with_capacity(segment_fruits.len());
+        let mut segment_terms = HashMap::new();
+        for (inner_fruit, terms) in segment_fruits {
+            inner_fruits.push(inner_fruit);
+            segment_terms.insert(terms.segment_ord, terms.terms);
+        }
+        let top_docs = self.inner.merge_fruits(inner_fruits)?;
+        let mut segment_docs: HashMap<SegmentOrdinal, Vec<DocId>> = HashMap::new();
+        for (_, address) in &top_docs {
+            segment_docs
+                .entry(address.segment_ord)
+                .or_default()
+                .push(address.doc_id);
+        }
+        let mut position_map = PositionMap::new();
+        let mut buffer = Vec::new();
+        for (segment_ord, mut doc_ids) in segment_docs {
+            // The postings can only seek forwards
+            doc_ids.sort_unstable();
+            let mut doc_positions = vec![Vec::new(); doc_ids.len()];
+            for (term, inverted_index) in segment_terms.get(&segment_ord).into_iter().flatten() {
+                let mut postings = match inverted_index
+                    .read_postings(term, IndexRecordOption::WithFreqsAndPositions)?
+                {
+                    Some(postings) => postings,
+                    // The term doesn't appear in this segment
+                    None => continue,
+                };
+                for (doc_id, positions) in doc_ids.iter().zip(doc_positions.iter_mut()) {
+                    if postings.doc() <= *doc_id && postings.seek(*doc_id) == *doc_id {
+                        postings.positions(&mut buffer);
+                        positions.extend_from_slice(&buffer);
                     }
-                    doc_id = segment_postings.advance();
                 }
             }
+            for (doc_id, positions) in doc_ids.into_iter().zip(doc_positions) {
+                position_map.insert(
+                    DocAddress::new(segment_ord, doc_id),
+                    positions.into_iter().map(Reverse).collect(),
+                );
+            }
         }
+        Ok((top_docs, position_map))
     }
+}
 
-    Ok(position_map)
+/// The readers for the postings of the `position_terms` in a segment
+pub struct SegmentTerms {
+    segment_ord: SegmentOrdinal,
+    terms: Vec<(Term, Arc<InvertedIndexReader>)>,
+}
+
+pub struct SegmentPositionCollector<C> {
+    inner: C,
+    terms: SegmentTerms,
+}
+
+impl<C> SegmentCollector for SegmentPositionCollector<C>
+where
+    C: SegmentCollector,
+{
+    type Fruit = (C::Fruit, SegmentTerms);
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        self.inner.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        (self.inner.harvest(), self.terms)
+    }
 }
 
 /// Converts token positions to lines of text
 ///
 /// Tantivy stores position data, but that just means token offsets relative to other tokens in the
 /// file. In order to find the actual lines of text that match a term, we have some work to do. At
-/// the moment this process involves reading the file from disk and then tokenizing it line-by-line
-/// as a means to recover the line-number-to-token-offset mapping.
+/// the moment this process involves reading the file (usually from disk) and then tokenizing it
+/// line-by-line as a means to recover the line-number-to-token-offset mapping.
+///
+/// If the index stores a `line_map` (the number of tokens on each line), only the lines that
+/// contain a matched position have to be tokenized, and the line numbers can be found without the
+/// file at all (see `line_map_to_lines`).
 ///
-/// At some point in the future it might be nice to modify Tantivy to *also* store byte offsets or
-/// line offsets for the terms. It would generate larger indexes, but then we wouldn't have to
-/// retokenize to recover the matched text.
-pub fn positions_to_lines(
+/// If a `heading_regex` is provided, each matched line will record the closest line above it that
+/// matches the regex (e.g. the enclosing function definition).
+pub fn positions_to_lines<R: BufRead>(
     index: &FileIndex,
-    filepath: &Path,
+    mut reader: R,
     positions: &mut BytePositions,
+    line_map: Option<&[u32]>,
+    heading_regex: Option<&Regex>,
+    trim_lines: bool,
     lines: &mut Vec<Line>,
 ) -> Result<(), anyhow::Error> {
     let tokenizer = index.index().tokenizer_for_field(*index.contents())?;
-    if let Some(Reverse(mut next_pos)) = positions.peek() {
-        let file = File::open(filepath)?;
-        let mut reader = io::BufReader::new(file);
-        let mut line = String::new();
-        let mut line_no = 1;
-        let mut num_tokens = 0;
-        'outer: while let Ok(bytes) = reader.read_line(&mut line) {
-            if bytes == 0 {
-                break;
-            }
-            let mut line_tokens = 0;
-            {
-                let mut token_stream = tokenizer.token_stream(&line);
-                while let Some(_) = token_stream.next() {
-                    line_tokens += 1;
+    let position_tokenizer = index.position_tokenizer();
+    let mut line = String::new();
+    let mut line_no = 1;
+    let mut num_tokens = 0;
+    let mut heading: Option<String> = None;
+    while let Ok(bytes) = reader.read_line(&mut line) {
+        if bytes == 0 || positions.is_empty() {
+            break;
+        }
+        let mut matches = Vec::new();
+        let mapped_tokens = line_map.and_then(|m| m.get(line_no as usize - 1)).copied();
+        let has_match = match (mapped_tokens, positions.peek()) {
+            (Some(count), Some(Reverse(next_pos))) => *next_pos < num_tokens + count,
+            _ => true,
+        };
+        let line_tokens = if !has_match {
+            mapped_tokens.unwrap_or_default()
+        } else {
+            // Several tokens can share a position (e.g. the n-grams of a word), so track the last
+            // matched position to highlight all of them
+            let mut matched_pos = None;
+            let mut token_stream = tokenizer.token_stream(&line);
+            while let Some(token) = token_stream.next() {
+                let pos = num_tokens + token.position as u32;
+                let mut is_match = matched_pos == Some(pos);
+                while let Some(Reverse(next_pos)) = positions.peek() {
+                    if *next_pos > pos {
+                        break;
+                    }
+                    is_match |= *next_pos == pos;
+                    positions.pop();
                 }
-            }
-            if num_tokens <= next_pos && next_pos < num_tokens + line_tokens {
-                lines.push(Line {
-                    number: line_no,
-                    text: line.trim_end().to_string(),
-                });
-                while next_pos < num_tokens + line_tokens {
-                    match positions.pop() {
-                        None => break 'outer,
-                        Some(Reverse(pos)) => next_pos = pos,
-                    };
+                if is_match {
+                    matched_pos = Some(pos);
+                    matches.push((token.offset_from, token.offset_to));
                 }
             }
-            num_tokens += line_tokens;
+            count_positions(&position_tokenizer, &line)
+        };
+        if !matches.is_empty() {
+            merge_ranges(&mut matches);
+            lines.push(Line {
+                number: line_no,
+                text: line_text(&line, trim_lines),
+                context_heading: heading.clone(),
+                is_context: false,
+                matches,
+            });
+        }
+        if heading_regex.map(|r| r.is_match(&line)).unwrap_or(false) {
+            heading = Some(line.trim().to_string());
+        }
+        num_tokens += line_tokens;
+        line.clear();
+        line_no += 1;
+    }
+
+    Ok(())
+}
+
+/// Converts token positions to lines with the token counts of each line stored by `line_map`,
+/// without reading the file
+///
+/// Each position is binary searched in the running totals of the counts. This is for when the
+/// file can't be read anymore (e.g. it was deleted since it was indexed), so the lines only have
+/// their numbers, and not their text.
+pub fn line_map_to_lines(line_map: &[u32], positions: &BytePositions, lines: &mut Vec<Line>) {
+    let mut line_starts = Vec::with_capacity(line_map.len());
+    let mut num_tokens = 0;
+    for count in line_map {
+        line_starts.push(num_tokens);
+        num_tokens += count;
+    }
+    let numbers: BTreeSet<u32> = positions
+        .iter()
+        .filter(|Reverse(pos)| *pos < num_tokens)
+        // Lines without any tokens start at the same position as the next line, so this finds the
+        // last line that starts at or before the position
+        .map(|Reverse(pos)| line_starts.partition_point(|start| start <= pos) as u32)
+        .collect();
+    lines.extend(numbers.into_iter().map(|number| Line {
+        number,
+        text: String::new(),
+        context_heading: None,
+        is_context: false,
+        matches: Vec::new(),
+    }));
+}
+
+/// Fill in the text of the `lines` found by `line_map_to_lines`, reading only up to the last of
+/// them
+///
+/// The file may have been edited since the line map was stored, so this is the text that is on
+/// those lines now. The lines past the end of the file are left without text.
+pub fn add_line_text<R: BufRead>(mut reader: R, lines: &mut [Line], trim_lines: bool) {
+    let mut line = String::new();
+    let mut line_no = 0;
+    for matched in lines.iter_mut() {
+        while line_no < matched.number {
             line.clear();
-            line_no += 1;
+            match reader.read_line(&mut line) {
+                Ok(bytes) if bytes > 0 => line_no += 1,
+                _ => return,
+            }
         }
+        matched.text = line_text(&line, trim_lines);
+    }
+}
+
+/// Count the token positions on each line of the text with the `FileIndex::position_tokenizer`,
+/// the same way `positions_to_lines` does
+///
+/// This is stored in the index with `store_line_map`, as the counts separated by spaces.
+pub fn line_map(tokenizer: &TextAnalyzer, text: &str) -> String {
+    let counts: Vec<String> = text
+        .split_inclusive('\n')
+        .map(|line| count_positions(tokenizer, line).to_string())
+        .collect();
+    counts.join(" ")
+}
+
+/// The number of token positions that a line takes up. This has to be counted without the filters
+/// that remove tokens, or a stop word at the end of a line would shift the lines after it.
+fn count_positions(tokenizer: &TextAnalyzer, line: &str) -> u32 {
+    let mut count = 0;
+    let mut token_stream = tokenizer.token_stream(line);
+    while let Some(token) = token_stream.next() {
+        count = token.position as u32 + 1;
+    }
+    count
+}
+
+/// Parse the token counts of each line stored by `line_map`. Invalid counts are treated as 0.
+pub fn parse_line_map(text: &str) -> Vec<u32> {
+    text.split(' ')
+        .filter(|count| !count.is_empty())
+        .map(|count| count.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Converts a snippet of the stored contents to the lines of text that it highlights
+///
+/// The snippet doesn't record where it came from in the text, so this finds it by searching for
+/// the snippet text. Returns false if the snippet is empty or couldn't be found.
+pub fn snippet_to_lines(
+    text: &str,
+    snippet: &Snippet,
+    heading_regex: Option<&Regex>,
+    trim_lines: bool,
+    lines: &mut Vec<Line>,
+) -> bool {
+    let start = match text.find(snippet.fragments()) {
+        Some(start) if !snippet.fragments().is_empty() => start,
+        _ => return false,
+    };
+    let mut highlights = snippet
+        .highlighted()
+        .iter()
+        .map(|range| (start + range.start, start + range.end))
+        .peekable();
+    let mut heading: Option<String> = None;
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let end = offset + line.len();
+        let mut matches = Vec::new();
+        while let Some((from, to)) = highlights.peek().copied() {
+            if from >= end {
+                break;
+            }
+            matches.push((from - offset, to.min(end) - offset));
+            highlights.next();
+        }
+        if !matches.is_empty() {
+            lines.push(Line {
+                number: i as u32 + 1,
+                text: line_text(line, trim_lines),
+                context_heading: heading.clone(),
+                is_context: false,
+                matches,
+            });
+        }
+        if highlights.peek().is_none() {
+            break;
+        }
+        if heading_regex.map(|r| r.is_match(line)).unwrap_or(false) {
+            heading = Some(line.trim().to_string());
+        }
+        offset = end;
+    }
+    true
+}
+
+/// The text of a line for a `Line`, without the line ending. If `trim` is true, all trailing
+/// whitespace is removed as well.
+fn line_text(line: &str, trim: bool) -> String {
+    if trim {
+        line.trim_end().to_string()
+    } else {
+        line.trim_end_matches(&['\n', '\r'][..]).to_string()
+    }
+}
+
+/// Sort the matched byte ranges of a line and merge the ones that overlap
+fn merge_ranges(ranges: &mut Vec<(usize, usize)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+/// Get the text of the query terms for each of the content fields
+pub fn content_terms(index: &FileIndex, query: &Box<dyn Query>) -> HashMap<Field, HashSet<String>> {
+    let mut terms = BTreeMap::new();
+    query.query_terms(&mut terms);
+    let content_fields = index.content_fields();
+    let mut ret: HashMap<Field, HashSet<String>> = HashMap::new();
+    for term in terms.keys() {
+        if content_fields.contains(&term.field()) {
+            ret.entry(term.field())
+                .or_default()
+                .insert(term.text().to_string());
+        }
+    }
+    ret
+}
+
+/// Find the lines of text that contain any of the query terms
+///
+/// This is the fallback for when the position data doesn't produce any lines. It tokenizes every
+/// line of the file and checks the tokens against the query terms, which is slower but doesn't
+/// depend on the token positions lining up.
+pub fn scan_lines<R: BufRead>(
+    index: &FileIndex,
+    mut reader: R,
+    terms: &HashMap<Field, HashSet<String>>,
+    heading_regex: Option<&Regex>,
+    trim_lines: bool,
+    lines: &mut Vec<Line>,
+) -> Result<(), anyhow::Error> {
+    let mut tokenizers = Vec::new();
+    for (field, field_terms) in terms {
+        tokenizers.push((index.index().tokenizer_for_field(*field)?, field_terms));
+    }
+    let mut line = String::new();
+    let mut line_no = 1;
+    let mut heading: Option<String> = None;
+    while let Ok(bytes) = reader.read_line(&mut line) {
+        if bytes == 0 {
+            break;
+        }
+        let mut matches = Vec::new();
+        for (tokenizer, field_terms) in &tokenizers {
+            let mut token_stream = tokenizer.token_stream(&line);
+            while let Some(token) = token_stream.next() {
+                if field_terms.contains(&token.text) {
+                    matches.push((token.offset_from, token.offset_to));
+                }
+            }
+        }
+        if !matches.is_empty() {
+            // Each language tokenizes the same words, so merge the duplicate ranges
+            merge_ranges(&mut matches);
+            lines.push(Line {
+                number: line_no,
+                text: line_text(&line, trim_lines),
+                context_heading: heading.clone(),
+                is_context: false,
+                matches,
+            });
+        }
+        if heading_regex.map(|r| r.is_match(&line)).unwrap_or(false) {
+            heading = Some(line.trim().to_string());
+        }
+        line.clear();
+        line_no += 1;
     }
 
     Ok(())
 }
+
+/// Add the lines around each of the matching lines as context
+///
+/// The context windows of nearby matches are merged, so each line appears at most once.
+pub fn add_context_lines<R: BufRead>(
+    mut reader: R,
+    matches: Vec<Line>,
+    before: usize,
+    after: usize,
+    trim_lines: bool,
+) -> Vec<Line> {
+    let mut wanted = BTreeSet::new();
+    for line in &matches {
+        let start = line.number.saturating_sub(before as u32).max(1);
+        wanted.extend(start..=line.number.saturating_add(after as u32));
+    }
+    let mut matches: BTreeMap<u32, Line> = matches.into_iter().map(|l| (l.number, l)).collect();
+    let last_line = wanted.iter().next_back().copied().unwrap_or(0);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_no: u32 = 1;
+    while line_no <= last_line {
+        match reader.read_line(&mut line) {
+            Ok(bytes) if bytes > 0 => {}
+            _ => break,
+        }
+        if let Some(matched) = matches.remove(&line_no) {
+            lines.push(matched);
+        } else if wanted.contains(&line_no) {
+            lines.push(Line {
+                number: line_no,
+                text: line_text(&line, trim_lines),
+                context_heading: None,
+                is_context: true,
+                matches: Vec::new(),
+            });
+        }
+        line.clear();
+        line_no += 1;
+    }
+    // Keep any matches that couldn't be read back from the file
+    lines.extend(matches.into_values());
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+
+    use tantivy::tokenizer::{SimpleTokenizer, TextAnalyzer};
+
+    use super::{line_map, line_map_to_lines, parse_line_map};
+
+    #[test]
+    fn line_map_counts_tokens_per_line() {
+        let tokenizer = TextAnalyzer::from(SimpleTokenizer);
+        let map = line_map(&tokenizer, "one two\n\nthree\nfour five six");
+        assert_eq!(map, "2 0 1 3");
+        assert_eq!(parse_line_map(&map), vec![2, 0, 1, 3]);
+    }
+
+    #[test]
+    fn line_map_finds_line_numbers() {
+        // one two / (empty) / three / four five six
+        let positions = [1, 2, 5, 1, 9].into_iter().map(Reverse).collect();
+        let mut lines = Vec::new();
+        line_map_to_lines(&[2, 0, 1, 3], &positions, &mut lines);
+        let numbers: Vec<u32> = lines.iter().map(|l| l.number).collect();
+        assert_eq!(numbers, vec![1, 3, 4]);
+    }
+}
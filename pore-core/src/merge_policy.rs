@@ -0,0 +1,53 @@
+use macros::create_option_copy;
+use tantivy::merge_policy::{LogMergePolicy, MergePolicy, NoMergePolicy};
+
+/// How the index merges its segments in the background
+///
+/// Every commit creates a new segment, and tantivy merges them in background threads so that
+/// searches don't have to visit too many segments. With the default (log) policy, a burst of
+/// commits can trigger a lot of merging that competes with ingestion for CPU and disk. Disabling
+/// `background_merges` keeps ingestion fast and predictable, at the cost of slower searches and
+/// more open files as segments pile up, until `optimize` is called to merge them all. Committed
+/// documents are durable either way; merging only changes how they are laid out on disk.
+#[create_option_copy(MergePolicyOptionsShape)]
+#[derive(Debug, Clone)]
+pub struct MergePolicyOptions {
+    /// Merge segments in background threads after each commit
+    pub background_merges: bool,
+    /// The minimum number of segments of a similar size to merge at once. Higher values merge less
+    /// often, but each merge does more work.
+    pub min_num_segments: usize,
+    /// Segments with more documents than this are never merged
+    pub max_docs_before_merge: usize,
+    /// Segments smaller than this number of documents are all treated as the same size
+    pub min_layer_size: u32,
+    /// The ratio (in log scale) between segment sizes that are considered the same size
+    pub level_log_size: f64,
+}
+
+impl Default for MergePolicyOptions {
+    fn default() -> Self {
+        // These match the defaults of tantivy's LogMergePolicy
+        MergePolicyOptions {
+            background_merges: true,
+            min_num_segments: 8,
+            max_docs_before_merge: 10_000_000,
+            min_layer_size: 10_000,
+            level_log_size: 0.75,
+        }
+    }
+}
+
+impl MergePolicyOptions {
+    pub fn merge_policy(&self) -> Box<dyn MergePolicy> {
+        if !self.background_merges {
+            return Box::new(NoMergePolicy);
+        }
+        let mut policy = LogMergePolicy::default();
+        policy.set_min_num_segments(self.min_num_segments);
+        policy.set_max_docs_before_merge(self.max_docs_before_merge);
+        policy.set_min_layer_size(self.min_layer_size);
+        policy.set_level_log_size(self.level_log_size);
+        Box::new(policy)
+    }
+}
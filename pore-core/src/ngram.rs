@@ -0,0 +1,116 @@
+use tantivy::tokenizer::{
+    BoxTokenStream, NgramTokenizer, SimpleTokenizer, Token, TokenStream, Tokenizer,
+};
+
+/// Splits text into words, and then each word into n-grams
+///
+/// Tantivy's `NgramTokenizer` treats the whole text as a single word, so the grams would span
+/// whitespace and every gram would be at position 0. This runs it on each word separately, and
+/// gives every gram the position of its word. That way the grams of a query word must all come
+/// from the same word in the file (the query parser turns them into a phrase query), and the
+/// positions still line up with the lines of the file. Words that are shorter than `min_gram` are
+/// kept whole, so they can still be searched for.
+#[derive(Clone)]
+pub struct WordNgramTokenizer {
+    min_gram: usize,
+    ngrams: NgramTokenizer,
+}
+
+impl WordNgramTokenizer {
+    pub fn new(min_gram: usize, max_gram: usize, prefix_only: bool) -> Self {
+        WordNgramTokenizer {
+            min_gram,
+            ngrams: NgramTokenizer::new(min_gram, max_gram, prefix_only),
+        }
+    }
+}
+
+impl Tokenizer for WordNgramTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        let mut tokens = Vec::new();
+        let mut words = SimpleTokenizer.token_stream(text);
+        while let Some(word) = words.next() {
+            if word.text.chars().count() < self.min_gram {
+                tokens.push(word.clone());
+                continue;
+            }
+            let mut grams = self.ngrams.token_stream(&word.text);
+            while let Some(gram) = grams.next() {
+                tokens.push(Token {
+                    offset_from: word.offset_from + gram.offset_from,
+                    offset_to: word.offset_from + gram.offset_to,
+                    position: word.position,
+                    text: gram.text.clone(),
+                    position_length: 1,
+                });
+            }
+        }
+        BoxTokenStream::from(WordNgramTokenStream {
+            tokens,
+            index: None,
+        })
+    }
+}
+
+struct WordNgramTokenStream {
+    tokens: Vec<Token>,
+    index: Option<usize>,
+}
+
+impl TokenStream for WordNgramTokenStream {
+    fn advance(&mut self) -> bool {
+        let next = self.index.map(|i| i + 1).unwrap_or(0);
+        self.index = Some(next);
+        next < self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.index.unwrap_or(0)]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.index.unwrap_or(0)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WordNgramTokenizer;
+    use tantivy::tokenizer::Tokenizer;
+
+    fn tokenize(tokenizer: &WordNgramTokenizer, text: &str) -> Vec<(String, usize)> {
+        let mut tokens = Vec::new();
+        let mut stream = tokenizer.token_stream(text);
+        while let Some(token) = stream.next() {
+            assert_eq!(&text[token.offset_from..token.offset_to], token.text);
+            tokens.push((token.text.clone(), token.position));
+        }
+        tokens
+    }
+
+    #[test]
+    fn splits_each_word_into_grams() {
+        let tokenizer = WordNgramTokenizer::new(2, 3, false);
+        let tokens = tokenize(&tokenizer, "a cat");
+        assert_eq!(
+            tokens,
+            vec![
+                ("a".to_string(), 0),
+                ("ca".to_string(), 1),
+                ("cat".to_string(), 1),
+                ("at".to_string(), 1),
+            ]
+        );
+        let tokenizer = WordNgramTokenizer::new(2, 3, true);
+        let tokens = tokenize(&tokenizer, "dog cats");
+        assert_eq!(
+            tokens,
+            vec![
+                ("do".to_string(), 0),
+                ("dog".to_string(), 0),
+                ("ca".to_string(), 1),
+                ("cat".to_string(), 1),
+            ]
+        );
+    }
+}
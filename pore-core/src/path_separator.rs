@@ -0,0 +1,37 @@
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+use serde::{Deserialize, Serialize};
+
+/// The separator to use for the paths in search results
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PathSeparator {
+    /// The platform separator (`\` on Windows)
+    Native,
+    /// Always use `/`
+    Slash,
+}
+
+impl PathSeparator {
+    /// Format a path using this separator
+    pub fn format(&self, path: &Path) -> String {
+        let path = path.to_string_lossy();
+        match self {
+            PathSeparator::Slash if MAIN_SEPARATOR != '/' => path.replace(MAIN_SEPARATOR, "/"),
+            _ => path.to_string(),
+        }
+    }
+
+    /// Convert a path to use this separator
+    pub fn apply(&self, path: PathBuf) -> PathBuf {
+        match self {
+            PathSeparator::Slash if MAIN_SEPARATOR != '/' => PathBuf::from(self.format(&path)),
+            _ => path,
+        }
+    }
+}
+
+string_enum!(PathSeparator, "path separator value", {
+    "native" => PathSeparator::Native,
+    "slash" => PathSeparator::Slash,
+});
@@ -0,0 +1,359 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tantivy::collector::{Collector, ScoreSegmentTweaker, ScoreTweaker, SegmentCollector};
+use tantivy::fastfield::{DynamicFastFieldReader, FastFieldReader};
+use tantivy::fieldnorm::FieldNormReader;
+use tantivy::postings::SegmentPostings;
+use tantivy::query::{BooleanQuery, BoostQuery, Query};
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::{
+    DocAddress, DocId, DocSet, InvertedIndexReader, Postings, Score, Searcher, SegmentOrdinal,
+    SegmentReader, Term,
+};
+
+/// The BM25 parameters that tantivy uses
+pub const DEFAULT_BM25_K1: f32 = 1.2;
+pub const DEFAULT_BM25_B: f32 = 0.75;
+
+/// The number of days it takes for the recency boost of a file to drop by half
+pub const RECENCY_HALF_LIFE_DAYS: f32 = 30.0;
+
+/// The model used to score how well a document matches a query
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringModel {
+    /// Okapi BM25 (tantivy's default), tuned with `bm25_k1` and `bm25_b`
+    Bm25,
+    /// Classic TF-IDF, which scores each term as `sqrt(tf) * idf^2 / sqrt(length)`
+    TfIdf,
+}
+
+string_enum!(ScoringModel, "scoring value", {
+    "bm25" => ScoringModel::Bm25,
+    "tf_idf" | "tfidf" => ScoringModel::TfIdf,
+});
+
+/// How to score search results
+///
+/// `k1` controls how quickly repeated occurrences of a term stop increasing the score (higher
+/// values reward repetition more), and `b` controls how much long documents are penalized (0 means
+/// document length is ignored, 1 means the term frequency is fully normalized by length).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ScoringParams {
+    pub model: ScoringModel,
+    pub k1: f32,
+    pub b: f32,
+}
+
+impl Default for ScoringParams {
+    fn default() -> Self {
+        ScoringParams {
+            model: ScoringModel::Bm25,
+            k1: DEFAULT_BM25_K1,
+            b: DEFAULT_BM25_B,
+        }
+    }
+}
+
+/// Recomputes the score of each matched document with custom `ScoringParams`
+///
+/// tantivy hardcodes its BM25 parameters, so the only way to change the scoring is to recompute
+/// the scores from the term statistics. The new score is the sum of the scores of each query term
+/// that appears in the document. tantivy doesn't expose the boost of a `BoostQuery`, so queries
+/// that boost some of their terms can't be rescored (see `has_boosted_terms`).
+#[derive(Clone)]
+pub(crate) struct Rescorer {
+    params: ScoringParams,
+    terms: Vec<Term>,
+    /// The weight of each term in `terms` (derived from its inverse document frequency)
+    weights: Vec<Score>,
+    avg_fieldnorms: HashMap<Field, Score>,
+}
+
+impl Rescorer {
+    /// Returns None if the params match tantivy's own scoring, so no rescoring is needed
+    pub fn new(
+        searcher: &Searcher,
+        query: &dyn Query,
+        params: ScoringParams,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        if params == ScoringParams::default() {
+            return Ok(None);
+        }
+        if has_boosted_terms(query) {
+            bail!(
+                "Boosted queries (e.g. with a filename boost or field boosts) can only be scored \
+                 with the default BM25 parameters"
+            );
+        }
+        let mut query_terms = BTreeMap::new();
+        query.query_terms(&mut query_terms);
+        let terms: Vec<Term> = query_terms.into_keys().collect();
+
+        let num_docs = searcher.num_docs();
+        let mut avg_fieldnorms = HashMap::new();
+        let mut weights = Vec::new();
+        for term in &terms {
+            if let Entry::Vacant(entry) = avg_fieldnorms.entry(term.field()) {
+                let mut num_tokens = 0;
+                for segment_reader in searcher.segment_readers() {
+                    num_tokens += segment_reader
+                        .inverted_index(term.field())?
+                        .total_num_tokens();
+                }
+                entry.insert(num_tokens as Score / num_docs.max(1) as Score);
+            }
+            let doc_freq = searcher.doc_freq(term)?;
+            weights.push(match params.model {
+                ScoringModel::Bm25 => {
+                    let x = (num_docs.saturating_sub(doc_freq) as Score + 0.5)
+                        / (doc_freq as Score + 0.5);
+                    (1.0 + x).ln() * (1.0 + params.k1)
+                }
+                ScoringModel::TfIdf => {
+                    let idf = 1.0 + (num_docs as Score / (doc_freq as Score + 1.0)).ln();
+                    idf * idf
+                }
+            });
+        }
+        Ok(Some(Rescorer {
+            params,
+            terms,
+            weights,
+            avg_fieldnorms,
+        }))
+    }
+}
+
+/// True if a `BoostQuery` in the query wraps any terms. Boosts without terms (e.g. a boost of 0 to
+/// make a range query only filter) don't affect the rescored scores.
+fn has_boosted_terms(query: &dyn Query) -> bool {
+    if let Some(boolean_query) = query.downcast_ref::<BooleanQuery>() {
+        return boolean_query
+            .clauses()
+            .iter()
+            .any(|(_, clause)| has_boosted_terms(clause.as_ref()));
+    }
+    if query.downcast_ref::<BoostQuery>().is_some() {
+        let mut terms = BTreeMap::new();
+        query.query_terms(&mut terms);
+        return !terms.is_empty();
+    }
+    false
+}
+
+impl ScoreTweaker<Score> for Rescorer {
+    type Child = SegmentRescorer;
+
+    fn segment_tweaker(&self, segment_reader: &SegmentReader) -> tantivy::Result<SegmentRescorer> {
+        let mut terms = Vec::new();
+        for (term, weight) in self.terms.iter().zip(&self.weights) {
+            let inverted_index = segment_reader.inverted_index(term.field())?;
+            let postings = inverted_index.read_postings(term, IndexRecordOption::WithFreqs)?;
+            terms.push(SegmentTerm {
+                term: term.clone(),
+                weight: *weight,
+                avg_fieldnorm: self.avg_fieldnorms[&term.field()],
+                fieldnorms: segment_reader.get_fieldnorms_reader(term.field()).ok(),
+                inverted_index,
+                postings,
+                last_doc: 0,
+            });
+        }
+        Ok(SegmentRescorer {
+            params: self.params,
+            terms,
+        })
+    }
+}
+
+struct SegmentTerm {
+    term: Term,
+    weight: Score,
+    avg_fieldnorm: Score,
+    fieldnorms: Option<FieldNormReader>,
+    inverted_index: Arc<InvertedIndexReader>,
+    postings: Option<SegmentPostings>,
+    /// The last document that was scored. The postings are past it, even once they run out.
+    last_doc: DocId,
+}
+
+impl SegmentTerm {
+    fn term_freq(&mut self, doc: DocId) -> u32 {
+        // Documents are usually scored in order, but the postings can only seek forwards
+        if doc < self.last_doc {
+            self.postings = self
+                .inverted_index
+                .read_postings(&self.term, IndexRecordOption::WithFreqs)
+                .ok()
+                .flatten();
+        }
+        self.last_doc = doc;
+        self.postings.as_mut().map_or(0, |postings| {
+            if postings.seek(doc) == doc {
+                postings.term_freq()
+            } else {
+                0
+            }
+        })
+    }
+}
+
+pub(crate) struct SegmentRescorer {
+    params: ScoringParams,
+    terms: Vec<SegmentTerm>,
+}
+
+impl ScoreSegmentTweaker<Score> for SegmentRescorer {
+    fn score(&mut self, doc: DocId, _score: Score) -> Score {
+        let params = self.params;
+        let mut score = 0.0;
+        for term in &mut self.terms {
+            let term_freq = term.term_freq(doc) as Score;
+            if term_freq == 0.0 {
+                continue;
+            }
+            let fieldnorm = term
+                .fieldnorms
+                .as_ref()
+                .map(|r| r.fieldnorm(doc) as Score)
+                .unwrap_or(term.avg_fieldnorm);
+            score += match params.model {
+                ScoringModel::Bm25 => {
+                    let norm =
+                        params.k1 * (1.0 - params.b + params.b * fieldnorm / term.avg_fieldnorm);
+                    term.weight * term_freq / (term_freq + norm)
+                }
+                ScoringModel::TfIdf => term.weight * term_freq.sqrt() / fieldnorm.max(1.0).sqrt(),
+            };
+        }
+        score
+    }
+}
+
+/// Multiplies the score of each document by a boost for how recently it was modified
+///
+/// A document modified just now has its score multiplied by `1 + boost`, and the extra boost
+/// halves every `RECENCY_HALF_LIFE_DAYS`. The scores are rescored first if there is a `Rescorer`.
+#[derive(Clone)]
+pub(crate) struct RecencyBoost {
+    field: Field,
+    boost: Score,
+    now: DateTime<Utc>,
+    rescorer: Option<Rescorer>,
+}
+
+impl RecencyBoost {
+    pub fn new(field: Field, boost: Score, rescorer: Option<Rescorer>) -> Self {
+        RecencyBoost {
+            field,
+            boost,
+            now: Utc::now(),
+            rescorer,
+        }
+    }
+}
+
+impl ScoreTweaker<Score> for RecencyBoost {
+    type Child = SegmentRecencyBoost;
+
+    fn segment_tweaker(
+        &self,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<SegmentRecencyBoost> {
+        Ok(SegmentRecencyBoost {
+            boost: self.boost,
+            now: self.now,
+            modified: segment_reader.fast_fields().date(self.field)?,
+            rescorer: self
+                .rescorer
+                .as_ref()
+                .map(|rescorer| rescorer.segment_tweaker(segment_reader))
+                .transpose()?,
+        })
+    }
+}
+
+pub(crate) struct SegmentRecencyBoost {
+    boost: Score,
+    now: DateTime<Utc>,
+    modified: DynamicFastFieldReader<DateTime<Utc>>,
+    rescorer: Option<SegmentRescorer>,
+}
+
+impl ScoreSegmentTweaker<Score> for SegmentRecencyBoost {
+    fn score(&mut self, doc: DocId, score: Score) -> Score {
+        let score = match &mut self.rescorer {
+            Some(rescorer) => rescorer.score(doc, score),
+            None => score,
+        };
+        let age_days = (self.now - self.modified.get(doc)).num_seconds().max(0) as Score / 86400.0;
+        score * (1.0 + self.boost * 0.5f32.powf(age_days / RECENCY_HALF_LIFE_DAYS))
+    }
+}
+
+/// Collects the score and address of every matching document (adjusted by a score tweaker, if
+/// there is one), e.g. to find a percentile of the scores
+///
+/// Unlike `TopDocs` with a limit of every document, this doesn't keep the documents sorted while
+/// they are collected.
+pub(crate) struct AllScores<T>(pub Option<T>);
+
+impl<T: ScoreTweaker<Score> + Send + Sync> Collector for AllScores<T> {
+    type Fruit = Vec<(Score, DocAddress)>;
+    type Child = SegmentScores<T::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        Ok(SegmentScores {
+            segment_ord: segment_local_id,
+            tweaker: self
+                .0
+                .as_ref()
+                .map(|tweaker| tweaker.segment_tweaker(segment))
+                .transpose()?,
+            scores: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<Vec<(Score, DocAddress)>>,
+    ) -> tantivy::Result<Self::Fruit> {
+        Ok(segment_fruits.into_iter().flatten().collect())
+    }
+}
+
+pub(crate) struct SegmentScores<T> {
+    segment_ord: SegmentOrdinal,
+    tweaker: Option<T>,
+    scores: Vec<(Score, DocAddress)>,
+}
+
+impl<T: ScoreSegmentTweaker<Score>> SegmentCollector for SegmentScores<T> {
+    type Fruit = Vec<(Score, DocAddress)>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let score = match &mut self.tweaker {
+            Some(tweaker) => tweaker.score(doc, score),
+            None => score,
+        };
+        self.scores
+            .push((score, DocAddress::new(self.segment_ord, doc)));
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.scores
+    }
+}
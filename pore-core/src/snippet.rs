@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+/// How to find the matching text of each search result
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnippetMode {
+    /// Re-read each matching file and map the matched tokens back to lines
+    Lines,
+    /// Use tantivy's snippet generator on the contents stored in the index. Requires the index to
+    /// be built with `store_contents`.
+    Snippet,
+}
+
+string_enum!(SnippetMode, "snippet mode", {
+    "lines" => SnippetMode::Lines,
+    "snippet" => SnippetMode::Snippet,
+});
@@ -0,0 +1,141 @@
+use std::cmp::Ordering;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// The order to return search results in
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    /// Highest score first
+    Score,
+    /// Most matched tokens first
+    Matches,
+    /// By path, in lexicographic order
+    Path,
+    /// By path, comparing the numbers in file and directory names by their value (so `file2` comes
+    /// before `file10`)
+    PathNatural,
+    /// Most recently modified first
+    #[serde(alias = "mtime")]
+    Modified,
+    /// Least recently modified first
+    ModifiedAsc,
+}
+
+string_enum!(SortBy, "sort value", {
+    "score" => SortBy::Score,
+    "matches" => SortBy::Matches,
+    "path" => SortBy::Path,
+    "path_natural" => SortBy::PathNatural,
+    "modified" | "mtime" => SortBy::Modified,
+    "modified_asc" => SortBy::ModifiedAsc,
+});
+
+/// How to order the matches: ranked by relevance, or by where they are in the tree
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultOrder {
+    /// The top results by score, ordered by `SortBy`
+    Score,
+    /// Every matching file by path, with the lines of each file in order (see
+    /// `FileIndex::search_by_location`)
+    Location,
+}
+
+string_enum!(ResultOrder, "order value", {
+    "score" => ResultOrder::Score,
+    "location" => ResultOrder::Location,
+});
+
+/// Compare two paths component by component with `natural_cmp`
+pub fn natural_path_cmp(a: &Path, b: &Path) -> Ordering {
+    let mut a_components = a.components();
+    let mut b_components = b.components();
+    loop {
+        match (a_components.next(), b_components.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => {
+                let ord = natural_cmp(
+                    &a.as_os_str().to_string_lossy(),
+                    &b.as_os_str().to_string_lossy(),
+                );
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+        }
+    }
+}
+
+/// Compare two strings, treating each run of digits as a number
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_num = take_digits(&mut a_chars);
+                let b_num = take_digits(&mut b_chars);
+                let a_value = a_num.trim_start_matches('0');
+                let b_value = b_num.trim_start_matches('0');
+                // The number with more digits is bigger. Otherwise compare the digits, and if the
+                // values are equal put the one with fewer leading zeros first.
+                let ord = a_value
+                    .len()
+                    .cmp(&b_value.len())
+                    .then_with(|| a_value.cmp(b_value))
+                    .then_with(|| a_num.len().cmp(&b_num.len()));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.cmp(y);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+fn take_digits<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        digits.push(*c);
+        chars.next();
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::natural_path_cmp;
+
+    #[test]
+    fn natural_sort_compares_numbers_by_value() {
+        let mut paths: Vec<PathBuf> = ["dir10/a", "file10", "file2", "file1", "dir9/b", "file02"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        paths.sort_by(|a, b| natural_path_cmp(a, b));
+        let expected: Vec<PathBuf> = ["dir9/b", "dir10/a", "file1", "file2", "file02", "file10"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(paths, expected);
+    }
+}
@@ -0,0 +1,69 @@
+/// Implement `FromStr` and `mlua::FromLua` for an enum whose values are written as strings (e.g.
+/// in the config file or the Lua options). The strings are matched case-insensitively, against
+/// lowercase patterns.
+///
+/// `$what` names the value in the error for an unknown string, as in "Invalid sort value 'foo'".
+///
+/// ```ignore
+/// string_enum!(DedupeBy, "dedupe value", {
+///     "none" => DedupeBy::None,
+///     "dir" | "directory" => DedupeBy::Directory,
+/// });
+/// ```
+macro_rules! string_enum {
+    ($name:ident, $what:literal, { $($pattern:pat => $value:expr),+ $(,)? }) => {
+        impl std::str::FromStr for $name {
+            type Err = anyhow::Error;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s.to_lowercase().as_str() {
+                    $($pattern => Ok($value),)+
+                    _ => Err(anyhow!("Invalid {} '{}'", $what, s)),
+                }
+            }
+        }
+
+        impl<'lua> mlua::FromLua<'lua> for $name {
+            fn from_lua(
+                lua_value: mlua::Value<'lua>,
+                _lua: &'lua mlua::Lua,
+            ) -> mlua::Result<Self> {
+                match &lua_value {
+                    mlua::Value::String(str) => <$name as std::str::FromStr>::from_str(
+                        str.to_str()?,
+                    )
+                    .map_err(|e| mlua::Error::FromLuaConversionError {
+                        from: lua_value.type_name(),
+                        to: stringify!($name),
+                        message: Some(e.to_string()),
+                    }),
+                    _ => Err(mlua::Error::FromLuaConversionError {
+                        from: lua_value.type_name(),
+                        to: stringify!($name),
+                        message: Some("Value is not a string".to_string()),
+                    }),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::DedupeBy;
+
+    #[test]
+    fn parses_strings_case_insensitively() {
+        assert_eq!(DedupeBy::from_str("Dir").unwrap(), DedupeBy::Directory);
+        assert_eq!(
+            DedupeBy::from_str("EXTENSION").unwrap(),
+            DedupeBy::Extension
+        );
+        assert_eq!(
+            DedupeBy::from_str("file").unwrap_err().to_string(),
+            "Invalid dedupe value 'file'"
+        );
+    }
+}
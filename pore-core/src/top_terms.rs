@@ -0,0 +1,53 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use mlua::ToLua;
+use serde::Serialize;
+use tantivy::schema::Field;
+use tantivy::Searcher;
+
+/// A term of the index, and the number of documents that contain it
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct TermFrequency {
+    pub term: String,
+    pub doc_freq: u64,
+}
+
+impl<'lua> ToLua<'lua> for TermFrequency {
+    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let tbl = lua.create_table()?;
+        tbl.set("term", self.term)?;
+        tbl.set("doc_freq", self.doc_freq)?;
+        Ok(mlua::Value::Table(tbl))
+    }
+}
+
+/// Find the `n` terms of a field that are in the most documents, most frequent first
+///
+/// This reads the whole terms dictionary of every segment, so it can be slow (and use a lot of
+/// memory) on a large index. The document frequencies include deleted documents until their
+/// segments are merged.
+pub(crate) fn top_terms(
+    searcher: &Searcher,
+    field: Field,
+    n: usize,
+) -> Result<Vec<TermFrequency>, anyhow::Error> {
+    let mut doc_freqs: HashMap<String, u64> = HashMap::new();
+    for segment_reader in searcher.segment_readers() {
+        let inverted_index = segment_reader.inverted_index(field)?;
+        let mut stream = inverted_index.terms().stream()?;
+        while let Some((key, term_info)) = stream.next() {
+            if let Ok(text) = std::str::from_utf8(key) {
+                *doc_freqs.entry(text.to_string()).or_insert(0) += term_info.doc_freq as u64;
+            }
+        }
+    }
+    let mut terms: Vec<TermFrequency> = doc_freqs
+        .into_iter()
+        .map(|(term, doc_freq)| TermFrequency { term, doc_freq })
+        .collect();
+    // Break ties by the term, so the result doesn't depend on the order of the hash map
+    terms.sort_by(|a, b| (Reverse(a.doc_freq), &a.term).cmp(&(Reverse(b.doc_freq), &b.term)));
+    terms.truncate(n);
+    Ok(terms)
+}
@@ -0,0 +1,18 @@
+use serde::Serialize;
+
+/// The versions of the underlying libraries that determine the on-disk index format
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub tantivy: String,
+    pub index_format: u32,
+}
+
+pub fn version_info() -> VersionInfo {
+    // tantivy doesn't expose the individual version fields, but it does serialize them
+    let version = serde_json::to_value(tantivy::version()).unwrap_or_default();
+    let field = |name: &str| version.get(name).and_then(|v| v.as_u64()).unwrap_or(0);
+    VersionInfo {
+        tantivy: format!("{}.{}.{}", field("major"), field("minor"), field("patch")),
+        index_format: field("index_format_version") as u32,
+    }
+}
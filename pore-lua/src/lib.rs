@@ -1,13 +1,18 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use mlua::prelude::*;
 use mlua::{MetaMethod, UserData, UserDataMethods};
 use pore_core::{
-    FileIndex, FileIndexOptionsShape, FileSearchOptionsShape, GenericIndex, IndexOptionsShape,
+    take_within_bytes, version_info, FieldDef, FileIndex, FileIndexOptionsShape,
+    FileSearchOptionsShape, GenericIndex, IndexOptionsShape, MergePolicyOptionsShape, PoreError,
     SearchOptionsShape,
 };
 use tantivy::query::QueryParser;
+use tantivy::schema::Schema;
 
 #[mlua::lua_module]
 fn pore_lua(lua: &Lua) -> LuaResult<LuaTable> {
@@ -29,23 +34,40 @@ fn pore_lua(lua: &Lua) -> LuaResult<LuaTable> {
                     .transpose()?,
                 &config.into(),
             )
-            .map_err(|e| LuaError::RuntimeError(format!("Error creating index {:?}", e)))?;
+            .map_err(lua_error)?;
             Ok(FileIndexLua { index })
         },
     )?;
     exports.set("get_file_index", get_file_index)?;
 
+    // Open an index that was already built, with the config that it was built with
+    let open_file_index = lua.create_function(|_, (for_dir, cache_dir): (String, String)| {
+        let index = FileIndex::open(&cache_dir).map_err(lua_error)?;
+        let for_dir = std::fs::canonicalize(&for_dir)
+            .map_err(|_| LuaError::RuntimeError(format!("Invalid path {}", for_dir)))?;
+        if index.for_dir() != for_dir {
+            return Err(LuaError::RuntimeError(format!(
+                "Index at {} is for {}, not {}",
+                cache_dir,
+                index.for_dir().to_string_lossy(),
+                for_dir.to_string_lossy()
+            )));
+        }
+        Ok(FileIndexLua { index })
+    })?;
+    exports.set("open_file_index", open_file_index)?;
+
     let get_index = lua.create_function(
         |_,
-         (id_field, text_fields, config, cache_dir): (
+         (id_field, fields, config, cache_dir): (
             String,
-            Vec<String>,
+            Vec<FieldDef>,
             IndexOptionsShape,
             Option<String>,
         )| {
             let index = GenericIndex::get_or_create(
                 &id_field,
-                text_fields,
+                fields,
                 &config.into(),
                 cache_dir
                     .as_ref()
@@ -57,7 +79,7 @@ fn pore_lua(lua: &Lua) -> LuaResult<LuaTable> {
                     .transpose()?
                     .as_deref(),
             )
-            .map_err(|e| LuaError::RuntimeError(format!("Error creating index {:?}", e)))?;
+            .map_err(lua_error)?;
             Ok(GenericIndexLua { index })
         },
     )?;
@@ -66,6 +88,23 @@ fn pore_lua(lua: &Lua) -> LuaResult<LuaTable> {
     Ok(exports)
 }
 
+/// Convert an error from pore-core to a Lua error
+///
+/// The message starts with the kind of error (e.g. "index_locked: ..."), so that Lua code can tell
+/// them apart. The kinds are index_locked, io, schema, metadata, query_parse, tantivy, and other.
+fn lua_error(err: PoreError) -> LuaError {
+    let kind = match err {
+        PoreError::IndexLocked => "index_locked",
+        PoreError::Io(_) => "io",
+        PoreError::Schema(_) => "schema",
+        PoreError::Metadata(_) => "metadata",
+        PoreError::QueryParse(_) => "query_parse",
+        PoreError::Tantivy(_) => "tantivy",
+        PoreError::Other(_) => "other",
+    };
+    LuaError::RuntimeError(format!("{}: {}", kind, err))
+}
+
 macro_rules! set_nonempty_env {
     ($tbl:ident, $key:literal, $env_key:literal) => {{
         let value = env!($env_key);
@@ -82,31 +121,161 @@ struct FileIndexLua {
 
 impl UserData for FileIndexLua {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method_mut("update", |_, this, (rebuild,): (Option<bool>,)| {
-            this.index
-                .update(rebuild.unwrap_or(false))
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-            Ok(())
+        // The progress callback is called on the Lua thread after each file, with the number of
+        // files processed so far. The files are read on the Lua thread when there is a callback,
+        // so it blocks the editor just like an update without one. If the callback returns false
+        // or raises an error, the update is cancelled (and the error is re-raised).
+        methods.add_method_mut(
+            "update",
+            |_, this, (rebuild, progress): (Option<bool>, Option<LuaFunction>)| {
+                let rebuild = rebuild.unwrap_or(false);
+                let summary = match progress {
+                    None => this.index.update(rebuild),
+                    Some(progress) => {
+                        let mut error = None;
+                        let summary = this.index.update_with_progress(rebuild, |processed| {
+                            match progress.call::<_, Option<bool>>(processed) {
+                                Ok(keep_going) => keep_going.unwrap_or(true),
+                                Err(err) => {
+                                    error = Some(err);
+                                    false
+                                }
+                            }
+                        });
+                        if let Some(err) = error {
+                            return Err(err);
+                        }
+                        summary
+                    }
+                }
+                .map_err(lua_error)?;
+                Ok(summary)
+            },
+        );
+        methods.add_method_mut("update_glob", |_, this, pattern: String| {
+            this.index.update_glob(&pattern).map_err(lua_error)
+        });
+        // Re-index or remove a single file, with a path relative to the indexed directory (or an
+        // absolute path). See `FileIndex::update_file` and `FileIndex::delete_file`.
+        methods.add_method_mut("update_file", |_, this, path: String| {
+            this.index.update_file(Path::new(&path)).map_err(lua_error)
+        });
+        methods.add_method_mut("delete_file", |_, this, path: String| {
+            this.index.delete_file(Path::new(&path)).map_err(lua_error)
+        });
+        methods.add_method("cancel_handle", |_, this, _: ()| {
+            Ok(CancelHandleLua {
+                flag: this.index.cancel_handle(),
+            })
+        });
+        methods.add_method("indexed_paths", |_, this, _: ()| {
+            this.index.indexed_paths().map_err(lua_error)
+        });
+        // The files that an update would index (see `FileIndex::list_files`), like `pore --files`
+        methods.add_method("list_files", |_, this, _: ()| {
+            this.index.list_files().map_err(lua_error)
+        });
+        methods.add_method("doc_count", |_, this, _: ()| {
+            this.index.num_docs().map_err(lua_error)
+        });
+        // The terms of a field (default "contents") that are in the most files. This may be slow
+        // for a large index.
+        methods.add_method(
+            "top_terms",
+            |_, this, (n, field): (usize, Option<String>)| {
+                let field_name = field.unwrap_or_else(|| "contents".to_string());
+                let field = this
+                    .index
+                    .index()
+                    .schema()
+                    .get_field(&field_name)
+                    .ok_or_else(|| LuaError::RuntimeError(format!("No field '{}'", field_name)))?;
+                this.index.top_terms(field, n).map_err(lua_error)
+            },
+        );
+        methods.add_method("get_document", |_, this, (path,): (String,)| {
+            this.index.get_document(&path).map_err(lua_error)
         });
         methods.add_method_mut("delete", |_, this, _: ()| {
-            this.index
-                .delete()
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+            this.index.delete().map_err(lua_error)?;
             Ok(())
         });
+        // In addition to the FileSearchOptions, this accepts `max_total_bytes`. The results are
+        // cut off once the text of their lines would exceed that many bytes in total, and the
+        // returned list has `truncated = true`. This is applied after `limit`, so the results are
+        // the first of the top `limit` results that fit in the budget. The first result is always
+        // returned, even if it's over the budget (see `take_within_bytes`).
+        // The returned list also has `total`, the number of files that matched the query
+        // (including the ones past the limit).
+        // If `fuzzy` is set, the query is matched with up to that many typos per word (see
+        // `FileIndex::fuzzy_query`) instead of being parsed. If `regex` is true, the query is a
+        // regex that matches whole indexed terms (see `FileIndex::regex_query`), and `fuzzy` must
+        // not be set. Otherwise the query is parsed with the `conjunction` and `field_boosts`
+        // options (see `QueryParserOptions`).
         methods.add_method(
             "search",
-            |_, this, (query_str, opts): (String, FileSearchOptionsShape)| {
-                let query_parser =
-                    QueryParser::for_index(this.index.index(), vec![*this.index.contents()]);
-                let query = query_parser
-                    .parse_query(&query_str)
-                    .map_err(|_| LuaError::RuntimeError("Error parsing query".to_string()))?;
-                let results = this
+            |lua, this, (query_str, opts): (String, Option<LuaTable>)| {
+                let max_total_bytes = opts
+                    .as_ref()
+                    .map(|o| o.get::<_, Option<usize>>("max_total_bytes"))
+                    .transpose()?
+                    .flatten();
+                let fuzzy = opts
+                    .as_ref()
+                    .map(|o| o.get::<_, Option<u8>>("fuzzy"))
+                    .transpose()?
+                    .flatten();
+                let regex = opts
+                    .as_ref()
+                    .map(|o| o.get::<_, Option<bool>>("regex"))
+                    .transpose()?
+                    .flatten()
+                    .unwrap_or(false);
+                let parser_opts = QueryParserOptions::from_opts(opts.as_ref())?;
+                let opts = FileSearchOptionsShape::from_lua_with_extra_keys(
+                    opts.map(LuaValue::Table).unwrap_or(LuaValue::Nil),
+                    &[
+                        "max_total_bytes",
+                        "fuzzy",
+                        "regex",
+                        QueryParserOptions::CONJUNCTION,
+                        QueryParserOptions::FIELD_BOOSTS,
+                    ],
+                )?;
+                if regex && fuzzy.is_some() {
+                    return Err(LuaError::RuntimeError(
+                        "The fuzzy and regex options can't be used together".to_string(),
+                    ));
+                }
+                let query = match fuzzy {
+                    _ if regex => this.index.regex_query(&query_str).map_err(lua_error)?,
+                    Some(distance) => this
+                        .index
+                        .fuzzy_query(&query_str, distance)
+                        .map_err(lua_error)?,
+                    None => {
+                        let mut query_parser = this.index.query_parser();
+                        parser_opts.apply(&mut query_parser, &this.index.index().schema())?;
+                        this.index
+                            .parse_query_with(&query_parser, &query_str)
+                            .map_err(lua_error)?
+                    }
+                };
+                let (results, total) = this
                     .index
-                    .search(&query, &opts.into())
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-                Ok(results)
+                    .search_with_total(&query, &opts.into())
+                    .map_err(lua_error)?;
+                let (results, truncated) = match max_total_bytes {
+                    Some(max) => take_within_bytes(results, max),
+                    None => (results, false),
+                };
+                let tbl = lua.create_table()?;
+                for (i, result) in results.into_iter().enumerate() {
+                    tbl.set(i + 1, result)?;
+                }
+                tbl.set("truncated", truncated)?;
+                tbl.set("total", total)?;
+                Ok(tbl)
             },
         );
         methods.add_meta_function(MetaMethod::ToString, |_, this: FileIndexLua| {
@@ -115,6 +284,72 @@ impl UserData for FileIndexLua {
     }
 }
 
+/// Options for the `QueryParser` of a search, read from the search options table
+///
+/// If `conjunction` is true, every word of the query has to match (like AND) instead of any of
+/// them (like OR). `field_boosts` is a table of field names to a factor to multiply the score of
+/// their matches by, e.g. `{title = 2.0}`. Boosted searches can only use the default BM25
+/// parameters.
+#[derive(Debug, Default)]
+struct QueryParserOptions {
+    conjunction: bool,
+    field_boosts: HashMap<String, f32>,
+}
+
+impl QueryParserOptions {
+    const CONJUNCTION: &'static str = "conjunction";
+    const FIELD_BOOSTS: &'static str = "field_boosts";
+
+    fn from_opts(opts: Option<&LuaTable>) -> LuaResult<Self> {
+        let opts = match opts {
+            Some(opts) => opts,
+            None => return Ok(QueryParserOptions::default()),
+        };
+        Ok(QueryParserOptions {
+            conjunction: opts
+                .get::<_, Option<bool>>(Self::CONJUNCTION)?
+                .unwrap_or(false),
+            field_boosts: opts
+                .get::<_, Option<HashMap<String, f32>>>(Self::FIELD_BOOSTS)?
+                .unwrap_or_default(),
+        })
+    }
+
+    fn apply(&self, query_parser: &mut QueryParser, schema: &Schema) -> LuaResult<()> {
+        if self.conjunction {
+            query_parser.set_conjunction_by_default();
+        }
+        for (name, boost) in &self.field_boosts {
+            let field = schema
+                .get_field(name)
+                .ok_or_else(|| LuaError::RuntimeError(format!("No field '{}'", name)))?;
+            query_parser.set_field_boost(field, *boost);
+        }
+        Ok(())
+    }
+}
+
+/// Handle for cancelling a FileIndex update. See `FileIndex::cancel_handle`.
+#[derive(Debug, Clone)]
+struct CancelHandleLua {
+    flag: Arc<AtomicBool>,
+}
+
+impl UserData for CancelHandleLua {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("cancel", |_, this, _: ()| {
+            this.flag.store(true, Ordering::Relaxed);
+            Ok(())
+        });
+        methods.add_method("is_cancelled", |_, this, _: ()| {
+            Ok(this.flag.load(Ordering::Relaxed))
+        });
+    }
+}
+
+/// The number of documents to commit at a time when add_documents is passed a function
+const DEFAULT_BATCH_SIZE: usize = 10_000;
+
 #[derive(Debug, Clone)]
 struct GenericIndexLua {
     index: GenericIndex,
@@ -123,48 +358,94 @@ struct GenericIndexLua {
 impl UserData for GenericIndexLua {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method_mut("delete", |_, this, _: ()| {
-            this.index
-                .delete()
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+            this.index.delete().map_err(lua_error)?;
+            Ok(())
+        });
+        methods.add_method_mut(
+            "set_merge_policy",
+            |_, this, (opts,): (MergePolicyOptionsShape,)| {
+                this.index.set_merge_policy(opts.into());
+                Ok(())
+            },
+        );
+        methods.add_method_mut("optimize", |_, this, _: ()| {
+            this.index.optimize().map_err(lua_error)?;
+            Ok(())
+        });
+        // Start a batch, so that the following add, update, and delete calls don't commit until
+        // `commit` is called. See `GenericIndex::begin`.
+        methods.add_method_mut("begin", |_, this, _: ()| {
+            this.index.begin().map_err(lua_error)?;
+            Ok(())
+        });
+        methods.add_method_mut("commit", |_, this, _: ()| {
+            this.index.commit().map_err(lua_error)?;
             Ok(())
         });
         methods.add_method_mut("delete_documents", |_, this, (doc_ids,): (Vec<String>,)| {
-            this.index
-                .delete_documents(doc_ids)
-                .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+            this.index.delete_documents(doc_ids).map_err(lua_error)?;
             Ok(())
         });
         methods.add_method_mut(
             "update_documents",
             |_, this, (documents,): (Vec<mlua::Table>,)| {
-                this.index
-                    .update_documents(documents)
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+                this.index.update_documents(documents).map_err(lua_error)?;
                 Ok(())
             },
         );
         methods.add_method_mut(
             "add_documents",
-            |_, this, (documents,): (Vec<mlua::Table>,)| {
-                this.index
-                    .add_documents(documents)
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+            |lua, this, (documents, opts): (LuaValue, Option<LuaTable>)| {
+                match documents {
+                    // A function that returns one document per call (and nil when done). The
+                    // documents are committed in batches so the full set is never in memory.
+                    LuaValue::Function(next_document) => {
+                        let batch_size = opts
+                            .map(|o| o.get::<_, Option<usize>>("batch_size"))
+                            .transpose()?
+                            .flatten()
+                            .unwrap_or(DEFAULT_BATCH_SIZE);
+                        let documents = std::iter::from_fn(|| {
+                            next_document.call::<_, Option<LuaTable>>(()).transpose()
+                        });
+                        this.index
+                            .add_documents_batched(documents, batch_size)
+                            .map_err(lua_error)?;
+                    }
+                    _ => {
+                        let documents: Vec<LuaTable> = lua.unpack(documents)?;
+                        this.index.add_documents(documents).map_err(lua_error)?;
+                    }
+                }
                 Ok(())
             },
         );
+        // Accepts the same `conjunction` and `field_boosts` options as `FileIndexLua:search`. Like
+        // it, the returned list has `total`, the number of documents that matched the query.
         methods.add_method(
             "search",
-            |_, this, (query_str, opts): (String, SearchOptionsShape)| {
-                let query_parser =
+            |lua, this, (query_str, opts): (String, Option<LuaTable>)| {
+                let parser_opts = QueryParserOptions::from_opts(opts.as_ref())?;
+                let opts = SearchOptionsShape::from_lua_with_extra_keys(
+                    opts.map(LuaValue::Table).unwrap_or(LuaValue::Nil),
+                    &[
+                        QueryParserOptions::CONJUNCTION,
+                        QueryParserOptions::FIELD_BOOSTS,
+                    ],
+                )?;
+                let mut query_parser =
                     QueryParser::for_index(this.index.index(), this.index.get_text_fields());
+                parser_opts.apply(&mut query_parser, &this.index.index().schema())?;
                 let query = query_parser
                     .parse_query(&query_str)
-                    .map_err(|_| LuaError::RuntimeError("Error parsing query".to_string()))?;
-                let results = this
+                    .map_err(|err| lua_error(err.into()))?;
+                let (results, total) = this
                     .index
-                    .search(&query, &opts.into())
-                    .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-                Ok(results)
+                    .search_with_total(&query, &opts.into())
+                    .map_err(lua_error)?;
+                let tbl = lua.create_sequence_from(results)?;
+                tbl.set("total", total)?;
+                Ok(tbl)
             },
         );
         methods.add_meta_function(MetaMethod::ToString, |_, this: FileIndexLua| {
@@ -182,5 +463,9 @@ fn make_version_tbl(lua: &Lua) -> LuaResult<LuaTable> {
     set_nonempty_env!(tbl, "patch", "CARGO_PKG_VERSION_PATCH");
     set_nonempty_env!(tbl, "pre", "CARGO_PKG_VERSION_PRE");
 
+    let info = version_info();
+    tbl.set("tantivy", info.tantivy)?;
+    tbl.set("index_format", info.index_format)?;
+
     Ok(tbl)
 }